@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+
+/// How a layer's colors combine with the layers below it
+/// (formulas follow the W3C Compositing and Blending spec)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    Darken,
+    Lighten,
+    Difference,
+    ColorDodge,
+    ColorBurn,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 14] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::SoftLight,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::Difference,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+
+    /// Next mode in `ALL`, wrapping around (for cycling from the keyboard)
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|m| *m == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+
+    /// Previous mode in `ALL`, wrapping around
+    pub fn prev(self) -> Self {
+        let idx = Self::ALL.iter().position(|m| *m == self).unwrap_or(0);
+        Self::ALL[(idx + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+
+    /// Blend a backdrop color `cb` with a source color `cs` (both 0..1, unpremultiplied)
+    fn blend(self, cb: [f32; 3], cs: [f32; 3]) -> [f32; 3] {
+        match self {
+            BlendMode::Normal => cs,
+            BlendMode::Multiply => separable(cb, cs, |b, s| b * s),
+            BlendMode::Screen => separable(cb, cs, screen),
+            BlendMode::Overlay => separable(cb, cs, |b, s| hard_light(s, b)),
+            BlendMode::SoftLight => separable(cb, cs, soft_light),
+            BlendMode::Darken => separable(cb, cs, f32::min),
+            BlendMode::Lighten => separable(cb, cs, f32::max),
+            BlendMode::Difference => separable(cb, cs, |b, s| (b - s).abs()),
+            BlendMode::ColorDodge => separable(cb, cs, color_dodge),
+            BlendMode::ColorBurn => separable(cb, cs, color_burn),
+            BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+            BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+            BlendMode::Color => set_lum(cs, lum(cb)),
+            BlendMode::Luminosity => set_lum(cb, lum(cs)),
+        }
+    }
+}
+
+/// Composite a straight-alpha source pixel onto a straight-alpha destination pixel,
+/// scaling the source alpha by `opacity` and mixing colors with `mode`
+pub fn blend_pixel(dst: &mut [u8], src: [u8; 4], opacity: f32, mode: BlendMode) {
+    let sa = src[3] as f32 / 255.0 * opacity.clamp(0.0, 1.0);
+    if sa <= 0.0 {
+        return;
+    }
+    let da = dst[3] as f32 / 255.0;
+    let cs = [src[0] as f32 / 255.0, src[1] as f32 / 255.0, src[2] as f32 / 255.0];
+    let cb = [dst[0] as f32 / 255.0, dst[1] as f32 / 255.0, dst[2] as f32 / 255.0];
+
+    // Where the backdrop is transparent the source shows through unblended
+    let mixed = mode.blend(cb, cs);
+    let out_a = sa + da * (1.0 - sa);
+    for i in 0..3 {
+        let source = (1.0 - da) * cs[i] + da * mixed[i];
+        let c = (sa * source + da * cb[i] * (1.0 - sa)) / out_a;
+        dst[i] = (c * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    dst[3] = (out_a * 255.0).round() as u8;
+}
+
+/// Porter-Duff "over" of a straight-alpha source color onto a straight-alpha destination pixel
+pub fn blend_over(dst: &mut [u8], src: [u8; 4]) {
+    blend_pixel(dst, src, 1.0, BlendMode::Normal);
+}
+
+fn separable(cb: [f32; 3], cs: [f32; 3], f: impl Fn(f32, f32) -> f32) -> [f32; 3] {
+    [f(cb[0], cs[0]), f(cb[1], cs[1]), f(cb[2], cs[2])]
+}
+
+fn screen(b: f32, s: f32) -> f32 {
+    b + s - b * s
+}
+
+fn hard_light(b: f32, s: f32) -> f32 {
+    if s <= 0.5 {
+        b * 2.0 * s
+    } else {
+        screen(b, 2.0 * s - 1.0)
+    }
+}
+
+fn soft_light(b: f32, s: f32) -> f32 {
+    if s <= 0.5 {
+        b - (1.0 - 2.0 * s) * b * (1.0 - b)
+    } else {
+        let d = if b <= 0.25 {
+            ((16.0 * b - 12.0) * b + 4.0) * b
+        } else {
+            b.sqrt()
+        };
+        b + (2.0 * s - 1.0) * (d - b)
+    }
+}
+
+fn color_dodge(b: f32, s: f32) -> f32 {
+    if b <= 0.0 {
+        0.0
+    } else if s >= 1.0 {
+        1.0
+    } else {
+        (b / (1.0 - s)).min(1.0)
+    }
+}
+
+fn color_burn(b: f32, s: f32) -> f32 {
+    if b >= 1.0 {
+        1.0
+    } else if s <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - b) / s).min(1.0)
+    }
+}
+
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn clip_color(c: [f32; 3]) -> [f32; 3] {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut out = c;
+    for v in out.iter_mut() {
+        if n < 0.0 {
+            *v = l + (*v - l) * l / (l - n);
+        }
+        if x > 1.0 {
+            *v = l + (*v - l) * (1.0 - l) / (x - l);
+        }
+    }
+    out
+}
+
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+fn sat(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3] {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max <= min {
+        return [0.0; 3];
+    }
+    let mut out = [0.0; 3];
+    for i in 0..3 {
+        out[i] = (c[i] - min) * s / (max - min);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blended(dst: [u8; 4], src: [u8; 4], opacity: f32, mode: BlendMode) -> [u8; 4] {
+        let mut out = dst;
+        blend_pixel(&mut out, src, opacity, mode);
+        out
+    }
+
+    #[test]
+    fn test_normal_respects_opacity() {
+        let out = blended([255, 255, 255, 255], [0, 0, 0, 255], 0.5, BlendMode::Normal);
+        assert_eq!(out, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn test_multiply_and_screen() {
+        let multiply = blended([128, 255, 0, 255], [128, 128, 128, 255], 1.0, BlendMode::Multiply);
+        assert_eq!(multiply, [64, 128, 0, 255]);
+        let screen = blended([128, 255, 0, 255], [128, 128, 128, 255], 1.0, BlendMode::Screen);
+        assert_eq!(screen, [192, 255, 128, 255]);
+    }
+
+    #[test]
+    fn test_blend_over_transparent_backdrop_keeps_source() {
+        let out = blended([0, 0, 0, 0], [200, 10, 30, 255], 1.0, BlendMode::Difference);
+        assert_eq!(out, [200, 10, 30, 255]);
+    }
+
+    #[test]
+    fn test_luminosity_keeps_backdrop_hue() {
+        let out = blended([255, 0, 0, 255], [255, 255, 255, 255], 1.0, BlendMode::Luminosity);
+        assert_eq!(out, [255, 255, 255, 255]);
+        let out = blended([255, 0, 0, 255], [0, 0, 0, 255], 1.0, BlendMode::Luminosity);
+        assert_eq!(out, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_mode_cycling_wraps() {
+        assert_eq!(BlendMode::Luminosity.next(), BlendMode::Normal);
+        assert_eq!(BlendMode::Normal.prev(), BlendMode::Luminosity);
+    }
+}
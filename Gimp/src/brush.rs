@@ -4,11 +4,52 @@ use crate::canvas::Canvas;
 pub struct Brush {
     pub radius: f32,
     pub color: [u8; 4],
+    pub hardness: f32, // 0..=1, share of the radius painted at full strength before the edge fades
+    pub opacity: f32,  // 0..=1, most paint one stroke can lay down, however often dabs overlap
+    pub flow: f32,     // 0..=1, paint laid down by each dab
+    pub pencil: bool,  // Aliased edges: whole pixels in or out, for pixel art
 }
 
 impl Brush {
+    /// A hard, fully opaque brush
+    pub fn new(radius: f32, color: [u8; 4]) -> Self {
+        Self {
+            radius,
+            color,
+            hardness: 1.0,
+            opacity: 1.0,
+            flow: 1.0,
+            pencil: false,
+        }
+    }
+
+    /// Paint one dab lays down on the pixel whose center is `distance` from the
+    /// dab's: full inside the hard core, easing out to nothing at `radius`,
+    /// scaled by the flow. The edge covers pixels by the fraction of them
+    /// inside the circle; the pencil takes a pixel whole when its center is.
+    pub fn dab_alpha(&self, distance: f32, radius: f32) -> f32 {
+        let flow = self.flow.clamp(0.0, 1.0);
+        if self.pencil {
+            return if distance <= radius { flow } else { 0.0 };
+        }
+        // A one pixel ramp across the edge approximates the covered area;
+        // circles smaller than a pixel cover at most their own area
+        let edge = (radius + 0.5 - distance).clamp(0.0, 1.0) * (std::f32::consts::PI * radius * radius).min(1.0);
+        if edge <= 0.0 {
+            return 0.0;
+        }
+        let core = radius * self.hardness.clamp(0.0, 1.0);
+        let falloff = if distance <= core || core >= radius {
+            1.0
+        } else {
+            let t = ((distance - core) / (radius - core)).min(1.0);
+            1.0 - t * t * (3.0 - 2.0 * t)
+        };
+        falloff * edge * flow
+    }
+
     pub fn stamp(&self, canvas: &mut Canvas, pos: (f32, f32)) {
-        canvas.stamp_circle(pos.0, pos.1, self.radius, self.color);
+        canvas.stamp_dab(pos.0, pos.1, self);
     }
 
     pub fn stroke(&self, canvas: &mut Canvas, from: (f32, f32), to: (f32, f32)) {
@@ -21,7 +62,7 @@ impl Brush {
         let mut x = from.0;
         let mut y = from.1;
         for _ in 0..=steps as i32 {
-            canvas.stamp_circle(x, y, self.radius, self.color);
+            canvas.stamp_dab(x, y, self);
             x += step_x;
             y += step_y;
         }
@@ -1,434 +1,743 @@
 use wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
 
+use crate::blend::blend_over;
+use crate::brush::Brush;
+use crate::crop::{CropRect, HANDLE_SIZE};
+use crate::document::{Anchor, Document, LayerPath};
+use crate::free_transform::{rect_quad, warp, warp_layer, FreeTransform, Quad};
+use crate::layer::{place_pixels, Layer};
+use crate::resample::Filter;
+use crate::selection::{mix_gray, mix_pixel, Selection, WandOptions};
+use crate::transform::Transform;
+
 pub struct Canvas {
     pub width: u32,
     pub height: u32,
     pub stride: usize,
     pub pixels: Vec<u8>, // Composite display buffer
     pub dirty: bool,
-    pub loaded_image_size: Option<(u32, u32)>, // Track size of loaded image for panning
-    pub loaded_image_data: Option<Vec<u8>>, // Store loaded image for re-panning
+    pub document: Document, // Layer stack being edited, in IMAGE-SPACE coordinates
+    composite: Vec<u8>, // Cached composite of the visible layers (tight RGBA, image-space)
     pub zoom_scale: f32, // Zoom level (1.0 = 100%, 2.0 = 200%, etc.)
-    pub drawing_layer: Vec<u8>, // User drawings layer in IMAGE-SPACE coordinates
     pub pan_offset: (i32, i32), // Store pan offset so drawings can use it
-    pub grayscale_active: bool, // Toggle state for grayscale filter
-    pub original_image_backup: Option<Vec<u8>>, // Backup before filters for restoration
+    pub ants_phase: u32, // Animation step of the marching ants around the selection
+    pub crop_preview: Option<CropRect>, // Pending crop tool rectangle, in image space
+    pub crop_thirds: bool, // Draw rule-of-thirds lines inside the crop rectangle
+    pub free_transform: Option<FreeTransform>, // Transform tool box and the pixels it holds
+    stroke: Option<Stroke>, // Brush stroke being painted
+}
+
+/// What a brush stroke paints into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StrokeTarget {
+    Pixels,    // Active layer RGBA
+    LayerMask, // Active layer mask
+    QuickMask, // Selection, while in quick mask mode
+}
+
+/// Brush stroke in progress: the target as it was before the stroke and how
+/// much paint each pixel has collected, so overlapping dabs build up to the
+/// brush opacity and no further
+struct Stroke {
+    target: StrokeTarget,
+    layer: LayerPath, // Active layer when the stroke started
+    origin: (i32, i32), // Image position of the first pixel of `before`
+    before: Vec<u8>,
+    coverage: Vec<f32>, // 0..=1 per image pixel
+}
+
+impl Stroke {
+    /// True when the stroke was painting `target` of `layer`, over a buffer
+    /// covering the image-space rectangle `bounds`
+    fn paints(&self, target: StrokeTarget, layer: &LayerPath, bounds: (i32, i32, i32, i32), channels: usize) -> bool {
+        let (x0, y0, x1, y1) = bounds;
+        self.target == target
+            && self.layer == *layer
+            && self.origin == (x0, y0)
+            && self.before.len() == (x1 - x0) as usize * (y1 - y0) as usize * channels
+    }
+
+    /// The stroke over `buffer`, grown from `old` to start at `origin`, with
+    /// what it painted so far kept in place
+    fn grown(self, old: (i32, i32, i32, i32), origin: (i32, i32), stride: u32, buffer: &[u8], channels: usize) -> Self {
+        let width = (old.2 - old.0) as usize;
+        let (dx, dy) = ((old.0 - origin.0) as usize, (old.1 - origin.1) as usize);
+        let mut before = buffer.to_vec();
+        let mut coverage = vec![0.0; buffer.len() / channels];
+        let rows = self.before.chunks_exact(width * channels).zip(self.coverage.chunks_exact(width));
+        for (row, (old_before, old_coverage)) in rows.enumerate() {
+            let start = (dy + row) * stride as usize + dx;
+            before[start * channels..(start + width) * channels].copy_from_slice(old_before);
+            coverage[start..start + width].copy_from_slice(old_coverage);
+        }
+        Stroke { origin, before, coverage, ..self }
+    }
+}
+
+/// Active layer contents inside the image-space rectangle [x0, x1) x [y0, y1),
+/// taken before an edit so the edit can be faded back outside the selection
+struct EditSnapshot {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    pixels: Vec<[u8; 4]>,
+    mask: Option<Vec<u8>>,
 }
 
 impl Canvas {
     pub fn new(width: u32, height: u32) -> Self {
         let stride = aligned_stride(width);
         let pixels = vec![255; stride * height as usize];
-        let drawing_layer = vec![]; // Will be sized to match loaded image
-        Self {
+        let document = Document::new(width, height);
+        let mut canvas = Self {
             width,
             height,
             stride,
             pixels,
             dirty: false,
-            loaded_image_size: None,
-            loaded_image_data: None,
+            composite: vec![],
             zoom_scale: 1.0,
-            drawing_layer,
+            document,
             pan_offset: (0, 0),
-            grayscale_active: false,
-            original_image_backup: None,
-        }
+            ants_phase: 0,
+            crop_preview: None,
+            crop_thirds: false,
+            free_transform: None,
+            stroke: None,
+        };
+        canvas.refresh();
+        canvas
     }
 
-    /// Load pixels from a tight-packed RGBA buffer (width*height*4)
-    /// and expand them to canvas stride
-    pub fn load_pixels(&mut self, width: u32, height: u32, tight_pixels: Vec<u8>) {
-        if width != self.width || height != self.height {
-            return;
-        }
-        
-        let tight_stride = width as usize * 4;
-        for y in 0..height as usize {
-            let tight_offset = y * tight_stride;
-            let canvas_offset = y * self.stride;
-            
-            if tight_offset + tight_stride <= tight_pixels.len() 
-                && canvas_offset + tight_stride <= self.pixels.len() {
-                self.pixels[canvas_offset..canvas_offset + tight_stride]
-                    .copy_from_slice(&tight_pixels[tight_offset..tight_offset + tight_stride]);
-            }
-        }
-        self.dirty = true;
+    /// Replace the document being edited and reset the view to 100% at the origin
+    pub fn set_document(&mut self, document: Document) {
+        self.document = document;
+        self.crop_preview = None;
+        self.zoom_scale = 1.0;
+        self.pan_offset = (0, 0);
+        self.refresh();
     }
 
-    /// Extract tight-packed RGBA pixels (without stride padding) for saving
-    pub fn extract_tight_pixels(&self) -> Vec<u8> {
-        let row_bytes = self.width as usize * 4;
-        let mut tight_pixels = Vec::with_capacity(row_bytes * self.height as usize);
-        
-        for y in 0..self.height as usize {
-            let row_offset = y * self.stride;
-            if row_offset + row_bytes <= self.pixels.len() {
-                tight_pixels.extend_from_slice(&self.pixels[row_offset..row_offset + row_bytes]);
-            }
-        }
-        
-        tight_pixels
+    /// Start a new document from an imported image (like GIMP's File > Open)
+    pub fn import_image(&mut self, layer: Layer) {
+        let (width, height) = (layer.width, layer.height);
+        self.set_document(Document::from_layers(width, height, vec![layer]));
     }
 
-    /// Extract the actual image content (background + drawing layer) without UI overlay for export
+    /// Swap in a document snapshot (undo/redo) while keeping the current view
+    pub fn restore_document(&mut self, document: Document) {
+        self.document = document;
+        self.free_transform = None;
+        self.stroke = None;
+        self.refresh();
+    }
+
+    /// Reallocate the display buffer for a new window size, keeping the document
+    pub fn resize_view(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.stride = aligned_stride(width);
+        self.pixels = vec![255; self.stride * height as usize];
+        self.render_view();
+    }
+
+    /// Extract the actual image content (all visible layers) without UI overlay for export
     pub fn extract_image_pixels(&self) -> Vec<u8> {
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            let img_stride = img_w as usize * 4;
-            let mut result = vec![255u8; (img_w * img_h * 4) as usize];
-            
-            // Copy background image if present
-            if let Some(img_data) = &self.loaded_image_data {
-                result.copy_from_slice(img_data);
-            }
-            
-            // Composite drawing layer on top
-            for y in 0..img_h {
-                for x in 0..img_w {
-                    let idx = (y as usize * img_stride) + (x as usize * 4);
-                    if idx + 3 < self.drawing_layer.len() {
-                        let alpha = self.drawing_layer[idx + 3] as f32 / 255.0;
-                        if alpha > 0.0 && idx + 3 < result.len() {
-                            for c in 0..3 {
-                                let bg = result[idx + c] as f32;
-                                let fg = self.drawing_layer[idx + c] as f32;
-                                result[idx + c] = (fg * alpha + bg * (1.0 - alpha)) as u8;
-                            }
-                        }
-                    }
-                }
-            }
-            result
+        self.document.composite()
+    }
+
+    /// Convert canvas (screen) coordinates to image coordinates using zoom/pan
+    pub fn canvas_to_image(&self, x: f32, y: f32) -> (f32, f32) {
+        let (offset_x, offset_y) = self.pan_offset;
+        (x / self.zoom_scale - offset_x as f32, y / self.zoom_scale - offset_y as f32)
+    }
+
+    /// Integer image pixel under a canvas pixel, if it lies inside the document
+    fn canvas_to_image_pixel(&self, x: u32, y: u32) -> Option<(u32, u32)> {
+        let (offset_x, offset_y) = self.pan_offset;
+        let img_x = ((x as f32 / self.zoom_scale) as i32) - offset_x;
+        let img_y = ((y as f32 / self.zoom_scale) as i32) - offset_y;
+        if img_x >= 0 && img_x < self.document.width as i32 && img_y >= 0 && img_y < self.document.height as i32 {
+            Some((img_x as u32, img_y as u32))
         } else {
-            // Fallback to canvas size if no image loaded
-            self.extract_tight_pixels()
+            None
         }
     }
 
-    /// Paste an image onto the canvas with offset (for panning large images)
-    /// This updates the background layer only, not the drawing layer
-    pub fn paste_image_with_offset(&mut self, img_width: u32, img_height: u32, img_pixels: &[u8], offset_x: i32, offset_y: i32) {
-        let is_new_image = self.loaded_image_size != Some((img_width, img_height));
-        
-        self.loaded_image_size = Some((img_width, img_height));
-        self.loaded_image_data = Some(img_pixels.to_vec());
-        self.pan_offset = (offset_x, offset_y);
-        
-        // Backup original image on first load
-        if is_new_image {
-            self.original_image_backup = Some(img_pixels.to_vec());
-        }
-        
-        // Initialize drawing layer to match image size if new image
-        if is_new_image {
-            self.drawing_layer = vec![0; (img_width * img_height * 4) as usize];
-        }
-        
-        let img_stride = img_width as usize * 4;
-        
-        // Render the background image with zoom/pan
-        for canvas_y in 0..self.height {
-            for canvas_x in 0..self.width {
-                // Calculate source image coordinates with zoom
-                let img_x = ((canvas_x as f32 / self.zoom_scale) as i32) - offset_x;
-                let img_y = ((canvas_y as f32 / self.zoom_scale) as i32) - offset_y;
-                
+    /// Recomposite the whole document and redraw the view
+    pub fn refresh(&mut self) {
+        self.composite = self.document.composite();
+        self.render_view();
+    }
+
+    /// Recomposite only the image-space rectangle [x0, x1) x [y0, y1) and redraw it
+    pub fn refresh_region(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) {
+        let expected = self.document.width as usize * self.document.height as usize * 4;
+        if self.composite.len() != expected {
+            self.refresh();
+            return;
+        }
+        self.document.composite_region(&mut self.composite, x0, y0, x1, y1);
+
+        // Map the image rectangle back to the canvas pixels that sample it
+        let (offset_x, offset_y) = self.pan_offset;
+        let cx0 = ((x0 as i32 + offset_x) as f32 * self.zoom_scale).floor().max(0.0) as u32;
+        let cy0 = ((y0 as i32 + offset_y) as f32 * self.zoom_scale).floor().max(0.0) as u32;
+        let cx1 = ((x1 as i32 + offset_x) as f32 * self.zoom_scale).ceil().max(0.0) as u32;
+        let cy1 = ((y1 as i32 + offset_y) as f32 * self.zoom_scale).ceil().max(0.0) as u32;
+        self.render_rect(cx0, cy0, cx1.saturating_add(1), cy1.saturating_add(1));
+    }
+
+    /// Redraw the whole view from the cached composite (after pan/zoom)
+    pub fn render_view(&mut self) {
+        self.render_rect(0, 0, self.width, self.height);
+    }
+
+    /// Redraw canvas pixels [x0, x1) x [y0, y1) from the cached composite,
+    /// flattening onto white and filling white outside the image bounds
+    fn render_rect(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) {
+        let x1 = x1.min(self.width);
+        let y1 = y1.min(self.height);
+        let img_stride = self.document.width as usize * 4;
+        for canvas_y in y0..y1 {
+            for canvas_x in x0..x1 {
                 let canvas_idx = (canvas_y as usize * self.stride) + (canvas_x as usize * 4);
-                
-                if img_x >= 0 && img_x < img_width as i32 && img_y >= 0 && img_y < img_height as i32 {
+                if canvas_idx + 4 > self.pixels.len() {
+                    continue;
+                }
+                let mut color = [255u8, 255, 255, 255];
+                if let Some((img_x, img_y)) = self.canvas_to_image_pixel(canvas_x, canvas_y) {
                     let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                    
-                    if img_idx + 4 <= img_pixels.len() && canvas_idx + 4 <= self.pixels.len() {
-                        self.pixels[canvas_idx..canvas_idx + 4].copy_from_slice(&img_pixels[img_idx..img_idx + 4]);
-                    }
-                } else {
-                    // Fill with white outside image bounds
-                    if canvas_idx + 4 <= self.pixels.len() {
-                        self.pixels[canvas_idx..canvas_idx + 4].copy_from_slice(&[255, 255, 255, 255]);
+                    if img_idx + 4 <= self.composite.len() {
+                        let src = &self.composite[img_idx..img_idx + 4];
+                        let alpha = src[3] as f32 / 255.0;
+                        for j in 0..3 {
+                            color[j] = (src[j] as f32 * alpha + 255.0 * (1.0 - alpha)).round() as u8;
+                        }
                     }
                 }
+                if let Some(coverage) = self.quick_mask_coverage(canvas_x, canvas_y) {
+                    color = quick_mask_tint(color, coverage);
+                } else if let Some(ant) = self.marching_ant(canvas_x, canvas_y) {
+                    color = ant;
+                }
+                if let Some(crop) = self.crop_preview {
+                    color = self.crop_overlay(crop, canvas_x, canvas_y, color);
+                }
+                if let Some(quad) = self.free_transform.as_ref().map(|t| t.quad) {
+                    color = self.transform_overlay(&quad, canvas_x, canvas_y).unwrap_or(color);
+                }
+                self.pixels[canvas_idx..canvas_idx + 4].copy_from_slice(&color);
             }
         }
-        
-        // Composite drawing layer on top
-        self.composite_layers();
-        // If grayscale is active, re-apply to the display buffer so panning keeps the effect
-        if self.grayscale_active {
-            self.apply_grayscale_to_display();
-        }
         self.dirty = true;
     }
-    
-    /// Composite the drawing layer on top of the background
-    /// Drawing layer is in image-space, so we need to transform coordinates
-    fn composite_layers(&mut self) {
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            let img_stride = img_w as usize * 4;
-            let (offset_x, offset_y) = self.pan_offset;
-            
-            for canvas_y in 0..self.height {
-                for canvas_x in 0..self.width {
-                    // Convert canvas coords to image coords
-                    let img_x = ((canvas_x as f32 / self.zoom_scale) as i32) - offset_x;
-                    let img_y = ((canvas_y as f32 / self.zoom_scale) as i32) - offset_y;
-                    
-                    if img_x >= 0 && img_x < img_w as i32 && img_y >= 0 && img_y < img_h as i32 {
-                        let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                        let canvas_idx = (canvas_y as usize * self.stride) + (canvas_x as usize * 4);
-                        
-                        if img_idx + 3 < self.drawing_layer.len() && canvas_idx + 3 < self.pixels.len() {
-                            let alpha = self.drawing_layer[img_idx + 3] as f32 / 255.0;
-                            if alpha > 0.0 {
-                                // Alpha blend drawing on top of background
-                                for j in 0..3 {
-                                    let bg = self.pixels[canvas_idx + j] as f32;
-                                    let fg = self.drawing_layer[img_idx + j] as f32;
-                                    self.pixels[canvas_idx + j] = (fg * alpha + bg * (1.0 - alpha)) as u8;
-                                }
-                            }
-                        }
-                    }
-                }
+
+    /// Crop tool display at a canvas pixel: the image outside the crop is
+    /// shaded, and the rectangle gets an outline, handles and optional thirds
+    fn crop_overlay(&self, crop: CropRect, x: u32, y: u32, color: [u8; 4]) -> [u8; 4] {
+        let (offset_x, offset_y) = self.pan_offset;
+        let to_canvas = |v: i32, offset: i32| ((v + offset) as f32 * self.zoom_scale).round() as i64;
+        let (cx0, cy0) = (to_canvas(crop.x0, offset_x), to_canvas(crop.y0, offset_y));
+        let (cx1, cy1) = (to_canvas(crop.x1, offset_x), to_canvas(crop.y1, offset_y));
+        let (x, y) = (x as i64, y as i64);
+        if x < cx0 || x >= cx1 || y < cy0 || y >= cy1 {
+            return [color[0] / 2, color[1] / 2, color[2] / 2, 255];
+        }
+
+        // Handles sit inside the corners and edge midpoints; the middle one is the body
+        let size = HANDLE_SIZE as i64;
+        let band = |v: i64, lo: i64, hi: i64| {
+            if v < lo + size {
+                Some(0)
+            } else if v >= hi - size {
+                Some(2)
+            } else if (v - (lo + hi) / 2).abs() < size / 2 {
+                Some(1)
+            } else {
+                None
             }
+        };
+        if let (Some(bx), Some(by)) = (band(x, cx0, cx1), band(y, cy0, cy1))
+            && (bx, by) != (1, 1)
+        {
+            return [255, 255, 255, 255];
+        }
+        if x == cx0 || x == cx1 - 1 || y == cy0 || y == cy1 - 1 {
+            return [255, 255, 255, 255];
         }
+        let on_third = |v: i64, lo: i64, hi: i64| (1..3).any(|k| v == lo + (hi - lo) * k / 3);
+        if self.crop_thirds && (on_third(x, cx0, cx1) || on_third(y, cy0, cy1)) {
+            let lighten = |c: u8| ((c as u16 + 255) / 2) as u8;
+            return [lighten(color[0]), lighten(color[1]), lighten(color[2]), 255];
+        }
+        color
     }
-    
-    /// Re-render the loaded image with a new offset
-    pub fn repan_image(&mut self, offset_x: i32, offset_y: i32) {
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            if let Some(img_data) = self.loaded_image_data.clone() {
-                self.paste_image_with_offset(img_w, img_h, &img_data, offset_x, offset_y);
+
+    /// Transform tool display at a canvas pixel: the box outline and square
+    /// handles at its corners and edge midpoints
+    fn transform_overlay(&self, quad: &Quad, x: u32, y: u32) -> Option<[u8; 4]> {
+        let (offset_x, offset_y) = self.pan_offset;
+        let corners = quad.map(|(qx, qy)| ((qx + offset_x as f32) * self.zoom_scale, (qy + offset_y as f32) * self.zoom_scale));
+        let point = (x as f32 + 0.5, y as f32 + 0.5);
+
+        let half = HANDLE_SIZE as f32 / 2.0;
+        let midpoints = (0..4).map(|i| {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0)
+        });
+        for handle in corners.iter().copied().chain(midpoints) {
+            let (dx, dy) = ((point.0 - handle.0).abs(), (point.1 - handle.1).abs());
+            if dx <= half && dy <= half {
+                let border = dx > half - 1.0 || dy > half - 1.0;
+                return Some(if border { [0, 0, 0, 255] } else { [255, 255, 255, 255] });
             }
         }
+        for i in 0..4 {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            let (ex, ey) = (b.0 - a.0, b.1 - a.1);
+            let length = ex * ex + ey * ey;
+            let t = if length > 0.0 { (((point.0 - a.0) * ex + (point.1 - a.1) * ey) / length).clamp(0.0, 1.0) } else { 0.0 };
+            let (cx, cy) = (a.0 + t * ex - point.0, a.1 + t * ey - point.1);
+            if cx * cx + cy * cy <= 0.25 {
+                return Some([0, 0, 0, 255]);
+            }
+        }
+        None
     }
 
-    /// Paste an image onto the canvas at position (0,0) without scaling
-    /// Only the overlapping region is copied (like GIMP)
-    pub fn paste_image(&mut self, img_width: u32, img_height: u32, img_pixels: &[u8]) {
-        self.paste_image_with_offset(img_width, img_height, img_pixels, 0, 0);
+    /// Start a free transform on the floating layer, the selection or the
+    /// active layer. The lifted pixels show as a floating layer while dragged.
+    pub fn begin_free_transform(&mut self) -> bool {
+        let original = self.document.clone();
+        let Some((source, whole_layer)) = self.document.lift_active() else {
+            return false;
+        };
+        let (width, height) = (source.layer.width as f32, source.layer.height as f32);
+        let quad = rect_quad(source.x as f32, source.y as f32, width, height);
+        self.document.floating = Some(source.clone());
+        self.free_transform = Some(FreeTransform { source, quad, original, whole_layer });
+        self.refresh();
+        true
     }
 
-    pub fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
-        if x >= self.width || y >= self.height {
+    /// Move the transform box, previewing with nearest-neighbour sampling
+    pub fn preview_free_transform(&mut self, quad: Quad) {
+        let (width, height) = (self.document.width, self.document.height);
+        let Some(transform) = self.free_transform.as_mut() else {
             return;
+        };
+        transform.quad = quad;
+        let clip = (0, 0, width as i32, height as i32);
+        self.document.floating = Some(warp_layer(&transform.source.layer, &quad, Filter::Nearest, clip));
+        self.refresh();
+    }
+
+    /// Resample the lifted pixels into the box once with `filter`. Pixels from
+    /// the whole layer go back into it; a selection stays floating, with the
+    /// selection warped to match.
+    pub fn commit_free_transform(&mut self, filter: Filter) -> bool {
+        let Some(transform) = self.free_transform.take() else {
+            return false;
+        };
+        let (width, height) = (self.document.width, self.document.height);
+        let source = &transform.source;
+        // Pixels going back into the layer keep what lands past the image edges,
+        // as far out as the layer already reaches; the rest of the box is dropped
+        let clip = match self.document.active_layer().map(|l| l.bounds()) {
+            Some((x0, y0, x1, y1)) if transform.whole_layer => {
+                (x0.min(0), y0.min(0), x1.max(width as i32), y1.max(height as i32))
+            }
+            _ => (0, 0, width as i32, height as i32),
+        };
+        self.document.floating = Some(warp_layer(&source.layer, &transform.quad, filter, clip));
+        if let Some(selection) = self.document.selection.as_mut() {
+            let size = (source.layer.width, source.layer.height);
+            let region = place_pixels(&selection.mask, (width, height), &[0], size, (-source.x, -source.y));
+            selection.mask = match warp(&region, size, &[0], &transform.quad, filter, (0, 0, width as i32, height as i32)) {
+                Some((mask, (x, y, w, h))) => place_pixels(&mask, (w, h), &[0], (width, height), (x, y)),
+                None => vec![0; width as usize * height as usize],
+            };
         }
-        let idx = y as usize * self.stride + x as usize * 4;
-        self.pixels[idx..idx + 4].copy_from_slice(&color);
-        self.dirty = true;
+        if self.document.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.document.selection = None;
+        }
+        if transform.whole_layer {
+            self.document.anchor_floating();
+        }
+        self.refresh();
+        true
     }
 
-    pub fn blend_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
-        if x >= self.width || y >= self.height {
-            return;
+    /// Drop the free transform and put the document back as it was
+    pub fn cancel_free_transform(&mut self) {
+        if let Some(transform) = self.free_transform.take() {
+            self.document = transform.original;
+            self.refresh();
         }
-        
-        // Convert canvas coordinates to image coordinates (so drawing moves with pan/zoom)
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            let (offset_x, offset_y) = self.pan_offset;
-            
-            let img_x = ((x as f32 / self.zoom_scale) as i32) - offset_x;
-            let img_y = ((y as f32 / self.zoom_scale) as i32) - offset_y;
-            
-            if img_x >= 0 && img_x < img_w as i32 && img_y >= 0 && img_y < img_h as i32 {
-                // Store in drawing layer at image coordinates
-                let img_stride = img_w as usize * 4;
-                let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                
-                if img_idx + 4 <= self.drawing_layer.len() {
-                    let dst = &mut self.drawing_layer[img_idx..img_idx + 4];
-                    let a = color[3] as f32 / 255.0;
-                    for i in 0..4 {
-                        let src_v = color[i] as f32;
-                        let dst_v = dst[i] as f32;
-                        dst[i] = (src_v * a + dst_v * (1.0 - a)).round() as u8;
-                    }
-                }
-            }
+    }
+
+    /// Crop the document to `rect` (clipped to the image) and redraw.
+    /// Returns false when nothing would be left.
+    pub fn crop(&mut self, rect: CropRect) -> bool {
+        let rect = rect.clamped(self.document.width, self.document.height);
+        if rect.is_empty() {
+            return false;
         }
-        
-        // Also update the display buffer at canvas coordinates
-        let idx = y as usize * self.stride + x as usize * 4;
-        if idx + 4 <= self.pixels.len() {
-            let display_dst = &mut self.pixels[idx..idx + 4];
-            let a = color[3] as f32 / 255.0;
-            for i in 0..4 {
-                let src_v = color[i] as f32;
-                let dst_v = display_dst[i] as f32;
-                display_dst[i] = (src_v * a + dst_v * (1.0 - a)).round() as u8;
-            }
+        let (x, y) = (rect.x0 as u32, rect.y0 as u32);
+        self.document.crop(x, y, rect.width(), rect.height());
+        self.crop_preview = None;
+        self.refresh();
+        true
+    }
+
+    /// Image > Canvas Size: resize the document around `anchor` and redraw
+    pub fn resize_canvas(&mut self, width: u32, height: u32, anchor: Anchor, fill: [u8; 4], resize_layers: bool) -> bool {
+        if !self.document.resize_canvas(width, height, anchor, fill, resize_layers) {
+            return false;
         }
-        
-        self.dirty = true;
+        self.crop_preview = None;
+        self.refresh();
+        true
     }
 
-    pub fn stamp_circle(&mut self, cx: f32, cy: f32, radius: f32, color: [u8; 4]) {
-        if radius <= 0.0 {
-            return;
+    /// Image > Scale Image: resample the document to `width` x `height` and redraw
+    pub fn scale_image(&mut self, width: u32, height: u32, filter: Filter) -> bool {
+        if !self.document.scale(width, height, filter) {
+            return false;
         }
-        let r2 = radius * radius;
-        let min_x = (cx - radius).floor().max(0.0) as i32;
-        let max_x = (cx + radius).ceil().min((self.width - 1) as f32) as i32;
-        let min_y = (cy - radius).floor().max(0.0) as i32;
-        let max_y = (cy + radius).ceil().min((self.height - 1) as f32) as i32;
+        self.crop_preview = None;
+        self.refresh();
+        true
+    }
 
+    /// Image > Transform: rotate or mirror the whole image and redraw
+    pub fn transform_image(&mut self, transform: Transform) {
+        self.document.transform(transform);
+        self.crop_preview = None;
+        self.refresh();
+    }
+
+    /// Image > Crop to Selection: crop to the bounding box of the selection
+    pub fn crop_to_selection(&mut self) -> bool {
+        let Some((x0, y0, x1, y1)) = self.document.selection.as_ref().and_then(|s| s.bounds()) else {
+            return false;
+        };
+        self.crop(CropRect { x0: x0 as i32, y0: y0 as i32, x1: x1 as i32, y1: y1 as i32 })
+    }
+
+    /// Turn quick mask on (the selection becomes a red overlay that the brush
+    /// and eraser paint into) or off (the painted overlay becomes the selection)
+    pub fn toggle_quick_mask(&mut self) {
+        let (width, height) = (self.document.width, self.document.height);
+        self.document.quick_mask = !self.document.quick_mask;
+        if self.document.quick_mask {
+            self.document.selection.get_or_insert_with(|| Selection::new(width, height));
+        } else if self.document.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.document.selection = None;
+        }
+        self.render_view();
+    }
+
+    /// Selection coverage under a canvas pixel while quick mask is shown
+    fn quick_mask_coverage(&self, x: u32, y: u32) -> Option<u8> {
+        if !self.document.quick_mask {
+            return None;
+        }
+        let selection = self.document.selection.as_ref()?;
+        let (img_x, img_y) = self.canvas_to_image_pixel(x, y)?;
+        Some(selection.value(img_x, img_y))
+    }
+
+    /// Paint a gray value into the quick mask selection over a dab (255 selects, 0 deselects)
+    fn paint_quick_mask(&mut self, dab: (f32, f32, f32, u32, u32, u32, u32), gray: u8, alpha: u8) {
+        let (ix, iy, r, min_x, min_y, max_x, max_y) = dab;
+        let (width, height) = (self.document.width, self.document.height);
+        let selection = self.document.selection.get_or_insert_with(|| Selection::new(width, height));
+        let a = alpha as f32 / 255.0;
         for y in min_y..=max_y {
             for x in min_x..=max_x {
-                let dx = x as f32 + 0.5 - cx;
-                let dy = y as f32 + 0.5 - cy;
-                if dx * dx + dy * dy <= r2 {
-                    self.blend_pixel(x as u32, y as u32, color);
+                let dx = x as f32 + 0.5 - ix;
+                let dy = y as f32 + 0.5 - iy;
+                if dx * dx + dy * dy <= r * r {
+                    let value = &mut selection.mask[(y * selection.width + x) as usize];
+                    *value = (gray as f32 * a + *value as f32 * (1.0 - a)).round() as u8;
                 }
             }
         }
+        self.refresh_region(min_x, min_y, max_x + 1, max_y + 1);
     }
 
-    /// Erase a circle (set pixels to transparent in drawing layer)
-    pub fn erase_circle(&mut self, cx: f32, cy: f32, radius: f32) {
-        if radius <= 0.0 {
+    /// Whether the canvas pixel shows a selected image pixel
+    fn canvas_pixel_selected(&self, x: i64, y: i64) -> bool {
+        let Some(selection) = &self.document.selection else {
+            return false;
+        };
+        if x < 0 || y < 0 {
+            return false;
+        }
+        let (offset_x, offset_y) = self.pan_offset;
+        let img_x = (x as f32 / self.zoom_scale).floor() as i64 - offset_x as i64;
+        let img_y = (y as f32 / self.zoom_scale).floor() as i64 - offset_y as i64;
+        selection.contains(img_x, img_y)
+    }
+
+    /// Color of the selection outline at a canvas pixel: a one pixel wide dashed
+    /// line just inside the selection edge, shifted by `ants_phase` to animate it
+    fn marching_ant(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        self.document.selection.as_ref()?;
+        if self.document.quick_mask {
+            return None;
+        }
+        let (x, y) = (x as i64, y as i64);
+        if !self.canvas_pixel_selected(x, y) {
+            return None;
+        }
+        let on_edge = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .any(|(dx, dy)| !self.canvas_pixel_selected(x + dx, y + dy));
+        if !on_edge {
+            return None;
+        }
+        let dash = (x + y + self.ants_phase as i64).rem_euclid(8) < 4;
+        Some(if dash { [0, 0, 0, 255] } else { [255, 255, 255, 255] })
+    }
+
+    /// Advance the marching ants and redraw the part of the view they cover
+    pub fn animate_selection(&mut self) {
+        let Some((x0, y0, x1, y1)) = self.document.selection.as_ref().and_then(|s| s.bounds()) else {
             return;
+        };
+        self.ants_phase = (self.ants_phase + 1) % 8;
+        let (offset_x, offset_y) = self.pan_offset;
+        let cx0 = ((x0 as i32 + offset_x) as f32 * self.zoom_scale).floor().max(0.0) as u32;
+        let cy0 = ((y0 as i32 + offset_y) as f32 * self.zoom_scale).floor().max(0.0) as u32;
+        let cx1 = ((x1 as i32 + offset_x) as f32 * self.zoom_scale).ceil().max(0.0) as u32;
+        let cy1 = ((y1 as i32 + offset_y) as f32 * self.zoom_scale).ceil().max(0.0) as u32;
+        self.render_rect(cx0, cy0, cx1.saturating_add(1), cy1.saturating_add(1));
+    }
+
+    /// Copy the active layer inside the image-space rectangle [x0, x1) x [y0, y1)
+    /// before an edit. Returns None when nothing is selected, in which case
+    /// edits apply everywhere.
+    fn snapshot_for_selection(&self, x0: i32, y0: i32, x1: i32, y1: i32) -> Option<EditSnapshot> {
+        self.document.selection.as_ref()?;
+        if self.document.quick_mask && self.document.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            // A fresh quick mask selects nothing yet; don't block every edit
+            return None;
         }
-        let r2 = radius * radius;
-        let min_x = (cx - radius).floor().max(0.0) as i32;
-        let max_x = (cx + radius).ceil().min((self.width - 1) as f32) as i32;
-        let min_y = (cy - radius).floor().max(0.0) as i32;
-        let max_y = (cy + radius).ceil().min((self.height - 1) as f32) as i32;
+        let layer = self.document.active_layer()?;
+        let mut pixels = Vec::new();
+        let mut mask = layer.mask.as_ref().map(|_| Vec::new());
+        for y in y0..y1 {
+            for x in x0..x1 {
+                pixels.push(layer.pixel_at(x, y));
+                if let Some(mask) = mask.as_mut() {
+                    mask.push(layer.mask_at(x, y));
+                }
+            }
+        }
+        Some(EditSnapshot { x0, y0, x1, y1, pixels, mask })
+    }
 
-        for y in min_y..=max_y {
-            for x in min_x..=max_x {
-                let dx = x as f32 + 0.5 - cx;
-                let dy = y as f32 + 0.5 - cy;
-                if dx * dx + dy * dy <= r2 {
-                    let canvas_x = x as u32;
-                    let canvas_y = y as u32;
-                    
-                    if canvas_x >= self.width || canvas_y >= self.height {
-                        continue;
-                    }
-                    
-                    // Erase from drawing layer if we have an image
-                    if let Some((img_w, img_h)) = self.loaded_image_size {
-                        let (offset_x, offset_y) = self.pan_offset;
-                        let img_x = ((canvas_x as f32 / self.zoom_scale) as i32) - offset_x;
-                        let img_y = ((canvas_y as f32 / self.zoom_scale) as i32) - offset_y;
-                        
-                        if img_x >= 0 && img_x < img_w as i32 && img_y >= 0 && img_y < img_h as i32 {
-                            let img_stride = img_w as usize * 4;
-                            let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                            if img_idx + 4 <= self.drawing_layer.len() {
-                                self.drawing_layer[img_idx..img_idx + 4].copy_from_slice(&[255, 255, 255, 0]);
-                            }
-                            // Also erase from loaded_image_data
-                            if let Some(img_data) = &mut self.loaded_image_data {
-                                if img_idx + 4 <= img_data.len() {
-                                    img_data[img_idx..img_idx + 4].copy_from_slice(&[255, 255, 255, 0]);
-                                }
-                            }
-                        }
-                    }
-                    
-                    // Also update display buffer
-                    let idx = canvas_y as usize * self.stride + canvas_x as usize * 4;
-                    if idx + 4 <= self.pixels.len() {
-                        // Get the background pixel (re-render from loaded image if available)
-                        if let Some((img_w, img_h)) = self.loaded_image_size {
-                            if let Some(img_data) = &self.loaded_image_data {
-                                let (offset_x, offset_y) = self.pan_offset;
-                                let img_x = ((canvas_x as f32 / self.zoom_scale) as i32) - offset_x;
-                                let img_y = ((canvas_y as f32 / self.zoom_scale) as i32) - offset_y;
-                                
-                                if img_x >= 0 && img_x < img_w as i32 && img_y >= 0 && img_y < img_h as i32 {
-                                    let img_stride = img_w as usize * 4;
-                                    let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                                    if img_idx + 4 <= img_data.len() {
-                                        self.pixels[idx..idx + 4].copy_from_slice(&img_data[img_idx..img_idx + 4]);
-                                    }
-                                } else {
-                                    self.pixels[idx..idx + 4].copy_from_slice(&[255, 255, 255, 255]);
-                                }
-                            }
-                        } else {
-                            // No image, set to white
-                            self.pixels[idx..idx + 4].copy_from_slice(&[255, 255, 255, 255]);
-                        }
+    /// `snapshot_for_selection` over the image and every pixel the active layer
+    /// holds past its edges, for edits that change the whole layer
+    fn snapshot_layer_for_selection(&self) -> Option<EditSnapshot> {
+        let (x0, y0, x1, y1) = self.document.active_layer()?.bounds();
+        let (width, height) = (self.document.width as i32, self.document.height as i32);
+        self.snapshot_for_selection(x0.min(0), y0.min(0), x1.max(width), y1.max(height))
+    }
+
+    /// Fade the edit made since `snapshot` back to the old contents by the
+    /// selection coverage, so only selected pixels change
+    fn clip_to_selection(&mut self, snapshot: Option<EditSnapshot>) {
+        let Some(snapshot) = snapshot else {
+            return;
+        };
+        let Some(selection) = self.document.selection.clone() else {
+            return;
+        };
+        let Some(layer) = self.document.active_layer_mut() else {
+            return;
+        };
+        let mut i = 0;
+        for y in snapshot.y0..snapshot.y1 {
+            for x in snapshot.x0..snapshot.x1 {
+                // Nothing past the image edges is selected
+                let coverage = if x < 0 || y < 0 { 0 } else { selection.value(x as u32, y as u32) };
+                if coverage < 255 {
+                    let mixed = mix_pixel(snapshot.pixels[i], layer.pixel_at(x, y), coverage);
+                    layer.set_pixel_at(x, y, mixed);
+                    if let (Some(before), Some(idx)) = (&snapshot.mask, layer.index_at(x, y))
+                        && let Some(mask) = layer.mask.as_mut()
+                    {
+                        mask[idx] = mix_gray(before[i], mask[idx], coverage);
                     }
                 }
+                i += 1;
             }
         }
-        
-        self.dirty = true;
     }
 
-    pub fn apply_brightness_circle(&mut self, cx: f32, cy: f32, radius: f32, intensity: i32) {
+    /// Re-render the loaded image with a new offset
+    pub fn repan_image(&mut self, offset_x: i32, offset_y: i32) {
+        self.pan_offset = (offset_x, offset_y);
+        self.render_view();
+    }
+
+    /// Image-space center, radius and clamped pixel bounds of a circular dab
+    /// given in canvas coordinates. Returns None when the dab misses the image.
+    fn dab_bounds(&self, cx: f32, cy: f32, radius: f32) -> Option<(f32, f32, f32, u32, u32, u32, u32)> {
         if radius <= 0.0 {
+            return None;
+        }
+        let (ix, iy) = self.canvas_to_image(cx, cy);
+        let r = radius / self.zoom_scale;
+        let min_x = (ix - r).floor().max(0.0);
+        let max_x = (ix + r).ceil().min(self.document.width as f32 - 1.0);
+        let min_y = (iy - r).floor().max(0.0);
+        let max_y = (iy + r).ceil().min(self.document.height as f32 - 1.0);
+        if max_x < min_x || max_y < min_y {
+            return None;
+        }
+        Some((ix, iy, r, min_x as u32, min_y as u32, max_x as u32, max_y as u32))
+    }
+
+    /// Paint one brush dab into the active layer (or its mask when editing the
+    /// mask, using the color's luminance: white reveals, black hides). Dabs add
+    /// up within a stroke until it reaches the brush opacity.
+    pub fn stamp_dab(&mut self, cx: f32, cy: f32, brush: &Brush) {
+        let Some((ix, iy, r, min_x, min_y, max_x, max_y)) = self.dab_bounds(cx, cy, brush.radius) else {
+            return;
+        };
+        let target = if self.document.quick_mask {
+            StrokeTarget::QuickMask
+        } else if self.document.painting_mask() {
+            StrokeTarget::LayerMask
+        } else {
+            StrokeTarget::Pixels
+        };
+        let snapshot = match target {
+            StrokeTarget::QuickMask => None,
+            _ => self.snapshot_for_selection(min_x as i32, min_y as i32, max_x as i32 + 1, max_y as i32 + 1),
+        };
+        let (width, height) = (self.document.width, self.document.height);
+        let layer = self.document.active.clone();
+        // A moved layer may not hold the pixels under the dab yet
+        let mut grown = None;
+        if target != StrokeTarget::QuickMask
+            && let Some(active) = self.document.active_layer_mut()
+            && (target == StrokeTarget::Pixels || active.mask.is_some())
+        {
+            let old = active.bounds();
+            active.cover(min_x as i32, min_y as i32, max_x as i32 + 1, max_y as i32 + 1);
+            grown = (active.bounds() != old).then_some(old);
+        }
+        // The buffer painted into, its size and the image position of its first pixel
+        let (buffer, (stride, rows), origin) = match target {
+            StrokeTarget::QuickMask => {
+                let selection = self.document.selection.get_or_insert_with(|| Selection::new(width, height));
+                (&mut selection.mask, (width, height), (0, 0))
+            }
+            StrokeTarget::LayerMask => match self.document.active_layer_mut() {
+                Some(Layer { mask: Some(mask), width, height, offset, .. }) => (mask, (*width, *height), *offset),
+                _ => return,
+            },
+            StrokeTarget::Pixels => match self.document.active_layer_mut() {
+                Some(Layer { pixels, width, height, offset, .. }) => (pixels, (*width, *height), *offset),
+                None => return,
+            },
+        };
+        let channels = if target == StrokeTarget::Pixels { 4 } else { 1 };
+        if buffer.len() != stride as usize * rows as usize * channels {
             return;
         }
-        let r2 = radius * radius;
-        let min_x = (cx - radius).floor().max(0.0) as i32;
-        let max_x = (cx + radius).ceil().min((self.width - 1) as f32) as i32;
-        let min_y = (cy - radius).floor().max(0.0) as i32;
-        let max_y = (cy + radius).ceil().min((self.height - 1) as f32) as i32;
+        // A stroke carries on into the pixels a moved layer grew by
+        if let Some(old) = grown
+            && let Some(stroke) = self.stroke.take_if(|stroke| stroke.paints(target, &layer, old, channels))
+        {
+            self.stroke = Some(stroke.grown(old, origin, stride, buffer, channels));
+        }
+        // Switching layers (or moving this one) mid-stroke starts over
+        let bounds = (origin.0, origin.1, origin.0 + stride as i32, origin.1 + rows as i32);
+        let stroke = match self.stroke.take() {
+            Some(stroke) if stroke.paints(target, &layer, bounds, channels) => stroke,
+            _ => Stroke { target, layer, origin, before: buffer.clone(), coverage: vec![0.0; buffer.len() / channels] },
+        };
+        let stroke = self.stroke.insert(stroke);
 
+        let gray = luminance(brush.color) as f32;
         for y in min_y..=max_y {
             for x in min_x..=max_x {
-                let dx = x as f32 + 0.5 - cx;
-                let dy = y as f32 + 0.5 - cy;
-                if dx * dx + dy * dy <= r2 {
-                    let idx = y as usize * self.stride + x as usize * 4;
-                    if idx + 3 < self.pixels.len() {
-                        let r = self.pixels[idx] as i32;
-                        let g = self.pixels[idx + 1] as i32;
-                        let b = self.pixels[idx + 2] as i32;
-                        
-                        let new_r = ((r + intensity) as i32).clamp(0, 255) as u8;
-                        let new_g = ((g + intensity) as i32).clamp(0, 255) as u8;
-                        let new_b = ((b + intensity) as i32).clamp(0, 255) as u8;
-                        
-                        self.pixels[idx] = new_r;
-                        self.pixels[idx + 1] = new_g;
-                        self.pixels[idx + 2] = new_b;
-                    }
+                let dx = x as f32 + 0.5 - ix;
+                let dy = y as f32 + 0.5 - iy;
+                let amount = brush.dab_alpha((dx * dx + dy * dy).sqrt(), r);
+                if amount <= 0.0 {
+                    continue;
+                }
+                let (lx, ly) = (x as i32 - origin.0, y as i32 - origin.1);
+                if lx < 0 || ly < 0 || lx as u32 >= stride || ly as u32 >= rows {
+                    continue;
+                }
+                let i = (ly as u32 * stride + lx as u32) as usize;
+                let coverage = &mut stroke.coverage[i];
+                *coverage += amount * (1.0 - *coverage);
+                let strength = *coverage * brush.opacity.clamp(0.0, 1.0);
+                if channels == 4 {
+                    let mut color = brush.color;
+                    color[3] = (color[3] as f32 * strength).round() as u8;
+                    let px = &mut buffer[i * 4..i * 4 + 4];
+                    px.copy_from_slice(&stroke.before[i * 4..i * 4 + 4]);
+                    blend_over(px, color);
+                } else {
+                    let a = brush.color[3] as f32 / 255.0 * strength;
+                    buffer[i] = (gray * a + stroke.before[i] as f32 * (1.0 - a)).round() as u8;
                 }
             }
         }
-        self.dirty = true;
+        self.clip_to_selection(snapshot);
+        self.refresh_region(min_x, min_y, max_x + 1, max_y + 1);
     }
 
-    pub fn apply_grayscale_circle(&mut self, cx: f32, cy: f32, radius: f32, intensity: i32) {
-        if radius <= 0.0 {
+    /// Finish the brush stroke; the next dab starts building up from scratch
+    pub fn end_stroke(&mut self) {
+        self.stroke = None;
+    }
+
+    /// Erase a circle (set pixels to transparent in the active layer, or hide
+    /// them non-destructively when editing the layer mask)
+    pub fn erase_circle(&mut self, cx: f32, cy: f32, radius: f32) {
+        self.end_stroke();
+        let Some(dab) = self.dab_bounds(cx, cy, radius) else {
+            return;
+        };
+        if self.document.quick_mask {
+            self.paint_quick_mask(dab, 0, 255);
             return;
         }
-        let r2 = radius * radius;
-        let min_x = (cx - radius).floor().max(0.0) as i32;
-        let max_x = (cx + radius).ceil().min((self.width - 1) as f32) as i32;
-        let min_y = (cy - radius).floor().max(0.0) as i32;
-        let max_y = (cy + radius).ceil().min((self.height - 1) as f32) as i32;
-        let intensity_f = (intensity.clamp(0, 100) as f32) / 100.0;
-
+        let (ix, iy, r, min_x, min_y, max_x, max_y) = dab;
+        let snapshot = self.snapshot_for_selection(min_x as i32, min_y as i32, max_x as i32 + 1, max_y as i32 + 1);
+        let painting_mask = self.document.painting_mask();
+        let Some(layer) = self.document.active_layer_mut() else {
+            return;
+        };
+        let r2 = r * r;
         for y in min_y..=max_y {
             for x in min_x..=max_x {
-                let dx = x as f32 + 0.5 - cx;
-                let dy = y as f32 + 0.5 - cy;
+                let dx = x as f32 + 0.5 - ix;
+                let dy = y as f32 + 0.5 - iy;
                 if dx * dx + dy * dy <= r2 {
-                    let idx = y as usize * self.stride + x as usize * 4;
-                    if idx + 3 < self.pixels.len() {
-                        let r = self.pixels[idx] as f32;
-                        let g = self.pixels[idx + 1] as f32;
-                        let b = self.pixels[idx + 2] as f32;
-                        
-                        let gray = (0.299 * r + 0.587 * g + 0.114 * b) as u8;
-                        let new_r = (r * (1.0 - intensity_f) + gray as f32 * intensity_f) as u8;
-                        let new_g = (g * (1.0 - intensity_f) + gray as f32 * intensity_f) as u8;
-                        let new_b = (b * (1.0 - intensity_f) + gray as f32 * intensity_f) as u8;
-                        
-                        self.pixels[idx] = new_r;
-                        self.pixels[idx + 1] = new_g;
-                        self.pixels[idx + 2] = new_b;
+                    if painting_mask {
+                        layer.blend_mask_at(x as i32, y as i32, 0, 255);
+                    } else {
+                        layer.set_pixel_at(x as i32, y as i32, [255, 255, 255, 0]);
                     }
                 }
             }
         }
-        self.dirty = true;
+        self.clip_to_selection(snapshot);
+        self.refresh_region(min_x, min_y, max_x + 1, max_y + 1);
     }
 
     pub fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: [u8; 4]) {
@@ -446,7 +755,7 @@ impl Canvas {
         }
         self.dirty = true;
     }
-    
+
     /// Get pixel color at canvas coordinates (for color picker)
     pub fn get_pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
         if x >= self.width || y >= self.height {
@@ -464,410 +773,580 @@ impl Canvas {
             None
         }
     }
-    
-    /// Flood fill at canvas coordinates
+
+    /// Flood fill the active layer (or its mask) starting at canvas coordinates
     pub fn flood_fill(&mut self, start_x: u32, start_y: u32, fill_color: [u8; 4]) {
-        if start_x >= self.width || start_y >= self.height {
+        let Some((img_x, img_y)) = self.canvas_to_image_pixel(start_x, start_y) else {
             return;
-        }
-        
-        let idx = start_y as usize * self.stride + start_x as usize * 4;
-        if idx + 4 > self.pixels.len() {
+        };
+        let snapshot = self.snapshot_layer_for_selection();
+        let painting_mask = self.document.painting_mask();
+        let (doc_width, doc_height) = (self.document.width as i32, self.document.height as i32);
+        let Some(layer) = self.document.active_layer_mut() else {
             return;
-        }
-        
-        let target_color = [
-            self.pixels[idx],
-            self.pixels[idx + 1],
-            self.pixels[idx + 2],
-            self.pixels[idx + 3],
-        ];
-        
-        // Don't fill if already the same color
-        if target_color == fill_color {
+        };
+        // The fill spreads over the whole image and the pixels the layer
+        // holds past its edges
+        layer.cover(0, 0, doc_width, doc_height);
+        let (width, height) = (layer.width, layer.height);
+        let Some(start) = layer.index_at(img_x as i32, img_y as i32) else {
             return;
-        }
-        
-        let mut stack = vec![(start_x, start_y)];
-        let mut visited = vec![false; (self.width * self.height) as usize];
-        
-        while let Some((x, y)) = stack.pop() {
-            if x >= self.width || y >= self.height {
-                continue;
-            }
-            
-            let visit_idx = (y * self.width + x) as usize;
-            if visited[visit_idx] {
-                continue;
+        };
+        let start = (start as u32 % width, start as u32 / width);
+
+        if painting_mask {
+            let gray = luminance(fill_color);
+            let Some(mask) = layer.mask.as_mut() else {
+                return;
+            };
+            let target = mask[(start.1 * width + start.0) as usize];
+            if target == gray {
+                return;
             }
-            visited[visit_idx] = true;
-            
-            let pixel_idx = y as usize * self.stride + x as usize * 4;
-            if pixel_idx + 4 > self.pixels.len() {
-                continue;
+            let region = flood_region(width, height, start, |idx| mask[idx] == target);
+            for (idx, inside) in region.iter().enumerate() {
+                if *inside {
+                    mask[idx] = gray;
+                }
             }
-            
-            let current = [
-                self.pixels[pixel_idx],
-                self.pixels[pixel_idx + 1],
-                self.pixels[pixel_idx + 2],
-                self.pixels[pixel_idx + 3],
-            ];
-            
-            if current != target_color {
-                continue;
+        } else {
+            let target_color = layer.get_pixel(start.0, start.1);
+
+            // Don't fill if already the same color
+            if target_color == fill_color {
+                return;
             }
-            
-            // Fill this pixel
-            self.pixels[pixel_idx..pixel_idx + 4].copy_from_slice(&fill_color);
-            
-            // Also fill in drawing layer if we have an image
-            if let Some((img_w, img_h)) = self.loaded_image_size {
-                let (offset_x, offset_y) = self.pan_offset;
-                let img_x = ((x as f32 / self.zoom_scale) as i32) - offset_x;
-                let img_y = ((y as f32 / self.zoom_scale) as i32) - offset_y;
-                
-                if img_x >= 0 && img_x < img_w as i32 && img_y >= 0 && img_y < img_h as i32 {
-                    let img_stride = img_w as usize * 4;
-                    let img_idx = (img_y as usize * img_stride) + (img_x as usize * 4);
-                    if img_idx + 4 <= self.drawing_layer.len() {
-                        self.drawing_layer[img_idx..img_idx + 4].copy_from_slice(&fill_color);
-                    }
+
+            let pixels = &layer.pixels;
+            let region = flood_region(width, height, start, |idx| {
+                pixels[idx * 4..idx * 4 + 4] == target_color
+            });
+            for (idx, inside) in region.iter().enumerate() {
+                if *inside {
+                    layer.pixels[idx * 4..idx * 4 + 4].copy_from_slice(&fill_color);
                 }
             }
-            
-            // Add neighbors
-            if x > 0 { stack.push((x - 1, y)); }
-            if x + 1 < self.width { stack.push((x + 1, y)); }
-            if y > 0 { stack.push((x, y - 1)); }
-            if y + 1 < self.height { stack.push((x, y + 1)); }
-        }
-        
-        self.dirty = true;
+        }
+
+        self.clip_to_selection(snapshot);
+        self.refresh();
+    }
+
+    /// Magic wand: select the pixels whose color is within the tolerance of the
+    /// pixel under canvas (x, y), either the region connected to it or every
+    /// match in the image. Colors come from the active layer or the merged image.
+    pub fn magic_wand(&self, start_x: u32, start_y: u32, options: WandOptions) -> Option<Selection> {
+        let (img_x, img_y) = self.canvas_to_image_pixel(start_x, start_y)?;
+        let (width, height) = (self.document.width, self.document.height);
+        let pixels = if options.sample_merged {
+            self.document.composite()
+        } else {
+            self.document.active_layer()?.placed(width, height)
+        };
+        Some(select_similar(width, height, &pixels, (img_x, img_y), options))
+    }
+
+    /// Drag the floating layer by an image-space offset, redrawing where it was and where it lands
+    pub fn move_floating(&mut self, dx: i32, dy: i32) {
+        let (width, height) = (self.document.width, self.document.height);
+        let Some(before) = self.document.floating.as_ref().map(|f| f.bounds(width, height)) else {
+            return;
+        };
+        self.document.move_floating(dx, dy);
+        let Some(after) = self.document.floating.as_ref().map(|f| f.bounds(width, height)) else {
+            return;
+        };
+        self.refresh_region(before.0.min(after.0), before.1.min(after.1), before.2.max(after.2), before.3.max(after.3));
     }
-    
+
+    /// Move the active layer's contents by an image-space offset (Move tool)
+    pub fn move_layer(&mut self, dx: i32, dy: i32) -> bool {
+        if !self.document.move_active_layer(dx, dy) {
+            return false;
+        }
+        self.refresh();
+        true
+    }
+
     /// Pan the image view by updating pan_offset and re-rendering
     pub fn pan_image(&mut self, offset_x: i32, offset_y: i32) {
         self.pan_offset.0 += offset_x;
         self.pan_offset.1 += offset_y;
-        
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            if let Some(img_data) = self.loaded_image_data.clone() {
-                self.paste_image_with_offset(img_w, img_h, &img_data, self.pan_offset.0, self.pan_offset.1);
-            }
-        }
-        
-        self.dirty = true;
+        self.render_view();
     }
-    
-    /// Apply invert filter to all canvas pixels and loaded image
+
+    /// Apply invert filter to the active layer
     pub fn filter_invert(&mut self) {
-        // Invert all pixels in the canvas display buffer
-        for i in (0..self.pixels.len()).step_by(4) {
-            if i + 3 < self.pixels.len() {
-                self.pixels[i] = 255 - self.pixels[i];          // R
-                self.pixels[i + 1] = 255 - self.pixels[i + 1];  // G
-                self.pixels[i + 2] = 255 - self.pixels[i + 2];  // B
+        let snapshot = self.snapshot_layer_for_selection();
+        if let Some(layer) = self.document.active_layer_mut() {
+            for px in layer.pixels.chunks_exact_mut(4) {
+                px[0] = 255 - px[0]; // R
+                px[1] = 255 - px[1]; // G
+                px[2] = 255 - px[2]; // B
                 // A stays the same
             }
         }
-        // Also invert loaded image data if present so it stays inverted when panned
-        if let Some(ref mut img_data) = self.loaded_image_data {
-            for i in (0..img_data.len()).step_by(4) {
-                if i + 3 < img_data.len() {
-                    img_data[i] = 255 - img_data[i];            // R
-                    img_data[i + 1] = 255 - img_data[i + 1];    // G
-                    img_data[i + 2] = 255 - img_data[i + 2];    // B
-                }
-            }
-        }
-        self.dirty = true;
-    }
-    
-    /// Remove grayscale by restoring from original backup
-    pub fn remove_grayscale(&mut self) {
-        if let Some(original) = self.original_image_backup.clone() {
-            self.loaded_image_data = Some(original.clone());
-            if let Some((img_w, img_h)) = self.loaded_image_size {
-                let (ox, oy) = self.pan_offset;
-                self.paste_image_with_offset(img_w, img_h, &original, ox, oy);
-            }
-            self.grayscale_active = false;
-            self.dirty = true;
-        }
+        self.clip_to_selection(snapshot);
+        self.refresh();
     }
-    
-    /// Apply grayscale filter to drawing layer
-    pub fn filter_grayscale(&mut self) {
-        // Toggle grayscale: if active, restore the original loaded image into pixels;
-        // if inactive, convert display buffer to grayscale without mutating loaded_image_data.
-        if self.grayscale_active {
-            if let Some((img_w, img_h)) = self.loaded_image_size {
-                if let Some(img_data) = self.loaded_image_data.clone() {
-                    let (ox, oy) = self.pan_offset;
-                    self.paste_image_with_offset(img_w, img_h, &img_data, ox, oy);
-                }
-            }
-            self.grayscale_active = false;
-            self.dirty = true;
-            return;
-        }
 
-        for idx in (0..self.pixels.len()).step_by(4) {
-            if idx + 3 < self.pixels.len() && self.pixels[idx + 3] > 0 {
-                let r = self.pixels[idx] as f32;
-                let g = self.pixels[idx + 1] as f32;
-                let b = self.pixels[idx + 2] as f32;
-                let gray = (0.299 * r + 0.587 * g + 0.114 * b) as u8;
-                self.pixels[idx] = gray;
-                self.pixels[idx + 1] = gray;
-                self.pixels[idx + 2] = gray;
+    /// Apply grayscale filter to the active layer
+    pub fn filter_grayscale(&mut self) {
+        let snapshot = self.snapshot_layer_for_selection();
+        if let Some(layer) = self.document.active_layer_mut() {
+            for px in layer.pixels.chunks_exact_mut(4) {
+                let gray = (0.299 * px[0] as f32 + 0.587 * px[1] as f32 + 0.114 * px[2] as f32) as u8;
+                px[..3].fill(gray);
             }
         }
-        self.grayscale_active = true;
-        self.dirty = true;
+        self.clip_to_selection(snapshot);
+        self.refresh();
     }
 
-    /// Helper: apply grayscale to the current display buffer without toggling state
-    fn apply_grayscale_to_display(&mut self) {
-        for idx in (0..self.pixels.len()).step_by(4) {
-            if idx + 3 < self.pixels.len() && self.pixels[idx + 3] > 0 {
-                let r = self.pixels[idx] as f32;
-                let g = self.pixels[idx + 1] as f32;
-                let b = self.pixels[idx + 2] as f32;
-                let gray = (0.299 * r + 0.587 * g + 0.114 * b) as u8;
-                self.pixels[idx] = gray;
-                self.pixels[idx + 1] = gray;
-                self.pixels[idx + 2] = gray;
-            }
-        }
-    }
-    
-    /// Remove brightness by restoring from original backup
-    pub fn remove_brightness(&mut self) {
-        if let Some(original) = self.original_image_backup.clone() {
-            self.loaded_image_data = Some(original.clone());
-            if let Some((img_w, img_h)) = self.loaded_image_size {
-                let (ox, oy) = self.pan_offset;
-                self.paste_image_with_offset(img_w, img_h, &original, ox, oy);
-            }
-            self.dirty = true;
-        }
-    }
-    
-    /// Apply brightness/contrast adjustment to drawing layer
+    /// Apply brightness/contrast adjustment to the active layer
     pub fn filter_brightness_contrast(&mut self, brightness: f32, contrast: f32) {
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            let img_stride = img_w as usize * 4;
-            let factor = (259.0 * (contrast + 255.0)) / (255.0 * (259.0 - contrast));
-            
-            for y in 0..img_h {
-                for x in 0..img_w {
-                    let idx = (y as usize * img_stride) + (x as usize * 4);
-                    if idx + 3 < self.drawing_layer.len() {
-                        if self.drawing_layer[idx + 3] > 0 {
-                            for i in 0..3 {
-                                let pixel = self.drawing_layer[idx + i] as f32;
-                                // Apply contrast
-                                let contrasted = factor * (pixel - 128.0) + 128.0;
-                                // Apply brightness
-                                let adjusted = contrasted + brightness;
-                                self.drawing_layer[idx + i] = adjusted.clamp(0.0, 255.0) as u8;
-                            }
-                        }
-                    }
+        let factor = (259.0 * (contrast + 255.0)) / (255.0 * (259.0 - contrast));
+        let snapshot = self.snapshot_layer_for_selection();
+        if let Some(layer) = self.document.active_layer_mut() {
+            for px in layer.pixels.chunks_exact_mut(4) {
+                if px[3] == 0 {
+                    continue;
                 }
-            }
-            // Also apply brightness/contrast to the background image so panning keeps the effect
-            if let Some(ref mut img_data) = self.loaded_image_data {
-                for i in (0..img_data.len()).step_by(4) {
-                    if i + 3 < img_data.len() {
-                        for c in 0..3 {
-                            let pixel = img_data[i + c] as f32;
-                            let contrasted = factor * (pixel - 128.0) + 128.0;
-                            let adjusted = contrasted + brightness;
-                            img_data[i + c] = adjusted.clamp(0.0, 255.0) as u8;
-                        }
-                    }
+                for c in px.iter_mut().take(3) {
+                    let pixel = *c as f32;
+                    // Apply contrast
+                    let contrasted = factor * (pixel - 128.0) + 128.0;
+                    // Apply brightness
+                    let adjusted = contrasted + brightness;
+                    *c = adjusted.clamp(0.0, 255.0) as u8;
                 }
             }
-            if let Some(img_data) = self.loaded_image_data.clone() {
-                let (offset_x, offset_y) = self.pan_offset;
-                self.paste_image_with_offset(img_w, img_h, &img_data, offset_x, offset_y);
-            }
         }
-        self.dirty = true;
+        self.clip_to_selection(snapshot);
+        self.refresh();
     }
-    
-    /// Apply box blur filter to drawing layer
+
+    /// Apply box blur filter to the active layer
     pub fn filter_blur(&mut self, radius: u32) {
         if radius == 0 {
             return;
         }
-        if let Some((img_w, img_h)) = self.loaded_image_size {
-            let img_stride = img_w as usize * 4;
-            let mut temp_layer = self.drawing_layer.clone();
-            
-            for y in 0..img_h {
-                for x in 0..img_w {
-                    let idx = (y as usize * img_stride) + (x as usize * 4);
-                    if idx + 3 >= self.drawing_layer.len() {
-                        continue;
-                    }
-                    
-                    // Only blur pixels that have been drawn on
-                    if self.drawing_layer[idx + 3] == 0 {
-                        continue;
-                    }
-                    
-                    let mut sum_r = 0u32;
-                    let mut sum_g = 0u32;
-                    let mut sum_b = 0u32;
-                    let mut sum_a = 0u32;
-                    let mut count = 0u32;
-                    
-                    // Box blur: average pixels in radius
-                    let min_y = y.saturating_sub(radius);
-                    let max_y = (y + radius).min(img_h - 1);
-                    let min_x = x.saturating_sub(radius);
-                    let max_x = (x + radius).min(img_w - 1);
-                    
-                    for by in min_y..=max_y {
-                        for bx in min_x..=max_x {
-                            let bidx = (by as usize * img_stride) + (bx as usize * 4);
-                            if bidx + 3 < self.drawing_layer.len() {
-                                sum_r += self.drawing_layer[bidx] as u32;
-                                sum_g += self.drawing_layer[bidx + 1] as u32;
-                                sum_b += self.drawing_layer[bidx + 2] as u32;
-                                sum_a += self.drawing_layer[bidx + 3] as u32;
-                                count += 1;
+        let snapshot = self.snapshot_layer_for_selection();
+        let Some(layer) = self.document.active_layer_mut() else {
+            return;
+        };
+        let (img_w, img_h) = (layer.width, layer.height);
+        let img_stride = img_w as usize * 4;
+        let source = &layer.pixels;
+        let mut temp_layer = source.clone();
+
+        for y in 0..img_h {
+            for x in 0..img_w {
+                let idx = (y as usize * img_stride) + (x as usize * 4);
+                if idx + 3 >= source.len() {
+                    continue;
+                }
+
+                // Only blur pixels that have been drawn on
+                if source[idx + 3] == 0 {
+                    continue;
+                }
+
+                let mut sum = [0u32; 4];
+                let mut count = 0u32;
+
+                // Box blur: average pixels in radius
+                let min_y = y.saturating_sub(radius);
+                let max_y = (y + radius).min(img_h - 1);
+                let min_x = x.saturating_sub(radius);
+                let max_x = (x + radius).min(img_w - 1);
+
+                for by in min_y..=max_y {
+                    for bx in min_x..=max_x {
+                        let bidx = (by as usize * img_stride) + (bx as usize * 4);
+                        if bidx + 3 < source.len() {
+                            for c in 0..4 {
+                                sum[c] += source[bidx + c] as u32;
                             }
+                            count += 1;
                         }
                     }
-                    
-                    if count > 0 {
-                        temp_layer[idx] = (sum_r / count) as u8;
-                        temp_layer[idx + 1] = (sum_g / count) as u8;
-                        temp_layer[idx + 2] = (sum_b / count) as u8;
-                        temp_layer[idx + 3] = (sum_a / count) as u8;
-                    }
                 }
-            }
-            
-            self.drawing_layer = temp_layer;
-            
-            if let Some(img_data) = self.loaded_image_data.clone() {
-                let (offset_x, offset_y) = self.pan_offset;
-                self.paste_image_with_offset(img_w, img_h, &img_data, offset_x, offset_y);
+
+                let count = count.max(1);
+                for c in 0..4 {
+                    temp_layer[idx + c] = (sum[c] / count) as u8;
+                }
             }
         }
-        self.dirty = true;
+
+        layer.pixels = temp_layer;
+        self.clip_to_selection(snapshot);
+        self.refresh();
     }
 
-    /// Blur a circular area at (x, y) with the given radius
-    /// Averages neighboring pixels and blends the result back
+    /// Blur a circular area at canvas position (x, y) with the given radius
+    /// Averages neighboring pixels of the active layer and blends the result back
     pub fn blur_circle(&mut self, x: f32, y: f32, radius: f32) {
-        if self.loaded_image_size.is_none() {
-            return; // No image loaded
-        }
-        
         // Cap radius to prevent UI freeze on huge blur areas
         let clamped_radius = radius.min(48.0);
-        
-        let r = clamped_radius.ceil() as i32;
-        let center_x = x as i32;
-        let center_y = y as i32;
-        
+
         // Reduce blur sample radius for speed (proportional to brush size, but capped)
-        let blur_radius = ((clamped_radius * 0.3).max(1.0)).min(8.0) as i32;
-        
-        // Define the affected region
-        let x_min = (center_x - r).max(0) as u32;
-        let x_max = (center_x + r).min(self.width as i32 - 1) as u32;
-        let y_min = (center_y - r).max(0) as u32;
-        let y_max = (center_y + r).min(self.height as i32 - 1) as u32;
-        
+        let blur_radius = ((clamped_radius / self.zoom_scale) * 0.3).clamp(1.0, 8.0) as i32;
+
+        let Some((ix, iy, r, x_min, y_min, x_max, y_max)) = self.dab_bounds(x, y, clamped_radius) else {
+            return;
+        };
+        let snapshot = self.snapshot_for_selection(x_min as i32, y_min as i32, x_max as i32 + 1, y_max as i32 + 1);
+        let Some(layer) = self.document.active_layer_mut() else {
+            return;
+        };
+        // Blurring spreads the pixels, so a moved layer must hold the whole area
+        layer.cover(x_min as i32, y_min as i32, x_max as i32 + 1, y_max as i32 + 1);
+
         let region_width = (x_max - x_min + 1) as usize;
         let region_height = (y_max - y_min + 1) as usize;
-        
-        // Early exit if region is too small
-        if region_width == 0 || region_height == 0 {
-            return;
-        }
-        
+
         // Create a temporary buffer for just the blur region
         let mut temp_region = vec![0u8; region_width * region_height * 4];
-        
+
         // Copy the region to temp buffer
-        for ry in 0..region_height {
-            let src_y = (y_min + ry as u32) as usize;
-            let src_offset = src_y * self.stride + (x_min as usize * 4);
-            let dst_offset = ry * region_width * 4;
-            let copy_size = region_width * 4;
-            
-            if src_offset + copy_size <= self.pixels.len() && dst_offset + copy_size <= temp_region.len() {
-                temp_region[dst_offset..dst_offset + copy_size]
-                    .copy_from_slice(&self.pixels[src_offset..src_offset + copy_size]);
-            }
+        for (i, px) in temp_region.chunks_exact_mut(4).enumerate() {
+            let (rx, ry) = ((i % region_width) as u32, (i / region_width) as u32);
+            px.copy_from_slice(&layer.pixel_at((x_min + rx) as i32, (y_min + ry) as i32));
         }
-        
+
         // Blur each pixel in the circle
         for py in y_min..=y_max {
             for px in x_min..=x_max {
-                let dx = (px as i32 - center_x) as f32;
-                let dy = (py as i32 - center_y) as f32;
-                let dist_sq = dx * dx + dy * dy;
-                
+                let dx = px as f32 + 0.5 - ix;
+                let dy = py as f32 + 0.5 - iy;
+
                 // Only blur pixels within the radius
-                if dist_sq <= clamped_radius * clamped_radius {
-                    let mut sum_r = 0u32;
-                    let mut sum_g = 0u32;
-                    let mut sum_b = 0u32;
-                    let mut sum_a = 0u32;
-                    let mut count = 0u32;
-                    
-                    // Sample nearby pixels from the temp region (limited range for speed)
-                    for by in (py as i32 - blur_radius).max(y_min as i32)..=(py as i32 + blur_radius).min(y_max as i32) {
-                        for bx in (px as i32 - blur_radius).max(x_min as i32)..=(px as i32 + blur_radius).min(x_max as i32) {
-                            let ry = (by - y_min as i32) as usize;
-                            let rx = (bx - x_min as i32) as usize;
-                            let tidx = (ry * region_width + rx) * 4;
-                            
-                            if tidx + 3 < temp_region.len() {
-                                sum_r += temp_region[tidx] as u32;
-                                sum_g += temp_region[tidx + 1] as u32;
-                                sum_b += temp_region[tidx + 2] as u32;
-                                sum_a += temp_region[tidx + 3] as u32;
-                                count += 1;
+                if dx * dx + dy * dy > r * r {
+                    continue;
+                }
+
+                let mut sum = [0u32; 4];
+                let mut count = 0u32;
+
+                // Sample nearby pixels from the temp region (limited range for speed)
+                for by in (py as i32 - blur_radius).max(y_min as i32)..=(py as i32 + blur_radius).min(y_max as i32) {
+                    for bx in (px as i32 - blur_radius).max(x_min as i32)..=(px as i32 + blur_radius).min(x_max as i32) {
+                        let ry = (by - y_min as i32) as usize;
+                        let rx = (bx - x_min as i32) as usize;
+                        let tidx = (ry * region_width + rx) * 4;
+
+                        if tidx + 3 < temp_region.len() {
+                            for c in 0..4 {
+                                sum[c] += temp_region[tidx + c] as u32;
                             }
+                            count += 1;
                         }
                     }
-                    
-                    if count > 0 {
-                        let avg_r = (sum_r / count) as u8;
-                        let avg_g = (sum_g / count) as u8;
-                        let avg_b = (sum_b / count) as u8;
-                        let avg_a = (sum_a / count) as u8;
-                        
-                        // Write the blurred pixel
-                        let idx = (py as usize * self.stride) + (px as usize * 4);
-                        if idx + 3 < self.pixels.len() {
-                            // Simple 50% blend with original
-                            self.pixels[idx] = ((self.pixels[idx] as u32 + avg_r as u32) / 2) as u8;
-                            self.pixels[idx + 1] = ((self.pixels[idx + 1] as u32 + avg_g as u32) / 2) as u8;
-                            self.pixels[idx + 2] = ((self.pixels[idx + 2] as u32 + avg_b as u32) / 2) as u8;
-                            self.pixels[idx + 3] = avg_a;
-                        }
+                }
+
+                let count = count.max(1);
+                let Some(idx) = layer.index_at(px as i32, py as i32).map(|i| i * 4) else {
+                    continue;
+                };
+                if idx + 3 < layer.pixels.len() {
+                    // Simple 50% blend with original
+                    for (c, total) in sum.iter().take(3).enumerate() {
+                        layer.pixels[idx + c] = ((layer.pixels[idx + c] as u32 + total / count) / 2) as u8;
                     }
+                    layer.pixels[idx + 3] = (sum[3] / count) as u8;
                 }
             }
         }
-        self.dirty = true;
+        self.clip_to_selection(snapshot);
+        self.refresh_region(x_min, y_min, x_max + 1, y_max + 1);
+    }
+}
+
+/// Walk the 4-connected region around `start` whose pixels satisfy `matches`
+/// (called with the pixel index `y * width + x`), returning a per-pixel membership map
+fn flood_region(width: u32, height: u32, start: (u32, u32), matches: impl Fn(usize) -> bool) -> Vec<bool> {
+    let mut region = vec![false; (width * height) as usize];
+    let mut visited = vec![false; (width * height) as usize];
+    let mut stack = vec![start];
+
+    while let Some((x, y)) = stack.pop() {
+        if x >= width || y >= height {
+            continue;
+        }
+
+        let idx = (y * width + x) as usize;
+        if visited[idx] {
+            continue;
+        }
+        visited[idx] = true;
+
+        if !matches(idx) {
+            continue;
+        }
+        region[idx] = true;
+
+        // Add neighbors
+        if x > 0 { stack.push((x - 1, y)); }
+        if x + 1 < width { stack.push((x + 1, y)); }
+        if y > 0 { stack.push((x, y - 1)); }
+        if y + 1 < height { stack.push((x, y + 1)); }
+    }
+
+    region
+}
+
+/// Selection of the RGBA pixels matching the color at `start` within the wand
+/// tolerance (largest difference over the four channels)
+fn select_similar(width: u32, height: u32, pixels: &[u8], start: (u32, u32), options: WandOptions) -> Selection {
+    let mut selection = Selection::new(width, height);
+    let start_idx = (start.1 * width + start.0) as usize * 4;
+    let Some(target) = pixels.get(start_idx..start_idx + 4) else {
+        return selection;
+    };
+    let matches = |idx: usize| {
+        pixels[idx * 4..idx * 4 + 4]
+            .iter()
+            .zip(target)
+            .all(|(a, b)| a.abs_diff(*b) <= options.tolerance)
+    };
+    let region = if options.contiguous {
+        flood_region(width, height, start, matches)
+    } else {
+        (0..selection.mask.len()).map(matches).collect()
+    };
+    for (value, inside) in selection.mask.iter_mut().zip(region) {
+        if inside {
+            *value = 255;
+        }
     }
+    selection
+}
+
+/// Quick mask display: tint unselected pixels red, fading out where selected
+fn quick_mask_tint(color: [u8; 4], coverage: u8) -> [u8; 4] {
+    let strength = 0.5 * (1.0 - coverage as f32 / 255.0);
+    let tint = [255.0, 0.0, 0.0];
+    let mut out = color;
+    for c in 0..3 {
+        out[c] = (color[c] as f32 * (1.0 - strength) + tint[c] * strength).round() as u8;
+    }
+    out
+}
+
+/// Gray level used when a color is painted into a layer mask
+fn luminance(color: [u8; 4]) -> u8 {
+    (0.299 * color[0] as f32 + 0.587 * color[1] as f32 + 0.114 * color[2] as f32).round() as u8
 }
 
 fn aligned_stride(width: u32) -> usize {
     let row = width as usize * 4;
     let align = COPY_BYTES_PER_ROW_ALIGNMENT as usize;
-    (row + align - 1) / align * align
+    row.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edits_stay_inside_the_selection() {
+        let mut canvas = Canvas::new(4, 4);
+        canvas.document.selection = Some(Selection::rect(4, 4, (0, 0), (1, 3)));
+        canvas.flood_fill(0, 0, [255, 0, 0, 255]);
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(1, 2), [255, 0, 0, 255]);
+        assert_eq!(layer.get_pixel(2, 2), [255, 255, 255, 255]);
+
+        canvas.filter_invert();
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(0, 0), [0, 255, 255, 255]);
+        assert_eq!(layer.get_pixel(3, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_grayscale_changes_only_the_selected_pixels() {
+        let mut canvas = Canvas::new(4, 1);
+        canvas.flood_fill(0, 0, [255, 0, 0, 255]);
+        canvas.document.selection = Some(Selection::rect(4, 1, (0, 0), (1, 0)));
+        canvas.filter_grayscale();
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(1, 0), [76, 76, 76, 255]);
+        assert_eq!(layer.get_pixel(2, 0), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_crop_to_selection_resizes_document_and_selection() {
+        let mut canvas = Canvas::new(6, 5);
+        canvas.document.selection = Some(Selection::rect(6, 5, (1, 2), (3, 3)));
+        canvas.flood_fill(0, 0, [0, 0, 255, 255]);
+        assert!(canvas.crop_to_selection());
+        assert_eq!((canvas.document.width, canvas.document.height), (3, 2));
+        assert_eq!(canvas.document.selection.as_ref().unwrap().bounds(), Some((0, 0, 3, 2)));
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(2, 1), [0, 0, 255, 255]);
+
+        canvas.document.selection = None;
+        assert!(!canvas.crop_to_selection());
+        assert!(!canvas.crop(CropRect { x0: 4, y0: 0, x1: 9, y1: 2 }));
+        assert_eq!((canvas.document.width, canvas.document.height), (3, 2));
+    }
+
+    #[test]
+    fn test_brush_opacity_caps_each_stroke() {
+        let mut canvas = Canvas::new(9, 9);
+        let mut brush = Brush::new(3.0, [0, 0, 0, 255]);
+        brush.opacity = 0.5;
+        brush.flow = 0.5;
+        let center = |canvas: &Canvas| canvas.document.active_layer().unwrap().get_pixel(4, 4)[0];
+
+        brush.stamp(&mut canvas, (4.5, 4.5));
+        let first = center(&canvas);
+        for _ in 0..20 {
+            brush.stamp(&mut canvas, (4.5, 4.5));
+        }
+        // Flow builds up to the opacity and stops there
+        assert!(center(&canvas) < first);
+        assert_eq!(center(&canvas), 128);
+
+        // A new stroke paints over the last one
+        canvas.end_stroke();
+        brush.stamp(&mut canvas, (4.5, 4.5));
+        assert!(center(&canvas) < 128);
+    }
+
+    #[test]
+    fn test_switching_layers_mid_stroke_starts_a_new_stroke() {
+        let mut canvas = Canvas::new(9, 9);
+        canvas.document.add_layer();
+        let mut brush = Brush::new(3.0, [0, 0, 0, 255]);
+        brush.opacity = 0.5;
+        brush.stamp(&mut canvas, (4.5, 4.5));
+
+        // Still holding the mouse button, the background gets its own stroke
+        canvas.document.select(vec![0]);
+        brush.stamp(&mut canvas, (4.5, 4.5));
+        let background = canvas.document.active_layer().unwrap();
+        assert_eq!(background.get_pixel(4, 4), [127, 127, 127, 255]);
+        canvas.document.select(vec![1]);
+        assert_eq!(canvas.document.active_layer().unwrap().get_pixel(4, 4), [0, 0, 0, 128]);
+    }
+
+    #[test]
+    fn test_stroke_on_a_moved_layer_grows_it_only_under_the_dabs() {
+        let mut canvas = Canvas::new(9, 9);
+        canvas.document.add_layer();
+        assert!(canvas.move_layer(20, 0));
+        let mut brush = Brush::new(3.0, [0, 0, 0, 255]);
+        brush.opacity = 0.5;
+        brush.stamp(&mut canvas, (4.5, 4.5));
+        assert_eq!(canvas.document.active_layer().unwrap().bounds(), (1, 0, 29, 9));
+
+        // Growing the layer mid-stroke keeps the stroke's opacity cap
+        brush.stamp(&mut canvas, (1.5, 4.5));
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.bounds(), (0, 0, 29, 9));
+        assert_eq!(layer.pixel_at(3, 4), [0, 0, 0, 128]);
+    }
+
+    #[test]
+    fn test_huge_free_transform_keeps_the_layer_to_its_old_reach() {
+        let mut canvas = Canvas::new(4, 4);
+        canvas.document.add_layer();
+        canvas.document.active_layer_mut().unwrap().set_pixel(1, 1, [255, 0, 0, 255]);
+        assert!(canvas.begin_free_transform());
+        canvas.free_transform.as_mut().unwrap().quad = rect_quad(-100_000.0, -100_000.0, 200_000.0, 200_000.0);
+        assert!(canvas.commit_free_transform(Filter::Nearest));
+        let layer = canvas.document.active_layer().unwrap();
+        assert_eq!(layer.bounds(), (0, 0, 4, 4));
+        assert_eq!(layer.pixel_at(3, 3), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_soft_brush_fades_towards_the_edge() {
+        let mut canvas = Canvas::new(9, 9);
+        let mut brush = Brush::new(4.0, [0, 0, 0, 255]);
+        brush.hardness = 0.0;
+        brush.stamp(&mut canvas, (4.5, 4.5));
+        let layer = canvas.document.active_layer().unwrap();
+        let (middle, near_edge) = (layer.get_pixel(4, 4)[0], layer.get_pixel(7, 4)[0]);
+        assert!(middle < near_edge && near_edge < 255, "{} {}", middle, near_edge);
+        assert_eq!(layer.get_pixel(0, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_brush_edges_are_anti_aliased_unless_pencil() {
+        let mut canvas = Canvas::new(9, 9);
+        let mut brush = Brush::new(2.0, [0, 0, 0, 255]);
+        // Centered between pixels: the ring just past the radius is partly covered
+        brush.stamp(&mut canvas, (4.2, 4.5));
+        let edge = canvas.document.active_layer().unwrap().get_pixel(6, 4)[0];
+        assert!(edge > 0 && edge < 255, "{}", edge);
+        assert_eq!(canvas.document.active_layer().unwrap().get_pixel(4, 4), [0, 0, 0, 255]);
+
+        let mut canvas = Canvas::new(9, 9);
+        brush.pencil = true;
+        brush.stamp(&mut canvas, (4.2, 4.5));
+        let layer = canvas.document.active_layer().unwrap();
+        assert!(layer.pixels.chunks_exact(4).all(|px| px[0] == 0 || px[0] == 255));
+        assert_eq!(layer.get_pixel(6, 4), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_quick_mask_paints_the_selection() {
+        let mut canvas = Canvas::new(8, 8);
+        canvas.toggle_quick_mask();
+        // Nothing selected yet: the whole image is tinted red
+        assert_eq!(canvas.get_pixel(0, 0), Some([255, 128, 128, 255]));
+
+        let mut pencil = Brush::new(2.0, [255, 255, 255, 255]);
+        pencil.pencil = true;
+        canvas.stamp_dab(4.0, 4.0, &pencil);
+        canvas.erase_circle(4.0, 4.0, 1.0);
+        assert_eq!(canvas.get_pixel(2, 4), Some([255, 255, 255, 255]));
+        assert_eq!(canvas.get_pixel(4, 4), Some([255, 128, 128, 255]));
+        // Layer pixels are untouched
+        assert!(canvas.document.active_layer().unwrap().pixels.iter().all(|&v| v == 255));
+
+        canvas.toggle_quick_mask();
+        let selection = canvas.document.selection.as_ref().unwrap();
+        assert_eq!(selection.value(2, 4), 255);
+        assert_eq!(selection.value(4, 4), 0);
+        assert_eq!(selection.value(0, 0), 0);
+
+        // Leaving quick mask without painting anything selects nothing
+        let mut canvas = Canvas::new(4, 4);
+        canvas.toggle_quick_mask();
+        canvas.toggle_quick_mask();
+        assert!(canvas.document.selection.is_none());
+    }
+
+    #[test]
+    fn test_magic_wand_tolerance_and_modes() {
+        // Row: dark | near-dark | white | dark
+        let pixels = [[10, 10, 10, 255], [30, 30, 30, 255], [255, 255, 255, 255], [10, 10, 10, 255]].concat();
+        let wand = |tolerance, contiguous| {
+            let options = WandOptions { tolerance, contiguous, sample_merged: false };
+            select_similar(4, 1, &pixels, (0, 0), options).mask
+        };
+        assert_eq!(wand(0, true), vec![255, 0, 0, 0]);
+        assert_eq!(wand(20, true), vec![255, 255, 0, 0]);
+        assert_eq!(wand(20, false), vec![255, 255, 0, 255]);
+        assert_eq!(wand(255, true), vec![255; 4]);
+    }
+
+    #[test]
+    fn test_magic_wand_samples_layer_or_merged_image() {
+        let mut canvas = Canvas::new(2, 1);
+        canvas.document.add_layer();
+        canvas.document.active_layer_mut().unwrap().set_pixel(1, 0, [0, 0, 0, 255]);
+        canvas.refresh();
+        // The new layer is transparent at (0, 0) and black at (1, 0); merged it is white and black
+        let layer_only = WandOptions { tolerance: 0, contiguous: false, sample_merged: false };
+        assert_eq!(canvas.magic_wand(0, 0, layer_only).unwrap().mask, vec![255, 0]);
+        let merged = WandOptions { sample_merged: true, ..layer_only };
+        assert_eq!(canvas.magic_wand(1, 0, merged).unwrap().mask, vec![0, 255]);
+        assert!(canvas.magic_wand(5, 0, merged).is_none());
+    }
 }
@@ -0,0 +1,209 @@
+/// Size of the crop handles drawn inside the corners and edge midpoints, in canvas pixels
+pub const HANDLE_SIZE: u32 = 8;
+
+/// Aspect ratios the crop tool cycles through (width, height); None is free-form
+pub const ASPECT_PRESETS: [Option<(u32, u32)>; 8] = [
+    None,
+    Some((1, 1)),
+    Some((4, 3)),
+    Some((3, 4)),
+    Some((3, 2)),
+    Some((2, 3)),
+    Some((16, 9)),
+    Some((9, 16)),
+];
+
+/// Crop rectangle in image space, covering pixels [x0, x1) x [y0, y1).
+/// Coordinates may leave the image while dragging; see `clamped`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CropRect {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+/// Part of the crop rectangle grabbed by the pointer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CropHandle {
+    Inside,
+    Left,
+    Right,
+    Top,
+    Bottom,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl CropRect {
+    /// Rectangle spanned from a fixed `anchor` corner to a `corner` under the
+    /// pointer, grown along the shorter side to match `aspect` when one is set
+    pub fn from_corners(anchor: (i32, i32), corner: (i32, i32), aspect: Option<(u32, u32)>) -> Self {
+        let (mut w, mut h) = (corner.0 - anchor.0, corner.1 - anchor.1);
+        if let Some((aw, ah)) = aspect {
+            // A zero-length side grows towards positive coordinates
+            let sign = |v: i32| if v < 0 { -1 } else { 1 };
+            let scale = (w.abs() as f32 / aw as f32).max(h.abs() as f32 / ah as f32);
+            w = sign(w) * (scale * aw as f32).round() as i32;
+            h = sign(h) * (scale * ah as f32).round() as i32;
+        }
+        Self {
+            x0: anchor.0.min(anchor.0 + w),
+            y0: anchor.1.min(anchor.1 + h),
+            x1: anchor.0.max(anchor.0 + w),
+            y1: anchor.1.max(anchor.1 + h),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        (self.x1 - self.x0).max(0) as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.y1 - self.y0).max(0) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width() == 0 || self.height() == 0
+    }
+
+    /// The part of the rectangle inside a `width` x `height` image
+    pub fn clamped(&self, width: u32, height: u32) -> Self {
+        let (w, h) = (width as i32, height as i32);
+        Self {
+            x0: self.x0.clamp(0, w),
+            y0: self.y0.clamp(0, h),
+            x1: self.x1.clamp(0, w),
+            y1: self.y1.clamp(0, h),
+        }
+    }
+
+    /// The rectangle shifted (not resized) so it lies inside the image where it fits
+    pub fn shifted_inside(&self, width: u32, height: u32) -> Self {
+        // Pull back from the far edge, but never past the near one
+        let dx = (width as i32 - self.x1).min(0).max(-self.x0);
+        let dy = (height as i32 - self.y1).min(0).max(-self.y0);
+        Self {
+            x0: self.x0 + dx,
+            y0: self.y0 + dy,
+            x1: self.x1 + dx,
+            y1: self.y1 + dy,
+        }
+        .clamped(width, height)
+    }
+
+    /// Handle under an image-space point; `reach` is how far (in image pixels)
+    /// from an edge still grabs it
+    pub fn handle_at(&self, x: f32, y: f32, reach: f32) -> Option<CropHandle> {
+        let near = |a: f32, b: i32| (a - b as f32).abs() <= reach;
+        let within_x = x >= self.x0 as f32 - reach && x <= self.x1 as f32 + reach;
+        let within_y = y >= self.y0 as f32 - reach && y <= self.y1 as f32 + reach;
+        if !within_x || !within_y {
+            return None;
+        }
+        let (left, right) = (near(x, self.x0), near(x, self.x1));
+        let (top, bottom) = (near(y, self.y0), near(y, self.y1));
+        Some(match (left, right, top, bottom) {
+            (true, _, true, _) => CropHandle::TopLeft,
+            (_, true, true, _) => CropHandle::TopRight,
+            (true, _, _, true) => CropHandle::BottomLeft,
+            (_, true, _, true) => CropHandle::BottomRight,
+            (true, _, _, _) => CropHandle::Left,
+            (_, true, _, _) => CropHandle::Right,
+            (_, _, true, _) => CropHandle::Top,
+            (_, _, _, true) => CropHandle::Bottom,
+            _ => CropHandle::Inside,
+        })
+    }
+
+    /// The rectangle after dragging `handle` by (dx, dy) image pixels. Corners
+    /// keep the opposite corner fixed; with an aspect ratio, side handles size
+    /// the other axis from its top or left edge.
+    pub fn drag(&self, handle: CropHandle, dx: i32, dy: i32, aspect: Option<(u32, u32)>) -> Self {
+        let r = *self;
+        match handle {
+            CropHandle::Inside => Self {
+                x0: r.x0 + dx,
+                y0: r.y0 + dy,
+                x1: r.x1 + dx,
+                y1: r.y1 + dy,
+            },
+            CropHandle::TopLeft => Self::from_corners((r.x1, r.y1), (r.x0 + dx, r.y0 + dy), aspect),
+            CropHandle::TopRight => Self::from_corners((r.x0, r.y1), (r.x1 + dx, r.y0 + dy), aspect),
+            CropHandle::BottomLeft => Self::from_corners((r.x1, r.y0), (r.x0 + dx, r.y1 + dy), aspect),
+            CropHandle::BottomRight => Self::from_corners((r.x0, r.y0), (r.x1 + dx, r.y1 + dy), aspect),
+            CropHandle::Left | CropHandle::Right => {
+                let (anchor, edge) = if handle == CropHandle::Left { (r.x1, r.x0 + dx) } else { (r.x0, r.x1 + dx) };
+                let height = match aspect {
+                    Some((aw, ah)) => ((anchor - edge).abs() as f32 * ah as f32 / aw as f32).round() as i32,
+                    None => r.y1 - r.y0,
+                };
+                Self { x0: anchor.min(edge), y0: r.y0, x1: anchor.max(edge), y1: r.y0 + height }
+            }
+            CropHandle::Top | CropHandle::Bottom => {
+                let (anchor, edge) = if handle == CropHandle::Top { (r.y1, r.y0 + dy) } else { (r.y0, r.y1 + dy) };
+                let width = match aspect {
+                    Some((aw, ah)) => ((anchor - edge).abs() as f32 * aw as f32 / ah as f32).round() as i32,
+                    None => r.x1 - r.x0,
+                };
+                Self { x0: r.x0, y0: anchor.min(edge), x1: r.x0 + width, y1: anchor.max(edge) }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> CropRect {
+        CropRect { x0, y0, x1, y1 }
+    }
+
+    #[test]
+    fn test_from_corners_normalizes_and_keeps_aspect() {
+        assert_eq!(CropRect::from_corners((10, 10), (4, 2), None), rect(4, 2, 10, 10));
+        // The shorter side grows to the ratio, in the direction of the drag
+        assert_eq!(CropRect::from_corners((0, 0), (40, 10), Some((4, 3))), rect(0, 0, 40, 30));
+        assert_eq!(CropRect::from_corners((50, 50), (40, 10), Some((1, 1))), rect(10, 10, 50, 50));
+        // A drag along one axis only still grows the other one
+        assert_eq!(CropRect::from_corners((0, 0), (16, 0), Some((16, 9))), rect(0, 0, 16, 9));
+    }
+
+    #[test]
+    fn test_handle_at_finds_corners_edges_and_inside() {
+        let r = rect(10, 10, 50, 30);
+        assert_eq!(r.handle_at(10.5, 9.0, 2.0), Some(CropHandle::TopLeft));
+        assert_eq!(r.handle_at(49.0, 31.0, 2.0), Some(CropHandle::BottomRight));
+        assert_eq!(r.handle_at(30.0, 30.0, 2.0), Some(CropHandle::Bottom));
+        assert_eq!(r.handle_at(11.0, 20.0, 2.0), Some(CropHandle::Left));
+        assert_eq!(r.handle_at(30.0, 20.0, 2.0), Some(CropHandle::Inside));
+        assert_eq!(r.handle_at(60.0, 20.0, 2.0), None);
+    }
+
+    #[test]
+    fn test_drag_handles() {
+        let r = rect(10, 10, 50, 30);
+        assert_eq!(r.drag(CropHandle::Inside, 5, -5, None), rect(15, 5, 55, 25));
+        assert_eq!(r.drag(CropHandle::Left, -4, 7, None), rect(6, 10, 50, 30));
+        assert_eq!(r.drag(CropHandle::BottomRight, 10, 10, None), rect(10, 10, 60, 40));
+        // Dragging a corner past the opposite one flips the rectangle
+        assert_eq!(r.drag(CropHandle::TopLeft, 50, 0, None), rect(50, 10, 60, 30));
+        // With a ratio the side handle also sets the height
+        assert_eq!(r.drag(CropHandle::Right, -20, 0, Some((1, 1))), rect(10, 10, 30, 30));
+    }
+
+    #[test]
+    fn test_clamped_to_image() {
+        let r = rect(-5, 10, 50, 120).clamped(40, 100);
+        assert_eq!(r, rect(0, 10, 40, 100));
+        assert_eq!((r.width(), r.height()), (40, 90));
+        assert!(rect(50, 10, 60, 20).clamped(40, 100).is_empty());
+
+        // Moving keeps the size and stops at the image border
+        assert_eq!(rect(30, -4, 50, 6).shifted_inside(40, 100), rect(20, 0, 40, 10));
+        assert_eq!(rect(-10, 0, 60, 10).shifted_inside(40, 100), rect(0, 0, 40, 10));
+    }
+}
@@ -0,0 +1,1309 @@
+use crate::blend::{blend_pixel, BlendMode};
+use crate::layer::{GroupMetadata, Layer, LayerGroup, LayerMetadata, LayerNode, NodeMetadata, Project};
+use crate::layer::{crop_pixels, place_pixels};
+use crate::resample::Filter;
+use crate::selection::Selection;
+use crate::transform::{transform_layer, Transform};
+
+/// Address of a node in the layer tree: index in the root list, then the
+/// index inside each nested group
+pub type LayerPath = Vec<usize>;
+
+/// Point of the old canvas that stays put when the canvas is resized, as in
+/// GIMP's 3x3 anchor grid. Column and row are 0 (left/top), 1 (center) or 2 (right/bottom).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Anchor {
+    pub column: u8,
+    pub row: u8,
+}
+
+impl Anchor {
+    pub const CENTER: Anchor = Anchor { column: 1, row: 1 };
+
+    /// Where the old canvas's top-left corner lands in a canvas resized from `size` to `new_size`
+    pub fn offset(&self, size: (u32, u32), new_size: (u32, u32)) -> (i32, i32) {
+        let dx = (new_size.0 as i64 - size.0 as i64) * self.column as i64 / 2;
+        let dy = (new_size.1 as i64 - size.1 as i64) * self.row as i64 / 2;
+        (dx as i32, dy as i32)
+    }
+}
+
+/// Pasted pixels hovering directly above the active layer until they are
+/// anchored into it or turned into a layer of their own (GIMP's floating selection).
+/// Also the form the clipboard keeps copied pixels in.
+#[derive(Clone, Debug)]
+pub struct FloatingLayer {
+    pub layer: Layer,
+    pub x: i32, // Image-space position of the layer's top-left corner
+    pub y: i32,
+}
+
+impl FloatingLayer {
+    /// Pixel covering image position (x, y); transparent outside the layer
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let (lx, ly) = (x as i64 - self.x as i64, y as i64 - self.y as i64);
+        if lx < 0 || ly < 0 {
+            return [0, 0, 0, 0];
+        }
+        self.layer.get_pixel(lx as u32, ly as u32)
+    }
+
+    /// Image-space rectangle [x0, x1) x [y0, y1) covered by the layer, clamped to the image
+    pub fn bounds(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let clamp = |v: i64, max: u32| v.clamp(0, max as i64) as u32;
+        (
+            clamp(self.x as i64, width),
+            clamp(self.y as i64, height),
+            clamp(self.x as i64 + self.layer.width as i64, width),
+            clamp(self.y as i64 + self.layer.height as i64, height),
+        )
+    }
+}
+
+/// An open image: a tree of layers and groups (bottom first) plus the
+/// path of the node that painting tools write into.
+#[derive(Clone, Debug)]
+pub struct Document {
+    pub width: u32,
+    pub height: u32,
+    pub layers: Vec<LayerNode>,
+    pub active: LayerPath,
+    pub edit_mask: bool, // Paint tools target the active layer's mask instead of its colors
+    pub selection: Option<Selection>, // Edits are limited to this mask; None means everything is editable
+    pub quick_mask: bool, // Brush and eraser paint the selection, shown as a red overlay
+    pub floating: Option<FloatingLayer>, // Pasted pixels not yet anchored, shown above the active node
+}
+
+impl Document {
+    /// Create a document with a single white background layer
+    pub fn new(width: u32, height: u32) -> Self {
+        let background = Layer::new("Background".to_string(), width, height);
+        Self::from_layers(width, height, vec![background])
+    }
+
+    /// Build a document from a flat list of layers; the top layer becomes active
+    pub fn from_layers(width: u32, height: u32, layers: Vec<Layer>) -> Self {
+        Self::from_nodes(width, height, layers.into_iter().map(LayerNode::Layer).collect())
+    }
+
+    /// Build a document from an existing layer tree; the top node becomes active
+    pub fn from_nodes(width: u32, height: u32, layers: Vec<LayerNode>) -> Self {
+        let active = if layers.is_empty() { vec![] } else { vec![layers.len() - 1] };
+        Self {
+            width,
+            height,
+            layers,
+            active,
+            edit_mask: false,
+            selection: None,
+            quick_mask: false,
+            floating: None,
+        }
+    }
+
+    /// Rebuild the layer tree described by a project from its layers, given in
+    /// `Project::layer_metadata` order
+    pub fn from_project(project: &Project, layers: Vec<Layer>) -> Self {
+        fn build(nodes: &[NodeMetadata], layers: &mut impl Iterator<Item = Layer>) -> Vec<LayerNode> {
+            let mut out = Vec::new();
+            for node in nodes {
+                match node {
+                    NodeMetadata::Layer(_) => {
+                        if let Some(layer) = layers.next() {
+                            out.push(LayerNode::Layer(layer));
+                        }
+                    }
+                    NodeMetadata::Group(meta) => {
+                        let mut group = LayerGroup::new(meta.name.clone(), build(&meta.children, layers));
+                        group.visible = meta.visible;
+                        group.opacity = meta.opacity;
+                        group.blend_mode = meta.blend_mode;
+                        out.push(LayerNode::Group(group));
+                    }
+                }
+            }
+            out
+        }
+        let mut layers = layers.into_iter();
+        let tree = build(&project.layers, &mut layers);
+        Self::from_nodes(project.width, project.height, tree)
+    }
+
+    pub fn node(&self, path: &[usize]) -> Option<&LayerNode> {
+        let (first, rest) = path.split_first()?;
+        let mut node = self.layers.get(*first)?;
+        for idx in rest {
+            match node {
+                LayerNode::Group(group) => node = group.children.get(*idx)?,
+                LayerNode::Layer(_) => return None,
+            }
+        }
+        Some(node)
+    }
+
+    pub fn node_mut(&mut self, path: &[usize]) -> Option<&mut LayerNode> {
+        let (first, rest) = path.split_first()?;
+        let mut node = self.layers.get_mut(*first)?;
+        for idx in rest {
+            match node {
+                LayerNode::Group(group) => node = group.children.get_mut(*idx)?,
+                LayerNode::Layer(_) => return None,
+            }
+        }
+        Some(node)
+    }
+
+    /// The list holding the children of `parent` (the root list for an empty path)
+    pub fn siblings_mut(&mut self, parent: &[usize]) -> Option<&mut Vec<LayerNode>> {
+        if parent.is_empty() {
+            return Some(&mut self.layers);
+        }
+        match self.node_mut(parent)? {
+            LayerNode::Group(group) => Some(&mut group.children),
+            LayerNode::Layer(_) => None,
+        }
+    }
+
+    pub fn active_node(&self) -> Option<&LayerNode> {
+        self.node(&self.active)
+    }
+
+    pub fn active_node_mut(&mut self) -> Option<&mut LayerNode> {
+        let path = self.active.clone();
+        self.node_mut(&path)
+    }
+
+    /// The active pixel layer; None when a group is selected
+    pub fn active_layer(&self) -> Option<&Layer> {
+        match self.active_node()? {
+            LayerNode::Layer(layer) => Some(layer),
+            LayerNode::Group(_) => None,
+        }
+    }
+
+    /// The active pixel layer for editing; None when a group is selected. A
+    /// layer moved by the Move tool may not cover the whole image: edits go
+    /// through the `_at` accessors, calling `Layer::cover` first where they
+    /// paint pixels the buffer does not hold yet.
+    pub fn active_layer_mut(&mut self) -> Option<&mut Layer> {
+        match self.active_node_mut()? {
+            LayerNode::Layer(layer) => Some(layer),
+            LayerNode::Group(_) => None,
+        }
+    }
+
+    /// Move the active layer's contents by an image-space offset without
+    /// touching its pixels; false when a group is active
+    pub fn move_active_layer(&mut self, dx: i32, dy: i32) -> bool {
+        match self.active_node_mut() {
+            Some(LayerNode::Layer(layer)) => {
+                layer.offset = (layer.offset.0 + dx, layer.offset.1 + dy);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every pixel layer in the tree, depth-first and bottom first
+    pub fn leaf_layers(&self) -> Vec<&Layer> {
+        fn collect<'a>(nodes: &'a [LayerNode], out: &mut Vec<&'a Layer>) {
+            for node in nodes {
+                match node {
+                    LayerNode::Layer(layer) => out.push(layer),
+                    LayerNode::Group(group) => collect(&group.children, out),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        collect(&self.layers, &mut out);
+        out
+    }
+
+    /// Mutable access to every pixel layer, in the same order as `leaf_layers`
+    pub fn layers_mut(&mut self) -> Vec<&mut Layer> {
+        fn collect<'a>(nodes: &'a mut [LayerNode], out: &mut Vec<&'a mut Layer>) {
+            for node in nodes {
+                match node {
+                    LayerNode::Layer(layer) => out.push(layer),
+                    LayerNode::Group(group) => collect(&mut group.children, out),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        collect(&mut self.layers, &mut out);
+        out
+    }
+
+    /// Insert a node directly above the active one (in the same group) and make it active
+    fn insert_above_active(&mut self, node: LayerNode) {
+        let (parent, idx) = match self.active.split_last() {
+            Some((last, parent)) if self.active_node().is_some() => (parent.to_vec(), last + 1),
+            _ => (vec![], self.layers.len()),
+        };
+        if let Some(siblings) = self.siblings_mut(&parent) {
+            let idx = idx.min(siblings.len());
+            siblings.insert(idx, node);
+            self.active = parent;
+            self.active.push(idx);
+        }
+    }
+
+    /// Insert a transparent layer above the active one and make it active
+    pub fn add_layer(&mut self) {
+        let name = format!("Layer {}", self.leaf_layers().len());
+        let layer = Layer::transparent(name, self.width, self.height);
+        self.insert_above_active(LayerNode::Layer(layer));
+    }
+
+    /// Insert a copy of the active node above it and make the copy active
+    pub fn duplicate_active(&mut self) {
+        let Some(mut node) = self.active_node().cloned() else {
+            return;
+        };
+        match &mut node {
+            LayerNode::Layer(layer) => layer.name = format!("{} copy", layer.name),
+            LayerNode::Group(group) => group.name = format!("{} copy", group.name),
+        }
+        self.insert_above_active(node);
+    }
+
+    /// Remove the active node and select its nearest sibling (the parent group
+    /// when it was the only child). The last remaining root node is never deleted.
+    pub fn delete_active(&mut self) {
+        let Some((&idx, parent)) = self.active.split_last() else {
+            return;
+        };
+        let parent = parent.to_vec();
+        if parent.is_empty() && self.layers.len() <= 1 {
+            return;
+        }
+        let Some(siblings) = self.siblings_mut(&parent) else {
+            return;
+        };
+        if idx >= siblings.len() {
+            return;
+        }
+        siblings.remove(idx);
+        let remaining = siblings.len();
+        self.active = parent;
+        if remaining > 0 {
+            self.active.push(idx.saturating_sub(1).min(remaining - 1));
+        }
+    }
+
+    /// Flatten the active node into the pixel layer directly below it, using the
+    /// active node's opacity, mask and blend mode. The lower layer's own mask is
+    /// applied first, as it would otherwise hide the merged pixels too. The
+    /// lower layer grows to hold a moved upper layer's pixels past the image
+    /// edges. The merged layer becomes active. Returns false, changing nothing,
+    /// when there is no pixel layer below or the active node is hidden.
+    pub fn merge_down(&mut self) -> bool {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let Some((&idx, parent)) = self.active.split_last() else {
+            return false;
+        };
+        if idx == 0 {
+            return false;
+        }
+        let parent = parent.to_vec();
+        let Some(siblings) = self.siblings_mut(&parent) else {
+            return false;
+        };
+        // Merging a hidden node would throw its pixels away
+        if idx >= siblings.len() || !matches!(siblings[idx - 1], LayerNode::Layer(_)) || !siblings[idx].visible() {
+            return false;
+        }
+        let upper = siblings.remove(idx);
+        let LayerNode::Layer(lower) = &mut siblings[idx - 1] else {
+            return false;
+        };
+        lower.cover(0, 0, width, height);
+        if let LayerNode::Layer(layer) = &upper {
+            let (x0, y0, x1, y1) = layer.bounds();
+            lower.cover(x0, y0, x1, y1);
+        }
+        lower.apply_mask();
+        let (ox, oy) = lower.offset;
+        for ly in 0..lower.height {
+            for lx in 0..lower.width {
+                let (x, y) = (lx as i32 + ox, ly as i32 + oy);
+                let on_image = x >= 0 && y >= 0 && x < width && y < height;
+                let (src, opacity) = match &upper {
+                    LayerNode::Layer(layer) => (layer.pixel_at(x, y), layer.opacity * layer.mask_at(x, y) as f32 / 255.0),
+                    LayerNode::Group(group) if on_image => (composite_nodes(&group.children, x as u32, y as u32, None), group.opacity),
+                    LayerNode::Group(_) => continue,
+                };
+                let idx = ((ly * lower.width + lx) * 4) as usize;
+                blend_pixel(&mut lower.pixels[idx..idx + 4], src, opacity, upper.blend_mode());
+            }
+        }
+        self.active = parent;
+        self.active.push(idx - 1);
+        true
+    }
+
+    /// Move the node at `from` to take the place of the node at `to` (same
+    /// semantics as dragging a row onto another row in the layers panel).
+    /// Returns false when the move is not possible, e.g. a group onto its own child.
+    pub fn move_node(&mut self, from: &[usize], to: &[usize]) -> bool {
+        if from == to || to.starts_with(from) || self.node(from).is_none() || self.node(to).is_none() {
+            return false;
+        }
+        let outline = self.outline();
+        let row = |path: &[usize]| outline.iter().position(|(p, _)| p == path);
+        let moving_up = row(to) < row(from);
+
+        let Some((&from_idx, from_parent)) = from.split_last() else {
+            return false;
+        };
+        let node = match self.siblings_mut(from_parent) {
+            Some(siblings) => siblings.remove(from_idx),
+            None => return false,
+        };
+
+        // Removing the node shifts later siblings (and anything inside them) down by one
+        let mut target = to.to_vec();
+        let depth = from_parent.len();
+        if target.len() > depth && target.starts_with(from_parent) && target[depth] > from_idx {
+            target[depth] -= 1;
+        }
+        let Some((&to_idx, to_parent)) = target.split_last() else {
+            return false;
+        };
+        let to_parent = to_parent.to_vec();
+        let Some(siblings) = self.siblings_mut(&to_parent) else {
+            return false;
+        };
+        // Rows are listed top first, so moving up lands above the target
+        let insert_at = if moving_up { to_idx + 1 } else { to_idx }.min(siblings.len());
+        siblings.insert(insert_at, node);
+        self.active = to_parent;
+        self.active.push(insert_at);
+        true
+    }
+
+    /// Wrap the active node in a new group, which becomes active
+    pub fn group_active(&mut self) {
+        let Some((&idx, parent)) = self.active.split_last() else {
+            return;
+        };
+        let parent = parent.to_vec();
+        let Some(siblings) = self.siblings_mut(&parent) else {
+            return;
+        };
+        if idx >= siblings.len() {
+            return;
+        }
+        let node = siblings.remove(idx);
+        let name = format!("Group {}", idx + 1);
+        siblings.insert(idx, LayerNode::Group(LayerGroup::new(name, vec![node])));
+    }
+
+    /// Replace the active group by its children; the topmost child becomes active
+    pub fn ungroup_active(&mut self) {
+        let Some((&idx, parent)) = self.active.split_last() else {
+            return;
+        };
+        let parent = parent.to_vec();
+        let Some(siblings) = self.siblings_mut(&parent) else {
+            return;
+        };
+        if !matches!(siblings.get(idx), Some(LayerNode::Group(_))) {
+            return;
+        }
+        let LayerNode::Group(group) = siblings.remove(idx) else {
+            return;
+        };
+        let count = group.children.len();
+        for (offset, child) in group.children.into_iter().enumerate() {
+            siblings.insert(idx + offset, child);
+        }
+        let remaining = siblings.len();
+        self.active = parent;
+        if remaining > 0 {
+            let top = if count == 0 { idx.min(remaining - 1) } else { idx + count - 1 };
+            self.active.push(top);
+        }
+    }
+
+    /// Every node with its depth, in panel order (top of the stack first,
+    /// a group listed before its children)
+    pub fn outline(&self) -> Vec<(LayerPath, usize)> {
+        fn walk(nodes: &[LayerNode], prefix: &mut LayerPath, out: &mut Vec<(LayerPath, usize)>) {
+            for (idx, node) in nodes.iter().enumerate().rev() {
+                prefix.push(idx);
+                out.push((prefix.clone(), prefix.len() - 1));
+                if let LayerNode::Group(group) = node {
+                    walk(&group.children, prefix, out);
+                }
+                prefix.pop();
+            }
+        }
+        let mut out = Vec::new();
+        walk(&self.layers, &mut vec![], &mut out);
+        out
+    }
+
+    pub fn select(&mut self, path: LayerPath) {
+        if self.node(&path).is_some() {
+            self.active = path;
+        }
+    }
+
+    /// Move the selection one row up in the outline
+    pub fn select_above(&mut self) {
+        let outline = self.outline();
+        if let Some(pos) = outline.iter().position(|(p, _)| *p == self.active)
+            && pos > 0
+        {
+            self.active = outline[pos - 1].0.clone();
+        }
+    }
+
+    /// Move the selection one row down in the outline
+    pub fn select_below(&mut self) {
+        let outline = self.outline();
+        if let Some(pos) = outline.iter().position(|(p, _)| *p == self.active)
+            && pos + 1 < outline.len()
+        {
+            self.active = outline[pos + 1].0.clone();
+        }
+    }
+
+    /// True when paint tools should write into the active layer's mask
+    pub fn painting_mask(&self) -> bool {
+        self.edit_mask && self.active_layer().is_some_and(|l| l.mask.is_some())
+    }
+
+    /// Composite all visible layers into a tight RGBA buffer (straight alpha,
+    /// transparent where no layer covers a pixel)
+    pub fn composite(&self) -> Vec<u8> {
+        let mut out = vec![0u8; self.width as usize * self.height as usize * 4];
+        self.composite_region(&mut out, 0, 0, self.width, self.height);
+        out
+    }
+
+    /// Recomposite the rectangle [x0, x1) x [y0, y1) of a full-size composite buffer
+    pub fn composite_region(&self, out: &mut [u8], x0: u32, y0: u32, x1: u32, y1: u32) {
+        let x1 = x1.min(self.width);
+        let y1 = y1.min(self.height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let idx = (y as usize * self.width as usize + x as usize) * 4;
+                if idx + 4 > out.len() {
+                    continue;
+                }
+                let floating = self.floating.as_ref().map(|f| (self.active.as_slice(), f));
+                out[idx..idx + 4].copy_from_slice(&composite_nodes(&self.layers, x, y, floating));
+            }
+        }
+    }
+
+    /// Copy the selected part of the active layer, or of the merged image, cropped
+    /// to the selection bounds (the whole image when nothing is selected).
+    /// Partly selected pixels keep a matching share of their alpha.
+    pub fn copy(&self, merged: bool) -> Option<FloatingLayer> {
+        let (x0, y0, x1, y1) = match &self.selection {
+            Some(selection) => selection.bounds()?,
+            None => (0, 0, self.width, self.height),
+        };
+        let source = if merged { self.composite() } else { self.active_layer()?.placed(self.width, self.height) };
+        let mut layer = Layer::transparent("Clipboard".to_string(), x1 - x0, y1 - y0);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let idx = (y as usize * self.width as usize + x as usize) * 4;
+                let mut color = [source[idx], source[idx + 1], source[idx + 2], source[idx + 3]];
+                let coverage = self.selection.as_ref().map_or(255, |s| s.value(x, y));
+                color[3] = (color[3] as u32 * coverage as u32 / 255) as u8;
+                layer.set_pixel(x - x0, y - y0, color);
+            }
+        }
+        Some(FloatingLayer { layer, x: x0 as i32, y: y0 as i32 })
+    }
+
+    /// Copy the selected part of the active layer, then erase it from the layer
+    pub fn cut(&mut self) -> Option<FloatingLayer> {
+        let copied = self.copy(false)?;
+        let selection = self.selection.clone();
+        let (width, height) = (self.width, self.height);
+        let layer = self.active_layer_mut()?;
+        for y in 0..height {
+            for x in 0..width {
+                let coverage = selection.as_ref().map_or(255, |s| s.value(x, y));
+                if coverage > 0 {
+                    let (x, y) = (x as i32, y as i32);
+                    let mut color = layer.pixel_at(x, y);
+                    color[3] = (color[3] as u32 * (255 - coverage as u32) / 255) as u8;
+                    layer.set_pixel_at(x, y, color);
+                }
+            }
+        }
+        Some(copied)
+    }
+
+    /// Paste clipboard pixels as a floating layer; a previous floating layer is anchored first
+    pub fn paste(&mut self, clip: &FloatingLayer) {
+        self.anchor_floating();
+        let mut floating = clip.clone();
+        floating.layer.name = "Floating Selection".to_string();
+        // Keep the copied position, unless that is off this image; then center it
+        let (x0, y0, x1, y1) = floating.bounds(self.width, self.height);
+        if x0 == x1 || y0 == y1 {
+            floating.x = (self.width as i32 - floating.layer.width as i32) / 2;
+            floating.y = (self.height as i32 - floating.layer.height as i32) / 2;
+        }
+        self.floating = Some(floating);
+    }
+
+    /// Shift the floating layer by an image-space offset
+    pub fn move_floating(&mut self, dx: i32, dy: i32) {
+        if let Some(floating) = self.floating.as_mut() {
+            floating.x += dx;
+            floating.y += dy;
+        }
+    }
+
+    /// Merge the floating layer into the active layer, growing the layer to
+    /// keep any part hanging past the image edges. When a group is active it
+    /// becomes a new layer instead, as there are no pixels to merge into.
+    pub fn anchor_floating(&mut self) {
+        if self.active_layer().is_none() {
+            self.floating_to_layer();
+            return;
+        }
+        let Some(floating) = self.floating.take() else {
+            return;
+        };
+        if let Some(layer) = self.active_layer_mut() {
+            let (width, height) = (floating.layer.width, floating.layer.height);
+            layer.cover(floating.x, floating.y, floating.x + width as i32, floating.y + height as i32);
+            for y in 0..height {
+                for x in 0..width {
+                    layer.blend_pixel_at(floating.x + x as i32, floating.y + y as i32, floating.layer.get_pixel(x, y));
+                }
+            }
+        }
+    }
+
+    /// Turn the floating layer into a new layer above the active node
+    pub fn floating_to_layer(&mut self) {
+        let Some(floating) = self.floating.take() else {
+            return;
+        };
+        let name = format!("Pasted Layer {}", self.leaf_layers().len());
+        let mut layer = Layer::transparent(name, self.width, self.height);
+        let (x0, y0, x1, y1) = floating.bounds(self.width, self.height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                layer.set_pixel(x, y, floating.pixel(x, y));
+            }
+        }
+        self.insert_above_active(LayerNode::Layer(layer));
+    }
+
+    /// Cut the image down to the `width` x `height` rectangle at (x, y): every
+    /// layer and mask, the selection and the floating layer's position. The
+    /// rectangle is clipped to the image; an empty result leaves it unchanged.
+    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> bool {
+        let x1 = x.saturating_add(width).min(self.width);
+        let y1 = y.saturating_add(height).min(self.height);
+        if x >= x1 || y >= y1 {
+            return false;
+        }
+        let size = (self.width, self.height);
+        let (width, height) = (x1 - x, y1 - y);
+        for layer in self.layers_mut() {
+            if layer.is_aligned(size.0, size.1) {
+                layer.crop(x, y, width, height);
+            } else {
+                // Moved layers are cut where the rectangle lies over them
+                let offset = (layer.offset.0 - x as i32, layer.offset.1 - y as i32);
+                layer.resize(width, height, offset, [0, 0, 0, 0]);
+                layer.offset = (0, 0);
+            }
+        }
+        if let Some(selection) = self.selection.as_mut() {
+            selection.crop(x, y, width, height);
+        }
+        if self.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.selection = None;
+        }
+        if let Some(floating) = self.floating.as_mut() {
+            floating.x -= x as i32;
+            floating.y -= y as i32;
+        }
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    /// Image > Canvas Size: change the image size around `anchor`. With
+    /// `resize_layers` the layers' new area gets `fill`; otherwise only the canvas
+    /// grows and the new area stays transparent. Content pushed outside is
+    /// dropped, except from layers moved with the Move tool: those only shift.
+    pub fn resize_canvas(&mut self, width: u32, height: u32, anchor: Anchor, fill: [u8; 4], resize_layers: bool) -> bool {
+        if width == 0 || height == 0 || (width, height) == (self.width, self.height) {
+            return false;
+        }
+        let size = (self.width, self.height);
+        let offset = anchor.offset(size, (width, height));
+        let fill = if resize_layers { fill } else { [0, 0, 0, 0] };
+        for layer in self.layers_mut() {
+            if layer.is_aligned(size.0, size.1) {
+                layer.resize(width, height, offset, fill);
+            } else {
+                layer.offset = (layer.offset.0 + offset.0, layer.offset.1 + offset.1);
+            }
+        }
+        if let Some(selection) = self.selection.as_mut() {
+            selection.resize(width, height, offset);
+        }
+        if self.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.selection = None;
+        }
+        if let Some(floating) = self.floating.as_mut() {
+            floating.x += offset.0;
+            floating.y += offset.1;
+        }
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    /// Image > Scale Image: resample every layer, mask and the selection to
+    /// `width` x `height`. A floating layer is scaled and moved to match.
+    pub fn scale(&mut self, width: u32, height: u32, filter: Filter) -> bool {
+        if width == 0 || height == 0 || (width, height) == (self.width, self.height) {
+            return false;
+        }
+        let size = (self.width, self.height);
+        let scale = (width as f32 / self.width as f32, height as f32 / self.height as f32);
+        for layer in self.layers_mut() {
+            if layer.is_aligned(size.0, size.1) {
+                layer.scale(width, height, filter);
+            } else {
+                layer.offset = scale_placed(layer, layer.offset, scale, filter);
+            }
+        }
+        if let Some(selection) = self.selection.as_mut() {
+            selection.scale(width, height, filter);
+        }
+        if self.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.selection = None;
+        }
+        if let Some(floating) = self.floating.as_mut() {
+            (floating.x, floating.y) = scale_placed(&mut floating.layer, (floating.x, floating.y), scale, filter);
+        }
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    /// Take the pixels a transform tool works on off the document: the floating
+    /// layer, else the selected part of the active layer, else the active
+    /// layer's visible content. The flag tells whether that was the whole layer.
+    pub fn lift_active(&mut self) -> Option<(FloatingLayer, bool)> {
+        if let Some(floating) = self.floating.take() {
+            return Some((floating, false));
+        }
+        if self.selection.is_some() {
+            return self.cut().map(|floating| (floating, false));
+        }
+        // The whole buffer, including pixels moved past the image edges
+        let layer = self.active_layer_mut()?;
+        let (mut x0, mut y0, mut x1, mut y1) = (layer.width, layer.height, 0, 0);
+        for y in 0..layer.height {
+            for x in 0..layer.width {
+                if layer.get_pixel(x, y)[3] > 0 {
+                    (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1));
+                }
+            }
+        }
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        let pixels = crop_pixels(&layer.pixels, layer.width, 4, (x0, y0, x1 - x0, y1 - y0));
+        layer.pixels.fill(0);
+        let lifted = Layer::from_rgba(layer.name.clone(), x1 - x0, y1 - y0, pixels);
+        let (x, y) = (x0 as i32 + layer.offset.0, y0 as i32 + layer.offset.1);
+        Some((FloatingLayer { layer: lifted, x, y }, true))
+    }
+
+    /// Image > Transform: rotate or mirror the whole image, with every layer
+    /// and mask, the selection and the floating layer
+    pub fn transform(&mut self, transform: Transform) {
+        let size = (self.width, self.height);
+        for layer in self.layers_mut() {
+            if layer.is_aligned(size.0, size.1) {
+                *layer = transform_layer(layer, transform);
+            } else {
+                let (transformed, offset) = transform_placed(layer, layer.offset, size, transform);
+                *layer = Layer { offset, ..transformed };
+            }
+        }
+        if let Some(selection) = self.selection.as_mut() {
+            let (mask, (width, height)) = transform.apply(&selection.mask, size, &[0]);
+            *selection = Selection { width, height, mask };
+        }
+        if self.selection.as_ref().is_some_and(|s| s.is_empty()) {
+            self.selection = None;
+        }
+        if let Some(floating) = self.floating.as_mut() {
+            let (layer, (x, y)) = transform_placed(&floating.layer, (floating.x, floating.y), size, transform);
+            *floating = FloatingLayer { layer, x, y };
+        }
+        (self.width, self.height) = transform.output_size(size);
+    }
+
+    /// Layer > Transform: rotate or mirror the selected part of the active layer
+    /// (lifted into a floating layer, as GIMP does), the floating layer if there
+    /// is one, or else the whole active layer about the image center
+    pub fn transform_active(&mut self, transform: Transform) -> bool {
+        if self.floating.is_none() && self.selection.is_some() {
+            self.floating = self.cut();
+        }
+        let size = (self.width, self.height);
+        if let Some(floating) = self.floating.as_mut() {
+            let (old_x, old_y) = (floating.x, floating.y);
+            let old_size = (floating.layer.width, floating.layer.height);
+            let transform = transform.expanded();
+            floating.layer = transform_layer(&floating.layer, transform);
+            floating.x = (old_x as f32 + (old_size.0 as f32 - floating.layer.width as f32) / 2.0).round() as i32;
+            floating.y = (old_y as f32 + (old_size.1 as f32 - floating.layer.height as f32) / 2.0).round() as i32;
+            // The selection turns with the pixels it outlines
+            if let Some(selection) = self.selection.as_mut() {
+                let region = place_pixels(&selection.mask, size, &[0], old_size, (-old_x, -old_y));
+                let (region, new_size) = transform.apply(&region, old_size, &[0]);
+                selection.mask = place_pixels(&region, new_size, &[0], size, (floating.x, floating.y));
+            }
+            return true;
+        }
+        let Some(layer) = self.active_layer_mut() else {
+            return false;
+        };
+        if !layer.is_aligned(size.0, size.1) {
+            // Turn a moved layer about the image center, keeping pixels past the edges
+            let (transformed, (x, y)) = transform_placed(layer, layer.offset, size, transform);
+            let (out_w, out_h) = transform.output_size(size);
+            let offset = (x + (size.0 as i32 - out_w as i32) / 2, y + (size.1 as i32 - out_h as i32) / 2);
+            *layer = Layer { offset, ..transformed };
+            return true;
+        }
+        let transformed = transform_layer(layer, transform);
+        let offset = (
+            (size.0 as i32 - transformed.width as i32) / 2,
+            (size.1 as i32 - transformed.height as i32) / 2,
+        );
+        *layer = transformed;
+        layer.resize(size.0, size.1, offset, [0, 0, 0, 0]);
+        true
+    }
+
+    /// Build the project metadata describing this document's layer tree
+    pub fn project(&self, name: String) -> Project {
+        fn describe(nodes: &[LayerNode], next_file: &mut usize) -> Vec<NodeMetadata> {
+            let mut out = Vec::new();
+            for node in nodes {
+                match node {
+                    LayerNode::Layer(layer) => {
+                        let filename = format!("layer_{:03}.png", *next_file);
+                        *next_file += 1;
+                        out.push(NodeMetadata::Layer(LayerMetadata::for_layer(layer, filename)));
+                    }
+                    LayerNode::Group(group) => {
+                        let children = describe(&group.children, next_file);
+                        out.push(NodeMetadata::Group(GroupMetadata::for_group(group, children)));
+                    }
+                }
+            }
+            out
+        }
+        let mut project = Project::new(name, self.width, self.height);
+        project.layers = describe(&self.layers, &mut 0);
+        project
+    }
+}
+
+/// Scale a layer whose top-left corner sits at `position` along with an image
+/// scaled by `scale`, returning its new position
+fn scale_placed(layer: &mut Layer, position: (i32, i32), scale: (f32, f32), filter: Filter) -> (i32, i32) {
+    let width = ((layer.width as f32 * scale.0).round() as u32).max(1);
+    let height = ((layer.height as f32 * scale.1).round() as u32).max(1);
+    layer.scale(width, height, filter);
+    ((position.0 as f32 * scale.0).round() as i32, (position.1 as f32 * scale.1).round() as i32)
+}
+
+/// Transform a layer whose top-left corner sits at `position` along with a
+/// `size` image, returning it grown to hold all its pixels and its new position
+fn transform_placed(layer: &Layer, position: (i32, i32), size: (u32, u32), transform: Transform) -> (Layer, (i32, i32)) {
+    let (w, h) = (layer.width as f32, layer.height as f32);
+    let center = transform.map_point((position.0 as f32 + w / 2.0, position.1 as f32 + h / 2.0), size);
+    let transformed = transform_layer(layer, transform.expanded());
+    let x = (center.0 - transformed.width as f32 / 2.0).round() as i32;
+    let y = (center.1 - transformed.height as f32 / 2.0).round() as i32;
+    (transformed, (x, y))
+}
+
+/// Composite one pixel of a list of nodes; groups are composited in
+/// isolation and then blended into their parent. A floating layer is blended
+/// right above the node its path (relative to `nodes`) points at.
+fn composite_nodes(nodes: &[LayerNode], x: u32, y: u32, floating: Option<(&[usize], &FloatingLayer)>) -> [u8; 4] {
+    let mut dst = [0u8; 4];
+    for (i, node) in nodes.iter().enumerate() {
+        let (floats_here, inner) = match floating {
+            Some((path, f)) if path.first() == Some(&i) => (path.len() == 1, Some((&path[1..], f))),
+            _ => (false, None),
+        };
+        if node.visible() {
+            match node {
+                LayerNode::Layer(layer) => {
+                    let (x, y) = (x as i32, y as i32);
+                    let opacity = layer.opacity * layer.mask_at(x, y) as f32 / 255.0;
+                    blend_pixel(&mut dst, layer.pixel_at(x, y), opacity, layer.blend_mode);
+                }
+                LayerNode::Group(group) => {
+                    let src = composite_nodes(&group.children, x, y, inner.filter(|(path, _)| !path.is_empty()));
+                    blend_pixel(&mut dst, src, group.opacity, group.blend_mode);
+                }
+            }
+        }
+        if let (true, Some((_, f))) = (floats_here, inner) {
+            blend_pixel(&mut dst, f.pixel(x, y), 1.0, BlendMode::Normal);
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::{Flip, Rotation};
+
+    #[test]
+    fn test_add_layer_goes_above_active() {
+        let mut doc = Document::new(4, 4);
+        doc.add_layer();
+        assert_eq!(doc.active, vec![1]);
+        assert_eq!(doc.layers.len(), 2);
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_composite_skips_hidden_layers() {
+        let mut doc = Document::new(2, 2);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        assert_eq!(&doc.composite()[0..4], &[255, 0, 0, 255]);
+
+        doc.layers[1].set_visible(false);
+        assert_eq!(&doc.composite()[0..4], &[255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_mask_hides_without_touching_pixels() {
+        let mut doc = Document::new(2, 1);
+        doc.add_layer();
+        let layer = doc.active_layer_mut().unwrap();
+        layer.pixels = vec![0, 0, 0, 255, 0, 0, 0, 255];
+        layer.add_mask();
+        layer.blend_mask(1, 0, 0, 255);
+
+        let out = doc.composite();
+        assert_eq!(&out[0..4], &[0, 0, 0, 255]);
+        assert_eq!(&out[4..8], &[255, 255, 255, 255]);
+        assert_eq!(doc.active_layer().unwrap().get_pixel(1, 0), [0, 0, 0, 255]);
+
+        // Painting the mask white again brings the pixel back
+        doc.active_layer_mut().unwrap().blend_mask(1, 0, 255, 255);
+        assert_eq!(&doc.composite()[4..8], &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_group_is_composited_in_isolation() {
+        let black = || Layer::from_rgba("black".to_string(), 1, 1, vec![0, 0, 0, 255]);
+        let mut group = LayerGroup::new("Group".to_string(), vec![LayerNode::Layer(black()), LayerNode::Layer(black())]);
+        group.opacity = 0.5;
+        let background = Layer::new("Background".to_string(), 1, 1);
+        let doc = Document::from_nodes(1, 1, vec![LayerNode::Layer(background), LayerNode::Group(group)]);
+
+        // Children merge to opaque black first, then the group is blended at 50%
+        assert_eq!(doc.composite(), vec![128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn test_group_and_ungroup_active() {
+        let mut doc = Document::new(2, 2);
+        doc.add_layer();
+        doc.group_active();
+        assert!(matches!(doc.active_node(), Some(LayerNode::Group(_))));
+        assert_eq!(doc.outline(), vec![(vec![1], 0), (vec![1, 0], 1), (vec![0], 0)]);
+
+        doc.select_below();
+        assert_eq!(doc.active, vec![1, 0]);
+        doc.add_layer();
+        assert_eq!(doc.active, vec![1, 1]);
+        assert_eq!(doc.leaf_layers().len(), 3);
+
+        doc.select(vec![1]);
+        doc.ungroup_active();
+        assert_eq!(doc.layers.len(), 3);
+        assert_eq!(doc.active, vec![2]);
+    }
+
+    #[test]
+    fn test_duplicate_and_delete_active() {
+        let mut doc = Document::new(2, 2);
+        doc.duplicate_active();
+        assert_eq!(doc.active, vec![1]);
+        assert_eq!(doc.active_node().unwrap().name(), "Background copy");
+
+        doc.delete_active();
+        assert_eq!(doc.layers.len(), 1);
+        assert_eq!(doc.active, vec![0]);
+        // The last layer stays
+        doc.delete_active();
+        assert_eq!(doc.layers.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_down_applies_opacity() {
+        let mut doc = Document::new(1, 1);
+        doc.add_layer();
+        let layer = doc.active_layer_mut().unwrap();
+        layer.set_pixel(0, 0, [0, 0, 0, 255]);
+        layer.opacity = 0.5;
+        let before = doc.composite();
+
+        assert!(doc.merge_down());
+        assert_eq!(doc.layers.len(), 1);
+        assert_eq!(doc.active, vec![0]);
+        assert_eq!(doc.composite(), before);
+    }
+
+    #[test]
+    fn test_merge_down_keeps_a_hidden_layer() {
+        let mut doc = Document::new(1, 1);
+        doc.add_layer();
+        let layer = doc.active_layer_mut().unwrap();
+        layer.set_pixel(0, 0, [0, 0, 0, 255]);
+        layer.visible = false;
+
+        assert!(!doc.merge_down());
+        assert_eq!(doc.layers.len(), 2);
+        assert_eq!(doc.active, vec![1]);
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 0), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_merge_down_applies_the_lower_mask() {
+        let mut doc = Document::new(2, 1);
+        let background = doc.active_layer_mut().unwrap();
+        background.add_mask();
+        background.blend_mask(0, 0, 0, 255);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        let before = doc.composite();
+        assert_eq!(&before[0..4], &[255, 0, 0, 255]);
+
+        assert!(doc.merge_down());
+        let layer = doc.active_layer().unwrap();
+        assert!(layer.mask.is_none());
+        assert_eq!(layer.get_pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(doc.composite(), before);
+    }
+
+    #[test]
+    fn test_move_node_reorders_rows() {
+        let mut doc = Document::new(1, 1);
+        doc.add_layer();
+        doc.add_layer();
+        let names = |doc: &Document| doc.layers.iter().map(|n| n.name().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&doc), vec!["Background", "Layer 1", "Layer 2"]);
+
+        // Drag the bottom row onto the top row
+        assert!(doc.move_node(&[0], &[2]));
+        assert_eq!(names(&doc), vec!["Layer 1", "Layer 2", "Background"]);
+        assert_eq!(doc.active, vec![2]);
+
+        // And back down again
+        assert!(doc.move_node(&[2], &[0]));
+        assert_eq!(names(&doc), vec!["Background", "Layer 1", "Layer 2"]);
+
+        // A group cannot be dropped into itself
+        doc.group_active();
+        assert!(!doc.move_node(&[0], &[0, 0]));
+    }
+
+    #[test]
+    fn test_project_round_trips_tree() {
+        let mut doc = Document::new(2, 2);
+        doc.add_layer();
+        doc.group_active();
+        doc.active_node_mut().unwrap().set_opacity(0.25);
+
+        let project = doc.project("Tree".to_string());
+        let json = serde_json::to_string(&project).unwrap();
+        let parsed: Project = serde_json::from_str(&json).unwrap();
+        let names: Vec<_> = parsed.layer_metadata().iter().map(|m| m.filename.clone()).collect();
+        assert_eq!(names, vec!["layer_000.png", "layer_001.png"]);
+
+        let layers = doc.leaf_layers().into_iter().cloned().collect();
+        let rebuilt = Document::from_project(&parsed, layers);
+        assert!(matches!(&rebuilt.layers[1], LayerNode::Group(g) if g.opacity == 0.25 && g.children.len() == 1));
+    }
+
+    #[test]
+    fn test_crop_resizes_every_layer_and_the_selection() {
+        let mut doc = Document::new(4, 3);
+        doc.add_layer();
+        let layer = doc.active_layer_mut().unwrap();
+        layer.set_pixel(2, 1, [255, 0, 0, 255]);
+        layer.add_mask();
+        layer.blend_mask(3, 2, 0, 255);
+        doc.group_active();
+        doc.selection = Some(Selection::rect(4, 3, (2, 1), (2, 1)));
+
+        assert!(doc.crop(1, 1, 3, 5));
+        assert_eq!((doc.width, doc.height), (3, 2));
+        for layer in doc.leaf_layers() {
+            assert_eq!((layer.width, layer.height, layer.pixels.len()), (3, 2, 24));
+        }
+        let layer = doc.leaf_layers()[1];
+        assert_eq!(layer.get_pixel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(layer.mask.as_ref().unwrap().len(), 6);
+        assert_eq!(layer.mask_value(2, 1), 0);
+        assert_eq!(layer.mask_value(1, 1), 255);
+        let selection = doc.selection.as_ref().unwrap();
+        assert_eq!(selection.bounds(), Some((1, 0, 2, 1)));
+
+        assert!(!doc.crop(5, 0, 2, 2));
+        assert_eq!((doc.width, doc.height), (3, 2));
+    }
+
+    #[test]
+    fn test_resize_canvas_around_anchor() {
+        let mut doc = Document::new(4, 2);
+        doc.add_layer();
+        for y in 0..2 {
+            for x in 0..4 {
+                doc.active_layer_mut().unwrap().set_pixel(x, y, [x as u8, y as u8, 7, 255]);
+            }
+        }
+        doc.selection = Some(Selection::rect(4, 2, (3, 1), (3, 1)));
+
+        // Growing around the bottom-right corner puts the new area top-left
+        let anchor = Anchor { column: 2, row: 2 };
+        assert!(doc.resize_canvas(6, 5, anchor, [1, 2, 3, 255], true));
+        assert_eq!((doc.width, doc.height), (6, 5));
+        let layers = doc.leaf_layers();
+        assert_eq!(layers[0].get_pixel(0, 0), [1, 2, 3, 255]);
+        assert_eq!(layers[0].get_pixel(2, 3), [255, 255, 255, 255]);
+        for y in 0..2 {
+            for x in 0..4 {
+                assert_eq!(layers[1].get_pixel(x + 2, y + 3), [x as u8, y as u8, 7, 255]);
+            }
+        }
+        assert_eq!(doc.selection.as_ref().unwrap().bounds(), Some((5, 4, 6, 5)));
+
+        // Canvas only: the new area stays transparent whatever the fill
+        assert!(doc.resize_canvas(8, 5, Anchor { column: 0, row: 0 }, [1, 2, 3, 255], false));
+        assert_eq!(doc.leaf_layers()[0].get_pixel(7, 0), [0, 0, 0, 0]);
+        assert_eq!(doc.leaf_layers()[0].get_pixel(0, 0), [1, 2, 3, 255]);
+
+        // Shrinking around the center drops the selected corner
+        assert!(doc.resize_canvas(4, 3, Anchor::CENTER, [0, 0, 0, 0], true));
+        assert_eq!(doc.leaf_layers()[1].get_pixel(0, 2), [0, 0, 7, 255]);
+        assert!(doc.selection.is_none());
+        assert!(!doc.resize_canvas(4, 3, Anchor::CENTER, [0, 0, 0, 0], true));
+    }
+
+    #[test]
+    fn test_scale_resamples_layers_selection_and_floating() {
+        let mut doc = Document::new(4, 2);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(3, 1, [0, 0, 255, 255]);
+        doc.active_layer_mut().unwrap().add_mask();
+        doc.selection = Some(Selection::rect(4, 2, (2, 0), (3, 1)));
+        doc.floating = Some(FloatingLayer { layer: Layer::new("Float".to_string(), 2, 1), x: 1, y: 1 });
+
+        assert!(doc.scale(8, 6, Filter::Nearest));
+        assert_eq!((doc.width, doc.height), (8, 6));
+        for layer in doc.leaf_layers() {
+            assert_eq!((layer.width, layer.height, layer.pixels.len()), (8, 6, 8 * 6 * 4));
+        }
+        let layer = doc.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(7, 5), [0, 0, 255, 255]);
+        assert_eq!(layer.get_pixel(5, 2), [0, 0, 0, 0]);
+        assert_eq!(layer.mask.as_ref().unwrap().len(), 48);
+        assert_eq!(doc.selection.as_ref().unwrap().bounds(), Some((4, 0, 8, 6)));
+        let floating = doc.floating.as_ref().unwrap();
+        assert_eq!((floating.x, floating.y, floating.layer.width, floating.layer.height), (2, 3, 4, 3));
+
+        assert!(!doc.scale(8, 6, Filter::Bicubic));
+        assert!(!doc.scale(0, 6, Filter::Bicubic));
+    }
+
+    #[test]
+    fn test_lift_active_takes_floating_selection_or_layer_content() {
+        let mut doc = Document::new(5, 5);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(1, 2, [255, 0, 0, 255]);
+        doc.active_layer_mut().unwrap().set_pixel(3, 3, [0, 255, 0, 255]);
+
+        // The whole layer's content, cropped to what is painted
+        let (lifted, whole_layer) = doc.lift_active().unwrap();
+        assert!(whole_layer);
+        assert_eq!((lifted.x, lifted.y, lifted.layer.width, lifted.layer.height), (1, 2, 3, 2));
+        assert_eq!(lifted.layer.get_pixel(2, 1), [0, 255, 0, 255]);
+        assert!(doc.active_layer().unwrap().pixels.iter().all(|v| *v == 0));
+        assert!(doc.lift_active().is_none());
+
+        // A selection cuts just that part; a floating layer is taken as it is
+        doc.floating = Some(lifted);
+        doc.anchor_floating();
+        doc.selection = Some(Selection::rect(5, 5, (0, 0), (2, 2)));
+        let (lifted, whole_layer) = doc.lift_active().unwrap();
+        assert!(!whole_layer);
+        assert_eq!((lifted.x, lifted.y, lifted.layer.width), (0, 0, 3));
+        assert_eq!(doc.active_layer().unwrap().get_pixel(3, 3), [0, 255, 0, 255]);
+        doc.floating = Some(lifted);
+        assert_eq!(doc.lift_active().unwrap().0.layer.width, 3);
+        assert!(doc.floating.is_none());
+    }
+
+    #[test]
+    fn test_transform_rotates_the_whole_image() {
+        let mut doc = Document::new(4, 2);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        doc.selection = Some(Selection::rect(4, 2, (0, 0), (0, 0)));
+        doc.floating = Some(FloatingLayer { layer: Layer::new("Float".to_string(), 2, 1), x: 2, y: 1 });
+
+        doc.transform(Transform::Rotate(Rotation::Cw90));
+        assert_eq!((doc.width, doc.height), (2, 4));
+        for layer in doc.leaf_layers() {
+            assert_eq!((layer.width, layer.height), (2, 4));
+        }
+        // The top-left corner turns to the top-right
+        assert_eq!(doc.active_layer().unwrap().get_pixel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(doc.selection.as_ref().unwrap().bounds(), Some((1, 0, 2, 1)));
+        let floating = doc.floating.as_ref().unwrap();
+        assert_eq!((floating.x, floating.y, floating.layer.width, floating.layer.height), (0, 2, 1, 2));
+
+        doc.transform(Transform::Flip(Flip::Horizontal));
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(doc.floating.as_ref().unwrap().x, 1);
+    }
+
+    #[test]
+    fn test_transform_active_layer_or_selection() {
+        let mut doc = Document::new(4, 4);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        doc.active_layer_mut().unwrap().set_pixel(3, 3, [0, 255, 0, 255]);
+
+        // Without a selection the active layer turns about the image center
+        assert!(doc.transform_active(Transform::Flip(Flip::Vertical)));
+        let layer = doc.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(0, 3), [255, 0, 0, 255]);
+        assert_eq!(layer.get_pixel(3, 0), [0, 255, 0, 255]);
+        assert_eq!(doc.leaf_layers()[0].get_pixel(0, 3), [255, 255, 255, 255]);
+
+        // A selection is lifted into a floating layer and turned in place
+        doc.selection = Some(Selection::rect(4, 4, (0, 2), (1, 3)));
+        assert!(doc.transform_active(Transform::Rotate(Rotation::Cw90)));
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 3)[3], 0);
+        let floating = doc.floating.as_ref().unwrap();
+        assert_eq!((floating.x, floating.y), (0, 2));
+        assert_eq!(floating.layer.get_pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(doc.selection.as_ref().unwrap().bounds(), Some((0, 2, 2, 4)));
+        doc.anchor_floating();
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 2), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_copy_and_cut_follow_the_selection() {
+        let mut doc = Document::new(4, 2);
+        doc.active_layer_mut().unwrap().set_pixel(1, 0, [255, 0, 0, 255]);
+        let mut selection = Selection::rect(4, 2, (1, 0), (2, 0));
+        selection.mask[2] = 128; // (2, 0) half selected
+        doc.selection = Some(selection);
+
+        let clip = doc.copy(false).unwrap();
+        assert_eq!((clip.x, clip.y, clip.layer.width, clip.layer.height), (1, 0, 2, 1));
+        assert_eq!(clip.layer.get_pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(clip.layer.get_pixel(1, 0), [255, 255, 255, 128]);
+
+        doc.cut();
+        let layer = doc.active_layer().unwrap();
+        assert_eq!(layer.get_pixel(1, 0)[3], 0);
+        assert_eq!(layer.get_pixel(2, 0)[3], 127);
+        assert_eq!(layer.get_pixel(0, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_paste_floats_above_active_until_anchored() {
+        let mut doc = Document::new(3, 1);
+        doc.add_layer(); // Active layer, transparent
+        doc.add_layer(); // Top layer covers the right pixel with blue
+        doc.active_layer_mut().unwrap().set_pixel(2, 0, [0, 0, 255, 255]);
+        doc.select_below();
+
+        let red = Layer::from_rgba("Clipboard".to_string(), 2, 1, [[255, 0, 0, 255], [255, 0, 0, 255]].concat());
+        doc.paste(&FloatingLayer { layer: red, x: 0, y: 0 });
+        doc.move_floating(1, 0);
+        let composite = doc.composite();
+        assert_eq!(&composite[0..4], &[255, 255, 255, 255]);
+        assert_eq!(&composite[4..8], &[255, 0, 0, 255]);
+        // The layer above the active one still covers the floating pixels
+        assert_eq!(&composite[8..12], &[0, 0, 255, 255]);
+        assert_eq!(doc.active_layer().unwrap().get_pixel(1, 0)[3], 0);
+
+        doc.anchor_floating();
+        assert!(doc.floating.is_none());
+        assert_eq!(doc.active_layer().unwrap().get_pixel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(doc.active_layer().unwrap().get_pixel(0, 0)[3], 0);
+    }
+
+    #[test]
+    fn test_floating_selection_becomes_a_new_layer() {
+        let mut doc = Document::new(2, 2);
+        let dot = Layer::from_rgba("Clipboard".to_string(), 1, 1, vec![0, 255, 0, 255]);
+        // Copied from a bigger image: off this one, so it is centered
+        doc.paste(&FloatingLayer { layer: dot, x: 10, y: 10 });
+        doc.floating_to_layer();
+        assert!(doc.floating.is_none());
+        assert_eq!(doc.active, vec![1]);
+        let layer = doc.active_layer().unwrap();
+        assert_eq!((layer.width, layer.height), (2, 2));
+        assert_eq!(layer.get_pixel(0, 0), [0, 255, 0, 255]);
+        assert_eq!(layer.get_pixel(1, 1), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_moved_layer_keeps_pixels_through_edits() {
+        let mut doc = Document::new(3, 1);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        assert!(doc.move_active_layer(2, 0));
+        assert_eq!(&doc.composite()[8..12], &[255, 0, 0, 255]);
+        assert_eq!(&doc.composite()[0..4], &[255, 255, 255, 255]);
+
+        // Push the pixel past the edge, paint, then bring it back
+        doc.move_active_layer(5, 0);
+        let layer = doc.active_layer_mut().unwrap();
+        // Getting the layer leaves its buffer alone; painting grows it first
+        assert_eq!(layer.bounds(), (7, 0, 10, 1));
+        layer.cover(1, 0, 2, 1);
+        layer.set_pixel_at(1, 0, [0, 0, 255, 255]);
+        assert_eq!(layer.bounds(), (1, 0, 10, 1));
+        assert_eq!(layer.pixel_at(7, 0), [255, 0, 0, 255]);
+        doc.move_active_layer(-7, 0);
+        assert_eq!(&doc.composite()[0..4], &[255, 0, 0, 255]);
+
+        doc.move_active_layer(1, 0);
+        let layer = doc.active_layer_mut().unwrap();
+        assert_eq!(layer.pixel_at(1, 0), [255, 0, 0, 255]);
+        assert_eq!(layer.pixel_at(-5, 0), [0, 0, 255, 255]);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::document::{Document, FloatingLayer};
+use crate::layer::Layer;
+use crate::resample::{from_filter_space, to_filter_space, Filter};
+use crate::transform::sample;
+
+/// Where the corners of the source rectangle go, in image space: top-left,
+/// top-right, bottom-right, bottom-left
+pub type Quad = [(f32, f32); 4];
+
+/// Warped pixels and the image-space rectangle (x, y, width, height) they cover
+pub type Warped = (Vec<u8>, (i32, i32, u32, u32));
+
+/// A free transform in progress: the pixels lifted off the layer and the box
+/// they are being dragged into. Nothing is resampled for good until commit.
+#[derive(Clone, Debug)]
+pub struct FreeTransform {
+    pub source: FloatingLayer, // Untransformed pixels at their original position
+    pub quad: Quad,
+    pub original: Document, // Document before lifting, put back on cancel
+    pub whole_layer: bool,  // Pixels came from the whole active layer and go back into it
+}
+
+/// Part of the transform box under the pointer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransformHandle {
+    Corner(usize), // Index into the quad
+    Edge(usize),   // Edge from corner i to corner i + 1
+    Inside,
+    Outside,
+}
+
+fn sub(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn midpoint(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0)
+}
+
+/// The quad covering a `width` x `height` rectangle at (x, y)
+pub fn rect_quad(x: f32, y: f32, width: f32, height: f32) -> Quad {
+    [(x, y), (x + width, y), (x + width, y + height), (x, y + height)]
+}
+
+/// Handle of `quad` under an image-space point; `reach` is how far (in image
+/// pixels) from a corner or edge midpoint still grabs it
+pub fn handle_at(quad: &Quad, point: (f32, f32), reach: f32) -> TransformHandle {
+    let near = |p: (f32, f32)| (p.0 - point.0).abs() <= reach && (p.1 - point.1).abs() <= reach;
+    if let Some(i) = (0..4).find(|&i| near(quad[i])) {
+        return TransformHandle::Corner(i);
+    }
+    if let Some(i) = (0..4).find(|&i| near(midpoint(quad[i], quad[(i + 1) % 4]))) {
+        return TransformHandle::Edge(i);
+    }
+    // Inside a convex quad the point is on the same side of every edge
+    let sides: Vec<f32> = (0..4)
+        .map(|i| {
+            let (edge, to_point) = (sub(quad[(i + 1) % 4], quad[i]), sub(point, quad[i]));
+            edge.0 * to_point.1 - edge.1 * to_point.0
+        })
+        .collect();
+    if sides.iter().all(|s| *s >= 0.0) || sides.iter().all(|s| *s <= 0.0) {
+        TransformHandle::Inside
+    } else {
+        TransformHandle::Outside
+    }
+}
+
+/// `quad` after dragging `handle` from `start` to `point`. Corners scale the
+/// box from the opposite corner, or with `perspective` move on their own;
+/// edges skew along themselves; inside moves and outside rotates about the center.
+pub fn drag(quad: &Quad, handle: TransformHandle, start: (f32, f32), point: (f32, f32), perspective: bool) -> Quad {
+    let delta = sub(point, start);
+    let mut out = *quad;
+    match handle {
+        TransformHandle::Inside => {
+            for corner in out.iter_mut() {
+                *corner = (corner.0 + delta.0, corner.1 + delta.1);
+            }
+        }
+        TransformHandle::Corner(i) if perspective => {
+            out[i] = (quad[i].0 + delta.0, quad[i].1 + delta.1);
+        }
+        TransformHandle::Corner(i) => {
+            // Scale along the box's own axes, measured from the opposite corner
+            let origin = quad[(i + 2) % 4];
+            let (u, v) = (sub(quad[1], quad[0]), sub(quad[3], quad[0]));
+            let det = u.0 * v.1 - u.1 * v.0;
+            if det.abs() < 1e-6 {
+                return out;
+            }
+            let axes = |p: (f32, f32)| ((p.0 * v.1 - p.1 * v.0) / det, (u.0 * p.1 - u.1 * p.0) / det);
+            let (a0, b0) = axes(sub(quad[i], origin));
+            let (a1, b1) = axes(sub(point, origin));
+            let (a_start, b_start) = axes(sub(start, origin));
+            // Follow the pointer's movement rather than snapping the corner to it
+            let fa = if a0.abs() > 1e-6 { (a0 + a1 - a_start) / a0 } else { 1.0 };
+            let fb = if b0.abs() > 1e-6 { (b0 + b1 - b_start) / b0 } else { 1.0 };
+            for (corner, original) in out.iter_mut().zip(quad) {
+                let (a, b) = axes(sub(*original, origin));
+                let (a, b) = (a * fa, b * fb);
+                *corner = (origin.0 + a * u.0 + b * v.0, origin.1 + a * u.1 + b * v.1);
+            }
+        }
+        TransformHandle::Edge(i) => {
+            let j = (i + 1) % 4;
+            let edge = sub(quad[j], quad[i]);
+            let length = (edge.0 * edge.0 + edge.1 * edge.1).sqrt();
+            if length < 1e-6 {
+                return out;
+            }
+            let along = (delta.0 * edge.0 + delta.1 * edge.1) / (length * length);
+            for k in [i, j] {
+                out[k] = (quad[k].0 + along * edge.0, quad[k].1 + along * edge.1);
+            }
+        }
+        TransformHandle::Outside => {
+            let center = (
+                quad.iter().map(|p| p.0).sum::<f32>() / 4.0,
+                quad.iter().map(|p| p.1).sum::<f32>() / 4.0,
+            );
+            let angle_of = |p: (f32, f32)| (p.1 - center.1).atan2(p.0 - center.0);
+            let (sin, cos) = (angle_of(point) - angle_of(start)).sin_cos();
+            for corner in out.iter_mut() {
+                let (dx, dy) = sub(*corner, center);
+                *corner = (center.0 + dx * cos - dy * sin, center.1 + dx * sin + dy * cos);
+            }
+        }
+    }
+    out
+}
+
+/// Row-major 3x3 projective map
+#[derive(Clone, Copy, Debug)]
+struct Homography([f32; 9]);
+
+impl Homography {
+    /// Map from a `size` rectangle at the origin onto `quad` (Heckbert's square-to-quad)
+    fn new(size: (u32, u32), quad: &Quad) -> Self {
+        let [(x0, y0), (x1, y1), (x2, y2), (x3, y3)] = *quad;
+        let (dx3, dy3) = (x0 - x1 + x2 - x3, y0 - y1 + y2 - y3);
+        let (g, h) = if dx3.abs() < 1e-6 && dy3.abs() < 1e-6 {
+            (0.0, 0.0)
+        } else {
+            let (dx1, dy1, dx2, dy2) = (x1 - x2, y1 - y2, x3 - x2, y3 - y2);
+            let det = dx1 * dy2 - dx2 * dy1;
+            ((dx3 * dy2 - dx2 * dy3) / det, (dx1 * dy3 - dx3 * dy1) / det)
+        };
+        let (w, hgt) = (size.0 as f32, size.1 as f32);
+        Homography([
+            (x1 - x0 + g * x1) / w,
+            (x3 - x0 + h * x3) / hgt,
+            x0,
+            (y1 - y0 + g * y1) / w,
+            (y3 - y0 + h * y3) / hgt,
+            y0,
+            g / w,
+            h / hgt,
+            1.0,
+        ])
+    }
+
+    fn inverse(&self) -> Option<Self> {
+        let [a, b, c, d, e, f, g, h, i] = self.0;
+        let adjugate = [
+            e * i - f * h,
+            c * h - b * i,
+            b * f - c * e,
+            f * g - d * i,
+            a * i - c * g,
+            c * d - a * f,
+            d * h - e * g,
+            b * g - a * h,
+            a * e - b * d,
+        ];
+        let det = a * adjugate[0] + b * adjugate[3] + c * adjugate[6];
+        if !det.is_finite() || det.abs() < 1e-12 {
+            return None;
+        }
+        Some(Homography(adjugate.map(|v| v / det)))
+    }
+
+    /// Mapped point, or None where the projection is undefined
+    fn map(&self, p: (f32, f32)) -> Option<(f32, f32)> {
+        let m = &self.0;
+        let w = m[6] * p.0 + m[7] * p.1 + m[8];
+        if w.abs() < 1e-9 {
+            return None;
+        }
+        Some(((m[0] * p.0 + m[1] * p.1 + m[2]) / w, (m[3] * p.0 + m[4] * p.1 + m[5]) / w))
+    }
+}
+
+/// Warp a `size` buffer with `fill.len()` bytes per pixel onto `quad`, over the
+/// quad's bounding box clipped to `clip` (x0, y0, x1, y1). None when the quad
+/// is degenerate or misses the clip rectangle.
+pub fn warp(pixels: &[u8], size: (u32, u32), fill: &[u8], quad: &Quad, filter: Filter, clip: (i32, i32, i32, i32)) -> Option<Warped> {
+    let channels = fill.len();
+    let inverse = Homography::new(size, quad).inverse()?;
+    let x0 = (quad.iter().map(|p| p.0).fold(f32::MAX, f32::min).floor() as i32).max(clip.0);
+    let y0 = (quad.iter().map(|p| p.1).fold(f32::MAX, f32::min).floor() as i32).max(clip.1);
+    let x1 = (quad.iter().map(|p| p.0).fold(f32::MIN, f32::max).ceil() as i32).min(clip.2);
+    let y1 = (quad.iter().map(|p| p.1).fold(f32::MIN, f32::max).ceil() as i32).min(clip.3);
+    if x0 >= x1 || y0 >= y1 {
+        return None;
+    }
+    let (width, height) = ((x1 - x0) as u32, (y1 - y0) as u32);
+
+    let source = to_filter_space(pixels, channels);
+    let mut out = to_filter_space(fill, channels).repeat(width as usize * height as usize);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let center = (x0 as f32 + x as f32 + 0.5, y0 as f32 + y as f32 + 0.5);
+            let Some((sx, sy)) = inverse.map(center) else {
+                continue;
+            };
+            // Pixels whose center maps outside the source keep the fill; inside, the
+            // filter repeats edge pixels so scaled-up edges don't fade out
+            if sx < 0.0 || sy < 0.0 || sx >= size.0 as f32 || sy >= size.1 as f32 {
+                continue;
+            }
+            let idx = (y * width as usize + x) * channels;
+            sample(&source, size, None, (sx - 0.5, sy - 0.5), filter, &mut out[idx..idx + channels]);
+        }
+    }
+    Some((from_filter_space(&out, channels), (x0, y0, width, height)))
+}
+
+/// The floating layer showing `source` warped onto `quad`, cropped to `clip`
+/// (x0, y0, x1, y1). Empty (1x1 transparent) when nothing is visible.
+pub fn warp_layer(source: &Layer, quad: &Quad, filter: Filter, clip: (i32, i32, i32, i32)) -> FloatingLayer {
+    let size = (source.width, source.height);
+    match warp(&source.pixels, size, &[0, 0, 0, 0], quad, filter, clip) {
+        Some((pixels, (x, y, w, h))) => FloatingLayer { layer: Layer::from_rgba(source.name.clone(), w, h, pixels), x, y },
+        None => FloatingLayer { layer: Layer::transparent(source.name.clone(), 1, 1), x: 0, y: 0 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3
+    }
+
+    #[test]
+    fn test_handle_at() {
+        let quad = rect_quad(10.0, 10.0, 20.0, 10.0);
+        assert_eq!(handle_at(&quad, (11.0, 9.0), 2.0), TransformHandle::Corner(0));
+        assert_eq!(handle_at(&quad, (30.0, 20.0), 2.0), TransformHandle::Corner(2));
+        assert_eq!(handle_at(&quad, (20.0, 20.5), 2.0), TransformHandle::Edge(2));
+        assert_eq!(handle_at(&quad, (15.0, 15.0), 2.0), TransformHandle::Inside);
+        assert_eq!(handle_at(&quad, (40.0, 15.0), 2.0), TransformHandle::Outside);
+    }
+
+    #[test]
+    fn test_drag_scales_skews_rotates_and_warps() {
+        let quad = rect_quad(0.0, 0.0, 10.0, 10.0);
+        let moved = drag(&quad, TransformHandle::Inside, (5.0, 5.0), (7.0, 4.0), false);
+        assert!(close(moved[0], (2.0, -1.0)) && close(moved[2], (12.0, 9.0)));
+
+        // The bottom-right corner scales away from the top-left one
+        let scaled = drag(&quad, TransformHandle::Corner(2), (10.0, 10.0), (20.0, 15.0), false);
+        assert!(close(scaled[0], (0.0, 0.0)) && close(scaled[1], (20.0, 0.0)) && close(scaled[2], (20.0, 15.0)));
+        assert!(close(scaled[3], (0.0, 15.0)));
+
+        // The top edge slides sideways, the bottom one stays
+        let skewed = drag(&quad, TransformHandle::Edge(0), (5.0, 0.0), (8.0, 4.0), false);
+        assert!(close(skewed[0], (3.0, 0.0)) && close(skewed[1], (13.0, 0.0)) && close(skewed[2], (10.0, 10.0)));
+
+        // A quarter turn around the center
+        let turned = drag(&quad, TransformHandle::Outside, (15.0, 5.0), (5.0, 15.0), false);
+        assert!(close(turned[0], (10.0, 0.0)) && close(turned[1], (10.0, 10.0)));
+
+        // Perspective moves one corner only
+        let warped = drag(&quad, TransformHandle::Corner(1), (10.0, 0.0), (8.0, 3.0), true);
+        assert!(close(warped[1], (8.0, 3.0)) && close(warped[0], (0.0, 0.0)) && close(warped[2], (10.0, 10.0)));
+    }
+
+    #[test]
+    fn test_homography_maps_corners() {
+        let quad = [(1.0, 2.0), (9.0, 0.0), (12.0, 11.0), (0.0, 8.0)];
+        let map = Homography::new((4, 2), &quad);
+        for (point, corner) in [(0.0, 0.0), (4.0, 0.0), (4.0, 2.0), (0.0, 2.0)].into_iter().zip(quad) {
+            assert!(close(map.map(point).unwrap(), corner));
+            assert!(close(map.inverse().unwrap().map(corner).unwrap(), point));
+        }
+    }
+
+    #[test]
+    fn test_warp_identity_and_scale() {
+        let mut layer = Layer::transparent("Source".to_string(), 3, 2);
+        layer.set_pixel(0, 0, [255, 0, 0, 255]);
+        layer.set_pixel(2, 1, [0, 0, 255, 255]);
+
+        for filter in [Filter::Bilinear, Filter::Bicubic] {
+            let floating = warp_layer(&layer, &rect_quad(4.0, 5.0, 3.0, 2.0), filter, (0, 0, 20, 20));
+            assert_eq!((floating.x, floating.y), (4, 5));
+            assert_eq!(floating.layer.pixels, layer.pixels, "{:?}", filter);
+        }
+
+        // Doubling keeps the colors in the right corners, clipped to the image
+        let floating = warp_layer(&layer, &rect_quad(-2.0, 0.0, 6.0, 4.0), Filter::Bilinear, (0, 0, 20, 20));
+        assert_eq!((floating.x, floating.y, floating.layer.width, floating.layer.height), (0, 0, 4, 4));
+        assert_eq!(floating.layer.get_pixel(3, 3), [0, 0, 255, 255]);
+        assert_eq!(floating.layer.get_pixel(0, 3)[3], 0);
+    }
+}
@@ -1,6 +1,5 @@
 use std::sync::Arc;
 
-use wgpu;
 use winit::{
     dpi::PhysicalSize,
     window::Window,
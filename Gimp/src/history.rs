@@ -1,18 +1,26 @@
 use crate::canvas::Canvas;
+use crate::document::Document;
 
 const MAX_HISTORY: usize = 50;
 
+/// Filter with a Remove button: the step it made can be taken back while it
+/// is still the latest one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterStep {
+    Grayscale,
+    Brightness,
+}
+
 pub struct HistoryState {
-    pub pixels: Vec<u8>,
-    pub drawing_layer: Vec<u8>,
-    pub stride: usize,
-    pub width: u32,
-    pub height: u32,
+    pub id: u64, // Unique within a session, so autosave can tell which states are already on disk
+    pub document: Document,
+    pub filter: Option<FilterStep>, // Filter that made this state, if any
 }
 
 pub struct History {
     states: Vec<HistoryState>,
     current: usize,
+    next_id: u64,
 }
 
 impl History {
@@ -20,66 +28,131 @@ impl History {
         History {
             states: Vec::new(),
             current: 0,
+            next_id: 0,
         }
     }
 
+    /// Rebuild a history from recovered documents (oldest first), positioned at `current`
+    pub fn from_documents(documents: Vec<Document>, current: usize) -> Self {
+        let mut history = History::new();
+        for document in documents {
+            history.push_document(document, None);
+        }
+        history.current = current.min(history.states.len().saturating_sub(1));
+        history
+    }
+
     pub fn push(&mut self, canvas: &Canvas) {
+        self.push_document(canvas.document.clone(), None);
+    }
+
+    /// Record the state left by a filter, so `remove_filter` can find it
+    pub fn push_filter(&mut self, canvas: &Canvas, filter: FilterStep) {
+        self.push_document(canvas.document.clone(), Some(filter));
+    }
+
+    fn push_document(&mut self, document: Document, filter: Option<FilterStep>) {
         // Remove any states after current (if user made a change after undoing)
         self.states.truncate(self.current + 1);
 
         // Limit history size
         if self.states.len() >= MAX_HISTORY {
             self.states.remove(0);
-        } else {
-            self.current += 1;
         }
 
         self.states.push(HistoryState {
-            pixels: canvas.pixels.clone(),
-            drawing_layer: canvas.drawing_layer.clone(),
-            stride: canvas.stride,
-            width: canvas.width,
-            height: canvas.height,
+            id: self.next_id,
+            document,
+            filter,
         });
+        self.next_id += 1;
+        self.current = self.states.len() - 1;
     }
 
     pub fn undo(&mut self, canvas: &mut Canvas) -> bool {
         if self.current > 0 {
             self.current -= 1;
-            let state = &self.states[self.current];
-            canvas.pixels = state.pixels.clone();
-            canvas.drawing_layer = state.drawing_layer.clone();
-            canvas.dirty = true;
+            canvas.restore_document(self.states[self.current].document.clone());
             true
         } else {
             false
         }
     }
 
+    /// Undo the latest step if `filter` made it; anything done since keeps it in place
+    pub fn remove_filter(&mut self, canvas: &mut Canvas, filter: FilterStep) -> bool {
+        if self.states.get(self.current).and_then(|s| s.filter) != Some(filter) {
+            return false;
+        }
+        self.undo(canvas)
+    }
+
     pub fn redo(&mut self, canvas: &mut Canvas) -> bool {
         if self.current + 1 < self.states.len() {
             self.current += 1;
-            let state = &self.states[self.current];
-            canvas.pixels = state.pixels.clone();
-            canvas.drawing_layer = state.drawing_layer.clone();
-            canvas.dirty = true;
+            canvas.restore_document(self.states[self.current].document.clone());
             true
         } else {
             false
         }
     }
 
+    /// Every state, oldest first
+    pub fn states(&self) -> &[HistoryState] {
+        &self.states
+    }
+
+    /// Index of the state shown on the canvas
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    /// The document at the current position
+    pub fn current_document(&self) -> Option<&Document> {
+        self.states.get(self.current).map(|s| &s.document)
+    }
+
+    #[allow(dead_code)]
     pub fn restore(&self, canvas: &mut Canvas, state: &HistoryState) {
-        canvas.pixels = state.pixels.clone();
-        canvas.drawing_layer = state.drawing_layer.clone();
-        canvas.dirty = true;
+        canvas.restore_document(state.document.clone());
     }
 
+    #[allow(dead_code)]
     pub fn can_undo(&self) -> bool {
         self.current > 0
     }
 
+    #[allow(dead_code)]
     pub fn can_redo(&self) -> bool {
         self.current + 1 < self.states.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::Brush;
+
+    #[test]
+    fn test_remove_filter_takes_back_only_its_own_step() {
+        let mut canvas = Canvas::new(4, 4);
+        let mut history = History::new();
+        history.push(&canvas);
+        Brush::new(1.0, [255, 0, 0, 255]).stamp(&mut canvas, (1.5, 1.5));
+        history.push(&canvas);
+
+        canvas.filter_grayscale();
+        history.push_filter(&canvas, FilterStep::Grayscale);
+        assert!(!history.remove_filter(&mut canvas, FilterStep::Brightness));
+        assert!(history.remove_filter(&mut canvas, FilterStep::Grayscale));
+        // The paint on the background survives
+        assert_eq!(canvas.document.active_layer().unwrap().get_pixel(1, 1), [255, 0, 0, 255]);
+
+        // Once something else happened the filter stays
+        canvas.filter_grayscale();
+        history.push_filter(&canvas, FilterStep::Grayscale);
+        canvas.filter_invert();
+        history.push(&canvas);
+        assert!(!history.remove_filter(&mut canvas, FilterStep::Grayscale));
+    }
+}
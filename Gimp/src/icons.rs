@@ -31,6 +31,13 @@ pub struct IconCache {
     pub grayscale: Icon,
     pub brightness: Icon,
     pub blur: Icon,
+    pub rect_select: Icon,
+    pub ellipse_select: Icon,
+    pub lasso_select: Icon,
+    pub polygon_select: Icon,
+    pub magic_wand: Icon,
+    pub crop: Icon,
+    pub transform: Icon,
 }
 
 impl IconCache {
@@ -48,6 +55,13 @@ impl IconCache {
             grayscale: load_icon("assets/grayscale.png"),
             brightness: load_icon("assets/brightness.png"),
             blur: load_icon("assets/blur.png"),
+            rect_select: load_icon("assets/rectselect.png"),
+            ellipse_select: load_icon("assets/ellipseselect.png"),
+            lasso_select: load_icon("assets/lassoselect.png"),
+            polygon_select: load_icon("assets/polygonselect.png"),
+            magic_wand: load_icon("assets/magicwand.png"),
+            crop: load_icon("assets/crop.png"),
+            transform: load_icon("assets/transform.png"),
         }
     }
 }
@@ -1,8 +1,18 @@
 use crate::brush::Brush;
+use crate::crop::{CropHandle, CropRect};
+use crate::free_transform::{Quad, TransformHandle};
+use crate::document::{Anchor, LayerPath};
+use crate::resample::Filter;
+use crate::selection::{Selection, SelectionMode, WandOptions};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SliderDrag {
     Size,
+    Tolerance,
+    Hardness,
+    Opacity,
+    Flow,
+    #[allow(dead_code)]
     Brightness,
 }
 
@@ -14,6 +24,21 @@ pub enum Tool {
     ColorPicker,
     Move,
     Blur,
+    RectSelect,
+    EllipseSelect,
+    LassoSelect,
+    PolygonSelect,
+    MagicWand,
+    Crop,
+    Transform,
+}
+
+/// What fills the area a canvas resize adds to the layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanvasFill {
+    Transparent,
+    Background,
+    Foreground,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -30,12 +55,27 @@ pub struct InputState {
     pub bg_color: [u8; 4],
     pub brightness: f32,
     pub slider_dragging: Option<SliderDrag>,
-    pub pan_offset: (i32, i32), // (x, y) offset for viewing large images
     pub shift_pressed: bool,
     pub ctrl_pressed: bool,
+    pub alt_pressed: bool,
+    pub space_pressed: bool, // Space+drag pans the view
+    pub panning: bool,       // Dragging the view with Space or the middle button
     pub current_tool: Tool,
-    pub selection_start: Option<(u32, u32)>,
+    pub selection_start: Option<(u32, u32)>, // Image pixel where a selection drag began
     pub selection_end: Option<(u32, u32)>,
+    pub selection_points: Vec<(f32, f32)>, // Image-space outline of a lasso or polygon selection
+    pub selection_mode: SelectionMode,     // How the shape being drawn combines with `selection_base`
+    pub selection_base: Option<Selection>, // Selection from before the current shape was started
+    pub wand: WandOptions,
+    pub crop_drag: Option<(CropHandle, CropRect, (f32, f32))>, // Handle, rectangle and image point at press
+    pub transform_drag: Option<(TransformHandle, Quad, (f32, f32))>, // Handle, box and image point at press
+    pub move_drag: Option<((f32, f32), (i32, i32))>, // Image point at press and how far the Move tool has moved so far
+    pub crop_aspect: usize, // Index into crop::ASPECT_PRESETS
+    pub canvas_anchor: Anchor, // Canvas Size options
+    pub canvas_fill: CanvasFill,
+    pub scale_filter: Filter, // Scale Image options
+    pub scale_lock_aspect: bool,
+    pub rotate_expand: bool, // Grow the canvas to fit arbitrary-angle rotations
     // Advanced color picker state
     pub show_color_picker: bool,
     pub hue: f32, // 0..1
@@ -43,6 +83,7 @@ pub struct InputState {
     pub val: f32, // 0..1
     pub active_is_foreground: bool,
     pub color_dragging: Option<ColorPickerDrag>,
+    pub layer_dragging: Option<LayerPath>, // Row grabbed by its drag handle in the layers panel
 }
 
 impl InputState {
@@ -55,18 +96,34 @@ impl InputState {
             brightness: 1.0,
             brush,
             slider_dragging: None,
-            pan_offset: (0, 0),
             shift_pressed: false,
             ctrl_pressed: false,
+            alt_pressed: false,
+            space_pressed: false,
+            panning: false,
             current_tool: Tool::Brush,
             selection_start: None,
             selection_end: None,
+            selection_points: Vec::new(),
+            selection_mode: SelectionMode::Replace,
+            selection_base: None,
+            wand: WandOptions::default(),
+            crop_drag: None,
+            transform_drag: None,
+            move_drag: None,
+            crop_aspect: 0,
+            canvas_anchor: Anchor::CENTER,
+            canvas_fill: CanvasFill::Transparent,
+            scale_filter: Filter::Bicubic,
+            scale_lock_aspect: true,
+            rotate_expand: true,
             show_color_picker: false,
             hue: 0.0,
             sat: 1.0,
             val: 1.0,
             active_is_foreground: true,
             color_dragging: None,
+            layer_dragging: None,
         }
     }
 
@@ -75,6 +132,22 @@ impl InputState {
         self.last_pos = None;
     }
 
+    /// Start drawing a selection shape on top of `current`, picking the
+    /// combine mode from the modifiers held right now
+    pub fn begin_selection(&mut self, current: Option<Selection>) {
+        self.selection_base = current;
+        self.selection_mode = SelectionMode::from_modifiers(self.shift_pressed, self.ctrl_pressed);
+        self.selection_points.clear();
+    }
+
+    /// Forget the shape being drawn
+    pub fn end_selection(&mut self) {
+        self.selection_start = None;
+        self.selection_end = None;
+        self.selection_points.clear();
+        self.selection_base = None;
+    }
+
     pub fn set_brush_color(&mut self, color: [u8; 4]) {
         self.base_color = color;
         self.apply_brightness();
@@ -84,6 +157,15 @@ impl InputState {
         self.bg_color = color;
     }
 
+    /// Color for the area a canvas resize adds to the layers
+    pub fn canvas_fill_color(&self) -> [u8; 4] {
+        match self.canvas_fill {
+            CanvasFill::Transparent => [0, 0, 0, 0],
+            CanvasFill::Background => self.bg_color,
+            CanvasFill::Foreground => self.brush.color,
+        }
+    }
+
     pub fn adjust_brush_radius(&mut self, delta: f32, min: f32, max: f32) {
         let r = (self.brush.radius + delta).clamp(min, max);
         self.brush.radius = r;
@@ -98,6 +180,7 @@ impl InputState {
         self.apply_brightness();
     }
 
+    #[allow(dead_code)]
     pub fn adjust_brightness(&mut self, delta: f32, min: f32, max: f32) {
         self.brightness = (self.brightness + delta).clamp(min, max);
         self.apply_brightness();
@@ -120,16 +203,12 @@ impl InputState {
     fn apply_brightness(&mut self) {
         let factor = self.brightness;
         let mut c = self.base_color;
-        for i in 0..3 {
-            c[i] = ((c[i] as f32 * factor).clamp(0.0, 255.0)).round() as u8;
+        for ch in c.iter_mut().take(3) {
+            *ch = ((*ch as f32 * factor).clamp(0.0, 255.0)).round() as u8;
         }
         self.brush.color = c;
     }
 
-    pub fn toggle_color_picker(&mut self) {
-        self.show_color_picker = !self.show_color_picker;
-    }
-
     pub fn open_color_picker_foreground(&mut self) {
         self.active_is_foreground = true;
         self.show_color_picker = true;
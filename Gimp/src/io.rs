@@ -1,14 +1,70 @@
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use image::{ImageBuffer, RgbaImage};
-use serde_json;
-use rfd::FileDialog;
+use std::io::{Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use image::{GrayImage, ImageBuffer, ImageFormat, RgbaImage};
+use rfd::{FileDialog, MessageButtons, MessageDialog, MessageDialogResult, MessageLevel};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
-use crate::layer::{Layer, Project};
+use crate::blend::blend_pixel;
+use crate::layer::{mask_filename_for, Layer, LayerMetadata, Project, PROJECT_FORMAT_VERSION};
 use crate::canvas::Canvas;
 
 pub type IoResult<T> = Result<T, String>;
 
+/// One problem found while loading a project folder
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectIssue {
+    /// project.json is missing or does not parse
+    Manifest(String),
+    /// project.json was written by a newer build than this one
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// A file name that is absolute or leaves the project folder
+    UnsafePath { file: String },
+    /// A referenced PNG that is missing or cannot be decoded
+    Unreadable { file: String, reason: String },
+    /// A PNG whose size differs from the project's width/height
+    SizeMismatch { file: String, expected: (u32, u32), found: (u32, u32) },
+}
+
+/// Every problem found while loading a project, reported together
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectLoadError {
+    pub source: String, // Project folder or archive path
+    pub issues: Vec<ProjectIssue>,
+}
+
+impl fmt::Display for ProjectIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectIssue::Manifest(reason) => write!(f, "project.json: {}", reason),
+            ProjectIssue::UnsupportedVersion { found, supported } => write!(
+                f,
+                "project.json: format version {} is newer than this build supports ({}), please update",
+                found, supported
+            ),
+            ProjectIssue::UnsafePath { file } => write!(f, "{}: path escapes the project folder", file),
+            ProjectIssue::Unreadable { file, reason } => write!(f, "{}: {}", file, reason),
+            ProjectIssue::SizeMismatch { file, expected, found } => write!(
+                f,
+                "{}: is {}x{}, expected {}x{}",
+                file, found.0, found.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ProjectLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Project {} has {} problem(s)", self.source, self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
 /// Open file dialog to select an image file (PNG/JPEG)
 pub fn select_image_file() -> IoResult<String> {
     FileDialog::new()
@@ -48,6 +104,38 @@ pub fn select_load_project_folder() -> IoResult<String> {
         .map(|p| p.to_string_lossy().to_string())
 }
 
+/// Extension of single-file project archives
+pub const PROJECT_ARCHIVE_EXTENSION: &str = "mgp";
+
+/// Save file dialog for a single-file project archive
+pub fn select_save_project_archive() -> IoResult<String> {
+    FileDialog::new()
+        .add_filter("MyGimp project", &[PROJECT_ARCHIVE_EXTENSION])
+        .set_file_name(format!("project.{}", PROJECT_ARCHIVE_EXTENSION))
+        .save_file()
+        .ok_or_else(|| "No file selected".to_string())
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Open file dialog for a single-file project archive
+pub fn select_load_project_archive() -> IoResult<String> {
+    FileDialog::new()
+        .add_filter("MyGimp project", &[PROJECT_ARCHIVE_EXTENSION])
+        .pick_file()
+        .ok_or_else(|| "No file selected".to_string())
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Ask a yes/no question in a native message box
+pub fn ask_yes_no(title: &str, description: &str) -> bool {
+    let answer = MessageDialog::new()
+        .set_level(MessageLevel::Warning)
+        .set_title(title)
+        .set_description(description)
+        .set_buttons(MessageButtons::YesNo)
+        .show();
+    answer == MessageDialogResult::Yes
+}
 
 /// Load a PNG or JPEG from disk into a Layer.
 pub fn load_image(path: &str) -> IoResult<Layer> {
@@ -67,43 +155,8 @@ pub fn load_image(path: &str) -> IoResult<Layer> {
     Ok(Layer::from_rgba(filename, _width, _height, pixels))
 }
 
-/// Load and resize image to fit canvas dimensions
-pub fn load_image_scaled(path: &str, target_width: u32, target_height: u32) -> IoResult<Layer> {
-    let img = image::open(path)
-        .map_err(|e| format!("Failed to load image {}: {}", path, e))?;
-    
-    let rgba_img = img.to_rgba8();
-    
-    // If dimensions match, return as-is
-    if rgba_img.width() == target_width && rgba_img.height() == target_height {
-        let pixels = rgba_img.to_vec();
-        let filename = Path::new(path)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("imported")
-            .to_string();
-        return Ok(Layer::from_rgba(filename, target_width, target_height, pixels));
-    }
-    
-    // Resize the image using nearest neighbor (fast)
-    let resized = image::imageops::resize(
-        &rgba_img,
-        target_width,
-        target_height,
-        image::imageops::FilterType::Nearest,
-    );
-    
-    let pixels = resized.to_vec();
-    let filename = Path::new(path)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("imported")
-        .to_string();
-    
-    Ok(Layer::from_rgba(filename, target_width, target_height, pixels))
-}
-
 /// Export a Layer as a PNG file.
+#[allow(dead_code)]
 pub fn export_layer_as_png(layer: &Layer, path: &str) -> IoResult<()> {
     let img: RgbaImage = ImageBuffer::from_raw(
         layer.width,
@@ -115,11 +168,31 @@ pub fn export_layer_as_png(layer: &Layer, path: &str) -> IoResult<()> {
         .map_err(|e| format!("Failed to save PNG {}: {}", path, e))
 }
 
+/// Encode a Layer as PNG bytes in memory.
+pub fn encode_layer_png(layer: &Layer) -> IoResult<Vec<u8>> {
+    let img: RgbaImage = ImageBuffer::from_raw(layer.width, layer.height, layer.pixels.clone())
+        .ok_or("Failed to create image buffer".to_string())?;
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode PNG for {}: {}", layer.name, e))?;
+    Ok(bytes)
+}
+
+/// Encode a layer mask as grayscale PNG bytes in memory.
+pub fn encode_mask_png(width: u32, height: u32, mask: &[u8]) -> IoResult<Vec<u8>> {
+    let img: GrayImage = ImageBuffer::from_raw(width, height, mask.to_vec())
+        .ok_or("Failed to create mask buffer".to_string())?;
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode mask PNG: {}", e))?;
+    Ok(bytes)
+}
+
 /// Export a Canvas as a PNG file.
 pub fn export_canvas_as_png(canvas: &Canvas, path: &str) -> IoResult<()> {
     // Extract actual image content (without UI overlay)
     let image_pixels = canvas.extract_image_pixels();
-    let (width, height) = canvas.loaded_image_size.unwrap_or((canvas.width, canvas.height));
+    let (width, height) = (canvas.document.width, canvas.document.height);
     
     let img: RgbaImage = ImageBuffer::from_raw(
         width,
@@ -131,55 +204,484 @@ pub fn export_canvas_as_png(canvas: &Canvas, path: &str) -> IoResult<()> {
         .map_err(|e| format!("Failed to save PNG {}: {}", path, e))
 }
 
-/// Save a Project (JSON + PNGs) to a folder.
+/// Save a Project (JSON + PNGs) to a folder, using the file names recorded
+/// in the project metadata. The project is written to `<folder>.tmp` first and
+/// only swapped in once complete; the previous save is kept as `<folder>.bak`.
+/// Other files kept in the project folder are carried over to the new save.
 pub fn save_project(project: &Project, layers: &[Layer], folder_path: &str) -> IoResult<()> {
-    // Create folder if it doesn't exist
-    fs::create_dir_all(folder_path)
-        .map_err(|e| format!("Failed to create folder {}: {}", folder_path, e))?;
+    let folder = Path::new(folder_path);
+    // Only a project folder may be replaced wholesale; never move unrelated files aside
+    if folder.is_dir() && !folder.join("project.json").exists() {
+        let has_entries = fs::read_dir(folder)
+            .map_err(|e| format!("Failed to read folder {}: {}", folder_path, e))?
+            .next()
+            .is_some();
+        if has_entries {
+            return Err(format!("Folder {} is not empty and holds no project; pick an empty folder", folder_path));
+        }
+    }
+
+    check_backup_path(folder, true)?;
+    let staging = staging_path(folder, true)?;
+    remove_path(&staging)?;
+    if let Err(e) = write_project_folder(project, layers, &staging)
+        .and_then(|_| carry_over_entries(folder, &staging))
+    {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    swap_in(&staging, folder)
+}
+
+/// A free `<target>.tmp` to stage a save in. A leftover from an interrupted
+/// save is reused; anything else of the same name is left alone and the next
+/// free `<target>.tmpN` is used instead.
+fn staging_path(target: &Path, folder: bool) -> IoResult<PathBuf> {
+    (0..100)
+        .map(|n| sibling_path(target, &if n == 0 { "tmp".to_string() } else { format!("tmp{}", n) }))
+        .find(|path| !path.exists() || is_previous_save(path, folder))
+        .ok_or_else(|| format!("No free staging name next to {}", target.display()))
+}
+
+/// Refuse to save when `<target>.bak` exists but is not an earlier save the
+/// backup may replace, so a user's own folder or file of that name survives
+fn check_backup_path(target: &Path, folder: bool) -> IoResult<()> {
+    let backup = sibling_path(target, "bak");
+    if backup.exists() && !is_previous_save(&backup, folder) {
+        return Err(format!("{} exists and is not a previous save; move it away to save here", backup.display()));
+    }
+    Ok(())
+}
+
+/// True for a save this module may delete: an empty folder or one holding a
+/// project.json, or an archive holding a project.json
+fn is_previous_save(path: &Path, folder: bool) -> bool {
+    if folder {
+        path.is_dir()
+            && (path.join("project.json").exists() || fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none()))
+    } else {
+        fs::File::open(path)
+            .ok()
+            .and_then(|file| ZipArchive::new(file).ok())
+            .is_some_and(|zip| zip.index_for_name("project.json").is_some())
+    }
+}
+
+/// Copy every entry of the existing project `folder` that the previous save did
+/// not write (notes, references, ...) into `staging`, so replacing the folder
+/// keeps them
+fn carry_over_entries(folder: &Path, staging: &Path) -> IoResult<()> {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return Ok(());
+    };
+    // Files the previous save wrote are rewritten or dropped by this one
+    let mut written = vec!["project.json".to_string()];
+    let previous = fs::read_to_string(folder.join("project.json"))
+        .ok()
+        .and_then(|json| parse_project(&json).ok());
+    if let Some(previous) = previous {
+        for metadata in previous.layer_metadata() {
+            written.push(metadata.filename.clone());
+            written.extend(metadata.mask_filename.clone());
+        }
+    }
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read folder {}: {}", folder.display(), e))?;
+        let name = entry.file_name();
+        let target = staging.join(&name);
+        if written.iter().any(|w| Path::new(w) == Path::new(&name)) || target.exists() {
+            continue;
+        }
+        copy_entry(&entry.path(), &target)?;
+    }
+    Ok(())
+}
+
+/// Copy a file, or a folder with everything in it
+fn copy_entry(from: &Path, to: &Path) -> IoResult<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to).map_err(|e| format!("Failed to create folder {}: {}", to.display(), e))?;
+        let entries = fs::read_dir(from).map_err(|e| format!("Failed to read folder {}: {}", from.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read folder {}: {}", from.display(), e))?;
+            copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(from, to)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to copy {} to {}: {}", from.display(), to.display(), e))
+    }
+}
+
+/// Write the project JSON and PNGs into a fresh folder, syncing every file to disk
+fn write_project_folder(project: &Project, layers: &[Layer], folder: &Path) -> IoResult<()> {
+    fs::create_dir_all(folder)
+        .map_err(|e| format!("Failed to create folder {}: {}", folder.display(), e))?;
     
     // Save each layer as PNG
+    let metadata = project.layer_metadata();
     for (idx, layer) in layers.iter().enumerate() {
-        let layer_filename = format!("layer_{:03}.png", idx);
-        let layer_path = Path::new(folder_path).join(&layer_filename);
-        export_layer_as_png(layer, layer_path.to_str().unwrap())?;
+        let (layer_filename, mask_filename) = stored_filenames(&metadata, idx, layer);
+        let layer_path = project_file_path(folder, &layer_filename)
+            .ok_or_else(|| format!("Layer file {} escapes the project folder", layer_filename))?;
+        write_synced(&layer_path, &encode_layer_png(layer)?)?;
+
+        // Masks are stored as a grayscale PNG beside the layer
+        if let (Some(mask), Some(mask_filename)) = (&layer.mask, mask_filename) {
+            let mask_path = project_file_path(folder, &mask_filename)
+                .ok_or_else(|| format!("Mask file {} escapes the project folder", mask_filename))?;
+            write_synced(&mask_path, &encode_mask_png(layer.width, layer.height, mask)?)?;
+        }
     }
     
     // Save project JSON
     let project_json = serde_json::to_string_pretty(project)
         .map_err(|e| format!("Failed to serialize project: {}", e))?;
-    
-    let json_path = Path::new(folder_path).join("project.json");
-    fs::write(&json_path, project_json)
-        .map_err(|e| format!("Failed to write project.json: {}", e))?;
+    write_synced(&folder.join("project.json"), project_json.as_bytes())?;
+    sync_dir(folder);
     
     Ok(())
 }
 
+/// `path` with `.suffix` appended to its last component (`art` -> `art.bak`)
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Delete a file or folder if it exists
+fn remove_path(path: &Path) -> IoResult<()> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        return Ok(());
+    };
+    result.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> IoResult<()> {
+    let mut file = fs::File::create(path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Flush a folder's entries to disk so renames inside it survive a crash.
+/// Best effort: not every platform can open a folder as a file.
+fn sync_dir(folder: &Path) {
+    if let Ok(dir) = fs::File::open(folder) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Replace `target` by the fully written `staged` file or folder, keeping the
+/// previous `target` as `<target>.bak`. At every step either the old or the
+/// new save is complete on disk. Callers check the `.bak` name with
+/// `check_backup_path` first.
+fn swap_in(staged: &Path, target: &Path) -> IoResult<()> {
+    let backup = sibling_path(target, "bak");
+    if target.is_file() {
+        // Files can be replaced in one atomic rename once the backup exists
+        remove_path(&backup)?;
+        if fs::hard_link(target, &backup).is_err() {
+            fs::copy(target, &backup)
+                .map_err(|e| format!("Failed to back up {}: {}", target.display(), e))?;
+        }
+    } else if target.exists() {
+        remove_path(&backup)?;
+        fs::rename(target, &backup)
+            .map_err(|e| format!("Failed to back up {}: {}", target.display(), e))?;
+    }
+    fs::rename(staged, target)
+        .map_err(|e| format!("Failed to move {} into place: {}", staged.display(), e))?;
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        sync_dir(parent);
+    }
+    Ok(())
+}
+
 /// Load a Project (JSON + PNGs) from a folder.
 pub fn load_project(folder_path: &str) -> IoResult<(Project, Vec<Layer>)> {
-    // Read project JSON
-    let json_path = Path::new(folder_path).join("project.json");
-    let json_content = fs::read_to_string(&json_path)
-        .map_err(|e| format!("Failed to read project.json: {}", e))?;
-    
-    let project: Project = serde_json::from_str(&json_content)
-        .map_err(|e| format!("Failed to parse project.json: {}", e))?;
-    
-    // Load layers
+    read_project(folder_path).map_err(|e| e.to_string())
+}
+
+/// Load a Project strictly: every layer and mask must use a file inside the
+/// folder and match the size recorded for it (the project size by default).
+/// All problems are collected before failing.
+pub fn read_project(folder_path: &str) -> Result<(Project, Vec<Layer>), ProjectLoadError> {
+    let folder = Path::new(folder_path);
+    let json = fs::read_to_string(folder.join("project.json")).map_err(|e| {
+        let backup = sibling_path(folder, "bak");
+        if backup.join("project.json").exists() {
+            format!("{} (the previous save is in {})", e, backup.display())
+        } else {
+            e.to_string()
+        }
+    });
+    build_project(folder_path, json, |file| {
+        let path = project_file_path(folder, file).ok_or_else(|| ProjectIssue::UnsafePath { file: file.to_string() })?;
+        fs::read(&path).map_err(|e| ProjectIssue::Unreadable { file: file.to_string(), reason: e.to_string() })
+    })
+}
+
+/// Save a Project as a single .mgp file: a zip holding the same project.json
+/// and PNGs as a project folder.
+/// Like folders, archives are written to `<file>.tmp` and swapped in, keeping `<file>.bak`.
+pub fn save_project_archive(project: &Project, layers: &[Layer], archive_path: &str) -> IoResult<()> {
+    let target = Path::new(archive_path);
+    check_backup_path(target, false)?;
+    let staging = staging_path(target, false)?;
+    if let Err(e) = write_project_archive(project, layers, &staging) {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+    swap_in(&staging, target)
+}
+
+fn write_project_archive(project: &Project, layers: &[Layer], path: &Path) -> IoResult<()> {
+    let archive_path = path.display().to_string();
+    let file = fs::File::create(path)
+        .map_err(|e| format!("Failed to create {}: {}", archive_path, e))?;
+    let mut zip = ZipWriter::new(file);
+    let zip_err = |e: zip::result::ZipError| format!("Failed to write {}: {}", archive_path, e);
+    // PNGs are already compressed; only the manifest is worth deflating
+    let png_options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let json_options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let project_json = serde_json::to_string_pretty(project)
+        .map_err(|e| format!("Failed to serialize project: {}", e))?;
+    zip.start_file("project.json", json_options).map_err(zip_err)?;
+    zip.write_all(project_json.as_bytes())
+        .map_err(|e| format!("Failed to write project.json: {}", e))?;
+
+    let metadata = project.layer_metadata();
+    for (idx, layer) in layers.iter().enumerate() {
+        let (layer_filename, mask_filename) = stored_filenames(&metadata, idx, layer);
+        let mut entries = vec![(layer_filename, encode_layer_png(layer)?)];
+        if let (Some(mask), Some(mask_filename)) = (&layer.mask, mask_filename) {
+            entries.push((mask_filename, encode_mask_png(layer.width, layer.height, mask)?));
+        }
+        for (name, bytes) in entries {
+            if !is_project_relative(&name) {
+                return Err(format!("File {} escapes the project archive", name));
+            }
+            zip.start_file(name.as_str(), png_options).map_err(zip_err)?;
+            zip.write_all(&bytes)
+                .map_err(|e| format!("Failed to write {}: {}", name, e))?;
+        }
+    }
+
+    let file = zip.finish().map_err(zip_err)?;
+    file.sync_all()
+        .map_err(|e| format!("Failed to write {}: {}", archive_path, e))
+}
+
+/// Load a Project from a single .mgp archive.
+pub fn load_project_archive(archive_path: &str) -> IoResult<(Project, Vec<Layer>)> {
+    read_project_archive(archive_path).map_err(|e| e.to_string())
+}
+
+/// Load a .mgp archive with the same checks as `read_project`
+pub fn read_project_archive(archive_path: &str) -> Result<(Project, Vec<Layer>), ProjectLoadError> {
+    let mut zip = fs::File::open(archive_path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| ZipArchive::new(file).map_err(|e| e.to_string()))
+        .map_err(|reason| ProjectLoadError {
+            source: archive_path.to_string(),
+            issues: vec![ProjectIssue::Manifest(format!("failed to open archive: {}", reason))],
+        })?;
+    let json = read_archive_entry(&mut zip, "project.json").and_then(|bytes| {
+        String::from_utf8(bytes).map_err(|e| e.to_string())
+    });
+    build_project(archive_path, json, |file| {
+        if !is_project_relative(file) {
+            return Err(ProjectIssue::UnsafePath { file: file.to_string() });
+        }
+        read_archive_entry(&mut zip, file).map_err(|reason| ProjectIssue::Unreadable { file: file.to_string(), reason })
+    })
+}
+
+fn read_archive_entry(zip: &mut ZipArchive<fs::File>, name: &str) -> Result<Vec<u8>, String> {
+    let mut entry = zip.by_name(name).map_err(|e| e.to_string())?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Rebuild the layers described by a manifest, reading each PNG through
+/// `read_file` (from a folder or an archive). All problems are collected.
+fn build_project(
+    source: &str,
+    json: Result<String, String>,
+    mut read_file: impl FnMut(&str) -> Result<Vec<u8>, ProjectIssue>,
+) -> Result<(Project, Vec<Layer>), ProjectLoadError> {
+    let fail = |issues| ProjectLoadError { source: source.to_string(), issues };
+    let json = json.map_err(|e| fail(vec![ProjectIssue::Manifest(format!("failed to read: {}", e))]))?;
+    let project = parse_project(&json).map_err(|issue| fail(vec![issue]))?;
+
+    let expected = (project.width, project.height);
+    let mut issues = Vec::new();
     let mut layers = Vec::new();
-    for (idx, metadata) in project.layers.iter().enumerate() {
-        let layer_filename = format!("layer_{:03}.png", idx);
-        let layer_path = Path::new(folder_path).join(&layer_filename);
-        
-        let mut layer = load_image(layer_path.to_str().unwrap())?;
-        layer.name = metadata.name.clone();
+    for metadata in project.layer_metadata() {
+        let size = metadata.size.unwrap_or(expected);
+        let Some(image) = decode_project_png(&metadata.filename, size, &mut read_file, &mut issues) else {
+            continue;
+        };
+        let mut layer = Layer::from_rgba(metadata.name.clone(), size.0, size.1, image.to_rgba8().into_raw());
         layer.visible = metadata.visible;
+        layer.opacity = metadata.opacity;
+        layer.blend_mode = metadata.blend_mode;
+        layer.offset = metadata.offset;
+        if let Some(mask_filename) = &metadata.mask_filename
+            && let Some(mask) = decode_project_png(mask_filename, size, &mut read_file, &mut issues)
+        {
+            layer.mask = Some(mask.to_luma8().into_raw());
+        }
         layers.push(layer);
     }
-    
+
+    if !issues.is_empty() {
+        return Err(fail(issues));
+    }
     Ok((project, layers))
 }
 
+/// Parse project.json, upgrading older format versions to the current one
+pub fn parse_project(json: &str) -> Result<Project, ProjectIssue> {
+    let mut value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| ProjectIssue::Manifest(format!("failed to parse: {}", e)))?;
+    migrate_project(&mut value)?;
+    serde_json::from_value(value).map_err(|e| ProjectIssue::Manifest(format!("failed to parse: {}", e)))
+}
+
+/// Upgrade a raw project.json value in place, one version step at a time.
+/// Files without a `format_version` field predate versioning and count as version 1.
+pub fn migrate_project(value: &mut serde_json::Value) -> Result<(), ProjectIssue> {
+    // MIGRATIONS[i] upgrades version i + 1 to version i + 2
+    const MIGRATIONS: [fn(&mut serde_json::Value); 2] = [migrate_v1_to_v2, migrate_v2_to_v3];
+
+    let version = match value.get("format_version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .filter(|v| *v >= 1)
+            .ok_or_else(|| ProjectIssue::Manifest(format!("invalid format_version {}", v)))? as u32,
+    };
+    if version > PROJECT_FORMAT_VERSION {
+        return Err(ProjectIssue::UnsupportedVersion { found: version, supported: PROJECT_FORMAT_VERSION });
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(value);
+    }
+    if let Some(object) = value.as_object_mut() {
+        object.insert("format_version".to_string(), PROJECT_FORMAT_VERSION.into());
+    }
+    Ok(())
+}
+
+/// Version 1 had a flat layer list without opacity, blend mode or masks
+fn migrate_v1_to_v2(value: &mut serde_json::Value) {
+    let Some(layers) = value.get_mut("layers").and_then(|l| l.as_array_mut()) else {
+        return;
+    };
+    for layer in layers.iter_mut().filter_map(|l| l.as_object_mut()) {
+        layer.entry("opacity").or_insert(1.0.into());
+        layer.entry("blend_mode").or_insert("normal".into());
+    }
+}
+
+/// Version 2 layers always lined up with the image; give each an offset of (0, 0)
+fn migrate_v2_to_v3(value: &mut serde_json::Value) {
+    fn walk(nodes: &mut serde_json::Value) {
+        let Some(nodes) = nodes.as_array_mut() else {
+            return;
+        };
+        for node in nodes.iter_mut().filter_map(|n| n.as_object_mut()) {
+            match node.get_mut("children") {
+                Some(children) => walk(children),
+                None => {
+                    node.entry("offset").or_insert(serde_json::json!([0, 0]));
+                }
+            }
+        }
+    }
+    if let Some(layers) = value.get_mut("layers") {
+        walk(layers);
+    }
+}
+
+/// File names a layer and its mask are stored under: the ones recorded in the
+/// metadata, or `layer_NNN.png` for layers the metadata does not describe
+fn stored_filenames(metadata: &[&LayerMetadata], idx: usize, layer: &Layer) -> (String, Option<String>) {
+    let layer_filename = metadata
+        .get(idx)
+        .map(|m| m.filename.clone())
+        .unwrap_or_else(|| format!("layer_{:03}.png", idx));
+    let mask_filename = layer.mask.as_ref().map(|_| {
+        metadata
+            .get(idx)
+            .and_then(|m| m.mask_filename.clone())
+            .unwrap_or_else(|| mask_filename_for(&layer_filename))
+    });
+    (layer_filename, mask_filename)
+}
+
+/// True for a plain relative name that stays inside the project (no root, no `..`)
+fn is_project_relative(file: &str) -> bool {
+    !file.is_empty() && Path::new(file).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Resolve a file name from project metadata inside the project folder.
+/// Returns None for absolute paths or names that climb out with `..`.
+fn project_file_path(folder: &Path, file: &str) -> Option<PathBuf> {
+    if !is_project_relative(file) {
+        return None;
+    }
+    let path = folder.join(file);
+    // A symlink inside the folder must not point outside it either
+    if let (Ok(real_folder), Ok(real_path)) = (folder.canonicalize(), path.canonicalize())
+        && !real_path.starts_with(real_folder)
+    {
+        return None;
+    }
+    Some(path)
+}
+
+/// Decode one PNG of a project, recording an issue instead of failing
+fn decode_project_png(
+    file: &str,
+    expected: (u32, u32),
+    read_file: &mut impl FnMut(&str) -> Result<Vec<u8>, ProjectIssue>,
+    issues: &mut Vec<ProjectIssue>,
+) -> Option<image::DynamicImage> {
+    let bytes = match read_file(file) {
+        Ok(bytes) => bytes,
+        Err(issue) => {
+            issues.push(issue);
+            return None;
+        }
+    };
+    let image = match image::load_from_memory(&bytes) {
+        Ok(image) => image,
+        Err(e) => {
+            issues.push(ProjectIssue::Unreadable { file: file.to_string(), reason: e.to_string() });
+            return None;
+        }
+    };
+    let found = (image.width(), image.height());
+    if found != expected {
+        issues.push(ProjectIssue::SizeMismatch { file: file.to_string(), expected, found });
+        return None;
+    }
+    Some(image)
+}
+
 /// Composite all visible layers into a single Canvas-like buffer.
 #[allow(dead_code)]
 pub fn composite_layers(width: u32, height: u32, layers: &[Layer]) -> Vec<u8> {
@@ -189,21 +691,14 @@ pub fn composite_layers(width: u32, height: u32, layers: &[Layer]) -> Vec<u8> {
         if !layer.visible {
             continue;
         }
-        // Simple alpha blend
-        for y in 0..layer.height.min(height) {
-            for x in 0..layer.width.min(width) {
-                let src_idx = ((y * layer.width + x) * 4) as usize;
+        // Blend using the layer's mode and opacity
+        for y in 0..height {
+            for x in 0..width {
                 let dst_idx = ((y * width + x) * 4) as usize;
-                
-                if src_idx + 3 < layer.pixels.len() && dst_idx + 3 < result.len() {
-                    let src = &layer.pixels[src_idx..src_idx + 4];
-                    let dst = &mut result[dst_idx..dst_idx + 4];
-                    
-                    let alpha = src[3] as f32 / 255.0;
-                    for i in 0..3 {
-                        dst[i] = (src[i] as f32 * alpha + dst[i] as f32 * (1.0 - alpha)) as u8;
-                    }
-                    dst[3] = 255;
+                if dst_idx + 3 < result.len() {
+                    let (x, y) = (x as i32, y as i32);
+                    let opacity = layer.opacity * layer.mask_at(x, y) as f32 / 255.0;
+                    blend_pixel(&mut result[dst_idx..dst_idx + 4], layer.pixel_at(x, y), opacity, layer.blend_mode);
                 }
             }
         }
@@ -215,6 +710,7 @@ pub fn composite_layers(width: u32, height: u32, layers: &[Layer]) -> Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::layer::{LayerMetadata, NodeMetadata};
 
     #[test]
     fn test_layer_creation() {
@@ -244,4 +740,114 @@ mod tests {
 
         let _ = std::fs::remove_dir_all(test_folder);
     }
+
+    #[test]
+    fn test_project_keeps_opacity_and_blend_mode() {
+        let test_folder = "test_project_blend_io";
+        let _ = std::fs::remove_dir_all(test_folder);
+
+        let mut layer = Layer::from_rgba("shade".to_string(), 8, 8, vec![90; 256]);
+        layer.opacity = 0.4;
+        layer.blend_mode = crate::blend::BlendMode::Multiply;
+        let mut project = Project::new("Blend".to_string(), 8, 8);
+        project.layers.push(NodeMetadata::Layer(LayerMetadata::for_layer(&layer, "layer_000.png".to_string())));
+
+        assert!(save_project(&project, &[layer], test_folder).is_ok());
+        let (_, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers[0].opacity, 0.4);
+        assert_eq!(layers[0].blend_mode, crate::blend::BlendMode::Multiply);
+
+        let _ = std::fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_project_saves_mask_beside_layer() {
+        let test_folder = "test_project_mask_io";
+        let _ = std::fs::remove_dir_all(test_folder);
+
+        let mut layer = Layer::from_rgba("masked".to_string(), 4, 4, vec![255; 64]);
+        layer.add_mask();
+        layer.blend_mask(2, 1, 0, 255);
+        let mut project = Project::new("Mask".to_string(), 4, 4);
+        project.layers.push(NodeMetadata::Layer(LayerMetadata::for_layer(&layer, "layer_000.png".to_string())));
+
+        assert!(save_project(&project, &[layer], test_folder).is_ok());
+        assert!(Path::new(test_folder).join("layer_000_mask.png").exists());
+
+        let (_, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers[0].mask_value(2, 1), 0);
+        assert_eq!(layers[0].mask_value(0, 0), 255);
+
+        let _ = std::fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_project_reopens_every_layer_at_its_own_size() {
+        let test_folder = "test_project_layers_io";
+        let _ = std::fs::remove_dir_all(test_folder);
+
+        let mut doc = crate::document::Document::new(5, 3);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(4, 2, [255, 0, 0, 255]);
+        doc.add_layer();
+        doc.group_active();
+        let project = doc.project("Layers".to_string());
+        let layers: Vec<_> = doc.leaf_layers().into_iter().cloned().collect();
+        assert!(save_project(&project, &layers, test_folder).is_ok());
+
+        let (project, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers.len(), 3);
+        let reopened = crate::document::Document::from_project(&project, layers);
+        assert_eq!((reopened.width, reopened.height), (5, 3));
+        assert_eq!(reopened.leaf_layers().len(), 3);
+        assert_eq!(reopened.composite(), doc.composite());
+
+        let _ = std::fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_load_honors_stored_filename() {
+        let test_folder = "test_project_filename_io";
+        let _ = std::fs::remove_dir_all(test_folder);
+
+        let layer = Layer::from_rgba("sky".to_string(), 2, 2, vec![10; 16]);
+        let mut project = Project::new("Named".to_string(), 2, 2);
+        project.add_layer_metadata("sky".to_string(), "sky.png".to_string());
+        assert!(save_project(&project, &[layer], test_folder).is_ok());
+        assert!(Path::new(test_folder).join("sky.png").exists());
+
+        let (_, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers[0].get_pixel(1, 1), [10, 10, 10, 10]);
+
+        let _ = std::fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_load_reports_every_problem() {
+        let test_folder = "test_project_invalid_io";
+        let _ = std::fs::remove_dir_all(test_folder);
+
+        let small = Layer::from_rgba("small".to_string(), 2, 2, vec![0; 16]);
+        let mut project = Project::new("Broken".to_string(), 4, 4);
+        project.add_layer_metadata("small".to_string(), "layer_000.png".to_string());
+        assert!(save_project(&project, &[small], test_folder).is_ok());
+
+        project.add_layer_metadata("escape".to_string(), "../outside.png".to_string());
+        project.add_layer_metadata("absolute".to_string(), "/etc/passwd".to_string());
+        project.add_layer_metadata("missing".to_string(), "layer_009.png".to_string());
+        let json = serde_json::to_string(&project).unwrap();
+        std::fs::write(Path::new(test_folder).join("project.json"), json).unwrap();
+
+        let err = read_project(test_folder).unwrap_err();
+        assert_eq!(err.issues.len(), 4);
+        assert_eq!(
+            err.issues[0],
+            ProjectIssue::SizeMismatch { file: "layer_000.png".to_string(), expected: (4, 4), found: (2, 2) }
+        );
+        assert_eq!(err.issues[1], ProjectIssue::UnsafePath { file: "../outside.png".to_string() });
+        assert_eq!(err.issues[2], ProjectIssue::UnsafePath { file: "/etc/passwd".to_string() });
+        assert!(matches!(&err.issues[3], ProjectIssue::Unreadable { file, .. } if file == "layer_009.png"));
+
+        let _ = std::fs::remove_dir_all(test_folder);
+    }
 }
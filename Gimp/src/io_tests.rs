@@ -1,7 +1,8 @@
 #[cfg(test)]
 mod tests {
     use crate::io::*;
-    use crate::layer::{Layer, Project};
+    use crate::document::Document;
+    use crate::layer::{Layer, Project, PROJECT_FORMAT_VERSION};
     use std::fs;
     use std::path::Path;
 
@@ -54,7 +55,7 @@ mod tests {
 
     #[test]
     fn test_project_save_load() {
-        let test_folder = "test_project_io";
+        let test_folder = "test_project_io_roundtrip";
         
         // Clean up any previous test
         let _ = fs::remove_dir_all(test_folder);
@@ -103,4 +104,311 @@ mod tests {
         // Result should have some blend of both layers
         assert!(!composite.is_empty());
     }
+
+    #[test]
+    fn test_load_version_1_fixture() {
+        // my_project/ predates format_version: a flat layer list without opacity or blend modes
+        let (project, layers) = load_project("my_project").expect("v1 project should load");
+        assert_eq!(project.format_version, PROJECT_FORMAT_VERSION);
+        assert_eq!((project.width, project.height), (800, 600));
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].name, "layer_0");
+        assert_eq!(layers[0].opacity, 1.0);
+        assert_eq!(layers[0].blend_mode, crate::blend::BlendMode::Normal);
+    }
+
+    #[test]
+    fn test_load_version_2_fixture() {
+        let (project, layers) = load_project("fixtures/project_v2").expect("v2 project should load");
+        assert_eq!(project.format_version, PROJECT_FORMAT_VERSION);
+        assert_eq!(layers.len(), 2);
+        assert!(layers[1].mask.is_some());
+        assert!(layers.iter().all(|l| l.offset == (0, 0)));
+
+        // Masked black ink in a 50% multiply group over white
+        let doc = Document::from_project(&project, layers);
+        let out = doc.composite();
+        assert_eq!(&out[0..4], &[128, 128, 128, 255]);
+        assert_eq!(&out[8..12], &[255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_load_version_3_fixture() {
+        let (project, layers) = load_project("fixtures/project_v3").expect("v3 project should load");
+        assert_eq!(project.format_version, PROJECT_FORMAT_VERSION);
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].offset, (0, 0));
+        assert_eq!((layers[0].width, layers[0].height), (4, 2));
+
+        // A moved layer smaller than the image, with a pixel past its right edge
+        let ink = &layers[1];
+        assert_eq!(ink.offset, (2, 1));
+        assert_eq!((ink.width, ink.height), (3, 1));
+        assert_eq!(ink.pixel_at(4, 1), [0, 0, 255, 255]);
+
+        let doc = Document::from_project(&project, layers);
+        let out = doc.composite();
+        assert_eq!(&out[16..20], &[255, 255, 255, 255]);
+        assert_eq!(&out[24..28], &[255, 0, 0, 255]);
+        assert_eq!(&out[28..32], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_migrate_version_1_manifest() {
+        let json = r#"{"name":"Old","width":2,"height":2,"layers":[{"name":"a","visible":false,"filename":"a.png"}]}"#;
+        let project = parse_project(json).unwrap();
+        assert_eq!(project.format_version, PROJECT_FORMAT_VERSION);
+        let meta = project.layer_metadata();
+        assert_eq!(meta[0].filename, "a.png");
+        assert!(!meta[0].visible);
+        assert_eq!(meta[0].opacity, 1.0);
+    }
+
+    #[test]
+    fn test_migrate_version_2_manifest() {
+        let json = r#"{"format_version":2,"name":"Old","width":2,"height":2,"layers":[
+            {"name":"a","visible":true,"opacity":1.0,"blend_mode":"normal","filename":"a.png"},
+            {"name":"g","visible":true,"opacity":1.0,"blend_mode":"normal","children":[
+                {"name":"b","visible":true,"opacity":1.0,"blend_mode":"normal","filename":"b.png"}
+            ]}
+        ]}"#;
+        let mut value: serde_json::Value = serde_json::from_str(json).unwrap();
+        migrate_project(&mut value).unwrap();
+        assert_eq!(value["format_version"], PROJECT_FORMAT_VERSION);
+        assert_eq!(value["layers"][0]["offset"], serde_json::json!([0, 0]));
+        assert_eq!(value["layers"][1]["children"][0]["offset"], serde_json::json!([0, 0]));
+        assert!(value["layers"][1].get("offset").is_none());
+
+        let project = parse_project(json).unwrap();
+        assert!(project.layer_metadata().iter().all(|m| m.offset == (0, 0)));
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let json = format!(
+            r#"{{"format_version":{},"name":"Future","width":2,"height":2,"layers":[]}}"#,
+            PROJECT_FORMAT_VERSION + 1
+        );
+        let err = parse_project(&json).unwrap_err();
+        assert_eq!(
+            err,
+            ProjectIssue::UnsupportedVersion { found: PROJECT_FORMAT_VERSION + 1, supported: PROJECT_FORMAT_VERSION }
+        );
+        assert!(err.to_string().contains("newer than this build supports"));
+    }
+
+    #[test]
+    fn test_saved_project_records_current_version() {
+        let test_folder = "test_project_version_io";
+        let _ = fs::remove_dir_all(test_folder);
+
+        let doc = Document::new(3, 3);
+        let project = doc.project("Versioned".to_string());
+        let layers: Vec<_> = doc.leaf_layers().into_iter().cloned().collect();
+        assert!(save_project(&project, &layers, test_folder).is_ok());
+
+        let json = fs::read_to_string(Path::new(test_folder).join("project.json")).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["format_version"], PROJECT_FORMAT_VERSION);
+
+        let _ = fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_moved_layer_round_trips_past_the_image_edges() {
+        let test_folder = "test_project_moved_layer_io";
+        let _ = fs::remove_dir_all(test_folder);
+
+        let mut doc = Document::new(3, 1);
+        doc.add_layer();
+        doc.active_layer_mut().unwrap().set_pixel(0, 0, [255, 0, 0, 255]);
+        doc.move_active_layer(-2, 0);
+        let layer = doc.active_layer_mut().unwrap();
+        layer.cover(0, 0, 3, 1);
+        layer.set_pixel_at(2, 0, [0, 255, 0, 255]);
+        let project = doc.project("Moved".to_string());
+        let layers: Vec<_> = doc.leaf_layers().into_iter().cloned().collect();
+        assert!(save_project(&project, &layers, test_folder).is_ok());
+
+        let (_, loaded) = load_project(test_folder).unwrap();
+        let moved = &loaded[1];
+        assert_eq!(moved.offset, (-2, 0));
+        assert_eq!((moved.width, moved.height), (5, 1));
+        assert_eq!(moved.pixel_at(-2, 0), [255, 0, 0, 255]);
+        assert_eq!(moved.pixel_at(2, 0), [0, 255, 0, 255]);
+
+        let _ = fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_project_archive_round_trip() {
+        let archive = "test_project_archive.mgp";
+        let _ = fs::remove_file(archive);
+
+        // Same contents as the v2 folder fixture, stored in one file
+        let (project, layers) = load_project("fixtures/project_v2").unwrap();
+        let save_result = save_project_archive(&project, &layers, archive);
+        assert!(save_result.is_ok(), "Save failed: {:?}", save_result);
+
+        let (loaded_project, loaded_layers) = load_project_archive(archive).unwrap();
+        assert_eq!(loaded_project.name, "Fixture");
+        assert_eq!(loaded_layers.len(), 2);
+        assert_eq!(loaded_layers[1].mask, layers[1].mask);
+        let original = Document::from_project(&project, layers).composite();
+        assert_eq!(Document::from_project(&loaded_project, loaded_layers).composite(), original);
+
+        let _ = fs::remove_file(archive);
+    }
+
+    #[test]
+    fn test_project_archive_rejects_unsafe_names() {
+        let archive = "test_project_archive_unsafe.mgp";
+        let _ = fs::remove_file(archive);
+
+        let layer = Layer::from_rgba("l".to_string(), 2, 2, vec![0; 16]);
+        let mut project = Project::new("Unsafe".to_string(), 2, 2);
+        project.add_layer_metadata("l".to_string(), "../l.png".to_string());
+        assert!(save_project_archive(&project, &[layer], archive).is_err());
+
+        let _ = fs::remove_file(archive);
+    }
+
+    #[test]
+    fn test_resave_keeps_previous_version_as_backup() {
+        let test_folder = "test_project_atomic";
+        let backup = "test_project_atomic.bak";
+        let _ = fs::remove_dir_all(test_folder);
+        let _ = fs::remove_dir_all(backup);
+
+        let mut project = Project::new("Atomic".to_string(), 2, 2);
+        project.add_layer_metadata("Layer 0".to_string(), "layer_000.png".to_string());
+        let first = Layer::from_rgba("first".to_string(), 2, 2, vec![10; 16]);
+        let second = Layer::from_rgba("second".to_string(), 2, 2, vec![20; 16]);
+        assert!(save_project(&project, &[first], test_folder).is_ok());
+        assert!(!Path::new(backup).exists());
+        assert!(save_project(&project, &[second], test_folder).is_ok());
+
+        let (_, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers[0].get_pixel(0, 0), [20, 20, 20, 20]);
+        let (_, previous) = load_project(backup).unwrap();
+        assert_eq!(previous[0].get_pixel(0, 0), [10, 10, 10, 10]);
+        assert!(!Path::new("test_project_atomic.tmp").exists());
+
+        let _ = fs::remove_dir_all(test_folder);
+        let _ = fs::remove_dir_all(backup);
+    }
+
+    #[test]
+    fn test_save_refuses_to_replace_unrelated_folder() {
+        let test_folder = "test_project_unrelated";
+        let _ = fs::remove_dir_all(test_folder);
+        fs::create_dir_all(test_folder).unwrap();
+        fs::write(Path::new(test_folder).join("notes.txt"), "keep me").unwrap();
+
+        let layer = Layer::from_rgba("l".to_string(), 2, 2, vec![0; 16]);
+        let mut project = Project::new("Unrelated".to_string(), 2, 2);
+        project.add_layer_metadata("l".to_string(), "layer_000.png".to_string());
+        assert!(save_project(&project, &[layer], test_folder).is_err());
+        assert!(Path::new(test_folder).join("notes.txt").exists());
+        assert!(!Path::new("test_project_unrelated.bak").exists());
+
+        let _ = fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_save_leaves_unrelated_staging_and_backup_names_alone() {
+        let test_folder = "test_project_sibling_names";
+        let staging = "test_project_sibling_names.tmp";
+        let backup = "test_project_sibling_names.bak";
+        for path in [test_folder, staging, backup] {
+            let _ = fs::remove_dir_all(path);
+        }
+        fs::create_dir_all(staging).unwrap();
+        fs::write(Path::new(staging).join("draft.txt"), "keep me").unwrap();
+
+        let mut project = Project::new("Siblings".to_string(), 2, 2);
+        project.add_layer_metadata("l".to_string(), "layer_000.png".to_string());
+        let layer = Layer::from_rgba("l".to_string(), 2, 2, vec![40; 16]);
+        assert!(save_project(&project, std::slice::from_ref(&layer), test_folder).is_ok());
+        assert!(Path::new(staging).join("draft.txt").exists());
+
+        // A folder that is not a previous save blocks the backup instead of being replaced
+        fs::create_dir_all(backup).unwrap();
+        fs::write(Path::new(backup).join("notes.txt"), "keep me").unwrap();
+        assert!(save_project(&project, &[layer], test_folder).is_err());
+        assert!(Path::new(backup).join("notes.txt").exists());
+        assert_eq!(load_project(test_folder).unwrap().1[0].get_pixel(0, 0), [40, 40, 40, 40]);
+
+        for path in [test_folder, staging, backup, "test_project_sibling_names.tmp1"] {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+
+    #[test]
+    fn test_resave_keeps_other_files_in_project_folder() {
+        let test_folder = "test_project_extra_files";
+        let backup = "test_project_extra_files.bak";
+        let _ = fs::remove_dir_all(test_folder);
+        let _ = fs::remove_dir_all(backup);
+
+        let mut project = Project::new("Extras".to_string(), 2, 2);
+        project.add_layer_metadata("l".to_string(), "layer_000.png".to_string());
+        let layer = Layer::from_rgba("l".to_string(), 2, 2, vec![50; 16]);
+        assert!(save_project(&project, std::slice::from_ref(&layer), test_folder).is_ok());
+        fs::write(Path::new(test_folder).join("notes.txt"), "keep me").unwrap();
+        fs::create_dir_all(Path::new(test_folder).join("refs")).unwrap();
+        fs::write(Path::new(test_folder).join("refs").join("a.txt"), "ref").unwrap();
+
+        // Two saves: the first moves the old folder to .bak, the second deletes that
+        assert!(save_project(&project, std::slice::from_ref(&layer), test_folder).is_ok());
+        assert!(save_project(&project, &[layer], test_folder).is_ok());
+        assert_eq!(fs::read_to_string(Path::new(test_folder).join("notes.txt")).unwrap(), "keep me");
+        assert_eq!(fs::read_to_string(Path::new(test_folder).join("refs").join("a.txt")).unwrap(), "ref");
+
+        let _ = fs::remove_dir_all(test_folder);
+        let _ = fs::remove_dir_all(backup);
+    }
+
+    #[test]
+    fn test_failed_save_leaves_previous_project_intact() {
+        let test_folder = "test_project_failed_save";
+        let _ = fs::remove_dir_all(test_folder);
+        let _ = fs::remove_dir_all("test_project_failed_save.bak");
+
+        let mut project = Project::new("Failing".to_string(), 2, 2);
+        project.add_layer_metadata("l".to_string(), "layer_000.png".to_string());
+        let layer = Layer::from_rgba("l".to_string(), 2, 2, vec![30; 16]);
+        assert!(save_project(&project, &[layer], test_folder).is_ok());
+
+        // A layer whose buffer does not match its size cannot be encoded
+        let broken = Layer::from_rgba("l".to_string(), 2, 2, vec![0; 3]);
+        assert!(save_project(&project, &[broken], test_folder).is_err());
+
+        let (_, layers) = load_project(test_folder).unwrap();
+        assert_eq!(layers[0].get_pixel(1, 1), [30, 30, 30, 30]);
+        assert!(!Path::new("test_project_failed_save.tmp").exists());
+
+        let _ = fs::remove_dir_all(test_folder);
+    }
+
+    #[test]
+    fn test_archive_resave_keeps_backup() {
+        let archive = "test_project_backup.mgp";
+        let backup = "test_project_backup.mgp.bak";
+        let _ = fs::remove_file(archive);
+        let _ = fs::remove_file(backup);
+
+        let mut project = Project::new("Backup".to_string(), 1, 1);
+        project.add_layer_metadata("l".to_string(), "layer_000.png".to_string());
+        let first = Layer::from_rgba("l".to_string(), 1, 1, vec![1, 2, 3, 255]);
+        let second = Layer::from_rgba("l".to_string(), 1, 1, vec![4, 5, 6, 255]);
+        assert!(save_project_archive(&project, &[first], archive).is_ok());
+        assert!(save_project_archive(&project, &[second], archive).is_ok());
+
+        assert_eq!(load_project_archive(archive).unwrap().1[0].get_pixel(0, 0), [4, 5, 6, 255]);
+        assert_eq!(load_project_archive(backup).unwrap().1[0].get_pixel(0, 0), [1, 2, 3, 255]);
+
+        let _ = fs::remove_file(archive);
+        let _ = fs::remove_file(backup);
+    }
 }
@@ -1,25 +1,39 @@
 use serde::{Deserialize, Serialize};
 
+use crate::blend::{blend_over, BlendMode};
+use crate::resample::{resample, Filter};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Layer {
     pub name: String,
     pub width: u32,
     pub height: u32,
     pub visible: bool,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32, // 0.0..=1.0, multiplied into alpha when compositing
+    #[serde(default)]
+    pub blend_mode: BlendMode,
     pub pixels: Vec<u8>, // RGBA8, packed in row-major order
+    #[serde(default)]
+    pub mask: Option<Vec<u8>>, // Grayscale, one byte per pixel; 255 shows, 0 hides
+    #[serde(default)]
+    pub offset: (i32, i32), // Image-space position of the top-left pixel, set by the Move tool
+}
+
+fn default_opacity() -> f32 {
+    1.0
 }
 
 impl Layer {
-    #[allow(dead_code)]
     pub fn new(name: String, width: u32, height: u32) -> Self {
         let size = (width as usize) * (height as usize) * 4;
-        Self {
-            name,
-            width,
-            height,
-            visible: true,
-            pixels: vec![255; size], // White by default
-        }
+        Self::from_rgba(name, width, height, vec![255; size]) // White by default
+    }
+
+    /// Create a fully transparent layer, used for new paint layers
+    pub fn transparent(name: String, width: u32, height: u32) -> Self {
+        let size = (width as usize) * (height as usize) * 4;
+        Self::from_rgba(name, width, height, vec![0; size])
     }
 
     pub fn from_rgba(name: String, width: u32, height: u32, pixels: Vec<u8>) -> Self {
@@ -28,11 +42,53 @@ impl Layer {
             width,
             height,
             visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
             pixels,
+            mask: None,
+            offset: (0, 0),
+        }
+    }
+
+    /// Attach a white (fully revealing) mask if the layer has none yet
+    pub fn add_mask(&mut self) {
+        if self.mask.is_none() {
+            self.mask = Some(vec![255; self.width as usize * self.height as usize]);
+        }
+    }
+
+    /// Multiply the mask into the alpha channel and drop it, keeping the look
+    pub fn apply_mask(&mut self) {
+        let Some(mask) = self.mask.take() else {
+            return;
+        };
+        for (px, m) in self.pixels.chunks_exact_mut(4).zip(mask) {
+            px[3] = (px[3] as u32 * m as u32 / 255) as u8;
+        }
+    }
+
+    /// Mask value at (x, y); layers without a mask are fully revealed
+    pub fn mask_value(&self, x: u32, y: u32) -> u8 {
+        match &self.mask {
+            Some(mask) if x < self.width && y < self.height => {
+                mask.get((y * self.width + x) as usize).copied().unwrap_or(255)
+            }
+            _ => 255,
+        }
+    }
+
+    /// Blend a gray value into the mask at (x, y) with the given alpha
+    pub fn blend_mask(&mut self, x: u32, y: u32, value: u8, alpha: u8) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let idx = (y * self.width + x) as usize;
+        if let Some(m) = self.mask.as_mut().and_then(|mask| mask.get_mut(idx)) {
+            let a = alpha as f32 / 255.0;
+            *m = (value as f32 * a + *m as f32 * (1.0 - a)).round() as u8;
         }
     }
 
-    #[allow(dead_code)]
     pub fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
         if x < self.width && y < self.height {
             let idx = ((y * self.width + x) * 4) as usize;
@@ -42,7 +98,16 @@ impl Layer {
         }
     }
 
-    #[allow(dead_code)]
+    /// Alpha-blend a color over the pixel at (x, y)
+    pub fn blend_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        if x < self.width && y < self.height {
+            let idx = ((y * self.width + x) * 4) as usize;
+            if idx + 3 < self.pixels.len() {
+                blend_over(&mut self.pixels[idx..idx + 4], color);
+            }
+        }
+    }
+
     pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
         if x < self.width && y < self.height {
             let idx = ((y * self.width + x) * 4) as usize;
@@ -55,14 +120,112 @@ impl Layer {
         [0, 0, 0, 0]
     }
 
-    #[allow(dead_code)]
-    pub fn resize(&mut self, new_width: u32, new_height: u32) {
-        let new_size = (new_width as usize) * (new_height as usize) * 4;
-        self.pixels.resize(new_size, 255);
+    /// Layer pixel under an image-space point, taking the offset into account
+    fn local(&self, x: i32, y: i32) -> Option<(u32, u32)> {
+        let lx = x as i64 - self.offset.0 as i64;
+        let ly = y as i64 - self.offset.1 as i64;
+        (lx >= 0 && ly >= 0 && lx < self.width as i64 && ly < self.height as i64).then_some((lx as u32, ly as u32))
+    }
+
+    /// Index into the pixel (and mask) buffer of the pixel under an image-space point
+    pub fn index_at(&self, x: i32, y: i32) -> Option<usize> {
+        self.local(x, y).map(|(lx, ly)| (ly * self.width + lx) as usize)
+    }
+
+    /// Color at an image-space point; transparent where the layer was moved away
+    pub fn pixel_at(&self, x: i32, y: i32) -> [u8; 4] {
+        self.local(x, y).map_or([0, 0, 0, 0], |(lx, ly)| self.get_pixel(lx, ly))
+    }
+
+    /// Mask value at an image-space point
+    pub fn mask_at(&self, x: i32, y: i32) -> u8 {
+        self.local(x, y).map_or(255, |(lx, ly)| self.mask_value(lx, ly))
+    }
+
+    /// `set_pixel` at an image-space point; ignored outside the buffer
+    pub fn set_pixel_at(&mut self, x: i32, y: i32, color: [u8; 4]) {
+        if let Some((lx, ly)) = self.local(x, y) {
+            self.set_pixel(lx, ly, color);
+        }
+    }
+
+    /// `blend_pixel` at an image-space point; ignored outside the buffer
+    pub fn blend_pixel_at(&mut self, x: i32, y: i32, color: [u8; 4]) {
+        if let Some((lx, ly)) = self.local(x, y) {
+            self.blend_pixel(lx, ly, color);
+        }
+    }
+
+    /// `blend_mask` at an image-space point; ignored outside the buffer
+    pub fn blend_mask_at(&mut self, x: i32, y: i32, value: u8, alpha: u8) {
+        if let Some((lx, ly)) = self.local(x, y) {
+            self.blend_mask(lx, ly, value, alpha);
+        }
+    }
+
+    /// Image-space rectangle [x0, x1) x [y0, y1) the buffer covers
+    pub fn bounds(&self) -> (i32, i32, i32, i32) {
+        let (x, y) = self.offset;
+        (x, y, x + self.width as i32, y + self.height as i32)
+    }
+
+    /// True when the buffer is exactly a `width` x `height` image, unmoved
+    pub fn is_aligned(&self, width: u32, height: u32) -> bool {
+        self.offset == (0, 0) && (self.width, self.height) == (width, height)
+    }
+
+    /// The pixels as they sit on a `width` x `height` image, offset applied
+    pub fn placed(&self, width: u32, height: u32) -> Vec<u8> {
+        place_pixels(&self.pixels, (self.width, self.height), &[0, 0, 0, 0], (width, height), self.offset)
+    }
+
+    /// Grow the buffer so it also covers the image-space rectangle
+    /// [x0, x1) x [y0, y1). New pixels are transparent and revealed by the
+    /// mask; nothing is ever cut off, so content moved past the image edges
+    /// survives edits.
+    pub fn cover(&mut self, x0: i32, y0: i32, x1: i32, y1: i32) {
+        let (bx0, by0, bx1, by1) = self.bounds();
+        let (nx0, ny0, nx1, ny1) = (bx0.min(x0), by0.min(y0), bx1.max(x1), by1.max(y1));
+        if (nx0, ny0, nx1, ny1) == (bx0, by0, bx1, by1) {
+            return;
+        }
+        self.resize((nx1 - nx0) as u32, (ny1 - ny0) as u32, (bx0 - nx0, by0 - ny0), [0, 0, 0, 0]);
+        self.offset = (nx0, ny0);
+    }
+
+    /// Change the layer size, placing the old contents at `offset` in the new
+    /// buffer. New pixels get `fill`; a mask reveals them.
+    pub fn resize(&mut self, new_width: u32, new_height: u32, offset: (i32, i32), fill: [u8; 4]) {
+        let (size, new_size) = ((self.width, self.height), (new_width, new_height));
+        self.pixels = place_pixels(&self.pixels, size, &fill, new_size, offset);
+        if let Some(mask) = &self.mask {
+            self.mask = Some(place_pixels(mask, size, &[255], new_size, offset));
+        }
+        self.width = new_width;
+        self.height = new_height;
+    }
+
+    /// Resample the layer and its mask to a new size
+    pub fn scale(&mut self, new_width: u32, new_height: u32, filter: Filter) {
+        let (size, new_size) = ((self.width, self.height), (new_width, new_height));
+        self.pixels = resample(&self.pixels, size, new_size, 4, filter);
+        if let Some(mask) = &self.mask {
+            self.mask = Some(resample(mask, size, new_size, 1, filter));
+        }
         self.width = new_width;
         self.height = new_height;
     }
 
+    /// Keep only the `width` x `height` block at (x, y), mask included
+    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        self.pixels = crop_pixels(&self.pixels, self.width, 4, (x, y, width, height));
+        if let Some(mask) = &self.mask {
+            self.mask = Some(crop_pixels(mask, self.width, 1, (x, y, width, height)));
+        }
+        self.width = width;
+        self.height = height;
+    }
+
     #[allow(dead_code)]
     pub fn clear(&mut self, color: [u8; 4]) {
         for i in (0..self.pixels.len()).step_by(4) {
@@ -71,24 +234,220 @@ impl Layer {
     }
 }
 
+/// Copy the block `(x, y, width, height)` out of a row-major buffer `stride`
+/// pixels wide with `channels` bytes per pixel. The block must lie inside the buffer.
+pub fn crop_pixels(pixels: &[u8], stride: u32, channels: usize, rect: (u32, u32, u32, u32)) -> Vec<u8> {
+    let (x, y, width, height) = rect;
+    let mut out = Vec::with_capacity(width as usize * height as usize * channels);
+    for row in y..y + height {
+        let start = (row as usize * stride as usize + x as usize) * channels;
+        out.extend_from_slice(&pixels[start..start + width as usize * channels]);
+    }
+    out
+}
+
+/// Copy a `size` buffer with `fill.len()` bytes per pixel into a new `new_size`
+/// buffer with its top-left corner at `offset`, filling the rest with `fill`.
+/// Parts that land outside the new buffer are dropped.
+pub fn place_pixels(pixels: &[u8], size: (u32, u32), fill: &[u8], new_size: (u32, u32), offset: (i32, i32)) -> Vec<u8> {
+    let channels = fill.len();
+    let (width, height) = (size.0 as i64, size.1 as i64);
+    let (new_width, new_height) = (new_size.0 as i64, new_size.1 as i64);
+    let mut out = fill.repeat(new_size.0 as usize * new_size.1 as usize);
+
+    // Columns of the old buffer that land inside the new one
+    let x0 = (-offset.0 as i64).clamp(0, width);
+    let x1 = (new_width - offset.0 as i64).clamp(x0, width);
+    if x0 == x1 {
+        return out;
+    }
+    for y in 0..height {
+        let new_y = y + offset.1 as i64;
+        if new_y < 0 || new_y >= new_height {
+            continue;
+        }
+        let src = ((y * width + x0) as usize) * channels;
+        let dst = ((new_y * new_width + x0 + offset.0 as i64) as usize) * channels;
+        let len = (x1 - x0) as usize * channels;
+        out[dst..dst + len].copy_from_slice(&pixels[src..src + len]);
+    }
+    out
+}
+
+/// A folder in the layer stack: its children are composited on their own
+/// (bottom first) and the result is blended into the parent like a single layer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerGroup {
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+    pub children: Vec<LayerNode>,
+}
+
+impl LayerGroup {
+    pub fn new(name: String, children: Vec<LayerNode>) -> Self {
+        Self {
+            name,
+            visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+            children,
+        }
+    }
+}
+
+/// One entry of the layer tree
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LayerNode {
+    Layer(Layer),
+    Group(LayerGroup),
+}
+
+impl LayerNode {
+    pub fn name(&self) -> &str {
+        match self {
+            LayerNode::Layer(layer) => &layer.name,
+            LayerNode::Group(group) => &group.name,
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        match self {
+            LayerNode::Layer(layer) => layer.visible,
+            LayerNode::Group(group) => group.visible,
+        }
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        match self {
+            LayerNode::Layer(layer) => layer.visible = visible,
+            LayerNode::Group(group) => group.visible = visible,
+        }
+    }
+
+    pub fn opacity(&self) -> f32 {
+        match self {
+            LayerNode::Layer(layer) => layer.opacity,
+            LayerNode::Group(group) => group.opacity,
+        }
+    }
+
+    pub fn set_opacity(&mut self, opacity: f32) {
+        let opacity = opacity.clamp(0.0, 1.0);
+        match self {
+            LayerNode::Layer(layer) => layer.opacity = opacity,
+            LayerNode::Group(group) => group.opacity = opacity,
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        match self {
+            LayerNode::Layer(layer) => layer.blend_mode,
+            LayerNode::Group(group) => group.blend_mode,
+        }
+    }
+
+    pub fn set_blend_mode(&mut self, mode: BlendMode) {
+        match self {
+            LayerNode::Layer(layer) => layer.blend_mode = mode,
+            LayerNode::Group(group) => group.blend_mode = mode,
+        }
+    }
+}
+
+/// Version of the project.json layout written by this build. Bump it and add a
+/// migration in `io::migrate_project` whenever `Project` or its metadata changes.
+///
+/// 1. flat list of layers with name, visible and filename (no version field)
+/// 2. layer tree with groups, opacity, blend modes and masks
+/// 3. layer offsets and sizes, as moved layers may hold pixels past the image edges
+pub const PROJECT_FORMAT_VERSION: u32 = 3;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Project {
+    pub format_version: u32,
     pub name: String,
     pub width: u32,
     pub height: u32,
-    pub layers: Vec<LayerMetadata>,
+    pub layers: Vec<NodeMetadata>, // Layer tree, bottom first
+}
+
+/// Entry of the serialized layer tree. Groups are recognised by their
+/// `children` list, so flat projects from before groups still parse.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum NodeMetadata {
+    Group(GroupMetadata),
+    Layer(LayerMetadata),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupMetadata {
+    pub name: String,
+    pub visible: bool,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend_mode: BlendMode,
+    pub children: Vec<NodeMetadata>,
+}
+
+impl GroupMetadata {
+    pub fn for_group(group: &LayerGroup, children: Vec<NodeMetadata>) -> Self {
+        Self {
+            name: group.name.clone(),
+            visible: group.visible,
+            opacity: group.opacity,
+            blend_mode: group.blend_mode,
+            children,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LayerMetadata {
     pub name: String,
     pub visible: bool,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend_mode: BlendMode,
     pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask_filename: Option<String>,
+    #[serde(default)]
+    pub offset: (i32, i32),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<(u32, u32)>, // Size of the layer PNG; None means the project size
+}
+
+impl LayerMetadata {
+    /// Describe a layer that will be stored as `filename` inside the project folder
+    pub fn for_layer(layer: &Layer, filename: String) -> Self {
+        Self {
+            name: layer.name.clone(),
+            visible: layer.visible,
+            opacity: layer.opacity,
+            blend_mode: layer.blend_mode,
+            mask_filename: layer.mask.as_ref().map(|_| mask_filename_for(&filename)),
+            filename,
+            offset: layer.offset,
+            size: Some((layer.width, layer.height)),
+        }
+    }
+}
+
+/// Name of the grayscale mask PNG stored beside a layer PNG (`layer_000.png` -> `layer_000_mask.png`)
+pub fn mask_filename_for(filename: &str) -> String {
+    let stem = filename.strip_suffix(".png").unwrap_or(filename);
+    format!("{}_mask.png", stem)
 }
 
 impl Project {
     pub fn new(name: String, width: u32, height: u32) -> Self {
         Self {
+            format_version: PROJECT_FORMAT_VERSION,
             name,
             width,
             height,
@@ -96,11 +455,111 @@ impl Project {
         }
     }
 
+    #[allow(dead_code)]
     pub fn add_layer_metadata(&mut self, name: String, filename: String) {
-        self.layers.push(LayerMetadata {
+        self.layers.push(NodeMetadata::Layer(LayerMetadata {
             name,
             visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
             filename,
-        });
+            mask_filename: None,
+            offset: (0, 0),
+            size: None,
+        }));
+    }
+
+    /// Metadata of every pixel layer in the tree, depth-first and bottom first
+    /// (the order layer PNGs are saved and loaded in)
+    pub fn layer_metadata(&self) -> Vec<&LayerMetadata> {
+        fn collect<'a>(nodes: &'a [NodeMetadata], out: &mut Vec<&'a LayerMetadata>) {
+            for node in nodes {
+                match node {
+                    NodeMetadata::Layer(meta) => out.push(meta),
+                    NodeMetadata::Group(group) => collect(&group.children, out),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        collect(&self.layers, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3x2 layer whose pixels encode their own coordinates
+    fn numbered_layer() -> Layer {
+        let mut layer = Layer::transparent("Numbered".to_string(), 3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                layer.set_pixel(x, y, [x as u8, y as u8, 0, 255]);
+            }
+        }
+        layer
+    }
+
+    #[test]
+    fn test_resize_keeps_rows_intact() {
+        let fill = [9, 9, 9, 9];
+
+        // Growing to the right and down keeps every row at its own place
+        let mut layer = numbered_layer();
+        layer.resize(5, 3, (0, 0), fill);
+        assert_eq!(layer.pixels.len(), 5 * 3 * 4);
+        for y in 0..2 {
+            for x in 0..3 {
+                assert_eq!(layer.get_pixel(x, y), [x as u8, y as u8, 0, 255]);
+            }
+            assert_eq!(layer.get_pixel(3, y), fill);
+            assert_eq!(layer.get_pixel(4, y), fill);
+        }
+        assert_eq!(layer.get_pixel(0, 2), fill);
+
+        // An offset moves the content; shrinking drops what falls outside
+        let mut layer = numbered_layer();
+        layer.resize(4, 4, (1, 1), fill);
+        assert_eq!(layer.get_pixel(1, 1), [0, 0, 0, 255]);
+        assert_eq!(layer.get_pixel(3, 2), [2, 1, 0, 255]);
+        assert_eq!(layer.get_pixel(0, 1), fill);
+        assert_eq!(layer.get_pixel(1, 3), fill);
+
+        let mut layer = numbered_layer();
+        layer.resize(2, 1, (-1, -1), fill);
+        assert_eq!(layer.pixels, vec![1, 1, 0, 255, 2, 1, 0, 255]);
+    }
+
+    #[test]
+    fn test_resize_reveals_new_mask_area() {
+        let mut layer = numbered_layer();
+        layer.add_mask();
+        layer.blend_mask(0, 0, 0, 255);
+        layer.resize(4, 2, (1, 0), [0, 0, 0, 0]);
+        assert_eq!(layer.mask.as_ref().unwrap().len(), 8);
+        assert_eq!(layer.mask_value(1, 0), 0);
+        assert_eq!(layer.mask_value(0, 0), 255);
+        assert_eq!(layer.mask_value(2, 1), 255);
+    }
+
+    #[test]
+    fn test_offset_moves_pixels_and_cover_keeps_them() {
+        let mut layer = numbered_layer();
+        layer.add_mask();
+        layer.offset = (1, -1);
+        assert_eq!(layer.pixel_at(1, 0), [0, 1, 0, 255]);
+        assert_eq!(layer.pixel_at(0, 0), [0, 0, 0, 0]);
+        assert_eq!(layer.mask_at(0, 0), 255);
+
+        // Covering a 3x2 image grows the buffer instead of cutting off row 0
+        layer.cover(0, 0, 3, 2);
+        assert_eq!(layer.bounds(), (0, -1, 4, 2));
+        assert_eq!(layer.pixel_at(1, -1), [0, 0, 0, 255]);
+        assert_eq!(layer.pixel_at(3, 0), [2, 1, 0, 255]);
+        assert_eq!(layer.pixel_at(0, 0), [0, 0, 0, 0]);
+        assert_eq!(layer.mask.as_ref().unwrap().len(), (layer.width * layer.height) as usize);
+        assert_eq!(layer.index_at(1, -1), Some(1));
+        assert_eq!(layer.index_at(4, 0), None);
     }
 }
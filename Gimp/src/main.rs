@@ -1,17 +1,28 @@
+mod blend;
 mod brush;
 mod canvas;
+mod document;
 mod gpu;
 mod input;
 mod layer;
 mod io;
+#[cfg(test)]
+mod io_tests;
 mod icons;
 mod history;
+mod recovery;
+mod selection;
+mod crop;
+mod resample;
+mod transform;
+mod free_transform;
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use winit::{
     dpi::{LogicalSize, PhysicalPosition, PhysicalSize},
     event::*,
-    event_loop::EventLoop,
+    event_loop::{ControlFlow, EventLoop},
     keyboard::{KeyCode, PhysicalKey},
     window::WindowAttributes,
 };
@@ -19,9 +30,14 @@ use winit::{
 use crate::{
     brush::Brush,
     canvas::Canvas,
+    crop::{CropHandle, CropRect, ASPECT_PRESETS},
+    document::{Anchor, Document, FloatingLayer, LayerPath},
     gpu::Gpu,
     input::{InputState, SliderDrag},
-    history::History,
+    resample::Filter,
+    history::{FilterStep, History},
+    selection::{Selection, SelectionMode},
+    transform::{Flip, Rotation, Transform},
 };
 
 const BRUSH_COLOR: [u8; 4] = [0, 0, 0, 255];
@@ -32,13 +48,24 @@ const BRIGHT_MIN: f32 = 0.3;
 const BRIGHT_MAX: f32 = 1.6;
 const TOOLBAR_HEIGHT: u32 = 64;
 const PANEL_WIDTH: u32 = 88;
-const UI_MARGIN: u32 = 6;
-const UI_BUTTON_H: u32 = 20;
-const UI_GAP: u32 = 6;
-const SLIDER_H: u32 = 8;
-const SLIDER_LABEL_W: u32 = 12;
-const SLIDER_ICON_W: u32 = 10;
-const SLIDER_KNOB_W: u32 = 12;
+const LAYER_ROW_HEIGHT: u32 = 18;
+const LAYER_BUTTON_SIZE: u32 = 16;
+const POLYGON_CLOSE_DISTANCE: f32 = 6.0; // Canvas pixels from the first vertex that close a polygon
+const CANVAS_SHRINK: f32 = 0.75; // Size factors for the S and L canvas size keys
+const CANVAS_GROW: f32 = 1.25;
+const SCALE_STEP: f32 = 1.25; // Size factor for the Ctrl+= and Ctrl+- scale image keys
+const ROTATE_STEP: f32 = 15.0; // Degrees turned by the , and . rotate keys
+const SELECTION_STEP: u32 = 4; // Pixels added or removed by Grow, Shrink and Border
+const SELECTION_FEATHER_RADIUS: f32 = 8.0;
+const SELECTION_SMOOTH_RADIUS: u32 = 3;
+const WAND_TOGGLE_SIZE: u32 = 14;
+const WAND_GLOBAL_X: u32 = 296; // Magic wand toggles sit right of the toolbar slider
+const WAND_MERGED_X: u32 = 314;
+const BRUSH_SLIDER_W: u32 = 80; // Brush hardness, opacity and flow sliders, also right of the size slider
+const BRUSH_HARDNESS_X: u32 = 304;
+const BRUSH_OPACITY_X: u32 = 400;
+const BRUSH_FLOW_X: u32 = 496;
+const ANTS_INTERVAL: Duration = Duration::from_millis(150); // Marching ants animation step
 const PALETTE: [[u8; 4]; 8] = [
     [0, 0, 0, 255],       // Black
     [255, 0, 0, 255],     // Red
@@ -63,7 +90,7 @@ fn window_to_canvas(
     Some((x.clamp(0.0, (canvas.width - 1) as f32), y.clamp(0.0, (canvas.height - 1) as f32)))
 }
 
-fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputState, icons: &crate::icons::IconCache) {
+fn draw_ui(canvas: &mut Canvas, brush: &Brush, _brightness: f32, input: &InputState, icons: &crate::icons::IconCache) {
     // Top toolbar background (dark)
     canvas.fill_rect(0, 0, canvas.width, TOOLBAR_HEIGHT, [50, 50, 50, 255]);
     
@@ -71,8 +98,8 @@ fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputSta
     canvas.fill_rect(0, TOOLBAR_HEIGHT, PANEL_WIDTH, canvas.height - TOOLBAR_HEIGHT, [220, 220, 220, 255]);
     
     // Toolbar: Tool buttons with icons
-    let tool_gap = 8;
-    let tool_size = 48;
+    let tool_gap = 4;
+    let tool_size = 40;
     let mut tool_x = 8;
     let tool_y = 8;
     
@@ -84,6 +111,13 @@ fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputSta
         (input::Tool::ColorPicker, &icons.picker),
         (input::Tool::Move, &icons.move_tool),
         (input::Tool::Blur, &icons.blur),
+        (input::Tool::RectSelect, &icons.rect_select),
+        (input::Tool::EllipseSelect, &icons.ellipse_select),
+        (input::Tool::LassoSelect, &icons.lasso_select),
+        (input::Tool::PolygonSelect, &icons.polygon_select),
+        (input::Tool::MagicWand, &icons.magic_wand),
+        (input::Tool::Crop, &icons.crop),
+        (input::Tool::Transform, &icons.transform),
     ];
     
     for (tool, icon) in &tools {
@@ -98,7 +132,7 @@ fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputSta
         
         // Draw icon centered in the button
         if !icon.pixels.is_empty() {
-            let icon_display_size = 32;
+            let icon_display_size = 28;
             let icon_x = tool_x + (tool_size - icon_display_size) / 2;
             let icon_y = tool_y + (tool_size - icon_display_size) / 2;
             draw_icon(canvas, icon, icon_x, icon_y, icon_display_size);
@@ -114,17 +148,18 @@ fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputSta
         (file_x + 60, &icons.save),        // Save
         (file_x + 90, &icons.brightness),  // Brightness filter
         (file_x + 120, &icons.invert),     // Invert filter
-        (file_x + 150, &icons.grayscale),  // Grayscale filter (toggle)
+        (file_x + 150, &icons.grayscale),  // Grayscale filter
         (file_x + 180, &icons.brightness), // Remove brightness (reuse icon)
         (file_x + 210, &icons.grayscale),  // Remove grayscale (reuse icon)
     ];
     
+    let file_btn_h = 48;
     for (x, icon) in &file_btns {
-        canvas.fill_rect(*x, tool_y, 24, tool_size, [100, 100, 100, 255]);
+        canvas.fill_rect(*x, tool_y, 24, file_btn_h, [100, 100, 100, 255]);
         if !icon.pixels.is_empty() {
             let icon_display_size = 20;
             let icon_x_pos = *x + (24 - icon_display_size) / 2;
-            let icon_y_pos = tool_y + (tool_size - icon_display_size) / 2;
+            let icon_y_pos = tool_y + (file_btn_h - icon_display_size) / 2;
             draw_icon(canvas, icon, icon_x_pos, icon_y_pos, icon_display_size);
         }
     }
@@ -179,16 +214,54 @@ fn draw_ui(canvas: &mut Canvas, brush: &Brush, brightness: f32, input: &InputSta
     let preview_x = panel_x + ((PANEL_WIDTH - 16).saturating_sub(preview_w)) / 2;
     canvas.fill_rect(preview_x, size_y + 16, preview_w.max(4), 12, brush.color);
 
-    // Brush size slider under toolbar icons
+    // Brush size slider under toolbar icons (wand tolerance while the magic wand is active)
     let slider_y = TOOLBAR_HEIGHT.saturating_sub(10);
     let slider_x = 8;
     let slider_w = 280u32;
     let track_color = [100, 100, 100, 255];
     canvas.fill_rect(slider_x, slider_y, slider_w, 6, track_color);
-    let t = ((brush.radius - BRUSH_RADIUS_MIN) / (BRUSH_RADIUS_MAX - BRUSH_RADIUS_MIN)).clamp(0.0, 1.0);
+    let t = if input.current_tool == input::Tool::MagicWand {
+        input.wand.tolerance as f32 / 255.0
+    } else {
+        ((brush.radius - BRUSH_RADIUS_MIN) / (BRUSH_RADIUS_MAX - BRUSH_RADIUS_MIN)).clamp(0.0, 1.0)
+    };
     let knob_x = slider_x + (t * slider_w as f32).round() as u32;
     canvas.fill_rect(knob_x.saturating_sub(3), slider_y.saturating_sub(2), 6, 10, [200, 200, 200, 255]);
 
+    // Magic wand toggles after the slider: global (select by color) and sample merged
+    if input.current_tool == input::Tool::MagicWand {
+        let toggles = [(WAND_GLOBAL_X, !input.wand.contiguous), (WAND_MERGED_X, input.wand.sample_merged)];
+        for (x, on) in toggles {
+            let color = if on { [100, 150, 255, 255] } else { [80, 80, 80, 255] };
+            canvas.fill_rect(x, slider_y.saturating_sub(2), WAND_TOGGLE_SIZE, 10, color);
+        }
+        // Global: scattered dots; merged: stacked bars
+        let glyph = [255, 255, 255, 255];
+        for (dx, dy) in [(3, 1), (9, 1), (6, 4), (3, 6), (9, 6)] {
+            canvas.fill_rect(WAND_GLOBAL_X + dx, slider_y.saturating_sub(2) + dy, 2, 2, glyph);
+        }
+        for dy in [1, 4, 7] {
+            canvas.fill_rect(WAND_MERGED_X + 3, slider_y.saturating_sub(2) + dy, 8, 2, glyph);
+        }
+    }
+
+    // Brush hardness, opacity and flow, each labelled with its initial
+    if input.current_tool == input::Tool::Brush {
+        let sliders = [
+            (BRUSH_HARDNESS_X, 'H', brush.hardness),
+            (BRUSH_OPACITY_X, 'O', brush.opacity),
+            (BRUSH_FLOW_X, 'F', brush.flow),
+        ];
+        for (x, label, value) in sliders {
+            draw_char(canvas, x - 8, slider_y.saturating_sub(1), label, [0, 0, 0, 255]);
+            // The pencil has no soft edge, so hardness is greyed out
+            let track = if label == 'H' && brush.pencil { [60, 60, 60, 255] } else { track_color };
+            canvas.fill_rect(x, slider_y, BRUSH_SLIDER_W, 6, track);
+            let knob_x = x + (value.clamp(0.0, 1.0) * BRUSH_SLIDER_W as f32).round() as u32;
+            canvas.fill_rect(knob_x.saturating_sub(3), slider_y.saturating_sub(2), 6, 10, [200, 200, 200, 255]);
+        }
+    }
+
     // Advanced color picker UI (Hue bar + SV square)
     if input.show_color_picker {
         // Geometry
@@ -291,6 +364,8 @@ fn draw_sv_square_fast(canvas: &mut Canvas, x: u32, y: u32, w: u32, h: u32, hue:
         }
     }
 }
+    draw_layers_panel(canvas, input);
+
     // Status bar at bottom
     let status_bar_height = 20;
     let status_bar_y = canvas.height.saturating_sub(status_bar_height);
@@ -307,21 +382,165 @@ fn draw_sv_square_fast(canvas: &mut Canvas, x: u32, y: u32, w: u32, h: u32, hue:
         draw_button_text(canvas, 8, status_bar_y + 2, &status_text);
         
         // Get color under cursor if within bounds
-        if canvas_x < canvas.width && canvas_y < canvas.height {
-            if let Some(color) = canvas.get_pixel(canvas_x, canvas_y) {
-                let color_text = format!("RGB({},{},{})", color[0], color[1], color[2]);
-                let color_x = 200;
-                draw_button_text(canvas, color_x, status_bar_y + 2, &color_text);
+        if canvas_x < canvas.width && canvas_y < canvas.height
+            && let Some(color) = canvas.get_pixel(canvas_x, canvas_y)
+        {
+            let color_text = format!("RGB({},{},{})", color[0], color[1], color[2]);
+            let color_x = 200;
+            draw_button_text(canvas, color_x, status_bar_y + 2, &color_text);
                 
-                // Draw color swatch
-                let swatch_x = color_x + 80;
-                canvas.fill_rect(swatch_x, status_bar_y + 2, 12, 16, color);
+            // Draw color swatch
+            let swatch_x = color_x + 80;
+            canvas.fill_rect(swatch_x, status_bar_y + 2, 12, 16, color);
+        }
+    }
+}
+
+/// Rows of the layers panel that fit on screen: (path, depth, row y).
+/// The list sits below the color picker area and scrolls to keep the active row visible.
+fn layer_rows(canvas: &Canvas) -> Vec<(LayerPath, usize, u32)> {
+    // Same geometry as draw_ui: palette, size preview, then the 120px hue bar
+    let top = TOOLBAR_HEIGHT + 8 + 30 + 24 * 4 + 4 + 36 + 120 + 8;
+    let bottom = layer_buttons_y(canvas).saturating_sub(4);
+    let capacity = (bottom.saturating_sub(top) / LAYER_ROW_HEIGHT) as usize;
+    let outline = canvas.document.outline();
+    let active_row = outline.iter().position(|(p, _)| *p == canvas.document.active).unwrap_or(0);
+    let first = (active_row + 1).saturating_sub(capacity);
+    outline
+        .into_iter()
+        .skip(first)
+        .take(capacity)
+        .enumerate()
+        .map(|(i, (path, depth))| (path, depth, top + i as u32 * LAYER_ROW_HEIGHT))
+        .collect()
+}
+
+fn layer_buttons_y(canvas: &Canvas) -> u32 {
+    // Just above the 20px status bar
+    canvas.height.saturating_sub(20 + 4 + LAYER_BUTTON_SIZE)
+}
+
+fn layer_row_indent(depth: usize) -> u32 {
+    (depth as u32 * 6).min(24)
+}
+
+/// Layer row under a canvas position, if any
+fn layer_row_at(pos: (f32, f32), canvas: &Canvas) -> Option<(LayerPath, usize, u32)> {
+    if pos.0 < 8.0 || pos.0 >= (PANEL_WIDTH - 8) as f32 {
+        return None;
+    }
+    let y = pos.1 as u32;
+    layer_rows(canvas)
+        .into_iter()
+        .find(|(_, _, row_y)| y >= *row_y && y < row_y + LAYER_ROW_HEIGHT - 2)
+}
+
+fn draw_layers_panel(canvas: &mut Canvas, input: &InputState) {
+    let panel_x = 8;
+    let row_w = PANEL_WIDTH - 16;
+    let rows = layer_rows(canvas);
+    if let Some((_, _, first_y)) = rows.first() {
+        canvas.fill_rect(panel_x, first_y.saturating_sub(4), row_w, 1, [150, 150, 150, 255]);
+    }
+
+    let drop_target = match (&input.layer_dragging, input.last_pos) {
+        (Some(_), Some(pos)) => layer_row_at(pos, canvas).map(|(path, _, _)| path),
+        _ => None,
+    };
+
+    for (path, depth, y) in rows {
+        let Some(node) = canvas.document.node(&path) else {
+            continue;
+        };
+        let visible = node.visible();
+        let indent = layer_row_indent(depth);
+        let thumb_x = panel_x + 16 + indent;
+        let thumb_w = (panel_x + row_w - 10).saturating_sub(thumb_x + 2);
+        let thumb_h = LAYER_ROW_HEIGHT - 6;
+        let thumb = layer_thumbnail(node, (canvas.document.width, canvas.document.height), thumb_w, thumb_h);
+
+        let is_active = path == canvas.document.active;
+        let bg = if is_active { [100, 150, 255, 255] } else { [235, 235, 235, 255] };
+        canvas.fill_rect(panel_x, y, row_w, LAYER_ROW_HEIGHT - 2, bg);
+
+        // Visibility toggle: filled box when visible
+        let eye_x = panel_x + 2 + indent;
+        let eye_y = y + 3;
+        canvas.fill_rect(eye_x, eye_y, 10, 10, [30, 30, 30, 255]);
+        if !visible {
+            canvas.fill_rect(eye_x + 1, eye_y + 1, 8, 8, bg);
+        }
+
+        // Thumbnail of the layer contents
+        for ty in 0..thumb_h {
+            for tx in 0..thumb_w {
+                let color = thumb[(ty * thumb_w + tx) as usize];
+                canvas.fill_rect(thumb_x + tx, y + 2 + ty, 1, 1, color);
+            }
+        }
+
+        // Drag handle: three grip lines at the right edge
+        let handle_x = panel_x + row_w - 9;
+        for i in 0..3 {
+            canvas.fill_rect(handle_x, y + 4 + i * 3, 7, 1, [90, 90, 90, 255]);
+        }
+
+        if drop_target.as_ref() == Some(&path) {
+            canvas.fill_rect(panel_x, y, row_w, 2, [30, 30, 30, 255]);
+        }
+    }
+
+    // Add / duplicate / delete / merge down
+    let by = layer_buttons_y(canvas);
+    let white = [255, 255, 255, 255];
+    let grey = [100, 100, 100, 255];
+    for i in 0..4 {
+        let bx = panel_x + i * (LAYER_BUTTON_SIZE + 2);
+        canvas.fill_rect(bx, by, LAYER_BUTTON_SIZE, LAYER_BUTTON_SIZE, grey);
+        match i {
+            0 => {
+                canvas.fill_rect(bx + 3, by + 7, 10, 2, white);
+                canvas.fill_rect(bx + 7, by + 3, 2, 10, white);
+            }
+            1 => {
+                canvas.fill_rect(bx + 3, by + 3, 7, 7, white);
+                canvas.fill_rect(bx + 6, by + 6, 7, 7, grey);
+                canvas.fill_rect(bx + 7, by + 7, 5, 5, white);
+            }
+            2 => draw_char(canvas, bx + 6, by + 5, 'X', white),
+            _ => {
+                canvas.fill_rect(bx + 7, by + 3, 2, 6, white);
+                canvas.fill_rect(bx + 4, by + 9, 8, 1, white);
+                canvas.fill_rect(bx + 5, by + 10, 6, 1, white);
+                canvas.fill_rect(bx + 6, by + 11, 4, 1, white);
+                canvas.fill_rect(bx + 3, by + 13, 10, 1, white);
             }
         }
     }
 }
 
-fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
+/// Nearest-neighbour preview of the part of a node on an `image` sized canvas,
+/// over a light backdrop; groups show as a folder color
+fn layer_thumbnail(node: &layer::LayerNode, image: (u32, u32), w: u32, h: u32) -> Vec<[u8; 4]> {
+    let mut out = vec![[200, 200, 200, 255]; (w * h) as usize];
+    let layer::LayerNode::Layer(layer) = node else {
+        out.fill([210, 180, 110, 255]);
+        return out;
+    };
+    if image.0 == 0 || image.1 == 0 {
+        return out;
+    }
+    for ty in 0..h {
+        for tx in 0..w {
+            let sx = tx * image.0 / w;
+            let sy = ty * image.1 / h;
+            blend::blend_over(&mut out[(ty * w + tx) as usize], layer.pixel_at(sx as i32, sy as i32));
+        }
+    }
+    out
+}
+
+fn panel_hit_test(pos: (f32, f32), canvas: &Canvas, input: &InputState) -> Option<PanelAction> {
     if pos.0 < 0.0 || pos.1 < 0.0 {
         return None;
     }
@@ -331,8 +550,8 @@ fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
     // Toolbar hit test
     if y < TOOLBAR_HEIGHT {
         // Tools
-        let tool_gap = 8;
-        let tool_size = 48;
+        let tool_gap = 4;
+        let tool_size = 40;
         let mut tool_x = 8;
         let tool_y = 8;
         
@@ -343,6 +562,13 @@ fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
             input::Tool::ColorPicker,
             input::Tool::Move,
             input::Tool::Blur,
+            input::Tool::RectSelect,
+            input::Tool::EllipseSelect,
+            input::Tool::LassoSelect,
+            input::Tool::PolygonSelect,
+            input::Tool::MagicWand,
+            input::Tool::Crop,
+            input::Tool::Transform,
         ];
         
         for tool in &tools {
@@ -371,10 +597,37 @@ fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
         let slider_x = 8;
         let slider_w = 280u32;
         if y >= slider_y && y < slider_y + 6 && x >= slider_x && x < slider_x + slider_w {
+            if input.current_tool == input::Tool::MagicWand {
+                return Some(PanelAction::WandTolerance(tolerance_value_from_x(x as f32)));
+            }
             let t = (x.saturating_sub(slider_x)) as f32 / slider_w as f32;
             let value = BRUSH_RADIUS_MIN + t * (BRUSH_RADIUS_MAX - BRUSH_RADIUS_MIN);
             return Some(PanelAction::SizeValue(value));
         }
+
+        // Brush hardness, opacity and flow sliders
+        if input.current_tool == input::Tool::Brush && y + 2 >= slider_y && y < slider_y + 8 {
+            let value = |slider_x| brush_param_from_x(x as f32, slider_x);
+            if (BRUSH_HARDNESS_X..BRUSH_HARDNESS_X + BRUSH_SLIDER_W).contains(&x) {
+                return Some(PanelAction::BrushHardness(value(BRUSH_HARDNESS_X)));
+            }
+            if (BRUSH_OPACITY_X..BRUSH_OPACITY_X + BRUSH_SLIDER_W).contains(&x) {
+                return Some(PanelAction::BrushOpacity(value(BRUSH_OPACITY_X)));
+            }
+            if (BRUSH_FLOW_X..BRUSH_FLOW_X + BRUSH_SLIDER_W).contains(&x) {
+                return Some(PanelAction::BrushFlow(value(BRUSH_FLOW_X)));
+            }
+        }
+
+        // Magic wand toggles
+        if input.current_tool == input::Tool::MagicWand && y + 2 >= slider_y && y < slider_y + 8 {
+            if (WAND_GLOBAL_X..WAND_GLOBAL_X + WAND_TOGGLE_SIZE).contains(&x) {
+                return Some(PanelAction::WandToggleGlobal);
+            }
+            if (WAND_MERGED_X..WAND_MERGED_X + WAND_TOGGLE_SIZE).contains(&x) {
+                return Some(PanelAction::WandToggleMerged);
+            }
+        }
         
         return None;
     }
@@ -407,6 +660,31 @@ fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
         }
     }
 
+    // Layers panel: buttons row, then the layer rows
+    let by = layer_buttons_y(canvas);
+    if y >= by && y < by + LAYER_BUTTON_SIZE && x >= panel_x {
+        let slot = (x - panel_x) / (LAYER_BUTTON_SIZE + 2);
+        if (x - panel_x) % (LAYER_BUTTON_SIZE + 2) < LAYER_BUTTON_SIZE {
+            match slot {
+                0 => return Some(PanelAction::LayerAdd),
+                1 => return Some(PanelAction::LayerDuplicate),
+                2 => return Some(PanelAction::LayerDelete),
+                3 => return Some(PanelAction::LayerMergeDown),
+                _ => {}
+            }
+        }
+    }
+    if let Some((path, depth, _)) = layer_row_at(pos, canvas) {
+        let eye_end = panel_x + 2 + layer_row_indent(depth) + 12;
+        let handle_x = panel_x + (PANEL_WIDTH - 16) - 10;
+        if x < eye_end {
+            return Some(PanelAction::LayerToggleVisible(path));
+        } else if x >= handle_x {
+            return Some(PanelAction::LayerDragStart(path));
+        }
+        return Some(PanelAction::LayerSelect(path));
+    }
+
     // Color picker interactions
     // Geometry mirrored from draw_ui() EXACTLY
     // Palette rows: start at panel_y + 30, 4 rows, each adds 24
@@ -434,6 +712,163 @@ fn panel_hit_test(pos: (f32, f32), canvas: &Canvas) -> Option<PanelAction> {
     None
 }
 
+/// Image pixel under a canvas position, clamped to the top-left image corner
+fn image_pixel_at(canvas: &Canvas, pos: (f32, f32)) -> (u32, u32) {
+    let (x, y) = canvas.canvas_to_image(pos.0, pos.1);
+    (x.floor().max(0.0) as u32, y.floor().max(0.0) as u32)
+}
+
+/// Shape drawn so far by the active selection tool; `hover` is the image-space
+/// cursor position, used as the next polygon vertex while placing it
+fn selection_shape(input: &InputState, canvas: &Canvas, hover: Option<(f32, f32)>) -> Option<Selection> {
+    let (width, height) = (canvas.document.width, canvas.document.height);
+    match input.current_tool {
+        input::Tool::RectSelect => Some(Selection::rect(width, height, input.selection_start?, input.selection_end?)),
+        input::Tool::EllipseSelect => Some(Selection::ellipse(width, height, input.selection_start?, input.selection_end?)),
+        input::Tool::LassoSelect | input::Tool::PolygonSelect => {
+            let mut points = input.selection_points.clone();
+            points.extend(hover);
+            (points.len() >= 3).then(|| Selection::polygon(width, height, &points))
+        }
+        _ => None,
+    }
+}
+
+/// Show the selection the shape being drawn would produce
+fn preview_selection(input: &InputState, canvas: &mut Canvas, hover: Option<(f32, f32)>) {
+    canvas.document.selection = match selection_shape(input, canvas, hover) {
+        Some(shape) => selection::combine(input.selection_base.as_ref(), shape, input.selection_mode),
+        None => input.selection_base.clone(),
+    };
+    canvas.render_view();
+}
+
+/// Apply the shape being drawn as one undo step. A click that drew no shape
+/// deselects everything in replace mode and changes nothing otherwise.
+fn finish_selection(input: &mut InputState, canvas: &mut Canvas, history: &mut History) {
+    let drawn = match input.current_tool {
+        input::Tool::RectSelect | input::Tool::EllipseSelect => input.selection_start != input.selection_end,
+        _ => input.selection_points.len() >= 3,
+    };
+    if drawn {
+        preview_selection(input, canvas, None);
+    } else if input.selection_mode == SelectionMode::Replace {
+        canvas.document.selection = None;
+    } else {
+        canvas.document.selection = input.selection_base.clone();
+    }
+    canvas.render_view();
+    if canvas.document.selection != input.selection_base {
+        history.push(canvas);
+    }
+    input.end_selection();
+}
+
+/// Run a Select menu operation on the current selection as one undo step;
+/// a result that covers nothing clears the selection
+fn modify_selection(canvas: &mut Canvas, history: &mut History, name: &str, op: impl FnOnce(&mut Selection)) {
+    let Some(mut selection) = canvas.document.selection.take() else {
+        println!("Nothing selected");
+        return;
+    };
+    op(&mut selection);
+    canvas.document.selection = (!selection.is_empty()).then_some(selection);
+    canvas.render_view();
+    history.push(canvas);
+    println!("Select: {}", name);
+}
+
+/// Drop the shape being drawn and put back the selection it started from
+fn cancel_selection(input: &mut InputState, canvas: &mut Canvas) {
+    if input.selection_start.is_none() && input.selection_points.is_empty() {
+        return;
+    }
+    canvas.document.selection = input.selection_base.clone();
+    canvas.render_view();
+    input.end_selection();
+}
+
+/// Image > Canvas Size by a factor, around the chosen anchor and with the
+/// chosen fill, as one undo step
+fn resize_canvas_by(input: &mut InputState, canvas: &mut Canvas, history: &mut History, factor: f32, resize_layers: bool) {
+    cancel_selection(input, canvas);
+    let width = (canvas.document.width as f32 * factor).round().max(1.0) as u32;
+    let height = (canvas.document.height as f32 * factor).round().max(1.0) as u32;
+    let fill = input.canvas_fill_color();
+    if canvas.resize_canvas(width, height, input.canvas_anchor, fill, resize_layers) {
+        history.push(canvas);
+        println!("Canvas size: {}x{}{}", width, height, if resize_layers { "" } else { " (canvas only)" });
+    }
+}
+
+/// Canvas anchor for a numpad key, laid out like the keypad (7 is top left)
+fn numpad_anchor(code: KeyCode) -> Option<Anchor> {
+    let index = match code {
+        KeyCode::Numpad1 => 0,
+        KeyCode::Numpad2 => 1,
+        KeyCode::Numpad3 => 2,
+        KeyCode::Numpad4 => 3,
+        KeyCode::Numpad5 => 4,
+        KeyCode::Numpad6 => 5,
+        KeyCode::Numpad7 => 6,
+        KeyCode::Numpad8 => 7,
+        KeyCode::Numpad9 => 8,
+        _ => return None,
+    };
+    Some(Anchor { column: index % 3, row: 2 - index / 3 })
+}
+
+/// Image > Scale Image by a factor with the chosen filter, as one undo step.
+/// With the aspect ratio unlocked only the width (or with `vertical` the height) changes.
+fn scale_image_by(input: &mut InputState, canvas: &mut Canvas, history: &mut History, factor: f32, vertical: bool) {
+    cancel_selection(input, canvas);
+    let scaled = |v: u32| (v as f32 * factor).round().max(1.0) as u32;
+    let (mut width, mut height) = (canvas.document.width, canvas.document.height);
+    if input.scale_lock_aspect || !vertical {
+        width = scaled(width);
+    }
+    if input.scale_lock_aspect || vertical {
+        height = scaled(height);
+    }
+    if canvas.scale_image(width, height, input.scale_filter) {
+        history.push(canvas);
+        println!("Scaled to {}x{} ({:?})", width, height, input.scale_filter);
+    }
+}
+
+/// Resample the transform tool's box once and record it as one undo step.
+/// Nearest and the slower filters fall back to bicubic.
+fn commit_free_transform(input: &mut InputState, canvas: &mut Canvas, history: &mut History) {
+    input.transform_drag = None;
+    let filter = if input.scale_filter == Filter::Bilinear { Filter::Bilinear } else { Filter::Bicubic };
+    if canvas.commit_free_transform(filter) {
+        history.push(canvas);
+        println!("Transform applied ({:?})", filter);
+    }
+}
+
+/// Rotate or mirror the whole image (Alt held), or else the selection, the
+/// floating layer or the active layer, as one undo step
+fn apply_transform(input: &mut InputState, canvas: &mut Canvas, history: &mut History, transform: Transform) {
+    cancel_selection(input, canvas);
+    if input.alt_pressed {
+        canvas.transform_image(transform);
+        println!("Image: {:?}", transform);
+    } else if canvas.document.transform_active(transform) {
+        canvas.refresh();
+        println!("Layer: {:?}", transform);
+    } else {
+        return;
+    }
+    history.push(canvas);
+}
+
+/// Arbitrary-angle rotation using the current interpolation and expand options
+fn rotate_by(input: &mut InputState, canvas: &mut Canvas, history: &mut History, degrees: f32) {
+    let transform = Transform::Angle { degrees, filter: input.scale_filter, expand: input.rotate_expand };
+    apply_transform(input, canvas, history, transform);
+}
+
 fn size_value_from_x(x: f32) -> f32 {
     // Map canvas X to slider percentage using actual slider geometry
     let slider_x = 8.0;
@@ -442,6 +877,19 @@ fn size_value_from_x(x: f32) -> f32 {
     BRUSH_RADIUS_MIN + t * (BRUSH_RADIUS_MAX - BRUSH_RADIUS_MIN)
 }
 
+fn tolerance_value_from_x(x: f32) -> u8 {
+    // Same slider geometry as the brush size, mapped to 0..=255
+    let slider_x = 8.0;
+    let slider_w = 280.0;
+    let t = ((x - slider_x) / slider_w).clamp(0.0, 1.0);
+    (t * 255.0).round() as u8
+}
+
+fn brush_param_from_x(x: f32, slider_x: u32) -> f32 {
+    // Hardness, opacity and flow all run 0..=1 along their own short slider
+    ((x - slider_x as f32) / BRUSH_SLIDER_W as f32).clamp(0.0, 1.0)
+}
+
 fn brightness_value_from_x(x: f32) -> f32 {
     // Map canvas X to brightness using same slider geometry for consistency
     let slider_x = 8.0;
@@ -450,6 +898,13 @@ fn brightness_value_from_x(x: f32) -> f32 {
     BRIGHT_MIN + t * (BRIGHT_MAX - BRIGHT_MIN)
 }
 
+fn print_active_layer(canvas: &Canvas) {
+    if let Some(node) = canvas.document.active_node() {
+        let depth = canvas.document.active.len() - 1;
+        println!("Layer: {}{}", "  ".repeat(depth), node.name());
+    }
+}
+
 fn draw_icon(canvas: &mut Canvas, icon: &crate::icons::Icon, x: u32, y: u32, size: u32) {
     if icon.pixels.is_empty() || icon.width == 0 || icon.height == 0 {
         return;
@@ -536,6 +991,16 @@ fn draw_char(canvas: &mut Canvas, x: u32, y: u32, ch: char, color: [u8; 4]) {
             canvas.fill_rect(x + 3, y + 1, 1, 1, color);
             canvas.fill_rect(x + 1, y + 2, 3, 1, color);
         }
+        'H' | 'h' => {
+            canvas.fill_rect(x, y, 1, 6, color);
+            canvas.fill_rect(x + 3, y, 1, 6, color);
+            canvas.fill_rect(x + 1, y + 3, 2, 1, color);
+        }
+        'F' | 'f' => {
+            canvas.fill_rect(x, y, 4, 1, color);
+            canvas.fill_rect(x, y + 2, 3, 1, color);
+            canvas.fill_rect(x, y, 1, 6, color);
+        }
         _ => {}
     }
 }
@@ -543,31 +1008,37 @@ fn draw_char(canvas: &mut Canvas, x: u32, y: u32, ch: char, color: [u8; 4]) {
 enum PanelAction {
     Color(u8),
     SizeValue(f32),
-    CanvasSmaller,
-    CanvasLarger,
+    WandTolerance(u8),
+    BrushHardness(f32),
+    BrushOpacity(f32),
+    BrushFlow(f32),
+    WandToggleGlobal,
+    WandToggleMerged,
     FileImport,
     FileExport,
     FileSave,
-    FileOpen,
     Tool(input::Tool),
     FilterInvert,
     FilterGrayscale,
     FilterBrightness,
     RemoveGrayscale,
     RemoveBrightness,
-    FilterBlur,
-    ToggleColorPicker,
     OpenColorPickerForeground,
     OpenColorPickerBackground,
     PickerHue(f32),
     PickerSV(f32, f32),
+    LayerSelect(LayerPath),
+    LayerToggleVisible(LayerPath),
+    LayerDragStart(LayerPath),
+    LayerAdd,
+    LayerDuplicate,
+    LayerDelete,
+    LayerMergeDown,
 }
 
 fn handle_panel_action(
     action: PanelAction,
     input: &mut InputState,
-    window_size: &mut PhysicalSize<u32>,
-    gpu: &mut Gpu,
     canvas: &mut Canvas,
     window: &winit::window::Window,
     history: &mut History,
@@ -583,33 +1054,35 @@ fn handle_panel_action(
             }
         }
         PanelAction::SizeValue(v) => input.set_brush_radius(v, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
-        // No filter intensity sliders; filters are applied from toolbar buttons
-        PanelAction::CanvasSmaller => {
-            let new_w = (window_size.width.max(1) as f32 * 0.75).round() as u32;
-            let new_h = (window_size.height.max(1) as f32 * 0.75).round() as u32;
-            *window_size = PhysicalSize::new(new_w.max(1), new_h.max(1));
-            gpu.resize(*window_size);
-            *canvas = Canvas::new(window_size.width.max(1), window_size.height.max(1));
+        PanelAction::BrushHardness(v) => input.brush.hardness = v,
+        PanelAction::BrushOpacity(v) => input.brush.opacity = v,
+        PanelAction::BrushFlow(v) => input.brush.flow = v,
+        PanelAction::WandTolerance(v) => {
+            input.wand.tolerance = v;
+            window.request_redraw();
+        }
+        PanelAction::WandToggleGlobal => {
+            input.wand.contiguous = !input.wand.contiguous;
+            println!("Magic wand: {}", if input.wand.contiguous { "contiguous region" } else { "select by color" });
             window.request_redraw();
         }
-        PanelAction::CanvasLarger => {
-            let new_w = (window_size.width.max(1) as f32 * 1.25).round() as u32;
-            let new_h = (window_size.height.max(1) as f32 * 1.25).round() as u32;
-            *window_size = PhysicalSize::new(new_w.max(1), new_h.max(1));
-            gpu.resize(*window_size);
-            *canvas = Canvas::new(window_size.width.max(1), window_size.height.max(1));
+        PanelAction::WandToggleMerged => {
+            input.wand.sample_merged = !input.wand.sample_merged;
+            println!("Magic wand samples: {}", if input.wand.sample_merged { "merged image" } else { "active layer" });
             window.request_redraw();
         }
+        // No filter intensity sliders; filters are applied from toolbar buttons
         // No brightness slider action; brightness filter is applied via toolbar button
         PanelAction::FileImport => {
             match io::select_image_file() {
                 Ok(path) => {
                     match io::load_image(&path) {
                         Ok(img_layer) => {
-                            canvas.pan_offset = (0, 0);
-                            canvas.paste_image(img_layer.width, img_layer.height, &img_layer.pixels);
+                            let (width, height) = (img_layer.width, img_layer.height);
+                            canvas.import_image(img_layer);
+                            history.push(canvas);
                             window.request_redraw();
-                            println!("✓ Imported ({}x{}) - Use arrow keys to pan", img_layer.width, img_layer.height);
+                            println!("✓ Imported ({}x{}) - Use arrow keys to pan", width, height);
                         }
                         Err(e) => eprintln!("✗ Import failed: {}", e),
                     }
@@ -631,23 +1104,15 @@ fn handle_panel_action(
         PanelAction::FileSave => {
             match io::select_save_project_folder() {
                 Ok(path) => {
-                    let image_pixels = canvas.extract_image_pixels();
-                    let (width, height) = canvas.loaded_image_size.unwrap_or((canvas.width, canvas.height));
-                    let layer = layer::Layer::from_rgba(
-                        "canvas".to_string(),
-                        width,
-                        height,
-                        image_pixels,
-                    );
                     let project_name = std::path::Path::new(&path)
                         .file_name()
                         .and_then(|n| n.to_str())
                         .unwrap_or("Project")
                         .to_string();
-                    let mut project = layer::Project::new(project_name, width, height);
-                    project.add_layer_metadata("canvas".to_string(), "layer_000.png".to_string());
-                    
-                    match io::save_project(&project, &[layer], &path) {
+                    let project = canvas.document.project(project_name);
+
+                    let layers: Vec<_> = canvas.document.leaf_layers().into_iter().cloned().collect();
+                    match io::save_project(&project, &layers, &path) {
                         Ok(_) => println!("✓ Saved"),
                         Err(e) => eprintln!("✗ Save failed: {}", e),
                     }
@@ -655,26 +1120,12 @@ fn handle_panel_action(
                 Err(e) => eprintln!("✗ {}", e),
             }
         }
-        PanelAction::FileOpen => {
-            match io::select_load_project_folder() {
-                Ok(path) => {
-                    match io::load_project(&path) {
-                        Ok((project, layers)) => {
-                            if !layers.is_empty() && layers[0].width == canvas.width && layers[0].height == canvas.height {
-                                canvas.load_pixels(layers[0].width, layers[0].height, layers[0].pixels.clone());
-                                window.request_redraw();
-                                println!("✓ Loaded: {}", project.name);
-                            } else {
-                                eprintln!("✗ Size mismatch");
-                            }
-                        }
-                        Err(e) => eprintln!("✗ Load failed: {}", e),
-                    }
-                }
-                Err(e) => eprintln!("✗ {}", e),
-            }
-        }
         PanelAction::Tool(tool) => {
+            cancel_selection(input, canvas);
+            commit_free_transform(input, canvas, history);
+            if canvas.crop_preview.take().is_some() {
+                canvas.render_view();
+            }
             input.current_tool = tool;
             println!("Tool: {:?}", tool);
             window.request_redraw();
@@ -687,37 +1138,31 @@ fn handle_panel_action(
         }
         PanelAction::FilterGrayscale => {
             canvas.filter_grayscale();
-            history.push(canvas);
+            history.push_filter(canvas, FilterStep::Grayscale);
             window.request_redraw();
             println!("✓ Applied Grayscale filter");
         }
         PanelAction::FilterBrightness => {
             canvas.filter_brightness_contrast(30.0, 20.0);
-            history.push(canvas);
+            history.push_filter(canvas, FilterStep::Brightness);
             window.request_redraw();
             println!("✓ Applied Brightness filter");
         }
         PanelAction::RemoveBrightness => {
-            canvas.remove_brightness();
-            history.push(canvas);
-            window.request_redraw();
-            println!("✓ Removed Brightness");
+            if history.remove_filter(canvas, FilterStep::Brightness) {
+                window.request_redraw();
+                println!("✓ Removed Brightness");
+            } else {
+                println!("✗ Brightness was not the last step");
+            }
         }
         PanelAction::RemoveGrayscale => {
-            canvas.remove_grayscale();
-            history.push(canvas);
-            window.request_redraw();
-            println!("✓ Removed Grayscale");
-        }
-        PanelAction::FilterBlur => {
-            canvas.filter_blur(2);
-            history.push(canvas);
-            window.request_redraw();
-            println!("✓ Applied Blur filter");
-        }
-        PanelAction::ToggleColorPicker => {
-            input.toggle_color_picker();
-            window.request_redraw();
+            if history.remove_filter(canvas, FilterStep::Grayscale) {
+                window.request_redraw();
+                println!("✓ Removed Grayscale");
+            } else {
+                println!("✗ Grayscale was not the last step");
+            }
         }
         PanelAction::OpenColorPickerForeground => {
             input.open_color_picker_foreground();
@@ -735,6 +1180,55 @@ fn handle_panel_action(
             input.set_hsv(input.hue, s, v);
             window.request_redraw();
         }
+        PanelAction::LayerSelect(path) => {
+            canvas.document.select(path);
+            window.request_redraw();
+            print_active_layer(canvas);
+        }
+        PanelAction::LayerToggleVisible(path) => {
+            if let Some(node) = canvas.document.node_mut(&path) {
+                node.set_visible(!node.visible());
+            }
+            canvas.refresh();
+            history.push(canvas);
+            window.request_redraw();
+        }
+        PanelAction::LayerDragStart(path) => {
+            canvas.document.select(path.clone());
+            input.layer_dragging = Some(path);
+            window.request_redraw();
+        }
+        PanelAction::LayerAdd => {
+            canvas.document.add_layer();
+            canvas.refresh();
+            history.push(canvas);
+            window.request_redraw();
+            print_active_layer(canvas);
+        }
+        PanelAction::LayerDuplicate => {
+            canvas.document.duplicate_active();
+            canvas.refresh();
+            history.push(canvas);
+            window.request_redraw();
+            print_active_layer(canvas);
+        }
+        PanelAction::LayerDelete => {
+            canvas.document.delete_active();
+            canvas.refresh();
+            history.push(canvas);
+            window.request_redraw();
+            print_active_layer(canvas);
+        }
+        PanelAction::LayerMergeDown => {
+            if canvas.document.merge_down() {
+                canvas.refresh();
+                history.push(canvas);
+                window.request_redraw();
+                print_active_layer(canvas);
+            } else {
+                println!("✗ Merge Down needs a visible layer above a pixel layer");
+            }
+        }
     }
 }
 
@@ -746,10 +1240,7 @@ fn main() {
     let mut window_size: PhysicalSize<u32> = PhysicalSize::new(0, 0);
     let mut window: Option<Arc<winit::window::Window>> = None;
     let mut canvas: Option<Canvas> = None;
-    let mut input = InputState::new(Brush {
-        radius: BRUSH_RADIUS,
-        color: BRUSH_COLOR,
-    });
+    let mut input = InputState::new(Brush::new(BRUSH_RADIUS, BRUSH_COLOR));
     
     // Load icons at startup
     let icons = crate::icons::IconCache::load();
@@ -757,511 +1248,1100 @@ fn main() {
     // Initialize history
     let mut history = History::new();
 
+    // Autosave session; created once the window exists
+    let mut session: Option<recovery::Session> = None;
+
+    // In-app clipboard filled by copy/cut and pasted as a floating layer
+    let mut clipboard: Option<FloatingLayer> = None;
+
+    // Last time the selection outline was advanced
+    let mut last_ants_step = Instant::now();
+
+    #[allow(deprecated)]
     event_loop
         .run(move |event, elwt| match event {
-            Event::Resumed => {
-                if gpu.is_none() {
-                    let attrs = WindowAttributes::default()
-                        .with_title("Pixel Editor")
-                        .with_inner_size(LogicalSize::new(800.0, 600.0));
-                    let w = Arc::new(elwt.create_window(attrs).unwrap());
-                    let (g, s) = pollster::block_on(Gpu::new(&w));
-                    window_size = s;
-                    canvas = Some(Canvas::new(s.width.max(1), s.height.max(1)));
-                    window = Some(w);
-                    gpu = Some(g);
+            Event::Resumed if gpu.is_none() => {
+                let attrs = WindowAttributes::default()
+                    .with_title("Pixel Editor")
+                    .with_inner_size(LogicalSize::new(880.0, 600.0));
+                let w = Arc::new(elwt.create_window(attrs).unwrap());
+                let (g, s) = pollster::block_on(Gpu::new(&w));
+                window_size = s;
+                let mut c = Canvas::new(s.width.max(1), s.height.max(1));
+                history.push(&c);
+
+                // Offer to restore work from sessions that did not exit cleanly
+                let root = recovery::recovery_root();
+                let mut restored_from = None;
+                for dir in recovery::crashed_sessions(&root) {
+                    let restore = io::ask_yes_no(
+                        "Restore unsaved work?",
+                        "The editor did not exit cleanly last time. Restore the autosaved document? Choosing No discards it.",
+                    );
+                    if !restore {
+                        recovery::discard_session(&dir);
+                        continue;
+                    }
+                    match recovery::load_session(&dir) {
+                        Ok(recovered) => {
+                            history = History::from_documents(recovered.documents, recovered.position);
+                            if let Some(document) = history.current_document() {
+                                c.set_document(document.clone());
+                            }
+                            println!("✓ Restored autosave from {}", dir.display());
+                            restored_from = Some(dir);
+                            break;
+                        }
+                        Err(e) => eprintln!("✗ Could not restore {}: {}", dir.display(), e),
+                    }
+                }
+                match recovery::Session::start(&root) {
+                    Ok(mut started) => {
+                        if let Some(dir) = restored_from {
+                            started.retire_after_next_save(dir);
+                        }
+                        session = Some(started);
+                    }
+                    Err(e) => eprintln!("✗ Autosave disabled: {}", e),
                 }
+                canvas = Some(c);
+                window = Some(w);
+                gpu = Some(g);
             }
 
             Event::WindowEvent { event, window_id } => {
-                if let (Some(g), Some(w), Some(c)) = (gpu.as_mut(), window.as_ref(), canvas.as_mut()) {
-                    if window_id == w.id() {
-                        match event {
-                            WindowEvent::CloseRequested => elwt.exit(),
-                            WindowEvent::Resized(new_size) => {
-                                window_size = new_size;
-                                g.resize(new_size);
-                                
-                                // Preserve old canvas pixels when resizing
-                                let old_pixels = c.pixels.clone();
-                                let old_width = c.width;
-                                let old_height = c.height;
-                                let old_stride = c.stride;
-                                
-                                // Create new canvas
-                                *c = Canvas::new(new_size.width.max(1), new_size.height.max(1));
+                if let (Some(g), Some(w), Some(c)) = (gpu.as_mut(), window.as_ref(), canvas.as_mut())
+                    && window_id == w.id()
+                {
+                    match event {
+                        WindowEvent::CloseRequested => {
+                            // A clean exit needs no recovery
+                            if let Some(s) = session.take() {
+                                s.finish();
+                            }
+                            elwt.exit();
+                        }
+                        WindowEvent::Resized(new_size) => {
+                            window_size = new_size;
+                            g.resize(new_size);
+
+                            // Keep the document, only reallocate the display buffer
+                            c.resize_view(new_size.width.max(1), new_size.height.max(1));
                                 
-                                // Copy old pixels to new canvas (preserve what fits)
-                                let copy_width = old_width.min(c.width);
-                                let copy_height = old_height.min(c.height);
-                                for y in 0..copy_height {
-                                    let old_row_offset = y as usize * old_stride;
-                                    let new_row_offset = y as usize * c.stride;
-                                    let row_bytes = copy_width as usize * 4;
-                                    
-                                    if old_row_offset + row_bytes <= old_pixels.len() 
-                                        && new_row_offset + row_bytes <= c.pixels.len() {
-                                        c.pixels[new_row_offset..new_row_offset + row_bytes]
-                                            .copy_from_slice(&old_pixels[old_row_offset..old_row_offset + row_bytes]);
+                            w.request_redraw();
+                        }
+                        WindowEvent::KeyboardInput { event, .. } => {
+                            // Track modifier keys
+                            if let PhysicalKey::Code(code) = event.physical_key {
+                                match code {
+                                    KeyCode::ShiftLeft | KeyCode::ShiftRight => {
+                                        input.shift_pressed = event.state == ElementState::Pressed;
+                                    }
+                                    KeyCode::ControlLeft | KeyCode::ControlRight => {
+                                        input.ctrl_pressed = event.state == ElementState::Pressed;
                                     }
+                                    KeyCode::AltLeft | KeyCode::AltRight => {
+                                        input.alt_pressed = event.state == ElementState::Pressed;
+                                    }
+                                    KeyCode::Space => {
+                                        input.space_pressed = event.state == ElementState::Pressed;
+                                    }
+                                    _ => {}
                                 }
-                                c.dirty = true;
-                                
-                                w.request_redraw();
                             }
-                            WindowEvent::KeyboardInput { event, .. } => {
-                                // Track modifier keys
-                                if let PhysicalKey::Code(code) = event.physical_key {
-                                    match code {
-                                        KeyCode::ShiftLeft | KeyCode::ShiftRight => {
-                                            input.shift_pressed = event.state == ElementState::Pressed;
+                                
+                            let shift_pressed = input.shift_pressed;
+                            let ctrl_pressed = input.ctrl_pressed;
+                            if event.state == ElementState::Pressed
+                                && let PhysicalKey::Code(code) = event.physical_key
+                            {
+                                match code {
+                                    // Image > Scale Image
+                                    KeyCode::Equal if ctrl_pressed => {
+                                        // Ctrl+=: Scale the image up (Ctrl+Shift+=: height only when the aspect is unlocked)
+                                        scale_image_by(&mut input, c, &mut history, SCALE_STEP, shift_pressed);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Minus if ctrl_pressed => {
+                                        // Ctrl+-: Scale the image down (Ctrl+Shift+-: height only when the aspect is unlocked)
+                                        scale_image_by(&mut input, c, &mut history, 1.0 / SCALE_STEP, shift_pressed);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyI if !ctrl_pressed => {
+                                        // I: Cycle the scale interpolation
+                                        input.scale_filter = input.scale_filter.next();
+                                        println!("Interpolation: {:?}", input.scale_filter);
+                                    }
+                                    KeyCode::KeyK if !ctrl_pressed => {
+                                        // K: Lock or unlock the aspect ratio for scaling
+                                        input.scale_lock_aspect = !input.scale_lock_aspect;
+                                        println!("Keep aspect ratio: {}", if input.scale_lock_aspect { "on" } else { "off" });
+                                    }
+                                    // Image / Layer > Transform (Alt applies to the whole image)
+                                    KeyCode::KeyR if !ctrl_pressed => {
+                                        // R: Rotate 90° clockwise (Shift+R: counter-clockwise)
+                                        let rotation = if shift_pressed { Rotation::Ccw90 } else { Rotation::Cw90 };
+                                        apply_transform(&mut input, c, &mut history, Transform::Rotate(rotation));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyU if !ctrl_pressed => {
+                                        // U: Rotate 180°
+                                        apply_transform(&mut input, c, &mut history, Transform::Rotate(Rotation::Half));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyH if !ctrl_pressed => {
+                                        // H: Flip horizontally (Shift+H: vertically)
+                                        let flip = if shift_pressed { Flip::Vertical } else { Flip::Horizontal };
+                                        apply_transform(&mut input, c, &mut history, Transform::Flip(flip));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Period if !ctrl_pressed => {
+                                        // .: Rotate clockwise by the rotate step
+                                        rotate_by(&mut input, c, &mut history, ROTATE_STEP);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Comma if !ctrl_pressed => {
+                                        // ,: Rotate counter-clockwise by the rotate step
+                                        rotate_by(&mut input, c, &mut history, -ROTATE_STEP);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyN if !ctrl_pressed => {
+                                        // N: Toggle pencil mode (aliased brush edges for pixel art)
+                                        input.brush.pencil = !input.brush.pencil;
+                                        println!("Brush: {}", if input.brush.pencil { "pencil" } else { "anti-aliased" });
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyE if !ctrl_pressed => {
+                                        // E: Toggle growing the canvas to fit arbitrary rotations
+                                        input.rotate_expand = !input.rotate_expand;
+                                        println!("Rotate expands canvas: {}", if input.rotate_expand { "on" } else { "off" });
+                                    }
+                                    // Check zoom first (with shift modifier)
+                                    KeyCode::PageUp | KeyCode::Equal if shift_pressed => {
+                                        // Zoom in (Shift+= or Shift+Page Up)
+                                        c.zoom_scale = (c.zoom_scale * 1.25).min(5.0);
+                                        c.repan_image(c.pan_offset.0, c.pan_offset.1);
+                                        w.request_redraw();
+                                        println!("Zoom: {:.0}%", c.zoom_scale * 100.0);
+                                    }
+                                    KeyCode::PageDown | KeyCode::Minus if shift_pressed => {
+                                        // Zoom out (Shift+- or Shift+Page Down)
+                                        c.zoom_scale = (c.zoom_scale / 1.25).max(0.1);
+                                        c.repan_image(c.pan_offset.0, c.pan_offset.1);
+                                        w.request_redraw();
+                                        println!("Zoom: {:.0}%", c.zoom_scale * 100.0);
+                                    }
+                                    KeyCode::Digit0 if shift_pressed => {
+                                        // Reset zoom to 100% (Shift+0)
+                                        c.zoom_scale = 1.0;
+                                        c.repan_image(0, 0);
+                                        w.request_redraw();
+                                        println!("Zoom: 100%");
+                                    }
+                                    KeyCode::Enter | KeyCode::NumpadEnter if !input.selection_points.is_empty() => {
+                                        // Close the polygon being placed
+                                        finish_selection(&mut input, c, &mut history);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Enter | KeyCode::NumpadEnter if c.free_transform.is_some() => {
+                                        // Enter: Apply the transform tool's box
+                                        commit_free_transform(&mut input, c, &mut history);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Enter | KeyCode::NumpadEnter if c.crop_preview.is_some() => {
+                                        // Enter: Crop the image to the crop tool rectangle
+                                        if let Some(rect) = c.crop_preview {
+                                            if c.crop(rect) {
+                                                history.push(c);
+                                                println!("Cropped to {}x{}", c.document.width, c.document.height);
+                                            }
+                                            w.request_redraw();
                                         }
-                                        KeyCode::ControlLeft | KeyCode::ControlRight => {
-                                            input.ctrl_pressed = event.state == ElementState::Pressed;
+                                    }
+                                    KeyCode::Escape => {
+                                        cancel_selection(&mut input, c);
+                                        input.transform_drag = None;
+                                        c.cancel_free_transform();
+                                        if c.crop_preview.take().is_some() {
+                                            c.render_view();
                                         }
-                                        _ => {}
+                                        w.request_redraw();
                                     }
-                                }
-                                
-                                let shift_pressed = input.shift_pressed;
-                                let ctrl_pressed = input.ctrl_pressed;
-                                if event.state == ElementState::Pressed {
-                                    if let PhysicalKey::Code(code) = event.physical_key {
-                                        match code {
-                                            // Check zoom first (with shift modifier)
-                                            KeyCode::PageUp | KeyCode::Equal if shift_pressed => {
-                                                // Zoom in (Shift+= or Page Up)
-                                                if c.loaded_image_size.is_some() {
-                                                    c.zoom_scale = (c.zoom_scale * 1.25).min(5.0);
-                                                    c.repan_image(c.pan_offset.0, c.pan_offset.1);
-                                                    w.request_redraw();
-                                                    println!("Zoom: {:.0}%", c.zoom_scale * 100.0);
-                                                }
-                                            }
-                                            KeyCode::PageDown | KeyCode::Minus if shift_pressed => {
-                                                // Zoom out (Shift+- or Page Down)
-                                                if c.loaded_image_size.is_some() {
-                                                    c.zoom_scale = (c.zoom_scale / 1.25).max(0.1);
-                                                    c.repan_image(c.pan_offset.0, c.pan_offset.1);
-                                                    w.request_redraw();
-                                                    println!("Zoom: {:.0}%", c.zoom_scale * 100.0);
-                                                }
-                                            }
-                                            KeyCode::Digit0 if shift_pressed => {
-                                                // Reset zoom to 100% (Shift+0)
-                                                if c.loaded_image_size.is_some() {
-                                                    c.zoom_scale = 1.0;
-                                                    c.pan_offset = (0, 0);
-                                                    c.repan_image(0, 0);
-                                                    w.request_redraw();
-                                                    println!("Zoom: 100%");
-                                                }
-                                            }
-                                            // Color palette selection
-                                            KeyCode::Digit1 => input.set_brush_color(PALETTE[0]),
-                                            KeyCode::Digit2 => input.set_brush_color(PALETTE[1]),
-                                            KeyCode::Digit3 => input.set_brush_color(PALETTE[2]),
-                                            KeyCode::Digit4 => input.set_brush_color(PALETTE[3]),
-                                            // Brush size adjustments (without shift)
-                                            KeyCode::Minus if !shift_pressed => input.adjust_brush_radius(-1.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
-                                            KeyCode::Equal if !shift_pressed => input.adjust_brush_radius(1.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
-                                            KeyCode::BracketLeft => input.adjust_brush_radius(-2.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
-                                            KeyCode::BracketRight => input.adjust_brush_radius(2.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
-                                            // Undo/Redo shortcuts
-                                            KeyCode::KeyZ if ctrl_pressed && !shift_pressed => {
-                                                // Ctrl+Z: Undo
-                                                if let Some(c) = canvas.as_mut() {
-                                                    if history.undo(c) {
-                                                        w.request_redraw();
-                                                        println!("↶ Undo");
-                                                    }
-                                                }
-                                            }
-                                            KeyCode::KeyZ if ctrl_pressed && shift_pressed => {
-                                                // Ctrl+Shift+Z: Redo
-                                                if let Some(c) = canvas.as_mut() {
-                                                    if history.redo(c) {
-                                                        w.request_redraw();
-                                                        println!("↷ Redo");
-                                                    }
-                                                }
-                                            }
-                                            // Filter shortcuts
-                                            KeyCode::KeyG if ctrl_pressed => {
-                                                // Ctrl+G: Grayscale
-                                                c.filter_grayscale();
-                                                history.push(c);
-                                                w.request_redraw();
-                                                println!("✓ Applied Grayscale filter");
+                                    KeyCode::KeyA if !ctrl_pressed && input.current_tool == input::Tool::Crop => {
+                                        // A: Cycle the crop aspect ratio (free, 1:1, 4:3, ...)
+                                        input.crop_aspect = (input.crop_aspect + 1) % ASPECT_PRESETS.len();
+                                        match ASPECT_PRESETS[input.crop_aspect] {
+                                            Some((aw, ah)) => println!("Crop aspect: {}:{}", aw, ah),
+                                            None => println!("Crop aspect: free"),
+                                        }
+                                    }
+                                    KeyCode::KeyT if !ctrl_pressed && input.current_tool == input::Tool::Crop => {
+                                        // T: Toggle the rule-of-thirds guides
+                                        c.crop_thirds = !c.crop_thirds;
+                                        c.render_view();
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyQ if shift_pressed && !ctrl_pressed => {
+                                        // Shift+Q: Toggle quick mask (paint the selection with brush and eraser)
+                                        c.toggle_quick_mask();
+                                        history.push(c);
+                                        println!("Quick mask: {}", if c.document.quick_mask { "on" } else { "off" });
+                                        w.request_redraw();
+                                    }
+                                    // Clipboard
+                                    KeyCode::KeyC if ctrl_pressed => {
+                                        // Ctrl+C: Copy the selection from the active layer (Ctrl+Shift+C: from the merged image)
+                                        match c.document.copy(shift_pressed) {
+                                            Some(clip) => {
+                                                println!("Copied {}x{}", clip.layer.width, clip.layer.height);
+                                                clipboard = Some(clip);
                                             }
-                                            KeyCode::KeyB if ctrl_pressed && shift_pressed => {
-                                                // Ctrl+Shift+B: Brightness/Contrast
-                                                c.filter_brightness_contrast(30.0, 20.0);
+                                            None => println!("Nothing to copy"),
+                                        }
+                                    }
+                                    KeyCode::KeyX if ctrl_pressed => {
+                                        // Ctrl+X: Cut the selection from the active layer
+                                        match c.document.cut() {
+                                            Some(clip) => {
+                                                println!("Cut {}x{}", clip.layer.width, clip.layer.height);
+                                                clipboard = Some(clip);
+                                                c.refresh();
                                                 history.push(c);
                                                 w.request_redraw();
-                                                println!("✓ Applied Brightness/Contrast filter");
                                             }
-                                            KeyCode::KeyU if ctrl_pressed => {
-                                                // Ctrl+U: Blur
-                                                c.filter_blur(2);
+                                            None => println!("Nothing to cut"),
+                                        }
+                                    }
+                                    KeyCode::KeyV if ctrl_pressed => {
+                                        // Ctrl+V: Paste as a floating selection; move it with the Move tool,
+                                        // then Ctrl+H anchors it or Ctrl+Shift+N makes it a new layer
+                                        if let Some(clip) = clipboard.as_ref() {
+                                            c.document.paste(clip);
+                                            c.refresh();
+                                            history.push(c);
+                                            println!("Pasted floating selection");
+                                            w.request_redraw();
+                                        }
+                                    }
+                                    KeyCode::KeyH if ctrl_pressed && c.document.floating.is_some() => {
+                                        // Ctrl+H: Anchor the floating selection into the active layer
+                                        c.document.anchor_floating();
+                                        c.refresh();
+                                        history.push(c);
+                                        println!("Anchored floating selection");
+                                        w.request_redraw();
+                                    }
+                                    // Select menu
+                                    KeyCode::KeyA if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+A: Select None (as a guard it would fall through to Select All)
+                                        let deselected = c.document.selection.take().is_some();
+                                        if deselected {
+                                            c.render_view();
+                                            history.push(c);
+                                            w.request_redraw();
+                                        }
+                                    }
+                                    KeyCode::KeyA if ctrl_pressed => {
+                                        // Ctrl+A: Select All
+                                        c.document.selection = Some(Selection::all(c.document.width, c.document.height));
+                                        c.render_view();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyR if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+R: Image > Crop to Selection
+                                        if c.crop_to_selection() {
+                                            history.push(c);
+                                            println!("Cropped to {}x{}", c.document.width, c.document.height);
+                                            w.request_redraw();
+                                        } else {
+                                            println!("Nothing selected");
+                                        }
+                                    }
+                                    KeyCode::KeyR if ctrl_pressed => {
+                                        // Ctrl+R: Invert the selection (nothing selected inverts to everything)
+                                        let (width, height) = (c.document.width, c.document.height);
+                                        let mut selection = c.document.selection.take().unwrap_or_else(|| Selection::new(width, height));
+                                        selection.invert();
+                                        c.document.selection = (!selection.is_empty()).then_some(selection);
+                                        c.render_view();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Period if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+.: Border
+                                        modify_selection(c, &mut history, "border", |s| s.border(SELECTION_STEP));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Period if ctrl_pressed => {
+                                        // Ctrl+.: Grow
+                                        modify_selection(c, &mut history, "grow", |s| s.grow(SELECTION_STEP));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::Comma if ctrl_pressed => {
+                                        // Ctrl+,: Shrink
+                                        modify_selection(c, &mut history, "shrink", |s| s.shrink(SELECTION_STEP));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyF if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+F: Smooth
+                                        modify_selection(c, &mut history, "smooth", |s| s.smooth(SELECTION_SMOOTH_RADIUS));
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyF if ctrl_pressed => {
+                                        // Ctrl+F: Feather
+                                        modify_selection(c, &mut history, "feather", |s| s.feather(SELECTION_FEATHER_RADIUS));
+                                        w.request_redraw();
+                                    }
+                                    // Layer selection (Page Up/Down without shift)
+                                    KeyCode::PageUp => {
+                                        c.document.select_above();
+                                        print_active_layer(c);
+                                    }
+                                    KeyCode::PageDown => {
+                                        c.document.select_below();
+                                        print_active_layer(c);
+                                    }
+                                    KeyCode::KeyN if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+N: New transparent layer above the active one,
+                                        // made from the floating selection when there is one
+                                        if c.document.floating.is_some() {
+                                            c.document.floating_to_layer();
+                                        } else {
+                                            c.document.add_layer();
+                                        }
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                        print_active_layer(c);
+                                    }
+                                    // Color palette selection
+                                    KeyCode::Digit1 => input.set_brush_color(PALETTE[0]),
+                                    KeyCode::Digit2 => input.set_brush_color(PALETTE[1]),
+                                    KeyCode::Digit3 => input.set_brush_color(PALETTE[2]),
+                                    KeyCode::Digit4 => input.set_brush_color(PALETTE[3]),
+                                    // Brush size adjustments (without shift)
+                                    KeyCode::Minus if !shift_pressed => input.adjust_brush_radius(-1.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
+                                    KeyCode::Equal if !shift_pressed => input.adjust_brush_radius(1.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
+                                    // Layer opacity / blend mode
+                                    KeyCode::BracketLeft if ctrl_pressed => {
+                                        // Ctrl+[: Lower active layer/group opacity
+                                        if let Some(node) = c.document.active_node_mut() {
+                                            node.set_opacity(node.opacity() - 0.1);
+                                            println!("Opacity: {:.0}%", node.opacity() * 100.0);
+                                        }
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::BracketRight if ctrl_pressed => {
+                                        // Ctrl+]: Raise active layer/group opacity
+                                        if let Some(node) = c.document.active_node_mut() {
+                                            node.set_opacity(node.opacity() + 0.1);
+                                            println!("Opacity: {:.0}%", node.opacity() * 100.0);
+                                        }
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyM if ctrl_pressed => {
+                                        // Ctrl+M / Ctrl+Shift+M: Cycle active layer/group blend mode
+                                        if let Some(node) = c.document.active_node_mut() {
+                                            let mode = if shift_pressed {
+                                                node.blend_mode().prev()
+                                            } else {
+                                                node.blend_mode().next()
+                                            };
+                                            node.set_blend_mode(mode);
+                                            println!("Blend mode: {:?}", mode);
+                                        }
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyK if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+K: Delete the active layer's mask
+                                        if let Some(layer) = c.document.active_layer_mut()
+                                            && layer.mask.take().is_some()
+                                        {
+                                            println!("✓ Deleted mask of {}", layer.name);
+                                        }
+                                        c.document.edit_mask = false;
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyK if ctrl_pressed => {
+                                        // Ctrl+K: Add a mask to the active layer, or toggle editing it
+                                        if let Some(layer) = c.document.active_layer_mut() {
+                                            if layer.mask.is_none() {
+                                                layer.add_mask();
+                                                c.document.edit_mask = true;
                                                 history.push(c);
-                                                w.request_redraw();
-                                                println!("✓ Applied Blur filter");
-                                            }
-                                            // Pan/zoom controls
-                                            KeyCode::ArrowLeft => {
-                                                if c.loaded_image_size.is_some() {
-                                                    c.pan_image(50, 0);
-                                                    w.request_redraw();
-                                                }
+                                            } else {
+                                                c.document.edit_mask = !c.document.edit_mask;
                                             }
-                                            KeyCode::ArrowRight => {
-                                                if c.loaded_image_size.is_some() {
-                                                    c.pan_image(-50, 0);
-                                                    w.request_redraw();
-                                                }
-                                            }
-                                            KeyCode::ArrowUp => {
-                                                if c.loaded_image_size.is_some() {
-                                                    c.pan_image(0, 50);
-                                                    w.request_redraw();
+                                        }
+                                        println!("Editing: {}", if c.document.painting_mask() { "layer mask" } else { "layer pixels" });
+                                    }
+                                    KeyCode::BracketLeft => input.adjust_brush_radius(-2.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
+                                    KeyCode::BracketRight => input.adjust_brush_radius(2.0, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX),
+                                    // Undo/Redo shortcuts
+                                    KeyCode::KeyZ if ctrl_pressed && !shift_pressed && c.free_transform.is_some() => {
+                                        // Ctrl+Z during a transform drops it, like Escape
+                                        input.transform_drag = None;
+                                        c.cancel_free_transform();
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyZ if ctrl_pressed && !shift_pressed && history.undo(c) => {
+                                        // Ctrl+Z: Undo
+                                        w.request_redraw();
+                                        println!("↶ Undo");
+                                    }
+                                    KeyCode::KeyZ if ctrl_pressed && shift_pressed && history.redo(c) => {
+                                        // Ctrl+Shift+Z: Redo
+                                        w.request_redraw();
+                                        println!("↷ Redo");
+                                    }
+                                    // Filter shortcuts
+                                    KeyCode::KeyG if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+G: Put the active layer into a new group
+                                        c.document.group_active();
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                        print_active_layer(c);
+                                    }
+                                    KeyCode::KeyU if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+U: Dissolve the active group into its parent
+                                        c.document.ungroup_active();
+                                        c.refresh();
+                                        history.push(c);
+                                        w.request_redraw();
+                                        print_active_layer(c);
+                                    }
+                                    KeyCode::KeyG if ctrl_pressed => {
+                                        // Ctrl+G: Grayscale
+                                        c.filter_grayscale();
+                                        history.push_filter(c, FilterStep::Grayscale);
+                                        w.request_redraw();
+                                        println!("✓ Applied Grayscale filter");
+                                    }
+                                    KeyCode::KeyB if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+B: Brightness/Contrast
+                                        c.filter_brightness_contrast(30.0, 20.0);
+                                        history.push_filter(c, FilterStep::Brightness);
+                                        w.request_redraw();
+                                        println!("✓ Applied Brightness/Contrast filter");
+                                    }
+                                    KeyCode::KeyU if ctrl_pressed => {
+                                        // Ctrl+U: Blur
+                                        c.filter_blur(2);
+                                        history.push(c);
+                                        w.request_redraw();
+                                        println!("✓ Applied Blur filter");
+                                    }
+                                    // Pan/zoom controls
+                                    KeyCode::ArrowLeft => {
+                                        c.pan_image(50, 0);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::ArrowRight => {
+                                        c.pan_image(-50, 0);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::ArrowUp => {
+                                        c.pan_image(0, 50);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::ArrowDown => {
+                                        c.pan_image(0, -50);
+                                        w.request_redraw();
+                                    }
+                                    // Image > Canvas Size
+                                    KeyCode::KeyS if !ctrl_pressed => {
+                                        // S: Shrink the canvas and layers around the anchor (Shift+S: canvas only)
+                                        resize_canvas_by(&mut input, c, &mut history, CANVAS_SHRINK, !shift_pressed);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyL if !ctrl_pressed => {
+                                        // L: Enlarge the canvas and layers around the anchor (Shift+L: canvas only)
+                                        resize_canvas_by(&mut input, c, &mut history, CANVAS_GROW, !shift_pressed);
+                                        w.request_redraw();
+                                    }
+                                    KeyCode::KeyF if !ctrl_pressed => {
+                                        // F: Cycle what fills the new canvas area
+                                        input.canvas_fill = match input.canvas_fill {
+                                            input::CanvasFill::Transparent => input::CanvasFill::Background,
+                                            input::CanvasFill::Background => input::CanvasFill::Foreground,
+                                            input::CanvasFill::Foreground => input::CanvasFill::Transparent,
+                                        };
+                                        println!("Canvas fill: {:?}", input.canvas_fill);
+                                    }
+                                    _ if numpad_anchor(code).is_some() => {
+                                        // Numpad 1-9: Pick the canvas anchor, laid out like the keypad
+                                        if let Some(anchor) = numpad_anchor(code) {
+                                            input.canvas_anchor = anchor;
+                                            println!("Canvas anchor: column {}, row {}", anchor.column, anchor.row);
+                                        }
+                                    }
+                                    // IO shortcuts (require Ctrl)
+                                    KeyCode::KeyE if ctrl_pressed => {
+                                        // Ctrl+E: Export canvas as PNG
+                                        match io::select_export_png_path() {
+                                            Ok(path) => {
+                                                match io::export_canvas_as_png(c, &path) {
+                                                    Ok(_) => {
+                                                        let filename = std::path::Path::new(&path)
+                                                            .file_name()
+                                                            .and_then(|n| n.to_str())
+                                                            .unwrap_or("file");
+                                                        println!("✓ Canvas exported to {}", filename);
+                                                    }
+                                                    Err(e) => eprintln!("✗ Export failed: {}", e),
                                                 }
                                             }
-                                            KeyCode::ArrowDown => {
-                                                if c.loaded_image_size.is_some() {
-                                                    c.pan_image(0, -50);
-                                                    w.request_redraw();
+                                            Err(e) => eprintln!("✗ {}", e),
+                                        }
+                                    }
+                                    KeyCode::KeyI if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+I: Invert filter
+                                        c.filter_invert();
+                                        w.request_redraw();
+                                        println!("✓ Applied Invert filter");
+                                    }
+                                    KeyCode::KeyI if ctrl_pressed && !shift_pressed => {
+                                        // Ctrl+I: Import PNG
+                                        match io::select_image_file() {
+                                            Ok(path) => {
+                                                match io::load_image(&path) {
+                                                    Ok(img_layer) => {
+                                                        c.import_image(img_layer);
+                                                        history.push(c);
+                                                        w.request_redraw();
+                                                        let filename = std::path::Path::new(&path)
+                                                            .file_name()
+                                                            .and_then(|n| n.to_str())
+                                                            .unwrap_or("image");
+                                                        println!("✓ Imported {} - Use arrow keys to pan", filename);
+                                                    }
+                                                    Err(e) => eprintln!("✗ Import failed: {}", e),
                                                 }
                                             }
-                                            KeyCode::KeyS => {
-                                                let new_w = (window_size.width.max(1) as f32 * 0.75).round() as u32;
-                                                let new_h = (window_size.height.max(1) as f32 * 0.75).round() as u32;
-                                                window_size = PhysicalSize::new(new_w.max(1), new_h.max(1));
-                                                g.resize(window_size);
-                                                *c = Canvas::new(window_size.width.max(1), window_size.height.max(1));
-                                                w.request_redraw();
-                                            }
-                                            KeyCode::KeyL => {
-                                                let new_w = (window_size.width.max(1) as f32 * 1.25).round() as u32;
-                                                let new_h = (window_size.height.max(1) as f32 * 1.25).round() as u32;
-                                                window_size = PhysicalSize::new(new_w.max(1), new_h.max(1));
-                                                g.resize(window_size);
-                                                *c = Canvas::new(window_size.width.max(1), window_size.height.max(1));
-                                                w.request_redraw();
-                                            }
-                                            // IO shortcuts (require Ctrl)
-                                            KeyCode::KeyE if ctrl_pressed => {
-                                                // Ctrl+E: Export canvas as PNG
-                                                match io::select_export_png_path() {
-                                                    Ok(path) => {
-                                                        match io::export_canvas_as_png(c, &path) {
-                                                            Ok(_) => {
-                                                                let filename = std::path::Path::new(&path)
-                                                                    .file_name()
-                                                                    .and_then(|n| n.to_str())
-                                                                    .unwrap_or("file");
-                                                                println!("✓ Canvas exported to {}", filename);
-                                                            }
-                                                            Err(e) => eprintln!("✗ Export failed: {}", e),
+                                            Err(e) => eprintln!("✗ {}", e),
+                                        }
+                                    }
+                                    KeyCode::KeyO if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+O: Open a single-file .mgp project
+                                        match io::select_load_project_archive() {
+                                            Ok(path) => {
+                                                match io::load_project_archive(&path) {
+                                                    Ok((project, layers)) => {
+                                                        if layers.is_empty() {
+                                                            eprintln!("✗ Project has no layers");
+                                                        } else {
+                                                            let count = layers.len();
+                                                            c.set_document(Document::from_project(&project, layers));
+                                                            history.push(c);
+                                                            w.request_redraw();
+                                                            println!("✓ Project loaded: {} ({}x{}, {} layers)", project.name, project.width, project.height, count);
                                                         }
                                                     }
-                                                    Err(e) => eprintln!("✗ {}", e),
+                                                    Err(e) => eprintln!("✗ Load failed: {}", e),
                                                 }
                                             }
-                                            KeyCode::KeyI if ctrl_pressed && shift_pressed => {
-                                                // Ctrl+Shift+I: Invert filter
-                                                c.filter_invert();
-                                                w.request_redraw();
-                                                println!("✓ Applied Invert filter");
-                                            }
-                                            KeyCode::KeyI if ctrl_pressed && !shift_pressed => {
-                                                // Ctrl+I: Import PNG
-                                                match io::select_image_file() {
-                                                    Ok(path) => {
-                                                        match io::load_image(&path) {
-                                                            Ok(img_layer) => {
-                                                                c.pan_offset = (0, 0);
-                                                                c.paste_image(img_layer.width, img_layer.height, &img_layer.pixels);
-                                                                w.request_redraw();
-                                                                let filename = std::path::Path::new(&path)
-                                                                    .file_name()
-                                                                    .and_then(|n| n.to_str())
-                                                                    .unwrap_or("image");
-                                                                println!("✓ Imported {} - Use arrow keys to pan", filename);
-                                                            }
-                                                            Err(e) => eprintln!("✗ Import failed: {}", e),
+                                            Err(e) => eprintln!("✗ {}", e),
+                                        }
+                                    }
+                                    KeyCode::KeyO if ctrl_pressed => {
+                                        // Ctrl+O: Load project
+                                        match io::select_load_project_folder() {
+                                            Ok(path) => {
+                                                match io::load_project(&path) {
+                                                    Ok((project, layers)) => {
+                                                        if layers.is_empty() {
+                                                            eprintln!("✗ Project has no layers");
+                                                        } else {
+                                                            // Rebuild the whole layer tree at the project's own size
+                                                            let count = layers.len();
+                                                            c.set_document(Document::from_project(&project, layers));
+                                                            history.push(c);
+                                                            w.request_redraw();
+                                                            println!("✓ Project loaded: {} ({}x{}, {} layers)", project.name, project.width, project.height, count);
                                                         }
                                                     }
-                                                    Err(e) => eprintln!("✗ {}", e),
+                                                    Err(e) => eprintln!("✗ Load failed: {}", e),
                                                 }
                                             }
-                                            KeyCode::KeyO if ctrl_pressed => {
-                                                // Ctrl+O: Load project
-                                                match io::select_load_project_folder() {
-                                                    Ok(path) => {
-                                                        match io::load_project(&path) {
-                                                            Ok((project, layers)) => {
-                                                                if !layers.is_empty() && layers[0].width == c.width && layers[0].height == c.height {
-                                                                    c.load_pixels(layers[0].width, layers[0].height, layers[0].pixels.clone());
-                                                                    w.request_redraw();
-                                                                    println!("✓ Project loaded: {} ({} layers)", project.name, layers.len());
-                                                                } else if layers.is_empty() {
-                                                                    eprintln!("✗ Project has no layers");
-                                                                } else {
-                                                                    eprintln!("✗ Layer size mismatch");
-                                                                }
-                                                            }
-                                                            Err(e) => eprintln!("✗ Load failed: {}", e),
-                                                        }
-                                                    }
-                                                    Err(e) => eprintln!("✗ {}", e),
+                                            Err(e) => eprintln!("✗ {}", e),
+                                        }
+                                    }
+                                    KeyCode::KeyP if ctrl_pressed && shift_pressed => {
+                                        // Ctrl+Shift+P: Save as a single-file .mgp project
+                                        match io::select_save_project_archive() {
+                                            Ok(path) => {
+                                                let project_name = std::path::Path::new(&path)
+                                                    .file_stem()
+                                                    .and_then(|n| n.to_str())
+                                                    .unwrap_or("Project")
+                                                    .to_string();
+                                                let project = c.document.project(project_name);
+
+                                                let layers: Vec<_> = c.document.leaf_layers().into_iter().cloned().collect();
+                                                match io::save_project_archive(&project, &layers, &path) {
+                                                    Ok(_) => println!("✓ Project saved to {}", path),
+                                                    Err(e) => eprintln!("✗ Save failed: {}", e),
                                                 }
                                             }
-                                            KeyCode::KeyP if ctrl_pressed => {
-                                                // Ctrl+P: Save project
-                                                match io::select_save_project_folder() {
-                                                    Ok(path) => {
-                                                        let image_pixels = c.extract_image_pixels();
-                                                        let (width, height) = c.loaded_image_size.unwrap_or((c.width, c.height));
-                                                        let layer = layer::Layer::from_rgba(
-                                                            "canvas".to_string(),
-                                                            width,
-                                                            height,
-                                                            image_pixels,
-                                                        );
-                                                        let project_name = std::path::Path::new(&path)
+                                            Err(e) => eprintln!("✗ {}", e),
+                                        }
+                                    }
+                                    KeyCode::KeyP if ctrl_pressed => {
+                                        // Ctrl+P: Save project
+                                        match io::select_save_project_folder() {
+                                            Ok(path) => {
+                                                let project_name = std::path::Path::new(&path)
+                                                    .file_name()
+                                                    .and_then(|n| n.to_str())
+                                                    .unwrap_or("Project")
+                                                    .to_string();
+                                                let project = c.document.project(project_name);
+
+                                                let layers: Vec<_> = c.document.leaf_layers().into_iter().cloned().collect();
+                                                match io::save_project(&project, &layers, &path) {
+                                                    Ok(_) => {
+                                                        let folder_name = std::path::Path::new(&path)
                                                             .file_name()
                                                             .and_then(|n| n.to_str())
-                                                            .unwrap_or("Project")
-                                                            .to_string();
-                                                        let mut project = layer::Project::new(project_name, width, height);
-                                                        project.add_layer_metadata("canvas".to_string(), "layer_000.png".to_string());
-                                                        
-                                                        match io::save_project(&project, &[layer], &path) {
-                                                            Ok(_) => {
-                                                                let folder_name = std::path::Path::new(&path)
-                                                                    .file_name()
-                                                                    .and_then(|n| n.to_str())
-                                                                    .unwrap_or("project");
-                                                                println!("✓ Project saved to {}/", folder_name);
-                                                            }
-                                                            Err(e) => eprintln!("✗ Save failed: {}", e),
-                                                        }
+                                                            .unwrap_or("project");
+                                                        println!("✓ Project saved to {}/", folder_name);
                                                     }
-                                                    Err(e) => eprintln!("✗ {}", e),
+                                                    Err(e) => eprintln!("✗ Save failed: {}", e),
                                                 }
                                             }
-                                            _ => {}
+                                            Err(e) => eprintln!("✗ {}", e),
                                         }
                                     }
+                                    _ => {}
                                 }
                             }
-                            WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
-                                if state == ElementState::Pressed {
-                                    if let Some(pos) = input.last_pos {
-                                        if let Some(action) = panel_hit_test(pos, c) {
-                                            if matches!(action, PanelAction::SizeValue(_)) {
-                                                input.set_slider_drag(Some(SliderDrag::Size));
-                                            } else if matches!(action, PanelAction::PickerHue(_)) {
-                                                input.set_color_drag(Some(input::ColorPickerDrag::Hue));
-                                            } else if matches!(action, PanelAction::PickerSV(_, _)) {
-                                                input.set_color_drag(Some(input::ColorPickerDrag::SV));
-                                            }
-                                            handle_panel_action(action, &mut input, &mut window_size, g, c, w, &mut history);
-                                            input.stop_drawing();
-                                            return;
+                        }
+                        WindowEvent::MouseInput { state, button: MouseButton::Middle, .. } => {
+                            // Middle drag pans the view with any tool
+                            input.panning = state == ElementState::Pressed;
+                        }
+                        WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                            if state == ElementState::Pressed {
+                                if let Some(pos) = input.last_pos {
+                                    if let Some(action) = panel_hit_test(pos, c, &input) {
+                                        if matches!(action, PanelAction::SizeValue(_)) {
+                                            input.set_slider_drag(Some(SliderDrag::Size));
+                                        } else if matches!(action, PanelAction::WandTolerance(_)) {
+                                            input.set_slider_drag(Some(SliderDrag::Tolerance));
+                                        } else if matches!(action, PanelAction::BrushHardness(_)) {
+                                            input.set_slider_drag(Some(SliderDrag::Hardness));
+                                        } else if matches!(action, PanelAction::BrushOpacity(_)) {
+                                            input.set_slider_drag(Some(SliderDrag::Opacity));
+                                        } else if matches!(action, PanelAction::BrushFlow(_)) {
+                                            input.set_slider_drag(Some(SliderDrag::Flow));
+                                        } else if matches!(action, PanelAction::PickerHue(_)) {
+                                            input.set_color_drag(Some(input::ColorPickerDrag::Hue));
+                                        } else if matches!(action, PanelAction::PickerSV(_, _)) {
+                                            input.set_color_drag(Some(input::ColorPickerDrag::SV));
                                         }
-                                        if pos.0 >= PANEL_WIDTH as f32 {
-                                            // Handle different tools
-                                            match input.current_tool {
-                                                input::Tool::Brush | input::Tool::Eraser | input::Tool::Blur => {
-                                                    input.drawing = true;
+                                        handle_panel_action(action, &mut input, c, w, &mut history);
+                                        input.stop_drawing();
+                                        return;
+                                    }
+                                    if input.space_pressed && pos.0 >= PANEL_WIDTH as f32 {
+                                        // Space+drag pans with any tool
+                                        input.panning = true;
+                                        return;
+                                    }
+                                    if pos.0 >= PANEL_WIDTH as f32 {
+                                        // Handle different tools
+                                        match input.current_tool {
+                                            input::Tool::Brush | input::Tool::Eraser | input::Tool::Blur => {
+                                                input.drawing = true;
+                                            }
+                                            input::Tool::FillBucket => {
+                                                if pos.0 >= PANEL_WIDTH as f32 && pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    let canvas_x = pos.0 as u32;
+                                                    let canvas_y = pos.1 as u32;
+                                                    c.flood_fill(canvas_x, canvas_y, input.brush.color);
+                                                    history.push(c);
+                                                    w.request_redraw();
                                                 }
-                                                input::Tool::FillBucket => {
-                                                    if pos.0 >= PANEL_WIDTH as f32 && pos.1 >= TOOLBAR_HEIGHT as f32 {
-                                                        let canvas_x = pos.0 as u32;
-                                                        let canvas_y = pos.1 as u32;
-                                                        c.flood_fill(canvas_x, canvas_y, input.brush.color);
+                                            }
+                                            input::Tool::ColorPicker => {
+                                                if pos.0 >= PANEL_WIDTH as f32 && pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    let canvas_x = pos.0 as u32;
+                                                    let canvas_y = pos.1 as u32;
+                                                    if let Some(color) = c.get_pixel(canvas_x, canvas_y) {
+                                                        if input.active_is_foreground {
+                                                            input.set_brush_color(color);
+                                                        } else {
+                                                            input.set_background_color(color);
+                                                        }
+                                                        println!("Picked color: {:?}", color);
+                                                    }
+                                                    w.request_redraw();
+                                                }
+                                            }
+                                            input::Tool::Move => {
+                                                // Move the floating selection, else the active layer's contents
+                                                if c.document.floating.is_none() && c.document.active_layer().is_none() {
+                                                    println!("Select a layer to move");
+                                                } else if pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    input.move_drag = Some((c.canvas_to_image(pos.0, pos.1), (0, 0)));
+                                                }
+                                            }
+                                            input::Tool::RectSelect | input::Tool::EllipseSelect | input::Tool::LassoSelect => {
+                                                let start = image_pixel_at(c, pos);
+                                                input.begin_selection(c.document.selection.clone());
+                                                input.selection_start = Some(start);
+                                                input.selection_end = Some(start);
+                                                if input.current_tool == input::Tool::LassoSelect {
+                                                    input.selection_points.push(c.canvas_to_image(pos.0, pos.1));
+                                                }
+                                            }
+                                            input::Tool::MagicWand => {
+                                                let shape = if pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    c.magic_wand(pos.0 as u32, pos.1 as u32, input.wand)
+                                                } else {
+                                                    None
+                                                };
+                                                if let Some(shape) = shape {
+                                                    input.begin_selection(c.document.selection.clone());
+                                                    c.document.selection = selection::combine(input.selection_base.as_ref(), shape, input.selection_mode);
+                                                    if c.document.selection != input.selection_base {
+                                                        c.render_view();
                                                         history.push(c);
-                                                        w.request_redraw();
                                                     }
+                                                    input.end_selection();
+                                                    w.request_redraw();
                                                 }
-                                                input::Tool::ColorPicker => {
-                                                    if pos.0 >= PANEL_WIDTH as f32 && pos.1 >= TOOLBAR_HEIGHT as f32 {
-                                                        let canvas_x = pos.0 as u32;
-                                                        let canvas_y = pos.1 as u32;
-                                                        if let Some(color) = c.get_pixel(canvas_x, canvas_y) {
-                                                            if input.active_is_foreground {
-                                                                input.set_brush_color(color);
-                                                            } else {
-                                                                input.set_background_color(color);
-                                                            }
-                                                            println!("Picked color: {:?}", color);
-                                                        }
-                                                        w.request_redraw();
+                                            }
+                                            input::Tool::Crop => {
+                                                // Grab a handle of the current rectangle, or start a new one
+                                                if pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    let point = c.canvas_to_image(pos.0, pos.1);
+                                                    let reach = crop::HANDLE_SIZE as f32 / c.zoom_scale;
+                                                    let grabbed = c.crop_preview.and_then(|rect| {
+                                                        rect.handle_at(point.0, point.1, reach).map(|handle| (handle, rect))
+                                                    });
+                                                    let (handle, rect) = grabbed.unwrap_or_else(|| {
+                                                        let (x, y) = (point.0.round() as i32, point.1.round() as i32);
+                                                        (CropHandle::BottomRight, CropRect { x0: x, y0: y, x1: x, y1: y })
+                                                    });
+                                                    input.crop_drag = Some((handle, rect, point));
+                                                }
+                                            }
+                                            input::Tool::Transform => {
+                                                // Put a box around the selection or layer, then grab a handle of it
+                                                if pos.1 >= TOOLBAR_HEIGHT as f32 {
+                                                    if c.free_transform.is_none() && !c.begin_free_transform() {
+                                                        println!("Nothing to transform");
+                                                    }
+                                                    if let Some(quad) = c.free_transform.as_ref().map(|t| t.quad) {
+                                                        let point = c.canvas_to_image(pos.0, pos.1);
+                                                        let reach = crop::HANDLE_SIZE as f32 / c.zoom_scale;
+                                                        let handle = free_transform::handle_at(&quad, point, reach);
+                                                        input.transform_drag = Some((handle, quad, point));
                                                     }
+                                                    w.request_redraw();
+                                                }
+                                            }
+                                            input::Tool::PolygonSelect => {
+                                                // Each click adds a vertex; clicking the first vertex closes the polygon
+                                                let point = c.canvas_to_image(pos.0, pos.1);
+                                                if input.selection_points.is_empty() {
+                                                    input.begin_selection(c.document.selection.clone());
                                                 }
-                                                input::Tool::Move => {
-                                                    input.drawing = true;
+                                                let snap = POLYGON_CLOSE_DISTANCE / c.zoom_scale;
+                                                let closes = input.selection_points.len() >= 3
+                                                    && input.selection_points.first().is_some_and(|first| {
+                                                        (first.0 - point.0).abs() <= snap && (first.1 - point.1).abs() <= snap
+                                                    });
+                                                if closes {
+                                                    finish_selection(&mut input, c, &mut history);
+                                                } else {
+                                                    input.selection_points.push(point);
+                                                    preview_selection(&input, c, None);
                                                 }
+                                                w.request_redraw();
                                             }
                                         }
                                     }
-                                } else {
-                                    // Mouse released - save to history after drawing
-                                    if input.current_tool == input::Tool::Move && input.drawing {
-                                        // Apply move if we dragged
-
-                                    }
-                                    if input.drawing {
+                                }
+                            } else {
+                                // Dropping a dragged layer row onto another row reorders the stack
+                                if let Some(from) = input.layer_dragging.take() {
+                                    let target = input.last_pos.and_then(|pos| layer_row_at(pos, c));
+                                    if let Some((to, _, _)) = target
+                                        && c.document.move_node(&from, &to)
+                                    {
+                                        c.refresh();
                                         history.push(c);
+                                        print_active_layer(c);
                                     }
-                                    input.set_slider_drag(None);
-                                    input.set_color_drag(None);
-                                    input.stop_drawing();
-                                    input.selection_start = None;
-                                    input.selection_end = None;
+                                    w.request_redraw();
+                                }
+                                input.panning = false;
+                                // Mouse released - save to history after drawing or moving
+                                if input.move_drag.take().is_some_and(|(_, moved)| moved != (0, 0)) {
+                                    history.push(c);
+                                }
+                                if input.drawing {
+                                    history.push(c);
+                                }
+                                // Releasing a crop drag that spans nothing drops the rectangle
+                                input.transform_drag = None;
+                                if input.crop_drag.take().is_some() && c.crop_preview.is_some_and(|r| r.is_empty()) {
+                                    c.crop_preview = None;
+                                    c.render_view();
+                                    w.request_redraw();
                                 }
+                                // Releasing ends a rectangle, ellipse or lasso drag
+                                if input.selection_start.is_some() {
+                                    finish_selection(&mut input, c, &mut history);
+                                    w.request_redraw();
+                                }
+                                input.set_slider_drag(None);
+                                input.set_color_drag(None);
+                                input.stop_drawing();
+                                c.end_stroke();
+                                input.selection_start = None;
+                                input.selection_end = None;
                             }
-                            WindowEvent::CursorMoved { position, .. } => {
-                                if let Some(p) = window_to_canvas(position, window_size, c) {
-                                    let prev = input.last_pos;
-                                    input.last_pos = Some(p);
-                                    if let Some(target) = input.slider_dragging {
-                                        match target {
-                                            SliderDrag::Brightness => {
-                                                let value = brightness_value_from_x(p.0);
-                                                input.set_brightness(value, BRIGHT_MIN, BRIGHT_MAX);
-                                            }
-                                            SliderDrag::Size => {
-                                                let value = size_value_from_x(p.0);
-                                                input.set_brush_radius(value, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX);
-                                            }
+                        }
+                        WindowEvent::CursorMoved { position, .. } => {
+                            if let Some(p) = window_to_canvas(position, window_size, c) {
+                                let prev = input.last_pos;
+                                input.last_pos = Some(p);
+                                if input.layer_dragging.is_some() {
+                                    w.request_redraw();
+                                    return;
+                                }
+                                if let Some(target) = input.slider_dragging {
+                                    match target {
+                                        SliderDrag::Brightness => {
+                                            let value = brightness_value_from_x(p.0);
+                                            input.set_brightness(value, BRIGHT_MIN, BRIGHT_MAX);
+                                        }
+                                        SliderDrag::Size => {
+                                            let value = size_value_from_x(p.0);
+                                            input.set_brush_radius(value, BRUSH_RADIUS_MIN, BRUSH_RADIUS_MAX);
+                                        }
+                                        SliderDrag::Tolerance => {
+                                            input.wand.tolerance = tolerance_value_from_x(p.0);
+                                        }
+                                        SliderDrag::Hardness => input.brush.hardness = brush_param_from_x(p.0, BRUSH_HARDNESS_X),
+                                        SliderDrag::Opacity => input.brush.opacity = brush_param_from_x(p.0, BRUSH_OPACITY_X),
+                                        SliderDrag::Flow => input.brush.flow = brush_param_from_x(p.0, BRUSH_FLOW_X),
+                                    }
+                                    w.request_redraw();
+                                    return;
+                                }
+                                if let Some(cp_drag) = input.color_dragging {
+                                    // Geometry: same as panel_hit_test/draw_ui
+                                    let panel_x = 8.0;
+                                    let panel_y = TOOLBAR_HEIGHT as f32 + 8.0;
+                                    let size_y = (panel_y + 30.0) + 24.0 * 4.0 + 4.0;
+                                    let hue_x = panel_x;
+                                    let hue_y = size_y + 36.0;
+                                    let hue_w = 14.0;
+                                    let hue_h = 120.0;
+                                    let sv_x = hue_x + hue_w + 6.0;
+                                    let sv_y = hue_y;
+                                    let sv_w = (PANEL_WIDTH as f32 - 16.0) - (hue_w + 6.0);
+                                    let sv_h = sv_w;
+                                    match cp_drag {
+                                        input::ColorPickerDrag::Hue => {
+                                            // Clamp to hue bar and update hue
+                                            let yy = p.1.clamp(hue_y, hue_y + hue_h);
+                                            let hh = (yy - hue_y) / hue_h;
+                                            input.set_hsv(hh, input.sat, input.val);
+                                        }
+                                        input::ColorPickerDrag::SV => {
+                                            let xx = p.0.clamp(sv_x, sv_x + sv_w);
+                                            let yy = p.1.clamp(sv_y, sv_y + sv_h);
+                                            let s = (xx - sv_x) / sv_w;
+                                            let v = 1.0 - (yy - sv_y) / sv_h;
+                                            input.set_hsv(input.hue, s, v);
                                         }
+                                    }
+                                    w.request_redraw();
+                                    return;
+                                }
+                                if input.selection_start.is_some() {
+                                    let end = image_pixel_at(c, p);
+                                    if input.selection_end != Some(end) {
+                                        input.selection_end = Some(end);
+                                        if input.current_tool == input::Tool::LassoSelect {
+                                            input.selection_points.push(c.canvas_to_image(p.0, p.1));
+                                        }
+                                        preview_selection(&input, c, None);
                                         w.request_redraw();
-                                        return;
                                     }
-                                    if let Some(cp_drag) = input.color_dragging {
-                                        // Geometry: same as panel_hit_test/draw_ui
-                                        let panel_x = 8.0;
-                                        let panel_y = TOOLBAR_HEIGHT as f32 + 8.0;
-                                        let size_y = (panel_y + 30.0) + 24.0 * 4.0 + 4.0;
-                                        let hue_x = panel_x;
-                                        let hue_y = size_y + 36.0;
-                                        let hue_w = 14.0;
-                                        let hue_h = 120.0;
-                                        let sv_x = hue_x + hue_w + 6.0;
-                                        let sv_y = hue_y;
-                                        let sv_w = (PANEL_WIDTH as f32 - 16.0) - (hue_w + 6.0);
-                                        let sv_h = sv_w;
-                                        match cp_drag {
-                                            input::ColorPickerDrag::Hue => {
-                                                // Clamp to hue bar and update hue
-                                                let yy = p.1.clamp(hue_y, hue_y + hue_h);
-                                                let hh = (yy - hue_y) / hue_h;
-                                                input.set_hsv(hh, input.sat, input.val);
-                                            }
-                                            input::ColorPickerDrag::SV => {
-                                                let xx = p.0.clamp(sv_x, sv_x + sv_w);
-                                                let yy = p.1.clamp(sv_y, sv_y + sv_h);
-                                                let s = (xx - sv_x) / sv_w;
-                                                let v = 1.0 - (yy - sv_y) / sv_h;
-                                                input.set_hsv(input.hue, s, v);
-                                            }
+                                    return;
+                                }
+                                if input.panning {
+                                    if let Some(last) = prev {
+                                        let dx = ((p.0 - last.0) / c.zoom_scale) as i32;
+                                        let dy = ((p.1 - last.1) / c.zoom_scale) as i32;
+                                        if dx != 0 || dy != 0 {
+                                            c.pan_image(dx, dy);
+                                            w.request_redraw();
+                                        } else {
+                                            // Keep the remainder for the next event
+                                            input.last_pos = Some(last);
                                         }
+                                    }
+                                    return;
+                                }
+                                if let Some((start, moved)) = input.move_drag {
+                                    // Whole image pixels from the press point, so slow drags still add up
+                                    let point = c.canvas_to_image(p.0, p.1);
+                                    let target = ((point.0 - start.0).round() as i32, (point.1 - start.1).round() as i32);
+                                    let (dx, dy) = (target.0 - moved.0, target.1 - moved.1);
+                                    if dx != 0 || dy != 0 {
+                                        if c.document.floating.is_some() {
+                                            c.move_floating(dx, dy);
+                                        } else {
+                                            c.move_layer(dx, dy);
+                                        }
+                                        input.move_drag = Some((start, target));
                                         w.request_redraw();
+                                    }
+                                    return;
+                                }
+                                if let Some((handle, quad, start)) = input.transform_drag {
+                                    let point = c.canvas_to_image(p.0, p.1);
+                                    let dragged = free_transform::drag(&quad, handle, start, point, input.ctrl_pressed);
+                                    c.preview_free_transform(dragged);
+                                    w.request_redraw();
+                                    return;
+                                }
+                                if let Some((handle, rect, start)) = input.crop_drag {
+                                    let point = c.canvas_to_image(p.0, p.1);
+                                    let dx = (point.0 - start.0).round() as i32;
+                                    let dy = (point.1 - start.1).round() as i32;
+                                    let dragged = rect.drag(handle, dx, dy, ASPECT_PRESETS[input.crop_aspect]);
+                                    let (width, height) = (c.document.width, c.document.height);
+                                    let dragged = if handle == CropHandle::Inside {
+                                        dragged.shifted_inside(width, height)
+                                    } else {
+                                        dragged.clamped(width, height)
+                                    };
+                                    if c.crop_preview != Some(dragged) {
+                                        c.crop_preview = Some(dragged);
+                                        c.render_view();
+                                        w.request_redraw();
+                                    }
+                                    return;
+                                }
+                                if input.current_tool == input::Tool::PolygonSelect && !input.selection_points.is_empty() {
+                                    // Rubber-band the next polygon edge to the cursor
+                                    preview_selection(&input, c, Some(c.canvas_to_image(p.0, p.1)));
+                                    w.request_redraw();
+                                    return;
+                                }
+                                if input.drawing {
+                                    if p.0 < PANEL_WIDTH as f32 {
+                                        input.stop_drawing();
                                         return;
                                     }
-                                    if input.drawing {
-                                        if p.0 < PANEL_WIDTH as f32 {
-                                            input.stop_drawing();
-                                            return;
-                                        }
                                         
-                                            match input.current_tool {
-                                                input::Tool::Brush => {
-                                                    // Block drawing in UI regions
-                                                    if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 {
-                                                        if let Some(last) = prev {
-                                                            input.brush.stroke(c, last, p);
-                                                        } else {
-                                                            input.brush.stamp(c, p);
-                                                        }
-                                                        w.request_redraw();
-                                                    }
-                                                }
-                                                input::Tool::Eraser => {
-                                                    if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 {
-                                                        c.erase_circle(p.0, p.1, input.brush.radius);
-                                                        if let Some(last) = prev {
-                                                            let dist = ((p.0 - last.0).powi(2) + (p.1 - last.1).powi(2)).sqrt();
-                                                            let steps = (dist / (input.brush.radius / 2.0)).ceil().max(1.0) as i32;
-                                                            for i in 0..=steps {
-                                                                let t = i as f32 / steps as f32;
-                                                                let ix = last.0 + (p.0 - last.0) * t;
-                                                                let iy = last.1 + (p.1 - last.1) * t;
-                                                                c.erase_circle(ix, iy, input.brush.radius);
-                                                            }
-                                                        }
-                                                        w.request_redraw();
-                                                    }
+                                        match input.current_tool {
+                                            // Block drawing in UI regions
+                                            input::Tool::Brush if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 => {
+                                                if let Some(last) = prev {
+                                                    input.brush.stroke(c, last, p);
+                                                } else {
+                                                    input.brush.stamp(c, p);
                                                 }
-                                                input::Tool::Blur => {
-                                                    if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 {
-                                                        c.blur_circle(p.0, p.1, input.brush.radius);
-                                                        if let Some(last) = prev {
-                                                            let dist = ((p.0 - last.0).powi(2) + (p.1 - last.1).powi(2)).sqrt();
-                                                            let steps = (dist / (input.brush.radius / 2.0)).ceil().max(1.0) as i32;
-                                                            for i in 0..=steps {
-                                                                let t = i as f32 / steps as f32;
-                                                                let ix = last.0 + (p.0 - last.0) * t;
-                                                                let iy = last.1 + (p.1 - last.1) * t;
-                                                                c.blur_circle(ix, iy, input.brush.radius);
-                                                            }
-                                                        }
-                                                        w.request_redraw();
+                                                w.request_redraw();
+                                            }
+                                            input::Tool::Eraser if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 => {
+                                                c.erase_circle(p.0, p.1, input.brush.radius);
+                                                if let Some(last) = prev {
+                                                    let dist = ((p.0 - last.0).powi(2) + (p.1 - last.1).powi(2)).sqrt();
+                                                    let steps = (dist / (input.brush.radius / 2.0)).ceil().max(1.0) as i32;
+                                                    for i in 0..=steps {
+                                                        let t = i as f32 / steps as f32;
+                                                        let ix = last.0 + (p.0 - last.0) * t;
+                                                        let iy = last.1 + (p.1 - last.1) * t;
+                                                        c.erase_circle(ix, iy, input.brush.radius);
                                                     }
                                                 }
-                                            input::Tool::Move => {
+                                                w.request_redraw();
+                                            }
+                                            input::Tool::Blur if p.0 >= PANEL_WIDTH as f32 && p.1 >= TOOLBAR_HEIGHT as f32 => {
+                                                c.blur_circle(p.0, p.1, input.brush.radius);
                                                 if let Some(last) = prev {
-                                                    let dx = ((p.0 - last.0) / c.zoom_scale) as i32;
-                                                    let dy = ((p.1 - last.1) / c.zoom_scale) as i32;
-                                                    if dx != 0 || dy != 0 {
-                                                        c.pan_image(dx, dy);
-                                                        w.request_redraw();
+                                                    let dist = ((p.0 - last.0).powi(2) + (p.1 - last.1).powi(2)).sqrt();
+                                                    let steps = (dist / (input.brush.radius / 2.0)).ceil().max(1.0) as i32;
+                                                    for i in 0..=steps {
+                                                        let t = i as f32 / steps as f32;
+                                                        let ix = last.0 + (p.0 - last.0) * t;
+                                                        let iy = last.1 + (p.1 - last.1) * t;
+                                                        c.blur_circle(ix, iy, input.brush.radius);
                                                     }
                                                 }
+                                                w.request_redraw();
                                             }
-                                            _ => {}
-                                        }
+                                        _ => {}
                                     }
                                 }
                             }
-                            WindowEvent::RedrawRequested => {
-                                draw_ui(c, &input.brush, input.brightness, &input, &icons);
+                        }
+                        WindowEvent::RedrawRequested => {
+                            draw_ui(c, &input.brush, input.brightness, &input, &icons);
                                 
-                                if let Err(e) = g.render(c) {
-                                    match e {
-                                        wgpu::SurfaceError::Lost => {
-                                            g.resize(window_size);
-                                            c.dirty = true;
-                                        }
-                                        wgpu::SurfaceError::OutOfMemory => elwt.exit(),
-                                        other => eprintln!("{other:?}"),
+                            if let Err(e) = g.render(c) {
+                                match e {
+                                    wgpu::SurfaceError::Lost => {
+                                        g.resize(window_size);
+                                        c.dirty = true;
                                     }
+                                    wgpu::SurfaceError::OutOfMemory => elwt.exit(),
+                                    other => eprintln!("{other:?}"),
                                 }
                             }
-                            _ => {}
                         }
+                        _ => {}
                     }
                 }
             }
 
             Event::AboutToWait => {
-                if let (Some(w), Some(c)) = (window.as_ref(), canvas.as_ref()) {
-                    if c.dirty {
-                        w.request_redraw();
+                // Keep the marching ants moving while something is selected
+                let mut wake_at = None;
+                if let Some(c) = canvas.as_mut()
+                    && c.document.selection.is_some() && !c.document.quick_mask
+                {
+                    if last_ants_step.elapsed() >= ANTS_INTERVAL {
+                        c.animate_selection();
+                        last_ants_step = Instant::now();
                     }
+                    wake_at = Some(last_ants_step + ANTS_INTERVAL);
+                }
+                if let (Some(w), Some(c)) = (window.as_ref(), canvas.as_ref())
+                    && c.dirty
+                {
+                    w.request_redraw();
+                }
+                // Periodic autosave; wake up for the next one even when idle
+                if let Some(s) = session.as_mut() {
+                    if s.due() {
+                        s.autosave(&history);
+                    }
+                    wake_at = Some(wake_at.map_or(s.next_due(), |t: Instant| t.min(s.next_due())));
+                }
+                match wake_at {
+                    Some(t) => elwt.set_control_flow(ControlFlow::WaitUntil(t)),
+                    None => elwt.set_control_flow(ControlFlow::Wait),
                 }
             }
 
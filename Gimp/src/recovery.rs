@@ -0,0 +1,366 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::document::Document;
+use crate::history::History;
+use crate::io::{self, IoResult};
+
+/// How often the working document is written to the recovery folder
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Without a way to ask whether a process is alive, a session whose heartbeat
+/// is older than this counts as crashed
+const STALE_AFTER: Duration = Duration::from_secs(3 * 60);
+
+/// Contents of session.json in a session's recovery folder
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionManifest {
+    pid: u32,
+    heartbeat: u64,   // Seconds since the Unix epoch, refreshed on every autosave
+    states: Vec<u64>, // History state ids, oldest first, each stored as state_<id>.mgp
+    position: usize,  // Index into `states` of the state shown on the canvas
+}
+
+/// Where recovery sessions live: ~/.mygimp/recovery, or the temp folder without a home
+pub fn recovery_root() -> PathBuf {
+    match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        Some(home) => Path::new(&home).join(".mygimp").join("recovery"),
+        None => std::env::temp_dir().join("mygimp-recovery"),
+    }
+}
+
+/// Recovery folder of the running editor. It is removed by `finish` on a clean
+/// exit; after a crash it stays on disk so the next start can offer to restore it.
+pub struct Session {
+    dir: PathBuf,
+    written: Vec<u64>,                        // State ids already on disk
+    last_saved: Option<(Vec<u64>, usize)>,    // History layout of the last finished autosave
+    next_due: Instant,
+    worker: Option<JoinHandle<IoResult<()>>>, // Autosave running in the background
+    retire: Vec<PathBuf>,                     // Restored sessions to delete once this one is saved
+}
+
+impl Session {
+    /// Create a fresh session folder under `root`
+    pub fn start(root: &Path) -> IoResult<Self> {
+        let pid = std::process::id();
+        let dir = root.join(format!("session-{}-{}", pid, unix_now()));
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create recovery folder {}: {}", dir.display(), e))?;
+        write_manifest(&dir, &SessionManifest { pid, heartbeat: unix_now(), states: vec![], position: 0 })?;
+        Ok(Self {
+            dir,
+            written: Vec::new(),
+            last_saved: None,
+            next_due: Instant::now() + AUTOSAVE_INTERVAL,
+            worker: None,
+            retire: Vec::new(),
+        })
+    }
+
+    pub fn next_due(&self) -> Instant {
+        self.next_due
+    }
+
+    pub fn due(&self) -> bool {
+        Instant::now() >= self.next_due
+    }
+
+    /// Delete a restored session's folder once this session has autosaved the
+    /// restored work, and autosave as soon as possible
+    pub fn retire_after_next_save(&mut self, dir: PathBuf) {
+        self.retire.push(dir);
+        self.next_due = Instant::now();
+    }
+
+    /// Write history states that are not on disk yet on a background thread.
+    /// Unchanged history only refreshes the heartbeat.
+    pub fn autosave(&mut self, history: &History) {
+        self.next_due = Instant::now() + AUTOSAVE_INTERVAL;
+        if self.worker.as_ref().is_some_and(|w| !w.is_finished()) {
+            return;
+        }
+        self.collect_worker();
+
+        let ids: Vec<u64> = history.states().iter().map(|s| s.id).collect();
+        let position = history.position();
+        let manifest = SessionManifest { pid: std::process::id(), heartbeat: unix_now(), states: ids.clone(), position };
+        if self.last_saved.as_ref() == Some(&(ids.clone(), position)) {
+            if let Err(e) = write_manifest(&self.dir, &manifest) {
+                eprintln!("✗ Autosave failed: {}", e);
+            }
+            return;
+        }
+
+        // States never change once pushed, so only new ones need encoding
+        let pending: Vec<(u64, Document)> = history
+            .states()
+            .iter()
+            .filter(|s| !self.written.contains(&s.id))
+            .map(|s| (s.id, s.document.clone()))
+            .collect();
+        let dir = self.dir.clone();
+        self.written = ids.clone();
+        self.last_saved = Some((ids, position));
+        self.worker = Some(std::thread::spawn(move || {
+            for (id, document) in &pending {
+                save_state(&dir, *id, document)?;
+            }
+            write_manifest(&dir, &manifest)?;
+            remove_unlisted_states(&dir, &manifest.states);
+            Ok(())
+        }));
+    }
+
+    /// Handle the result of a finished background autosave
+    fn collect_worker(&mut self) {
+        let Some(worker) = self.worker.take() else {
+            return;
+        };
+        match worker.join() {
+            Ok(Ok(())) => {
+                for dir in self.retire.drain(..) {
+                    discard_session(&dir);
+                }
+            }
+            Ok(Err(e)) => {
+                eprintln!("✗ Autosave failed: {}", e);
+                // Write everything again next time
+                self.written.clear();
+                self.last_saved = None;
+            }
+            Err(_) => {
+                eprintln!("✗ Autosave thread panicked");
+                self.written.clear();
+                self.last_saved = None;
+            }
+        }
+    }
+
+    /// Clean exit: wait for a running autosave and remove the session folder
+    pub fn finish(mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        for dir in self.retire.drain(..) {
+            discard_session(&dir);
+        }
+        discard_session(&self.dir);
+    }
+}
+
+/// Work recovered from a session that did not exit cleanly
+pub struct RecoveredSession {
+    pub documents: Vec<Document>, // History states, oldest first
+    pub position: usize,
+}
+
+/// Session folders under `root` left behind by editors that did not exit
+/// cleanly, most recent first. Abandoned sessions that never autosaved
+/// anything are removed instead of being offered.
+pub fn crashed_sessions(root: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut sessions = Vec::new();
+    for dir in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+        let Ok(manifest) = read_manifest(&dir) else {
+            continue;
+        };
+        if !is_abandoned(&manifest) {
+            continue;
+        }
+        if manifest.states.is_empty() {
+            discard_session(&dir);
+        } else {
+            sessions.push((manifest.heartbeat, dir));
+        }
+    }
+    sessions.sort_by_key(|(heartbeat, _)| std::cmp::Reverse(*heartbeat));
+    sessions.into_iter().map(|(_, dir)| dir).collect()
+}
+
+/// Load every autosaved history state of a session
+pub fn load_session(dir: &Path) -> IoResult<RecoveredSession> {
+    let manifest = read_manifest(dir)?;
+    if manifest.states.is_empty() {
+        return Err("Nothing was autosaved in this session".to_string());
+    }
+    let mut documents = Vec::new();
+    for id in &manifest.states {
+        let (project, layers) = io::load_project_archive(&state_file(dir, *id)?)?;
+        documents.push(Document::from_project(&project, layers));
+    }
+    Ok(RecoveredSession { documents, position: manifest.position })
+}
+
+pub fn discard_session(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        eprintln!("✗ Failed to remove recovery folder {}: {}", dir.display(), e);
+    }
+}
+
+fn is_abandoned(manifest: &SessionManifest) -> bool {
+    if manifest.pid == std::process::id() {
+        return false;
+    }
+    // /proc answers directly on Linux; elsewhere fall back to the heartbeat age
+    if Path::new("/proc/self").exists() {
+        return !Path::new(&format!("/proc/{}", manifest.pid)).exists();
+    }
+    unix_now().saturating_sub(manifest.heartbeat) > STALE_AFTER.as_secs()
+}
+
+fn state_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("state_{:06}.{}", id, io::PROJECT_ARCHIVE_EXTENSION))
+}
+
+/// `state_path` as the string the archive functions take
+fn state_file(dir: &Path, id: u64) -> IoResult<String> {
+    let path = state_path(dir, id);
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Recovery path {} is not valid UTF-8", path.display()))
+}
+
+fn save_state(dir: &Path, id: u64, document: &Document) -> IoResult<()> {
+    let project = document.project(format!("Autosave {}", id));
+    let layers: Vec<_> = document.leaf_layers().into_iter().cloned().collect();
+    io::save_project_archive(&project, &layers, &state_file(dir, id)?)
+}
+
+/// Delete state files of history entries that were undone and overwritten or dropped
+fn remove_unlisted_states(dir: &Path, keep: &[u64]) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let keep: Vec<PathBuf> = keep.iter().map(|id| state_path(dir, *id)).collect();
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        let is_state = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("state_"));
+        if is_state && !keep.contains(&path) {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+fn read_manifest(dir: &Path) -> IoResult<SessionManifest> {
+    let json = fs::read_to_string(dir.join("session.json"))
+        .map_err(|e| format!("Failed to read session.json: {}", e))?;
+    serde_json::from_str(&json).map_err(|e| format!("Failed to parse session.json: {}", e))
+}
+
+/// Replace session.json in one rename, so a crash never leaves half a manifest
+fn write_manifest(dir: &Path, manifest: &SessionManifest) -> IoResult<()> {
+    let json = serde_json::to_string_pretty(manifest)
+        .map_err(|e| format!("Failed to serialize session: {}", e))?;
+    let tmp = dir.join("session.json.tmp");
+    fs::write(&tmp, json).map_err(|e| format!("Failed to write session.json: {}", e))?;
+    fs::rename(&tmp, dir.join("session.json")).map_err(|e| format!("Failed to write session.json: {}", e))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+
+    fn wait_for_autosave(session: &mut Session) {
+        if let Some(worker) = session.worker.take() {
+            assert!(worker.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_autosave_restores_history_position() {
+        let root = Path::new("test_recovery_restore");
+        let _ = fs::remove_dir_all(root);
+
+        let mut canvas = Canvas::new(4, 4);
+        let mut history = History::new();
+        history.push(&canvas);
+        canvas.document.add_layer();
+        history.push(&canvas);
+        canvas.document.add_layer();
+        history.push(&canvas);
+        history.undo(&mut canvas);
+
+        let mut session = Session::start(root).unwrap();
+        session.autosave(&history);
+        wait_for_autosave(&mut session);
+
+        // Pretend the session belongs to a process that died
+        let mut manifest = read_manifest(&session.dir).unwrap();
+        manifest.pid = u32::MAX;
+        manifest.heartbeat = 0;
+        write_manifest(&session.dir, &manifest).unwrap();
+        assert_eq!(crashed_sessions(root), vec![session.dir.clone()]);
+
+        let recovered = load_session(&session.dir).unwrap();
+        assert_eq!(recovered.documents.len(), 3);
+        assert_eq!(recovered.position, 1);
+        let restored = History::from_documents(recovered.documents, recovered.position);
+        assert_eq!(restored.current_document().unwrap().leaf_layers().len(), 2);
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_autosave_only_writes_new_states_and_finish_cleans_up() {
+        let root = Path::new("test_recovery_incremental");
+        let _ = fs::remove_dir_all(root);
+
+        let mut canvas = Canvas::new(2, 2);
+        let mut history = History::new();
+        history.push(&canvas);
+        let mut session = Session::start(root).unwrap();
+        session.autosave(&history);
+        wait_for_autosave(&mut session);
+        assert!(state_path(&session.dir, 0).exists());
+
+        // Undo then branch: state 1 is dropped from the history and from disk
+        canvas.document.add_layer();
+        history.push(&canvas);
+        session.autosave(&history);
+        wait_for_autosave(&mut session);
+        history.undo(&mut canvas);
+        canvas.document.add_layer();
+        history.push(&canvas);
+        session.autosave(&history);
+        wait_for_autosave(&mut session);
+        assert!(!state_path(&session.dir, 1).exists());
+        assert!(state_path(&session.dir, 2).exists());
+
+        // A live session is never offered for recovery
+        assert!(crashed_sessions(root).is_empty());
+        let dir = session.dir.clone();
+        session.finish();
+        assert!(!dir.exists());
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_session_folder_is_an_error() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = Path::new("test_recovery_non_utf8");
+        let _ = fs::remove_dir_all(root);
+        let dir = root.join(OsStr::from_bytes(b"session-\xff"));
+        fs::create_dir_all(&dir).unwrap();
+        write_manifest(&dir, &SessionManifest { pid: u32::MAX, heartbeat: 0, states: vec![0], position: 0 }).unwrap();
+
+        assert!(load_session(&dir).is_err());
+        assert!(save_state(&dir, 1, &Canvas::new(2, 2).document).is_err());
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
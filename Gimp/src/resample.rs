@@ -0,0 +1,260 @@
+/// Interpolation used when scaling pixels, as in GIMP's Scale Image dialog
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+    Area, // Averages every source pixel the destination pixel covers
+}
+
+impl Filter {
+    pub const ALL: [Filter; 5] = [Filter::Nearest, Filter::Bilinear, Filter::Bicubic, Filter::Lanczos3, Filter::Area];
+
+    /// The filter after this one in `ALL`, wrapping around
+    pub fn next(self) -> Filter {
+        let index = Self::ALL.iter().position(|f| *f == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// Half-width of the kernel in source pixels at 1:1
+    pub fn support(self) -> f32 {
+        match self {
+            Filter::Nearest | Filter::Area => 0.5,
+            Filter::Bilinear => 1.0,
+            Filter::Bicubic => 2.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// Kernel weight at distance `x` (in source pixels at 1:1)
+    pub fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Nearest | Filter::Area => if x < 0.5 { 1.0 } else { 0.0 },
+            Filter::Bilinear => (1.0 - x).max(0.0),
+            Filter::Bicubic => {
+                // Catmull-Rom (a = -0.5)
+                if x < 1.0 {
+                    1.5 * x * x * x - 2.5 * x * x + 1.0
+                } else if x < 2.0 {
+                    -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos3 => {
+                if x < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+/// Source pixels and weights that make up each destination pixel along one axis
+fn axis_weights(len: u32, new_len: u32, filter: Filter) -> Vec<(usize, Vec<f32>)> {
+    let ratio = len as f32 / new_len as f32;
+    (0..new_len)
+        .map(|d| {
+            let (start, mut weights) = match filter {
+                Filter::Nearest => {
+                    let i = (((d as f32 + 0.5) * ratio) as usize).min(len as usize - 1);
+                    (i, vec![1.0])
+                }
+                Filter::Area => {
+                    // Overlap of each source pixel with the destination footprint
+                    let (lo, hi) = (d as f32 * ratio, (d + 1) as f32 * ratio);
+                    let start = lo.floor() as usize;
+                    let end = (hi.ceil() as usize).min(len as usize).max(start + 1);
+                    let weights = (start..end).map(|i| (hi.min(i as f32 + 1.0) - lo.max(i as f32)).max(0.0)).collect();
+                    (start, weights)
+                }
+                _ => {
+                    // Widen the kernel when shrinking so every source pixel contributes
+                    let scale = ratio.max(1.0);
+                    let center = (d as f32 + 0.5) * ratio - 0.5;
+                    let support = filter.support() * scale;
+                    let first = (center - support).floor() as i64;
+                    let last = (center + support).ceil() as i64;
+                    let mut weights = vec![0.0; len as usize];
+                    let (mut lo, mut hi) = (len as usize, 0);
+                    for i in first..=last {
+                        let w = filter.weight((i as f32 - center) / scale);
+                        if w == 0.0 {
+                            continue;
+                        }
+                        // Edge pixels repeat outside the image
+                        let clamped = i.clamp(0, len as i64 - 1) as usize;
+                        weights[clamped] += w;
+                        lo = lo.min(clamped);
+                        hi = hi.max(clamped);
+                    }
+                    if lo > hi {
+                        let i = (center.round().max(0.0) as usize).min(len as usize - 1);
+                        (i, vec![1.0])
+                    } else {
+                        (lo, weights[lo..=hi].to_vec())
+                    }
+                }
+            };
+            let sum: f32 = weights.iter().sum();
+            if sum != 0.0 {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            }
+            (start, weights)
+        })
+        .collect()
+}
+
+/// Scale a row-major `size` buffer with `channels` bytes per pixel to `new_size`.
+/// Four-channel buffers are RGBA and are filtered premultiplied by alpha, so
+/// transparent pixels don't bleed their color into the edges around them.
+pub fn resample(pixels: &[u8], size: (u32, u32), new_size: (u32, u32), channels: usize, filter: Filter) -> Vec<u8> {
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let (new_width, new_height) = (new_size.0 as usize, new_size.1 as usize);
+    if width == 0 || height == 0 || new_width == 0 || new_height == 0 {
+        return vec![0; new_width * new_height * channels];
+    }
+    let source = to_filter_space(pixels, channels);
+
+    // Horizontal pass, then vertical
+    let columns = axis_weights(size.0, new_size.0, filter);
+    let mut wide = vec![0.0f32; new_width * height * channels];
+    for y in 0..height {
+        for (x, (start, weights)) in columns.iter().enumerate() {
+            let out = (y * new_width + x) * channels;
+            for (k, w) in weights.iter().enumerate() {
+                let src = (y * width + start + k) * channels;
+                for c in 0..channels {
+                    wide[out + c] += source[src + c] * w;
+                }
+            }
+        }
+    }
+    let rows = axis_weights(size.1, new_size.1, filter);
+    let mut scaled = vec![0.0f32; new_width * new_height * channels];
+    for (y, (start, weights)) in rows.iter().enumerate() {
+        for (k, w) in weights.iter().enumerate() {
+            let src_row = (start + k) * new_width * channels;
+            let out_row = y * new_width * channels;
+            for i in 0..new_width * channels {
+                scaled[out_row + i] += wide[src_row + i] * w;
+            }
+        }
+    }
+
+    from_filter_space(&scaled, channels)
+}
+
+/// Bytes as floats ready for filtering; RGBA (four channels) is premultiplied by alpha
+pub fn to_filter_space(pixels: &[u8], channels: usize) -> Vec<f32> {
+    let mut values: Vec<f32> = pixels.iter().map(|&v| v as f32).collect();
+    if channels == 4 {
+        for px in values.chunks_exact_mut(4) {
+            let a = px[3] / 255.0;
+            px.iter_mut().take(3).for_each(|c| *c *= a);
+        }
+    }
+    values
+}
+
+/// Filtered floats back to bytes, undoing `to_filter_space`
+pub fn from_filter_space(values: &[f32], channels: usize) -> Vec<u8> {
+    let mut out = vec![0u8; values.len()];
+    if channels == 4 {
+        for (px, dst) in values.chunks_exact(4).zip(out.chunks_exact_mut(4)) {
+            // Bicubic and Lanczos overshoot; keep colors within the alpha they were multiplied by
+            let alpha = px[3].clamp(0.0, 255.0);
+            if alpha > 0.0 {
+                for c in 0..3 {
+                    dst[c] = (px[c].clamp(0.0, alpha) * 255.0 / alpha).round() as u8;
+                }
+            }
+            dst[3] = alpha.round() as u8;
+        }
+    } else {
+        for (v, dst) in values.iter().zip(out.iter_mut()) {
+            *dst = v.clamp(0.0, 255.0).round() as u8;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_image_stays_flat_with_every_filter() {
+        let pixels = [10u8, 200, 30, 255].repeat(5 * 3);
+        for filter in Filter::ALL {
+            for new_size in [(12, 7), (2, 1), (5, 3)] {
+                let out = resample(&pixels, (5, 3), new_size, 4, filter);
+                assert_eq!(out.len(), (new_size.0 * new_size.1 * 4) as usize);
+                assert!(out.chunks_exact(4).all(|px| px == [10, 200, 30, 255]), "{:?} {:?}", filter, new_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nearest_and_area_sampling() {
+        // Column index in red, row index in green
+        let mut pixels = Vec::new();
+        for y in 0..2u8 {
+            for x in 0..4u8 {
+                pixels.extend_from_slice(&[x * 60, y * 100, 0, 255]);
+            }
+        }
+        let nearest = resample(&pixels, (4, 2), (8, 4), 4, Filter::Nearest);
+        assert_eq!(&nearest[(3 * 8 + 5) * 4..(3 * 8 + 5) * 4 + 4], &[120, 100, 0, 255]);
+
+        // Halving averages each 2x2 block
+        let area = resample(&pixels, (4, 2), (2, 1), 4, Filter::Area);
+        assert_eq!(area, vec![30, 50, 0, 255, 150, 50, 0, 255]);
+    }
+
+    #[test]
+    fn test_transparent_edges_do_not_darken() {
+        // Opaque red next to fully transparent black
+        let pixels = vec![255, 0, 0, 255, 0, 0, 0, 0];
+        for filter in [Filter::Bilinear, Filter::Bicubic, Filter::Lanczos3] {
+            let out = resample(&pixels, (2, 1), (8, 1), 4, filter);
+            for px in out.chunks_exact(4).filter(|px| px[3] > 0) {
+                assert_eq!(&px[..3], &[255, 0, 0], "{:?}", filter);
+            }
+            assert!(out.chunks_exact(4).any(|px| px[3] > 0 && px[3] < 255), "{:?}", filter);
+        }
+
+        // Area averaging blends the edge when shrinking
+        let pixels = [pixels.clone(), vec![0, 0, 0, 0]].concat();
+        assert_eq!(resample(&pixels, (3, 1), (2, 1), 4, Filter::Area), vec![255, 0, 0, 170, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_single_channel_masks_are_filtered_straight() {
+        let mask = vec![0, 255];
+        let out = resample(&mask, (2, 1), (1, 1), 1, Filter::Bilinear);
+        assert_eq!(out, vec![128]);
+    }
+
+    #[test]
+    fn test_filter_cycle_covers_all() {
+        let mut filter = Filter::Nearest;
+        for expected in Filter::ALL.iter().cycle().skip(1).take(5) {
+            filter = filter.next();
+            assert_eq!(filter, *expected);
+        }
+    }
+}
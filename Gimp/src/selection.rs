@@ -0,0 +1,554 @@
+use std::collections::VecDeque;
+
+use crate::layer::{crop_pixels, place_pixels};
+use crate::resample::{resample, Filter};
+
+/// Sub-rows sampled per pixel row when rasterizing shapes; coverage along
+/// each sub-row is exact, so edges get smooth anti-aliasing in both directions
+const SUBSAMPLES: u32 = 4;
+
+/// How a newly drawn shape combines with the current selection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionMode {
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionMode {
+    /// Mode picked by the held modifiers, as in GIMP: Shift adds, Ctrl
+    /// subtracts and both together intersect
+    pub fn from_modifiers(shift: bool, ctrl: bool) -> Self {
+        match (shift, ctrl) {
+            (true, true) => SelectionMode::Intersect,
+            (true, false) => SelectionMode::Add,
+            (false, true) => SelectionMode::Subtract,
+            (false, false) => SelectionMode::Replace,
+        }
+    }
+}
+
+/// Settings of the magic wand tool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WandOptions {
+    pub tolerance: u8,       // Largest per-channel difference from the clicked color that still matches
+    pub contiguous: bool,    // Only the region connected to the clicked pixel, or every matching pixel
+    pub sample_merged: bool, // Compare colors of the merged image instead of the active layer
+}
+
+impl Default for WandOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 32,
+            contiguous: true,
+            sample_merged: false,
+        }
+    }
+}
+
+/// Per-pixel selection coverage in image space: 0 is outside, 255 fully
+/// selected, values in between partially selected (anti-aliased or feathered edges)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub width: u32,
+    pub height: u32,
+    pub mask: Vec<u8>, // One byte per pixel, row-major
+}
+
+impl Selection {
+    /// An empty selection covering nothing
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            mask: vec![0; width as usize * height as usize],
+        }
+    }
+
+    /// Every pixel of the image selected
+    pub fn all(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            mask: vec![255; width as usize * height as usize],
+        }
+    }
+
+    /// Select the pixels between two corners (inclusive, in any order), clamped to the image
+    pub fn rect(width: u32, height: u32, a: (u32, u32), b: (u32, u32)) -> Self {
+        let mut selection = Self::new(width, height);
+        if width == 0 || height == 0 {
+            return selection;
+        }
+        let x0 = a.0.min(b.0).min(width - 1);
+        let x1 = a.0.max(b.0).min(width - 1);
+        let y0 = a.1.min(b.1).min(height - 1);
+        let y1 = a.1.max(b.1).min(height - 1);
+        for y in y0..=y1 {
+            let row = (y * width) as usize;
+            selection.mask[row + x0 as usize..=row + x1 as usize].fill(255);
+        }
+        selection
+    }
+
+    /// Anti-aliased ellipse inscribed in the pixel box between two corners
+    /// (inclusive, in any order)
+    pub fn ellipse(width: u32, height: u32, a: (u32, u32), b: (u32, u32)) -> Self {
+        let (x0, x1) = (a.0.min(b.0) as f32, a.0.max(b.0) as f32 + 1.0);
+        let (y0, y1) = (a.1.min(b.1) as f32, a.1.max(b.1) as f32 + 1.0);
+        let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let (rx, ry) = ((x1 - x0) / 2.0, (y1 - y0) / 2.0);
+        Self::from_spans(width, height, y0, y1, |sy| {
+            let dy = (sy - cy) / ry;
+            if dy.abs() >= 1.0 {
+                return vec![];
+            }
+            let half = rx * (1.0 - dy * dy).sqrt();
+            vec![(cx - half, cx + half)]
+        })
+    }
+
+    /// Anti-aliased polygon through image-space points (pixel (x, y) covers
+    /// [x, x + 1) x [y, y + 1)), closed back to the first point. Self-crossing
+    /// outlines such as freehand lassos are filled with the even-odd rule.
+    pub fn polygon(width: u32, height: u32, points: &[(f32, f32)]) -> Self {
+        if points.len() < 3 {
+            return Self::new(width, height);
+        }
+        let top = points.iter().map(|p| p.1).fold(f32::INFINITY, f32::min);
+        let bottom = points.iter().map(|p| p.1).fold(f32::NEG_INFINITY, f32::max);
+        Self::from_spans(width, height, top, bottom, |sy| {
+            let mut crossings = Vec::new();
+            for (i, &(xa, ya)) in points.iter().enumerate() {
+                let (xb, yb) = points[(i + 1) % points.len()];
+                if (ya <= sy) != (yb <= sy) {
+                    crossings.push(xa + (sy - ya) * (xb - xa) / (yb - ya));
+                }
+            }
+            crossings.sort_by(f32::total_cmp);
+            crossings.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect()
+        })
+    }
+
+    /// Rasterize a shape given by the horizontal spans it covers on a sub-row
+    /// at height `sy`, for the sub-rows between `top` and `bottom`
+    fn from_spans(width: u32, height: u32, top: f32, bottom: f32, spans: impl Fn(f32) -> Vec<(f32, f32)>) -> Self {
+        let mut selection = Self::new(width, height);
+        let first_row = top.floor().max(0.0) as u32;
+        let last_row = (bottom.ceil().max(0.0) as u32).min(height);
+        let mut coverage = vec![0.0f32; width as usize];
+        for y in first_row..last_row {
+            coverage.fill(0.0);
+            for sub in 0..SUBSAMPLES {
+                let sy = y as f32 + (sub as f32 + 0.5) / SUBSAMPLES as f32;
+                for (xa, xb) in spans(sy) {
+                    let (xa, xb) = (xa.max(0.0), xb.min(width as f32));
+                    if xb <= xa {
+                        continue;
+                    }
+                    for x in xa.floor() as u32..(xb.ceil() as u32).min(width) {
+                        let overlap = xb.min(x as f32 + 1.0) - xa.max(x as f32);
+                        coverage[x as usize] += overlap / SUBSAMPLES as f32;
+                    }
+                }
+            }
+            let row = (y * width) as usize;
+            for (x, value) in coverage.iter().enumerate() {
+                selection.mask[row + x] = (value.min(1.0) * 255.0).round() as u8;
+            }
+        }
+        selection
+    }
+
+    /// Coverage at (x, y); pixels outside the image are never selected
+    pub fn value(&self, x: u32, y: u32) -> u8 {
+        if x < self.width && y < self.height {
+            self.mask[(y * self.width + x) as usize]
+        } else {
+            0
+        }
+    }
+
+    /// Whether (x, y) counts as inside when drawing the outline (at least half covered)
+    pub fn contains(&self, x: i64, y: i64) -> bool {
+        x >= 0 && y >= 0 && self.value(x as u32, y as u32) >= 128
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mask.iter().all(|&v| v == 0)
+    }
+
+    /// Keep only the `width` x `height` block at (x, y)
+    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        self.mask = crop_pixels(&self.mask, self.width, 1, (x, y, width, height));
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Change the selection size, moving the mask by `offset`; new area is unselected
+    pub fn resize(&mut self, width: u32, height: u32, offset: (i32, i32)) {
+        self.mask = place_pixels(&self.mask, (self.width, self.height), &[0], (width, height), offset);
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Resample the selection mask to a new size
+    pub fn scale(&mut self, width: u32, height: u32, filter: Filter) {
+        self.mask = resample(&self.mask, (self.width, self.height), (width, height), 1, filter);
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Swap selected and unselected pixels
+    pub fn invert(&mut self) {
+        for value in &mut self.mask {
+            *value = 255 - *value;
+        }
+    }
+
+    /// Expand the selection outwards by `radius` pixels (rounded corners)
+    pub fn grow(&mut self, radius: u32) {
+        self.mask = self.morph(radius, true);
+    }
+
+    /// Pull the selection edge inwards by `radius` pixels. The image border
+    /// counts as unselected, so the selection also shrinks away from it.
+    pub fn shrink(&mut self, radius: u32) {
+        self.mask = self.morph(radius, false);
+    }
+
+    /// Replace the selection by a band `radius` pixels wide on either side of its edge
+    pub fn border(&mut self, radius: u32) {
+        let outer = self.morph(radius, true);
+        let inner = self.morph(radius, false);
+        self.mask = outer.iter().zip(&inner).map(|(o, i)| o.saturating_sub(*i)).collect();
+    }
+
+    /// Soften the edge with a Gaussian blur, so the selection fades out over
+    /// roughly `radius` pixels on each side of its edge
+    pub fn feather(&mut self, radius: f32) {
+        if radius <= 0.0 {
+            return;
+        }
+        let sigma = radius / 2.0;
+        let half = (sigma * 3.0).ceil() as i64;
+        let kernel: Vec<f32> = (-half..=half).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+        let total: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|k| k / total).collect();
+        let blurred = self.convolve(&kernel);
+        self.mask = blurred.iter().map(|v| v.round().clamp(0.0, 255.0) as u8).collect();
+    }
+
+    /// Round off jagged edges and sharp corners and drop specks and holes smaller
+    /// than about `radius` pixels: a box blur followed by a 50% threshold
+    pub fn smooth(&mut self, radius: u32) {
+        if radius == 0 {
+            return;
+        }
+        let kernel = vec![1.0 / (2 * radius + 1) as f32; 2 * radius as usize + 1];
+        let blurred = self.convolve(&kernel);
+        self.mask = blurred.iter().map(|&v| if v >= 127.5 { 255 } else { 0 }).collect();
+    }
+
+    /// Dilate (or erode) the mask with a disk of `radius` pixels: each disk row
+    /// is a horizontal window whose max (or min) is taken along the image rows
+    fn morph(&self, radius: u32, dilate: bool) -> Vec<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let r = radius as i64;
+        let mut out = vec![if dilate { 0 } else { 255 }; width * height];
+        let mut window = vec![0u8; width];
+        for dy in -r..=r {
+            let half = ((r * r - dy * dy) as f64).sqrt().floor() as usize;
+            for y in 0..height {
+                let src_y = y as i64 + dy;
+                let row_out = &mut out[y * width..(y + 1) * width];
+                if src_y < 0 || src_y >= height as i64 {
+                    if !dilate {
+                        row_out.fill(0);
+                    }
+                    continue;
+                }
+                let src_y = src_y as usize;
+                window_extreme(&self.mask[src_y * width..(src_y + 1) * width], half, dilate, &mut window);
+                for (dst, &v) in row_out.iter_mut().zip(&window) {
+                    *dst = if dilate { (*dst).max(v) } else { (*dst).min(v) };
+                }
+            }
+        }
+        out
+    }
+
+    /// Separable convolution of the mask with a symmetric kernel, horizontally
+    /// then vertically; pixels outside the image count as unselected
+    fn convolve(&self, kernel: &[f32]) -> Vec<f32> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let half = (kernel.len() / 2) as i64;
+        let sample = |data: &[f32], x: usize, y: usize, dx: i64, dy: i64| {
+            let (sx, sy) = (x as i64 + dx, y as i64 + dy);
+            if sx < 0 || sy < 0 || sx >= width as i64 || sy >= height as i64 {
+                0.0
+            } else {
+                data[sy as usize * width + sx as usize]
+            }
+        };
+        let source: Vec<f32> = self.mask.iter().map(|&v| v as f32).collect();
+        let mut horizontal = vec![0.0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                horizontal[y * width + x] = (-half..=half)
+                    .zip(kernel)
+                    .map(|(d, k)| sample(&source, x, y, d, 0) * k)
+                    .sum();
+            }
+        }
+        let mut out = vec![0.0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                out[y * width + x] = (-half..=half)
+                    .zip(kernel)
+                    .map(|(d, k)| sample(&horizontal, x, y, 0, d) * k)
+                    .sum();
+            }
+        }
+        out
+    }
+
+    /// Smallest rectangle [x0, x1) x [y0, y1) holding every selected pixel
+    pub fn bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.value(x, y) == 0 {
+                    continue;
+                }
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)),
+                    None => (x, y, x + 1, y + 1),
+                });
+            }
+        }
+        bounds
+    }
+}
+
+/// Max (or min) of `row` over the window [x - half, x + half] for every x,
+/// written to `out`. Outside the row counts as 0, so erosion clears the ends.
+fn window_extreme(row: &[u8], half: usize, max: bool, out: &mut [u8]) {
+    let len = row.len();
+    let better = |a: u8, b: u8| if max { a >= b } else { a <= b };
+    // Indices whose values are monotonic (decreasing for max, increasing for min)
+    let mut candidates: VecDeque<usize> = VecDeque::new();
+    let mut next = 0;
+    for (x, slot) in out.iter_mut().enumerate().take(len) {
+        while next < len && next <= x + half {
+            while candidates.back().is_some_and(|&i| better(row[next], row[i])) {
+                candidates.pop_back();
+            }
+            candidates.push_back(next);
+            next += 1;
+        }
+        while candidates.front().is_some_and(|&i| i + half < x) {
+            candidates.pop_front();
+        }
+        let clipped = x < half || x + half >= len;
+        *slot = if !max && clipped { 0 } else { candidates.front().map_or(0, |&i| row[i]) };
+    }
+}
+
+/// Merge a newly drawn shape into the current selection (None meaning nothing
+/// is selected). Returns None when the result selects no pixel at all.
+pub fn combine(current: Option<&Selection>, shape: Selection, mode: SelectionMode) -> Option<Selection> {
+    let combined = match (mode, current) {
+        (SelectionMode::Replace, _) | (SelectionMode::Add, None) => shape,
+        (SelectionMode::Subtract | SelectionMode::Intersect, None) => return None,
+        (_, Some(current)) if current.width != shape.width || current.height != shape.height => shape,
+        (mode, Some(current)) => {
+            let mut out = current.clone();
+            for (dst, &src) in out.mask.iter_mut().zip(&shape.mask) {
+                *dst = match mode {
+                    SelectionMode::Add => (*dst).max(src),
+                    SelectionMode::Subtract => ((*dst as u32 * (255 - src as u32) + 127) / 255) as u8,
+                    SelectionMode::Intersect => (*dst).min(src),
+                    SelectionMode::Replace => src,
+                };
+            }
+            out
+        }
+    };
+    (!combined.is_empty()).then_some(combined)
+}
+
+/// Fade an edited RGBA pixel back to its previous value by the selection
+/// coverage at that pixel (255 keeps the edit, 0 restores the original).
+/// Colors are mixed premultiplied so transparent pixels don't bleed their color.
+pub fn mix_pixel(before: [u8; 4], after: [u8; 4], coverage: u8) -> [u8; 4] {
+    match coverage {
+        255 => return after,
+        0 => return before,
+        _ => {}
+    }
+    let t = coverage as f32 / 255.0;
+    let a0 = before[3] as f32 / 255.0;
+    let a1 = after[3] as f32 / 255.0;
+    let alpha = a0 + (a1 - a0) * t;
+    let mut out = [0u8; 4];
+    if alpha > 0.0 {
+        for c in 0..3 {
+            let value = (before[c] as f32 * a0 * (1.0 - t) + after[c] as f32 * a1 * t) / alpha;
+            out[c] = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    out[3] = (alpha * 255.0).round() as u8;
+    out
+}
+
+/// Same as `mix_pixel` for a single gray value (layer mask pixels)
+pub fn mix_gray(before: u8, after: u8, coverage: u8) -> u8 {
+    let t = coverage as f32 / 255.0;
+    (before as f32 + (after as f32 - before as f32) * t).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_selects_inclusive_corners_in_any_order() {
+        let selection = Selection::rect(5, 4, (3, 2), (1, 1));
+        assert_eq!(selection.bounds(), Some((1, 1, 4, 3)));
+        assert_eq!(selection.value(1, 1), 255);
+        assert_eq!(selection.value(3, 2), 255);
+        assert_eq!(selection.value(0, 1), 0);
+        assert_eq!(selection.value(4, 2), 0);
+        assert_eq!(selection.value(2, 3), 0);
+    }
+
+    #[test]
+    fn test_rect_is_clamped_to_the_image() {
+        let selection = Selection::rect(3, 3, (1, 1), (10, 10));
+        assert_eq!(selection.bounds(), Some((1, 1, 3, 3)));
+        assert_eq!(Selection::new(3, 3).bounds(), None);
+    }
+
+    #[test]
+    fn test_ellipse_is_round_and_anti_aliased() {
+        let selection = Selection::ellipse(20, 20, (0, 0), (19, 19));
+        assert_eq!(selection.value(10, 10), 255);
+        assert_eq!(selection.value(0, 0), 0);
+        assert_eq!(selection.value(19, 19), 0);
+        // The pixel the diagonal edge passes through is only partly covered
+        let edge = selection.value(2, 2);
+        assert!(edge > 0 && edge < 255, "edge coverage {}", edge);
+        assert_eq!(selection.value(10, 0), selection.value(9, 19));
+    }
+
+    #[test]
+    fn test_polygon_covers_its_area() {
+        // Right triangle covering half of a 10x10 square
+        let selection = Selection::polygon(10, 10, &[(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)]);
+        let total: u32 = selection.mask.iter().map(|&v| v as u32).sum();
+        let area = total as f32 / 255.0;
+        assert!((area - 50.0).abs() < 0.5, "area {}", area);
+        assert_eq!(selection.value(1, 1), 255);
+        assert_eq!(selection.value(8, 8), 0);
+        // Pixels on the diagonal are half covered
+        assert!((selection.value(4, 5) as i32 - 128).abs() <= 2);
+        // Fewer than three points select nothing
+        assert!(Selection::polygon(10, 10, &[(0.0, 0.0), (5.0, 5.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_combine_modes() {
+        let left = Selection::rect(4, 1, (0, 0), (1, 0));
+        let middle = Selection::rect(4, 1, (1, 0), (2, 0));
+        let mask = |s: Option<Selection>| s.map(|s| s.mask);
+
+        assert_eq!(mask(combine(Some(&left), middle.clone(), SelectionMode::Replace)), Some(vec![0, 255, 255, 0]));
+        assert_eq!(mask(combine(Some(&left), middle.clone(), SelectionMode::Add)), Some(vec![255, 255, 255, 0]));
+        assert_eq!(mask(combine(Some(&left), middle.clone(), SelectionMode::Subtract)), Some(vec![255, 0, 0, 0]));
+        assert_eq!(mask(combine(Some(&left), middle.clone(), SelectionMode::Intersect)), Some(vec![0, 255, 0, 0]));
+        assert_eq!(combine(Some(&left), left.clone(), SelectionMode::Subtract), None);
+        assert_eq!(combine(None, middle.clone(), SelectionMode::Intersect), None);
+        assert_eq!(mask(combine(None, middle, SelectionMode::Add)), Some(vec![0, 255, 255, 0]));
+        assert_eq!(SelectionMode::from_modifiers(true, true), SelectionMode::Intersect);
+    }
+
+    #[test]
+    fn test_all_and_invert() {
+        let mut selection = Selection::rect(3, 1, (0, 0), (0, 0));
+        selection.invert();
+        assert_eq!(selection.mask, vec![0, 255, 255]);
+        let mut all = Selection::all(2, 2);
+        all.invert();
+        assert!(all.is_empty());
+    }
+
+    #[test]
+    fn test_grow_and_shrink_use_a_round_brush() {
+        let mut selection = Selection::rect(9, 9, (4, 4), (4, 4));
+        selection.grow(2);
+        assert_eq!(selection.bounds(), Some((2, 2, 7, 7)));
+        assert_eq!(selection.value(4, 2), 255);
+        assert_eq!(selection.value(3, 3), 255);
+        // Corners of the 5x5 box lie outside the radius
+        assert_eq!(selection.value(2, 2), 0);
+
+        selection.shrink(2);
+        assert_eq!(selection.mask, Selection::rect(9, 9, (4, 4), (4, 4)).mask);
+
+        let mut all = Selection::all(5, 5);
+        all.shrink(1);
+        assert_eq!(all.bounds(), Some((1, 1, 4, 4)));
+    }
+
+    #[test]
+    fn test_border_selects_a_band_around_the_edge() {
+        let mut selection = Selection::rect(12, 12, (2, 2), (9, 9));
+        selection.border(1);
+        assert_eq!(selection.value(1, 5), 255);
+        assert_eq!(selection.value(2, 5), 255);
+        assert_eq!(selection.value(3, 5), 0);
+        assert_eq!(selection.value(5, 5), 0);
+        assert_eq!(selection.value(0, 5), 0);
+    }
+
+    #[test]
+    fn test_feather_softens_the_edge() {
+        let mut selection = Selection::rect(40, 21, (0, 0), (19, 20));
+        selection.feather(6.0);
+        // Sampled along the middle row, far from the top and bottom image edges
+        let inside = selection.value(10, 10);
+        let edge_in = selection.value(19, 10);
+        let edge_out = selection.value(20, 10);
+        let outside = selection.value(30, 10);
+        assert!(inside > edge_in && edge_in > edge_out && edge_out > outside);
+        assert!((edge_in as i32 + edge_out as i32 - 255).abs() <= 2);
+    }
+
+    #[test]
+    fn test_smooth_removes_specks_and_keeps_large_areas() {
+        let mut selection = Selection::rect(20, 20, (5, 5), (14, 14));
+        selection.mask[0] = 255; // A lone selected pixel
+        selection.mask[(9 * 20 + 9) as usize] = 0; // A one pixel hole
+        selection.smooth(2);
+        assert_eq!(selection.value(0, 0), 0);
+        assert_eq!(selection.value(9, 9), 255);
+        assert_eq!(selection.value(7, 10), 255);
+        assert_eq!(selection.value(3, 10), 0);
+        // The sharp corner gets rounded off
+        assert_eq!(selection.value(5, 5), 0);
+    }
+
+    #[test]
+    fn test_mix_follows_coverage() {
+        let before = [0, 0, 0, 255];
+        let after = [255, 255, 255, 255];
+        assert_eq!(mix_pixel(before, after, 255), after);
+        assert_eq!(mix_pixel(before, after, 0), before);
+        assert_eq!(mix_pixel(before, after, 128)[0], 128);
+        // A half-selected stroke onto transparency keeps the stroke color
+        assert_eq!(mix_pixel([255, 255, 255, 0], [255, 0, 0, 255], 128), [255, 0, 0, 128]);
+        assert_eq!(mix_gray(0, 255, 51), 51);
+    }
+}
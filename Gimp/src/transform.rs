@@ -0,0 +1,278 @@
+use crate::layer::Layer;
+use crate::resample::{from_filter_space, to_filter_space, Filter};
+
+/// Quarter-turn rotations, clockwise as seen on screen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Cw90,
+    Half,
+    Ccw90,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flip {
+    Horizontal, // Mirror left to right
+    Vertical,   // Mirror top to bottom
+}
+
+/// A rotation or mirror of a pixel buffer about its center
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    Rotate(Rotation),
+    Flip(Flip),
+    /// Clockwise rotation by any angle. With `expand` the result grows to hold
+    /// the whole rotated image; otherwise it keeps the original size.
+    Angle { degrees: f32, filter: Filter, expand: bool },
+}
+
+impl Transform {
+    /// The same transform, growing the result to fit for arbitrary angles
+    pub fn expanded(self) -> Self {
+        match self {
+            Transform::Angle { degrees, filter, .. } => Transform::Angle { degrees, filter, expand: true },
+            other => other,
+        }
+    }
+
+    /// Size of a `size` buffer after the transform
+    pub fn output_size(&self, size: (u32, u32)) -> (u32, u32) {
+        match self {
+            Transform::Rotate(Rotation::Cw90 | Rotation::Ccw90) => (size.1, size.0),
+            Transform::Angle { degrees, expand: true, .. } => {
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                let (w, h) = (size.0 as f32, size.1 as f32);
+                // Trim float noise so exact quarter turns don't grow by a pixel
+                let fit = |v: f32| ((v - 1e-3).ceil() as u32).max(1);
+                (fit(w * cos.abs() + h * sin.abs()), fit(w * sin.abs() + h * cos.abs()))
+            }
+            _ => size,
+        }
+    }
+
+    /// Where a point of a `size` buffer lands in the transformed buffer
+    /// (continuous coordinates, pixel edges at integers)
+    pub fn map_point(&self, point: (f32, f32), size: (u32, u32)) -> (f32, f32) {
+        let (w, h) = (size.0 as f32, size.1 as f32);
+        let (x, y) = point;
+        match self {
+            Transform::Rotate(Rotation::Cw90) => (h - y, x),
+            Transform::Rotate(Rotation::Half) => (w - x, h - y),
+            Transform::Rotate(Rotation::Ccw90) => (y, w - x),
+            Transform::Flip(Flip::Horizontal) => (w - x, y),
+            Transform::Flip(Flip::Vertical) => (x, h - y),
+            Transform::Angle { degrees, .. } => {
+                let (new_w, new_h) = self.output_size(size);
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                let (dx, dy) = (x - w / 2.0, y - h / 2.0);
+                (new_w as f32 / 2.0 + dx * cos - dy * sin, new_h as f32 / 2.0 + dx * sin + dy * cos)
+            }
+        }
+    }
+
+    /// Apply the transform to a row-major `size` buffer with `fill.len()` bytes
+    /// per pixel. Pixels with no source (corners of an arbitrary rotation) get `fill`.
+    pub fn apply(&self, pixels: &[u8], size: (u32, u32), fill: &[u8]) -> (Vec<u8>, (u32, u32)) {
+        let channels = fill.len();
+        let new_size = self.output_size(size);
+        let (w, h) = (size.0 as usize, size.1 as usize);
+        let out = match self {
+            Transform::Angle { degrees, filter, .. } => rotate_by(pixels, size, fill, *degrees, *filter, new_size),
+            _ => {
+                // Quarter turns and mirrors only move whole pixels
+                let mut out = Vec::with_capacity(pixels.len());
+                for y in 0..new_size.1 as usize {
+                    for x in 0..new_size.0 as usize {
+                        let (src_x, src_y) = match self {
+                            Transform::Rotate(Rotation::Cw90) => (y, h - 1 - x),
+                            Transform::Rotate(Rotation::Half) => (w - 1 - x, h - 1 - y),
+                            Transform::Rotate(Rotation::Ccw90) => (w - 1 - y, x),
+                            Transform::Flip(Flip::Horizontal) => (w - 1 - x, y),
+                            _ => (x, h - 1 - y),
+                        };
+                        let idx = (src_y * w + src_x) * channels;
+                        out.extend_from_slice(&pixels[idx..idx + channels]);
+                    }
+                }
+                out
+            }
+        };
+        (out, new_size)
+    }
+}
+
+/// Rotate clockwise by `degrees` about the center into a `new_size` buffer,
+/// sampling the source with `filter`
+fn rotate_by(pixels: &[u8], size: (u32, u32), fill: &[u8], degrees: f32, filter: Filter, new_size: (u32, u32)) -> Vec<u8> {
+    let channels = fill.len();
+    let source = to_filter_space(pixels, channels);
+    let fill = to_filter_space(fill, channels);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (center_x, center_y) = (size.0 as f32 / 2.0, size.1 as f32 / 2.0);
+    let (new_center_x, new_center_y) = (new_size.0 as f32 / 2.0, new_size.1 as f32 / 2.0);
+
+    let mut out = vec![0.0f32; new_size.0 as usize * new_size.1 as usize * channels];
+    for y in 0..new_size.1 as usize {
+        for x in 0..new_size.0 as usize {
+            // Inverse rotation of the pixel center, in source pixel-center coordinates
+            let (dx, dy) = (x as f32 + 0.5 - new_center_x, y as f32 + 0.5 - new_center_y);
+            let src_x = center_x + dx * cos + dy * sin - 0.5;
+            let src_y = center_y - dx * sin + dy * cos - 0.5;
+            let idx = (y * new_size.0 as usize + x) * channels;
+            sample(&source, size, Some(&fill), (src_x, src_y), filter, &mut out[idx..idx + channels]);
+        }
+    }
+    from_filter_space(&out, channels)
+}
+
+/// Filter a `to_filter_space` buffer at a point in pixel-center coordinates
+/// into `out`. Taps outside the buffer read `fill`, or the nearest edge pixel
+/// without one. Area averaging samples like bilinear.
+pub fn sample(source: &[f32], size: (u32, u32), fill: Option<&[f32]>, point: (f32, f32), filter: Filter, out: &mut [f32]) {
+    let (w, h) = (size.0 as i64, size.1 as i64);
+    let (x, y) = point;
+    let filter = if filter == Filter::Area { Filter::Bilinear } else { filter };
+    let taps: Vec<(i64, i64, f32)> = if filter == Filter::Nearest {
+        vec![(x.round() as i64, y.round() as i64, 1.0)]
+    } else {
+        let support = filter.support();
+        let xs = (x - support).ceil() as i64..=(x + support).floor() as i64;
+        let ys = (y - support).ceil() as i64..=(y + support).floor() as i64;
+        ys.flat_map(|j| xs.clone().map(move |i| (i, j)))
+            .map(|(i, j)| (i, j, filter.weight(i as f32 - x) * filter.weight(j as f32 - y)))
+            .filter(|(_, _, weight)| *weight != 0.0)
+            .collect()
+    };
+
+    out.iter_mut().for_each(|v| *v = 0.0);
+    let mut total = 0.0;
+    for (i, j, weight) in taps {
+        let inside = i >= 0 && i < w && j >= 0 && j < h;
+        let value = match fill {
+            Some(fill) if !inside => fill,
+            _ => {
+                let idx = (j.clamp(0, h - 1) * w + i.clamp(0, w - 1)) as usize * out.len();
+                &source[idx..idx + out.len()]
+            }
+        };
+        for (o, v) in out.iter_mut().zip(value) {
+            *o += v * weight;
+        }
+        total += weight;
+    }
+    if total != 0.0 {
+        out.iter_mut().for_each(|o| *o /= total);
+    } else if let Some(fill) = fill {
+        out.copy_from_slice(fill);
+    }
+}
+
+/// A copy of `layer` with the transform applied to its pixels and mask. New
+/// pixels are transparent and revealed by the mask.
+pub fn transform_layer(layer: &Layer, transform: Transform) -> Layer {
+    let size = (layer.width, layer.height);
+    let (pixels, (width, height)) = transform.apply(&layer.pixels, size, &[0, 0, 0, 0]);
+    let mask = layer.mask.as_ref().map(|mask| transform.apply(mask, size, &[255]).0);
+    Layer { width, height, pixels, mask, ..layer.clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3x2 layer whose pixels encode their own coordinates
+    fn numbered_layer() -> Layer {
+        let mut layer = Layer::transparent("Numbered".to_string(), 3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                layer.set_pixel(x, y, [x as u8, y as u8, 0, 255]);
+            }
+        }
+        layer
+    }
+
+    fn coords(layer: &Layer) -> Vec<(u8, u8)> {
+        layer.pixels.chunks_exact(4).map(|px| (px[0], px[1])).collect()
+    }
+
+    #[test]
+    fn test_quarter_turns_and_flips() {
+        let layer = numbered_layer();
+        let cw = transform_layer(&layer, Transform::Rotate(Rotation::Cw90));
+        assert_eq!((cw.width, cw.height), (2, 3));
+        assert_eq!(coords(&cw), vec![(0, 1), (0, 0), (1, 1), (1, 0), (2, 1), (2, 0)]);
+
+        let ccw = transform_layer(&layer, Transform::Rotate(Rotation::Ccw90));
+        assert_eq!(coords(&ccw), vec![(2, 0), (2, 1), (1, 0), (1, 1), (0, 0), (0, 1)]);
+
+        let half = transform_layer(&layer, Transform::Rotate(Rotation::Half));
+        assert_eq!(coords(&half), vec![(2, 1), (1, 1), (0, 1), (2, 0), (1, 0), (0, 0)]);
+
+        let mirrored = transform_layer(&layer, Transform::Flip(Flip::Horizontal));
+        assert_eq!(coords(&mirrored), vec![(2, 0), (1, 0), (0, 0), (2, 1), (1, 1), (0, 1)]);
+        let flipped = transform_layer(&layer, Transform::Flip(Flip::Vertical));
+        assert_eq!(coords(&flipped), vec![(0, 1), (1, 1), (2, 1), (0, 0), (1, 0), (2, 0)]);
+
+        // Four quarter turns, or two flips, give back the original
+        let mut turned = layer.clone();
+        for _ in 0..4 {
+            turned = transform_layer(&turned, Transform::Rotate(Rotation::Cw90));
+        }
+        assert_eq!(turned.pixels, layer.pixels);
+        let twice = transform_layer(&mirrored, Transform::Flip(Flip::Horizontal));
+        assert_eq!(twice.pixels, layer.pixels);
+    }
+
+    #[test]
+    fn test_mask_follows_the_pixels() {
+        let mut layer = numbered_layer();
+        layer.add_mask();
+        layer.blend_mask(0, 0, 0, 255);
+        let cw = transform_layer(&layer, Transform::Rotate(Rotation::Cw90));
+        assert_eq!(cw.mask_value(1, 0), 0);
+        assert_eq!(cw.mask_value(0, 0), 255);
+    }
+
+    #[test]
+    fn test_angle_rotation_matches_quarter_turn() {
+        let layer = numbered_layer();
+        for filter in [Filter::Nearest, Filter::Bilinear, Filter::Bicubic, Filter::Lanczos3] {
+            let angle = Transform::Angle { degrees: 90.0, filter, expand: true };
+            let rotated = transform_layer(&layer, angle);
+            let cw = transform_layer(&layer, Transform::Rotate(Rotation::Cw90));
+            assert_eq!((rotated.width, rotated.height), (2, 3), "{:?}", filter);
+            assert_eq!(rotated.pixels, cw.pixels, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn test_angle_rotation_expands_or_keeps_size() {
+        let layer = Layer::new("White".to_string(), 10, 10);
+        let keep = Transform::Angle { degrees: 45.0, filter: Filter::Bilinear, expand: false };
+        let rotated = transform_layer(&layer, keep);
+        assert_eq!((rotated.width, rotated.height), (10, 10));
+        // The corners are cut off, the middle stays opaque white
+        assert_eq!(rotated.get_pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(rotated.get_pixel(5, 5), [255, 255, 255, 255]);
+
+        let grown = transform_layer(&layer, keep.expanded());
+        assert_eq!((grown.width, grown.height), (15, 15));
+        assert_eq!(grown.get_pixel(7, 3), [255, 255, 255, 255]);
+        assert_eq!(grown.get_pixel(0, 0), [0, 0, 0, 0]);
+        // Partly covered edge pixels stay white instead of fading to black
+        let edge = grown.get_pixel(3, 4);
+        assert!(edge[3] > 0 && edge[3] < 255);
+        assert_eq!(&edge[..3], &[255, 255, 255]);
+    }
+
+    #[test]
+    fn test_map_point_follows_the_pixels() {
+        let size = (3, 2);
+        let cw = Transform::Rotate(Rotation::Cw90);
+        // The top-left pixel's center ends up in the top-right pixel
+        assert_eq!(cw.map_point((0.5, 0.5), size), (1.5, 0.5));
+        assert_eq!(Transform::Flip(Flip::Vertical).map_point((1.0, 0.0), size), (1.0, 2.0));
+        let angle = Transform::Angle { degrees: 180.0, filter: Filter::Nearest, expand: false };
+        let (x, y) = angle.map_point((0.5, 0.5), size);
+        assert!((x - 2.5).abs() < 1e-4 && (y - 1.5).abs() < 1e-4);
+    }
+}